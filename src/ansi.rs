@@ -0,0 +1,250 @@
+//! Rendering task logs that contain ANSI escape sequences.
+//!
+//! ansible and make both color their output, which is unreadable as raw text.
+//! `to_html` turns the color codes into styled spans and each section of the
+//! log (the environment dumps, stdout and stderr) into a collapsible block.
+
+/// Colors for SGR foreground codes 30-37, also used for the bright 90-97.
+const COLORS: [&'static str; 8] = ["#555", "#c33", "#3a3", "#b80", "#36c", "#a3a", "#3aa", "#ccc"];
+
+const STYLE: &'static str = "body { background: #111; color: #ddd; font-family: monospace; }\n\
+                             pre { margin: 0 0 0 1em; white-space: pre-wrap; }\n\
+                             summary { cursor: pointer; font-weight: bold; margin-top: 0.5em; }\n\
+                             .bold { font-weight: bold; }";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Style {
+    color: Option<usize>,
+    bold: bool,
+}
+
+impl Style {
+    fn apply(&mut self, params: &str) {
+        // An empty parameter list is the same as a reset
+        for param in params.split(';') {
+            match param.parse::<usize>().unwrap_or(0) {
+                0 => *self = Style::default(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                39 => self.color = None,
+                n @ 30...37 => self.color = Some(n - 30),
+                n @ 90...97 => self.color = Some(n - 90),
+                _ => {}
+            }
+        }
+    }
+
+    fn open_tag(&self) -> Option<String> {
+        match (self.color, self.bold) {
+            (None, false) => None,
+            (None, true) => Some(String::from("<span class=\"bold\">")),
+            (Some(color), bold) => Some(format!("<span{} style=\"color: {}\">",
+                                                if bold { " class=\"bold\"" } else { "" },
+                                                COLORS[color])),
+        }
+    }
+}
+
+/// An escape sequence or a run of plain text in a line of output.
+enum Token<'a> {
+    Text(&'a str),
+    /// A Select Graphic Rendition sequence (`ESC [ ... m`) with its
+    /// parameters.
+    Sgr(&'a str),
+    /// Any other escape sequence, e.g. cursor movement.
+    Other,
+}
+
+fn tokenize(line: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let bytes = line.as_bytes();
+    let mut text_start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != 0x1b {
+            i += 1;
+            continue;
+        }
+        if text_start < i {
+            tokens.push(Token::Text(&line[text_start..i]));
+        }
+        if i + 1 < bytes.len() && bytes[i + 1] == b'[' {
+            // Control Sequence: parameters and intermediates up to a final
+            // byte in 0x40-0x7e
+            let params_start = i + 2;
+            let mut end = params_start;
+            while end < bytes.len() && !(bytes[end] >= 0x40 && bytes[end] <= 0x7e) {
+                end += 1;
+            }
+            if end < bytes.len() && bytes[end] == b'm' {
+                tokens.push(Token::Sgr(&line[params_start..end]));
+            } else {
+                tokens.push(Token::Other);
+            }
+            i = end + 1;
+        } else {
+            // A lone escape or a two character sequence
+            tokens.push(Token::Other);
+            i += if i + 1 < bytes.len() { 2 } else { 1 };
+        }
+        // Sequences are ascii, but a truncated one could end in the middle
+        // of a multibyte character
+        while i < bytes.len() && !line.is_char_boundary(i) {
+            i += 1;
+        }
+        text_start = i;
+    }
+    if text_start < bytes.len() {
+        tokens.push(Token::Text(&line[text_start..]));
+    }
+    tokens
+}
+
+/// Remove every escape sequence from `s`.
+pub fn strip(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for token in tokenize(s) {
+        if let Token::Text(text) = token {
+            out.push_str(text);
+        }
+    }
+    out
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;").replace("\"", "&quot;")
+}
+
+/// Render one line, carrying the style over from the previous line. Spans are
+/// closed at the end of every line so sections never split a span.
+fn render_line(line: &str, style: &mut Style) -> String {
+    let mut out = String::new();
+    let mut open = false;
+    if let Some(tag) = style.open_tag() {
+        out.push_str(&tag);
+        open = true;
+    }
+    for token in tokenize(line) {
+        match token {
+            Token::Text(text) => out.push_str(&escape_html(text)),
+            Token::Sgr(params) => {
+                let previous = *style;
+                style.apply(params);
+                if *style == previous {
+                    continue;
+                }
+                if open {
+                    out.push_str("</span>");
+                    open = false;
+                }
+                if let Some(tag) = style.open_tag() {
+                    out.push_str(&tag);
+                    open = true;
+                }
+            }
+            Token::Other => {}
+        }
+    }
+    if open {
+        out.push_str("</span>");
+    }
+    out
+}
+
+/// The title of the section a line starts, if it does. Sections are either
+/// `==title==` lines or a line underlined with dashes.
+fn section_title<'a>(line: &'a str, next: Option<&str>) -> Option<&'a str> {
+    let trimmed = line.trim();
+    if trimmed.len() > 4 && trimmed.starts_with("==") && trimmed.ends_with("==") {
+        return Some(trimmed.trim_matches('='));
+    }
+    match next {
+        Some(next) if !trimmed.is_empty() && next.len() >= 3 &&
+                      next.trim().chars().all(|c| c == '-') => Some(trimmed),
+        _ => None,
+    }
+}
+
+/// Render a task log as a standalone HTML document.
+pub fn to_html(title: &str, log: &str) -> String {
+    let mut out = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                           <title>{}</title>\n<style>\n{}\n</style>\n</head>\n<body>\n<pre>",
+                          escape_html(title),
+                          STYLE);
+    let lines = log.lines().collect::<Vec<_>>();
+    let mut style = Style::default();
+    let mut in_section = false;
+    let mut i = 0;
+    while i < lines.len() {
+        let next = lines.get(i + 1).map(|l| *l);
+        match section_title(lines[i], next) {
+            Some(title) => {
+                out.push_str("</pre>\n");
+                if in_section {
+                    out.push_str("</details>\n");
+                }
+                out.push_str(&format!("<details open>\n<summary>{}</summary>\n<pre>",
+                                      escape_html(&strip(title))));
+                in_section = true;
+                // Skip the underline too
+                let underlined = !lines[i].trim().starts_with("==");
+                style = Style::default();
+                i += if underlined { 2 } else { 1 };
+            }
+            None => {
+                out.push_str(&render_line(lines[i], &mut style));
+                out.push('\n');
+                i += 1;
+            }
+        }
+    }
+    out.push_str("</pre>\n");
+    if in_section {
+        out.push_str("</details>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip() {
+        assert_eq!(strip("\x1b[0;32mok: [localhost]\x1b[0m"), "ok: [localhost]");
+        assert_eq!(strip("\x1b[2Kprogress\x1b[1A"), "progress");
+        assert_eq!(strip("no escapes here"), "no escapes here");
+        assert_eq!(strip("trailing \x1b["), "trailing ");
+        assert_eq!(strip("ünïcödé \x1b[31mred\x1b[39m"), "ünïcödé red");
+    }
+
+    #[test]
+    fn test_to_html_colors() {
+        let html = to_html("task", "\x1b[31mfailed: <host>\x1b[0m done");
+        assert!(html.contains("<span style=\"color: #c33\">failed: &lt;host&gt;</span> done"));
+    }
+
+    #[test]
+    fn test_to_html_style_spans_lines() {
+        let html = to_html("task", "\x1b[1;32mfirst\nsecond\x1b[0m\nthird");
+        assert!(html.contains("<span class=\"bold\" style=\"color: #3a3\">first</span>\n"));
+        assert!(html.contains("<span class=\"bold\" style=\"color: #3a3\">second</span>\n"));
+        assert!(html.contains("\nthird\n"));
+    }
+
+    #[test]
+    fn test_to_html_sections() {
+        let log = "started\nhookshot environment:\n---------------------\nkey: value\n\
+                   \n==stdout==\nout\n\n==stderr==\nerr\n";
+        let html = to_html("task", log);
+        assert!(html.contains("<summary>hookshot environment:</summary>\n<pre>key: value\n"));
+        assert!(html.contains("<summary>stdout</summary>\n<pre>out\n"));
+        assert!(html.contains("<summary>stderr</summary>\n<pre>err\n"));
+        assert!(!html.contains("------"));
+        assert_eq!(html.matches("<details open>").count(), 3);
+        assert_eq!(html.matches("</details>").count(), 3);
+        // Sections follow each other, they aren't nested
+        assert!(html.contains("out\n\n</pre>\n</details>\n<details open>\n<summary>stderr"));
+    }
+}
@@ -1,15 +1,56 @@
+use ansi;
 use error::CommandError;
-use rustc_serialize::json;
+use process::ProcessGroups;
 use server_config::Environment;
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+
+// Only needed to run ansible-playbook
+#[cfg(feature = "ansible")]
+use libc;
+#[cfg(feature = "ansible")]
+use process::CommandLine;
+#[cfg(feature = "ansible")]
+use rustc_serialize::json;
+#[cfg(feature = "ansible")]
 use std::ascii::AsciiExt;
-use std::process::{Command, Output};
+#[cfg(feature = "ansible")]
+use std::ffi::CString;
+#[cfg(feature = "ansible")]
+use std::fs::{self, OpenOptions};
+#[cfg(feature = "ansible")]
+use std::io::{self, Write};
+#[cfg(feature = "ansible")]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(feature = "ansible")]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(feature = "ansible")]
+use std::thread::{self, JoinHandle};
+#[cfg(feature = "ansible")]
+use tempdir::TempDir;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AnsibleTask<'a> {
     pub playbook: String,
     pub inventory: String,
     pub project_root: &'a Path,
+    /// Run with `--check --diff`, reporting what would change without
+    /// changing anything.
+    pub check: bool,
+    /// The environment variable with the vault password. It is handed to
+    /// ansible through `--vault-password-file` instead of as a variable.
+    pub vault_password: Option<String>,
+    /// A file in the project with the vault password, or a script printing
+    /// it, relative to the directory ansible runs in. Handed to ansible
+    /// through `--vault-password-file` as it is.
+    pub vault_password_file: Option<String>,
+    /// Subdirectory of the project to run from, with the playbook and
+    /// inventory relative to it. The project root unless set.
+    pub workdir: Option<PathBuf>,
+    /// Extra arguments for ansible-playbook, like `--limit web`, passed as
+    /// they are before the inventory and playbook.
+    pub args: Vec<String>,
 }
 
 impl<'a> AnsibleTask<'a> {
@@ -18,13 +59,59 @@ impl<'a> AnsibleTask<'a> {
             playbook: playbook,
             inventory: inventory,
             project_root: project_root,
+            check: false,
+            vault_password: None,
+            vault_password_file: None,
+            workdir: None,
+            args: vec![],
+        }
+    }
+
+    /// The directory ansible-playbook runs in.
+    pub fn dir(&self) -> PathBuf {
+        match self.workdir {
+            Some(ref workdir) => self.project_root.join(workdir),
+            None => self.project_root.to_path_buf(),
         }
     }
 
-    pub fn run(&self, env: &Environment) -> Result<Output, CommandError> {
-        let mut command = Command::new("ansible-playbook");
-        command.current_dir(&self.project_root);
-        for (k, v) in env {
+    #[cfg(feature = "ansible")]
+    pub fn run(&self, env: &Environment, groups: &ProcessGroups) -> Result<Output, CommandError> {
+        let mut command = CommandLine::new("ansible-playbook");
+        command.current_dir(self.dir());
+
+        let mut env = env.clone();
+        // Kept until ansible is done with it
+        let _vault_fifo = match self.vault_password {
+            None => None,
+            Some(ref key) => {
+                let password = match env.remove(key) {
+                    Some(password) => password,
+                    None => return Err(CommandError {
+                        desc: "vault password is not set in the environment",
+                        output: None,
+                        detail: Some(key.clone()),
+                    }),
+                };
+                let fifo = match VaultPasswordFifo::new(password) {
+                    Ok(fifo) => fifo,
+                    Err(e) => return Err(CommandError {
+                        desc: "could not create vault password file, see detail",
+                        output: None,
+                        detail: Some(format!("{}", e)),
+                    }),
+                };
+                command.arg("--vault-password-file");
+                command.arg(fifo.path());
+                Some(fifo)
+            }
+        };
+        if let Some(ref file) = self.vault_password_file {
+            command.arg("--vault-password-file");
+            command.arg(file);
+        }
+
+        for (k, v) in &env {
             let uppercase_key = k.chars().map(|c| c.to_ascii_uppercase()).collect::<String>();
             command.env(uppercase_key, v);
             command.arg("-e");
@@ -32,10 +119,17 @@ impl<'a> AnsibleTask<'a> {
             // use as a quoted command line variable.
             command.arg(format!("{}={}", k, json::encode(v).unwrap()));
         }
+        if self.check {
+            command.arg("--check");
+            command.arg("--diff");
+        }
+        for arg in &self.args {
+            command.arg(arg);
+        }
         command.arg("-i");
         command.arg(&self.inventory);
         command.arg(&self.playbook);
-        match command.output() {
+        match groups.output(&command) {
             Ok(r) => Ok(r),
             Err(e) => return Err(CommandError {
                 desc: "failed to execute `ansible-playbook`, see detail",
@@ -44,51 +138,299 @@ impl<'a> AnsibleTask<'a> {
             }),
         }
     }
+
+    /// Repo configs can't pick ansible in a build without it, see
+    /// `repo_config::Error::AnsibleNotSupported`.
+    #[cfg(not(feature = "ansible"))]
+    pub fn run(&self, _: &Environment, _: &ProcessGroups) -> Result<Output, CommandError> {
+        Err(CommandError {
+            desc: "hookshot was built without the `ansible` feature",
+            output: None,
+            detail: None,
+        })
+    }
+}
+
+/// One host's line from the `PLAY RECAP` ansible-playbook prints at the end
+/// of a run.
+#[derive(Debug, Clone, Default, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct HostRecap {
+    pub ok: u64,
+    pub changed: u64,
+    pub unreachable: u64,
+    pub failed: u64,
+    pub skipped: u64,
+    pub rescued: u64,
+    pub ignored: u64,
+}
+
+impl HostRecap {
+    /// Whether the play got through on this host. ansible exits 0 in some
+    /// cases where hosts couldn't be reached, this is what catches them.
+    pub fn is_healthy(&self) -> bool {
+        self.unreachable == 0 && self.failed == 0
+    }
+}
+
+/// The per-host results of the last `PLAY RECAP` in ansible-playbook's
+/// output, keyed by host. `None` when there is no recap, e.g. the playbook
+/// didn't parse.
+pub fn parse_recap(output: &str) -> Option<BTreeMap<String, HostRecap>> {
+    let output = ansi::strip(output);
+    let recap = match output.rfind("PLAY RECAP") {
+        Some(start) => &output[start..],
+        None => return None,
+    };
+    let mut hosts = BTreeMap::new();
+    // The first line is the `PLAY RECAP ****` header, the recap ends at the
+    // first line that isn't a host
+    for line in recap.lines().skip(1).skip_while(|line| line.trim().is_empty()) {
+        let colon = match line.find(" : ") {
+            Some(colon) => colon,
+            None => break,
+        };
+        let host = line[..colon].trim();
+        let mut counts = HostRecap::default();
+        for count in line[colon + 3..].split_whitespace() {
+            let (key, value) = match count.find('=') {
+                Some(eq) => (&count[..eq], count[eq + 1..].parse::<u64>().unwrap_or(0)),
+                None => continue,
+            };
+            match key {
+                "ok" => counts.ok = value,
+                "changed" => counts.changed = value,
+                "unreachable" => counts.unreachable = value,
+                "failed" => counts.failed = value,
+                "skipped" => counts.skipped = value,
+                "rescued" => counts.rescued = value,
+                "ignored" => counts.ignored = value,
+                _ => (),
+            }
+        }
+        hosts.insert(String::from(host), counts);
+    }
+    Some(hosts)
+}
+
+/// A named pipe in a private temporary directory that gives the vault
+/// password to the first process that reads it. The password only ever goes
+/// through the pipe, it never lands on disk. Dropping it removes the pipe.
+#[cfg(feature = "ansible")]
+struct VaultPasswordFifo {
+    path: PathBuf,
+    writer: Option<JoinHandle<()>>,
+    _dir: TempDir,
+}
+
+#[cfg(feature = "ansible")]
+impl VaultPasswordFifo {
+    fn new(password: String) -> io::Result<VaultPasswordFifo> {
+        let dir = try!(TempDir::new("hookshot-vault"));
+        try!(fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700)));
+        let path = dir.path().join("vault-password");
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Opening the pipe blocks until ansible opens it for reading
+        let writer_path = path.clone();
+        let writer = thread::spawn(move || {
+            if let Ok(mut fifo) = OpenOptions::new().write(true).open(&writer_path) {
+                let _ = fifo.write_all(format!("{}\n", password).as_bytes());
+            }
+        });
+        Ok(VaultPasswordFifo {
+            path: path,
+            writer: Some(writer),
+            _dir: dir,
+        })
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(feature = "ansible")]
+impl Drop for VaultPasswordFifo {
+    fn drop(&mut self) {
+        // If nothing read the password the writer is still waiting for a
+        // reader, open one (without blocking) to let it finish
+        let c_path = CString::new(self.path.as_os_str().as_bytes()).unwrap();
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_RDONLY | libc::O_NONBLOCK) };
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+        if fd >= 0 {
+            unsafe { libc::close(fd) };
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
-    use server_config::Environment;
-    use std::io::{self, Read};
-    use std::env;
-    use std::fs::File;
-    use std::path::{Path, PathBuf};
-    use uuid::Uuid;
-
-    fn tmpfile() -> Result<PathBuf, io::Error> {
-        Ok(try!(env::current_dir())
-               .join("tmp")
-               .join("hookshot-test-file.txt"))
+    use super::{HostRecap, parse_recap};
+
+    // Running ansible-playbook needs the `ansible` feature
+    #[cfg(feature = "ansible")]
+    mod run {
+        use super::super::*;
+
+        use process::ProcessGroups;
+        use server_config::Environment;
+        use std::io::{self, Read};
+        use std::env;
+        use std::fs::{self, File};
+        use std::path::{Path, PathBuf};
+        use uuid::Uuid;
+
+        fn tmpfile() -> Result<PathBuf, io::Error> {
+            Ok(try!(env::current_dir())
+                   .join("tmp")
+                   .join("hookshot-test-file.txt"))
+        }
+
+        #[test]
+        fn test_run_ansible_task() {
+            let test_dir = Path::new("./src/test/ansible_task");
+            let ansible = AnsibleTask {
+                playbook: String::from("playbook.yml"),
+                inventory: String::from("inventory"),
+                project_root: test_dir,
+                check: false,
+                vault_password: None,
+                vault_password_file: None,
+                workdir: None,
+                args: vec![],
+            };
+            let mut env = Environment::new();
+            let tmpfile = String::from(tmpfile().unwrap().to_str().unwrap());
+            let (uuid1, uuid2) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+            env.insert(String::from("uuid1"), uuid1.clone());
+            env.insert(String::from("uuid2"), uuid2.clone());
+            env.insert(String::from("tmpfile"), tmpfile.clone());
+            match ansible.run(&env, &ProcessGroups::new()) {
+                Ok(_) => (),
+                Err(_) => panic!("ansible task failed"),
+            }
+
+            let mut file = match File::open(tmpfile) {
+                Ok(f) => f,
+                Err(_) => panic!("could not open tmpfile"),
+            };
+            let mut contents = String::new();
+            file.read_to_string(&mut contents).unwrap();
+
+            assert_eq!(format!("{} $ {}", uuid1, uuid2), contents);
+        }
+
+        #[test]
+        fn test_vault_password_fifo() {
+            let fifo = super::super::VaultPasswordFifo::new(String::from("open sesame")).unwrap();
+            let path = fifo.path().to_path_buf();
+            let mut password = String::new();
+            File::open(&path).unwrap().read_to_string(&mut password).unwrap();
+            assert_eq!(password, "open sesame\n");
+            drop(fifo);
+            assert!(fs::metadata(&path).is_err());
+
+            // Never read, dropping it doesn't hang
+            let fifo = super::super::VaultPasswordFifo::new(String::from("open sesame")).unwrap();
+            drop(fifo);
+        }
+
+        #[test]
+        fn test_missing_vault_password() {
+            let mut ansible = AnsibleTask::new(String::from("playbook.yml"),
+                                               String::from("inventory"),
+                                               Path::new("./src/test/ansible_task"));
+            ansible.vault_password = Some(String::from("vault_password"));
+            match ansible.run(&Environment::new(), &ProcessGroups::new()) {
+                Err(e) => assert_eq!(e.detail, Some(String::from("vault_password"))),
+                Ok(_) => panic!("should not have run without the vault password"),
+            }
+        }
+
+        #[test]
+        fn test_vault_password_file() {
+            let mut ansible = AnsibleTask::new(String::from("playbook.yml"),
+                                               String::from("inventory"),
+                                               Path::new("./src/test/ansible_task"));
+            ansible.vault_password_file = Some(String::from("secrets/vault-password"));
+            let groups = ProcessGroups::recording();
+            ansible.run(&Environment::new(), &groups).unwrap();
+            assert_eq!(groups.recorded()[0].args,
+                       vec!["--vault-password-file", "secrets/vault-password", "-i", "inventory", "playbook.yml"]);
+        }
+
+        #[test]
+        fn test_workdir() {
+            let mut ansible = AnsibleTask::new(String::from("playbook.yml"),
+                                               String::from("inventory"),
+                                               Path::new("./src/test"));
+            ansible.workdir = Some(PathBuf::from("ansible_task"));
+            assert_eq!(ansible.dir(), Path::new("./src/test/ansible_task"));
+
+            let groups = ProcessGroups::recording();
+            ansible.run(&Environment::new(), &groups).unwrap();
+            assert_eq!(groups.recorded()[0].cwd.as_ref().unwrap(), &ansible.dir());
+        }
+
+        #[test]
+        fn test_args() {
+            let mut ansible = AnsibleTask::new(String::from("playbook.yml"),
+                                               String::from("inventory"),
+                                               Path::new("./src/test/ansible_task"));
+            ansible.args = vec![String::from("--limit"), String::from("web*:!web3"), String::from("--forks=10")];
+            let mut env = Environment::new();
+            env.insert(String::from("git_ref"), String::from("master"));
+            let groups = ProcessGroups::recording();
+            ansible.run(&env, &groups).unwrap();
+            assert_eq!(groups.recorded()[0].args,
+                       vec!["-e", "git_ref=\"master\"", "--limit", "web*:!web3", "--forks=10", "-i", "inventory",
+                            "playbook.yml"]);
+        }
+
+        #[test]
+        fn test_check_mode() {
+            let test_dir = Path::new("./src/test/ansible_task");
+            let mut ansible = AnsibleTask::new(String::from("playbook.yml"),
+                                               String::from("inventory"),
+                                               test_dir);
+            ansible.check = true;
+            let mut env = Environment::new();
+            let tmpfile = tmpfile().unwrap().with_file_name("hookshot-check-mode.txt");
+            env.insert(String::from("uuid1"), String::from("a"));
+            env.insert(String::from("uuid2"), String::from("b"));
+            env.insert(String::from("tmpfile"), String::from(tmpfile.to_str().unwrap()));
+            let output = match ansible.run(&env, &ProcessGroups::new()) {
+                Ok(output) => output,
+                Err(_) => panic!("ansible task failed"),
+            };
+            assert!(output.status.success());
+            // Nothing was changed
+            assert!(File::open(tmpfile).is_err());
+        }
     }
 
     #[test]
-    fn test_run_ansible_task() {
-        let test_dir = Path::new("./src/test/ansible_task");
-        let ansible = AnsibleTask {
-            playbook: String::from("playbook.yml"),
-            inventory: String::from("inventory"),
-            project_root: test_dir,
-        };
-        let mut env = Environment::new();
-        let tmpfile = String::from(tmpfile().unwrap().to_str().unwrap());
-        let (uuid1, uuid2) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
-        env.insert(String::from("uuid1"), uuid1.clone());
-        env.insert(String::from("uuid2"), uuid2.clone());
-        env.insert(String::from("tmpfile"), tmpfile.clone());
-        match ansible.run(&env) {
-            Ok(_) => (),
-            Err(_) => panic!("ansible task failed"),
-        }
-
-        let mut file = match File::open(tmpfile) {
-            Ok(f) => f,
-            Err(_) => panic!("could not open tmpfile"),
-        };
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
+    fn test_parse_recap() {
+        let output = "PLAY [all] *****\n\n\
+                      TASK [ping] *****\n\
+                      ok: [web1]\n\n\
+                      PLAY RECAP *********************************************\n\
+                      web1   : ok=3    changed=1    unreachable=0    failed=0    skipped=2\n\
+                      \x1b[0;31mweb2\x1b[0m   : ok=0    changed=0    unreachable=1    failed=0\n\
+                      \n";
+        let hosts = parse_recap(output).unwrap();
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts["web1"],
+                   HostRecap { ok: 3, changed: 1, skipped: 2, ..HostRecap::default() });
+        assert!(hosts["web1"].is_healthy());
+        assert_eq!(hosts["web2"].unreachable, 1);
+        assert!(!hosts["web2"].is_healthy());
 
-        assert_eq!(format!("{} $ {}", uuid1, uuid2), contents);
+        assert_eq!(parse_recap("ERROR! the playbook could not be found"), None);
     }
 }
@@ -0,0 +1,346 @@
+//! A client for the hookshot server API.
+//!
+//! ```no_run
+//! use hookshot::api_client::Client;
+//!
+//! let client = Client::new("http://hookshot.website.biz:1469").with_secret("s3cret");
+//! let message = r#"{"ref": "production", "refType": "branch", ...}"#;
+//! let triggered = client.trigger(message, None).unwrap();
+//! let record = client.wait(&triggered.task_id, 600).unwrap();
+//! println!("{:?}", record.status);
+//! ```
+//!
+//! Requests that need to be signed (triggering tasks, rollbacks and admin
+//! endpoints) are signed with the secret set by `with_secret`, along with an
+//! `X-Hookshot-Timestamp` so they pass the server's replay protection.
+//! The `hookshot status`, `hookshot deploy` and `hookshot logs` commands
+//! are built on this client.
+//!
+//! Requests time out like the server's own, see `with_timeouts`. Servers
+//! with a `read_token` need it set with `with_read_token`.
+
+use headers::{XCorrelationId, XHookshotTimestamp, XSignature};
+use health::HealthReport;
+use history::{self, TaskRecord};
+use http::{self, Request, Timeouts};
+use message::SimpleMessage;
+use percent;
+use hyper;
+use hyper::header::Location;
+use hyper::status::StatusCode;
+use replay;
+use rustc_serialize::{json, Decodable};
+use signature::{HashType, Signature};
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use workspace::CleanupReport;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request couldn't be sent or the response couldn't be read.
+    Http(hyper::Error),
+    Io(io::Error),
+    /// The response body wasn't what the endpoint should respond with.
+    Decode(json::DecoderError),
+    /// The server responded with an unexpected status. Has the response
+    /// body, which explains what went wrong.
+    Status(StatusCode, String),
+    /// A task was scheduled but the response had no `Location` to find it.
+    MissingLocation,
+}
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Http(_) => "could not make request",
+            Error::Io(_) => "could not read response",
+            Error::Decode(_) => "could not decode response",
+            Error::Status(..) => "unexpected response status",
+            Error::MissingLocation => "response is missing the task location",
+        }
+    }
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Http(ref e) => write!(f, "{}: {}", self.description(), e),
+            Error::Io(ref e) => write!(f, "{}: {}", self.description(), e),
+            Error::Decode(ref e) => write!(f, "{}: {}", self.description(), e),
+            Error::Status(status, ref body) => write!(f, "{} {}: {}", self.description(), status, body),
+            Error::MissingLocation => write!(f, "{}", self.description()),
+        }
+    }
+}
+impl From<hyper::Error> for Error {
+    fn from(e: hyper::Error) -> Error {
+        Error::Http(e)
+    }
+}
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+impl From<json::DecoderError> for Error {
+    fn from(e: json::DecoderError) -> Error {
+        Error::Decode(e)
+    }
+}
+
+/// A task the server accepted.
+#[derive(Debug, Clone)]
+pub struct Triggered {
+    /// The task id or, if one was given, the correlation id.
+    pub task_id: String,
+    /// Where to find the task log or status.
+    pub location: String,
+    /// The task record, if the server waited for the task to finish.
+    pub record: Option<TaskRecord>,
+}
+
+pub struct Client {
+    base_url: String,
+    secret: Option<String>,
+    read_token: Option<String>,
+    timeouts: Timeouts,
+}
+
+impl Client {
+    /// A client for the server at `base_url`, e.g.
+    /// `http://hookshot.website.biz:1469`.
+    pub fn new(base_url: &str) -> Client {
+        Client {
+            base_url: String::from(base_url.trim_right_matches('/')),
+            secret: None,
+            read_token: None,
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    /// Sign requests with `secret`, the server's `config.secret`.
+    pub fn with_secret(mut self, secret: &str) -> Client {
+        self.secret = Some(String::from(secret));
+        self
+    }
+
+    /// Send `token`, the server's `config.read_token`, with every request.
+    pub fn with_read_token(mut self, token: &str) -> Client {
+        self.read_token = Some(String::from(token));
+        self
+    }
+
+    /// Use other timeouts. Requests that ask the server to wait get that
+    /// much longer on top.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Client {
+        self.timeouts = timeouts;
+        self
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    fn sign(&self, request: Request, body: &str) -> Request {
+        match self.secret {
+            Some(ref secret) => {
+                let timestamp = history::now().to_string();
+                let signed = replay::signed_data(&timestamp, body);
+                let signature = Signature::create(HashType::SHA256, &signed, secret);
+                request.header(XSignature(signature.to_string()))
+                       .header(XHookshotTimestamp(timestamp))
+            }
+            None => request,
+        }
+    }
+
+    /// Send a request, returning the response status, location and body.
+    /// `wait` is how long the server may hold on to the request.
+    fn send(&self, mut request: Request, wait: Option<u64>) -> Result<(StatusCode, Option<String>, String), Error> {
+        // The server redirects to the status of a task that is still running,
+        // the caller decides whether to follow it
+        request.follow_redirects = false;
+        if let Some(ref token) = self.read_token {
+            request.headers.set_raw("Authorization", vec![format!("Bearer {}", token).into_bytes()]);
+        }
+        let response = try!(http::send(request, self.timeouts.extended_by(wait.unwrap_or(0))));
+        let location = response.headers.get::<Location>().map(|l| l.0.clone());
+        Ok((response.status, location, response.body))
+    }
+
+    fn get_json<T: Decodable>(&self, path: &str, wait: Option<u64>, expected: &[StatusCode]) -> Result<T, Error> {
+        let url = self.url(path);
+        let (status, _, body) = try!(self.send(Request::get(&url), wait));
+        decode_if(status, &body, expected)
+    }
+
+    fn triggered(&self, status: StatusCode, location: Option<String>, body: String) -> Result<Triggered, Error> {
+        let location = match location {
+            Some(location) => location,
+            None if status == StatusCode::Ok => String::new(),
+            None => return Err(Error::MissingLocation),
+        };
+        let record = match status {
+            StatusCode::Ok => Some(try!(json::decode::<TaskRecord>(&body))),
+            StatusCode::Accepted | StatusCode::SeeOther => None,
+            _ => return Err(Error::Status(status, body)),
+        };
+        let task_id = match record {
+            Some(ref record) => record.correlation_id.clone().unwrap_or(record.id.clone()),
+            None => match task_id_from_location(&location) {
+                Some(id) => id,
+                None => return Err(Error::MissingLocation),
+            },
+        };
+        Ok(Triggered {
+            task_id: task_id,
+            location: location,
+            record: record,
+        })
+    }
+
+    /// Send a webhook message to `POST /tasks`. With `wait` the server holds
+    /// on to the request up to that many seconds for the task to finish.
+    pub fn trigger(&self, message: &str, wait: Option<u64>) -> Result<Triggered, Error> {
+        self.trigger_with_correlation_id(message, None, wait)
+    }
+
+    /// Like `trigger`, setting the task's correlation id.
+    pub fn trigger_with_correlation_id(&self,
+                                       message: &str,
+                                       correlation_id: Option<&str>,
+                                       wait: Option<u64>)
+                                       -> Result<Triggered, Error> {
+        let url = self.url(&with_wait("/tasks", wait));
+        let mut request = self.sign(Request::post(&url, message), message);
+        if let Some(correlation_id) = correlation_id {
+            request = request.header(XCorrelationId(String::from(correlation_id)));
+        }
+        let (status, location, body) = try!(self.send(request, wait));
+        self.triggered(status, location, body)
+    }
+
+    /// Deploy a ref with a simple message.
+    pub fn deploy(&self, message: &SimpleMessage, wait: Option<u64>) -> Result<Triggered, Error> {
+        self.trigger(&json::encode(message).unwrap(), wait)
+    }
+
+    /// Redeploy the last successful sha of a ref.
+    pub fn rollback(&self, owner: &str, repo: &str, refstring: &str, wait: Option<u64>) -> Result<Triggered, Error> {
+        let path = ref_path("rollback", owner, repo, refstring);
+        let url = self.url(&with_wait(&path, wait));
+        let (status, location, body) = try!(self.send(self.sign(Request::post(&url, ""), ""), wait));
+        self.triggered(status, location, body)
+    }
+
+    /// The record of a task, by task id or correlation id.
+    pub fn status(&self, id: &str) -> Result<TaskRecord, Error> {
+        self.get_json(&format!("/tasks/{}/status", id), None, &[StatusCode::Ok])
+    }
+
+    /// The records of many tasks at once. Ids without a task map to `None`.
+    pub fn batch_status(&self, ids: &[String]) -> Result<BTreeMap<String, Option<TaskRecord>>, Error> {
+        let body = json::encode(&ids).unwrap();
+        let url = self.url("/tasks/status");
+        let (status, _, body) = try!(self.send(Request::post(&url, &body), None));
+        decode_if(status, &body, &[StatusCode::Ok])
+    }
+
+    /// Wait up to `timeout` seconds for a task to finish. The record is
+    /// returned either way, check its status to see whether it finished.
+    pub fn wait(&self, id: &str, timeout: u64) -> Result<TaskRecord, Error> {
+        self.get_json(&format!("/tasks/{}/wait?timeout={}", id, timeout),
+                      Some(timeout),
+                      &[StatusCode::Ok, StatusCode::Accepted])
+    }
+
+    /// The task log.
+    pub fn log(&self, id: &str) -> Result<String, Error> {
+        let url = self.url(&format!("/tasks/{}", id));
+        match try!(self.send(Request::get(&url), None)) {
+            (StatusCode::Ok, _, body) => Ok(body),
+            (status, _, body) => Err(Error::Status(status, body)),
+        }
+    }
+
+    /// Every task for a ref, oldest first.
+    pub fn history(&self, owner: &str, repo: &str, refstring: &str) -> Result<Vec<TaskRecord>, Error> {
+        self.get_json(&ref_path("history", owner, repo, refstring), None, &[StatusCode::Ok])
+    }
+
+    /// The health report. Unhealthy servers respond with a `503`, which is
+    /// still a report rather than an error.
+    pub fn health(&self) -> Result<HealthReport, Error> {
+        self.get_json("/health", None, &[StatusCode::Ok, StatusCode::ServiceUnavailable])
+    }
+
+    /// Run the checkout cleanup now.
+    pub fn cleanup(&self) -> Result<CleanupReport, Error> {
+        let url = self.url("/admin/cleanup");
+        let (status, _, body) = try!(self.send(self.sign(Request::post(&url, ""), ""), None));
+        decode_if(status, &body, &[StatusCode::Ok])
+    }
+}
+
+fn decode_if<T: Decodable>(status: StatusCode, body: &str, expected: &[StatusCode]) -> Result<T, Error> {
+    if !expected.contains(&status) {
+        return Err(Error::Status(status, String::from(body)));
+    }
+    Ok(try!(json::decode(body)))
+}
+
+fn with_wait(path: &str, wait: Option<u64>) -> String {
+    match wait {
+        Some(seconds) => format!("{}?wait={}", path, seconds),
+        None => String::from(path),
+    }
+}
+
+/// The path of a route like `/history/:owner/:repo/:ref`, with the names
+/// percent-encoded so refs with slashes or spaces stay in their segment.
+fn ref_path(route: &str, owner: &str, repo: &str, refstring: &str) -> String {
+    format!("/{}/{}/{}/{}", route, percent::encode(owner), percent::encode(repo), percent::encode(refstring))
+}
+
+/// The task id in a task log or status url.
+fn task_id_from_location(location: &str) -> Option<String> {
+    let path = location.trim_right_matches("/status");
+    match path.rfind("/tasks/") {
+        Some(index) => {
+            let id = &path[index + "/tasks/".len()..];
+            match id.is_empty() || id.contains('/') {
+                true => None,
+                false => Some(String::from(id)),
+            }
+        }
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ref_path, task_id_from_location, with_wait};
+
+    #[test]
+    fn test_task_id_from_location() {
+        assert_eq!(task_id_from_location("http://example.org:1469/tasks/abc-123"),
+                   Some(String::from("abc-123")));
+        assert_eq!(task_id_from_location("http://example.org:1469/tasks/build-7/status"),
+                   Some(String::from("build-7")));
+        assert_eq!(task_id_from_location("http://example.org:1469/health"), None);
+        assert_eq!(task_id_from_location("http://example.org:1469/tasks/"), None);
+    }
+
+    #[test]
+    fn test_with_wait() {
+        assert_eq!(with_wait("/tasks", None), "/tasks");
+        assert_eq!(with_wait("/tasks", Some(60)), "/tasks?wait=60");
+    }
+
+    #[test]
+    fn test_ref_path() {
+        assert_eq!(ref_path("history", "owner", "repo", "master"), "/history/owner/repo/master");
+        assert_eq!(ref_path("rollback", "owner", "repo", "feature/añadir soporte"),
+                   "/rollback/owner/repo/feature%2Fa%C3%B1adir%20soporte");
+    }
+}
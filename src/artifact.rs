@@ -0,0 +1,398 @@
+//! Deploying tags from a release asset instead of a git checkout.
+//!
+//! Repos with `[repo.<owner>.<name>.artifacts."<tag pattern>"]` tables in
+//! the server config don't clone or fetch anything for matching tags. The
+//! task downloads the named asset from the tag's GitHub release, checks it
+//! against the sha256 in the release's checksum asset and unpacks it where
+//! the checkout would be. The `.hookshot.conf` and everything the task runs
+//! come from the unpacked asset.
+//!
+//! The checksum comes from the same release, so it catches a corrupted or
+//! truncated download but not a release that was tampered with. For that an
+//! artifact can have a `public_key`: the asset then also has to come with a
+//! signature asset that `openssl dgst -sha256 -verify` accepts with that key,
+//! or the task fails before the checkout is touched.
+
+use error::CommandError;
+use git::GitRepo;
+use http::{self, Request, Timeouts};
+use hyper::header::Location;
+use hyper::status::StatusCode;
+use openssl::crypto::hash::{Hasher, Type};
+use percent;
+use rustc_serialize::{Encodable, Encoder};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::Json;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use template;
+
+pub const DEFAULT_API_URL: &'static str = "https://api.github.com";
+
+/// Time on top of the server's http timeouts for downloading an asset,
+/// which is usually a lot bigger than anything else hookshot sends or
+/// receives.
+pub const DOWNLOAD_SECONDS: u64 = 600;
+
+/// Where to find the build of the tags matching `pattern`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artifact {
+    pub pattern: String,
+    /// Name of the release asset to deploy. `{owner}`, `{repo}` and
+    /// `{refstring}` are filled in.
+    pub asset: String,
+    /// Name of the release asset with the sha256 of `asset`, in the format
+    /// `sha256sum` writes. `<asset>.sha256` unless configured.
+    pub checksum_asset: String,
+    /// PEM file with the public key the asset's signature has to check out
+    /// with. Signatures aren't checked without one.
+    pub public_key: Option<String>,
+    /// Name of the release asset with the signature of `asset`, a sha256
+    /// signature in the binary format `openssl dgst -sign` writes.
+    /// `<asset>.sig` unless configured.
+    pub signature_asset: String,
+    /// The GitHub API, for GitHub Enterprise.
+    pub api_url: String,
+}
+
+// Encoded as the table under the pattern, like it's configured.
+impl Encodable for Artifact {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_struct("Artifact", 5, |s| {
+            try!(s.emit_struct_field("asset", 0, |s| self.asset.encode(s)));
+            try!(s.emit_struct_field("checksum_asset", 1, |s| self.checksum_asset.encode(s)));
+            try!(s.emit_struct_field("public_key", 2, |s| self.public_key.encode(s)));
+            try!(s.emit_struct_field("signature_asset", 3, |s| self.signature_asset.encode(s)));
+            s.emit_struct_field("api_url", 4, |s| self.api_url.encode(s))
+        })
+    }
+}
+
+/// Whether `tag` matches `pattern`, where `*` matches anything.
+fn matches(pattern: &str, tag: &str) -> bool {
+    let parts = pattern.split('*').collect::<Vec<&str>>();
+    if parts.len() == 1 {
+        return pattern == tag;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !tag.starts_with(first) || tag.len() < first.len() + last.len() {
+        return false;
+    }
+    let mut rest = &tag[first.len()..];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// The artifact for `tag`: the one configured for exactly that tag, or else
+/// the one with the longest matching pattern.
+pub fn for_tag<'a>(artifacts: &'a [Artifact], tag: &str) -> Option<&'a Artifact> {
+    if let Some(artifact) = artifacts.iter().find(|artifact| artifact.pattern == tag) {
+        return Some(artifact);
+    }
+    artifacts.iter()
+             .filter(|artifact| matches(&artifact.pattern, tag))
+             .max_by_key(|artifact| artifact.pattern.len())
+}
+
+fn fetch_error(desc: &'static str, detail: String) -> CommandError {
+    CommandError {
+        desc: desc,
+        output: None,
+        detail: Some(detail),
+    }
+}
+
+/// The sha256 for `asset` in a checksum file: the line naming it, or the
+/// first line if no line does, like a file with only the checksum in it.
+fn expected_checksum(checksums: &str, asset: &str) -> Option<String> {
+    let line = checksums.lines()
+                        .find(|line| line.split_whitespace().nth(1).map(|name| name.trim_left_matches('*')) == Some(asset))
+                        .or(checksums.lines().next());
+    match line.and_then(|line| line.split_whitespace().next()) {
+        Some(sum) if sum.len() == 64 && sum.chars().all(|c| c.is_digit(16)) => Some(sum.to_lowercase()),
+        _ => None,
+    }
+}
+
+fn sha256(path: &Path) -> io::Result<String> {
+    let mut file = try!(File::open(path));
+    let mut hasher = Hasher::new(Type::SHA256);
+    try!(io::copy(&mut file, &mut hasher));
+    Ok(hasher.finish().to_hex())
+}
+
+impl Artifact {
+    fn names(&self, repo: &GitRepo) -> (String, String, String) {
+        let vars = [("owner", &repo.owner[..]), ("repo", &repo.name[..]), ("refstring", &repo.refstring[..])];
+        (template::expand(&self.asset, &vars, template::verbatim),
+         template::expand(&self.checksum_asset, &vars, template::verbatim),
+         template::expand(&self.signature_asset, &vars, template::verbatim))
+    }
+
+    /// A GitHub API request, with the repo's token if it has one.
+    fn request(&self, url: &str, token: Option<&String>, accept: &str) -> Request {
+        let mut request = Request::get(url);
+        request.headers.set_raw("User-Agent", vec![b"hookshot".to_vec()]);
+        request.headers.set_raw("Accept", vec![accept.as_bytes().to_vec()]);
+        if let Some(token) = token {
+            request.headers.set_raw("Authorization", vec![format!("token {}", token).into_bytes()]);
+        }
+        request
+    }
+
+    /// Download an asset by its api url to `path`. GitHub redirects to
+    /// storage that turns away requests with the token, so the redirect is
+    /// followed without it.
+    fn download(&self, url: &str, repo: &GitRepo, path: &Path, timeouts: Timeouts) -> Result<(), CommandError> {
+        let mut request = self.request(url, repo.token.as_ref(), "application/octet-stream");
+        request.follow_redirects = false;
+        let response = try!(http::download(request, path.to_path_buf(), timeouts)
+                                .map_err(|e| fetch_error("could not download release asset", format!("{}", e))));
+        let response = match (response.status, response.headers.get::<Location>().map(|l| l.0.clone())) {
+            (StatusCode::Found, Some(location)) |
+            (StatusCode::MovedPermanently, Some(location)) |
+            (StatusCode::TemporaryRedirect, Some(location)) => {
+                let request = self.request(&location, None, "application/octet-stream");
+                try!(http::download(request, path.to_path_buf(), timeouts)
+                         .map_err(|e| fetch_error("could not download release asset", format!("{}", e))))
+            }
+            _ => response,
+        };
+        match response.status.is_success() {
+            true => Ok(()),
+            false => Err(fetch_error("could not download release asset", format!("{}: {}", response.status, response.body))),
+        }
+    }
+
+    /// Replace the checkout of `repo` with the unpacked asset for its tag.
+    pub fn fetch(&self, repo: &GitRepo, timeouts: Timeouts) -> Result<(), CommandError> {
+        let (asset, checksum_asset, signature_asset) = self.names(repo);
+        let release_url = format!("{}/repos/{}/{}/releases/tags/{}",
+                                  self.api_url.trim_right_matches('/'),
+                                  percent::encode(&repo.owner),
+                                  percent::encode(&repo.name),
+                                  percent::encode(&repo.refstring));
+        let response = try!(http::send(self.request(&release_url, repo.token.as_ref(), "application/vnd.github.v3+json"),
+                                       timeouts)
+                                .map_err(|e| fetch_error("could not look up release", format!("{}", e))));
+        if !response.status.is_success() {
+            return Err(fetch_error("could not look up release", format!("{}: {}", response.status, response.body)));
+        }
+        let release = try!(Json::from_str(&response.body)
+                               .map_err(|e| fetch_error("could not parse release", format!("{}", e))));
+        let asset_url = |name: &str| {
+            release.find("assets")
+                   .and_then(|assets| assets.as_array())
+                   .and_then(|assets| {
+                       assets.iter().find(|a| a.find("name").and_then(|n| n.as_string()) == Some(name))
+                   })
+                   .and_then(|a| a.find("url"))
+                   .and_then(|url| url.as_string())
+                   .map(String::from)
+                   .ok_or(fetch_error("release asset not found", String::from(name)))
+        };
+        let (url, checksum_url) = (try!(asset_url(&asset)), try!(asset_url(&checksum_asset)));
+        let signature_url = match self.public_key {
+            Some(_) => Some(try!(asset_url(&signature_asset))),
+            None => None,
+        };
+
+        let download_path = download_path(repo, &asset);
+        let checksum_path = download_path.with_extension("sha256");
+        let signature_path = download_path.with_extension("sig");
+        let result = self.download(&checksum_url, repo, &checksum_path, timeouts)
+                         .and_then(|_| read_checksum(&checksum_path, &asset, &checksum_asset))
+                         .and_then(|expected| {
+                             self.download(&url, repo, &download_path, timeouts.extended_by(DOWNLOAD_SECONDS))
+                                 .and_then(|_| verify(&download_path, &expected))
+                         })
+                         .and_then(|_| match (&self.public_key, &signature_url) {
+                             (&Some(ref public_key), &Some(ref signature_url)) => {
+                                 self.download(signature_url, repo, &signature_path, timeouts)
+                                     .and_then(|_| verify_signature(&download_path, &signature_path, public_key))
+                             }
+                             _ => Ok(()),
+                         })
+                         .and_then(|_| unpack(&download_path, &repo.local_path));
+        let _ = fs::remove_file(&checksum_path);
+        let _ = fs::remove_file(&signature_path);
+        let _ = fs::remove_file(&download_path);
+        result
+    }
+}
+
+/// Next to the checkout, so unpacking never has to cross filesystems.
+fn download_path(repo: &GitRepo, asset: &str) -> PathBuf {
+    let checkout = repo.local_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or(String::new());
+    let extension = match asset.rfind('.') {
+        Some(dot) => &asset[dot..],
+        None => "",
+    };
+    repo.local_path.with_file_name(format!("{}.download{}", checkout, extension))
+}
+
+fn read_checksum(path: &Path, asset: &str, checksum_asset: &str) -> Result<String, CommandError> {
+    let mut checksums = String::new();
+    try!(File::open(path)
+             .and_then(|mut file| file.read_to_string(&mut checksums))
+             .map_err(|e| fetch_error("could not read checksum asset", format!("{}", e))));
+    expected_checksum(&checksums, asset)
+        .ok_or(fetch_error("checksum asset has no sha256 for the release asset", String::from(checksum_asset)))
+}
+
+fn verify(path: &Path, expected: &str) -> Result<(), CommandError> {
+    let actual = try!(sha256(path).map_err(|e| fetch_error("could not read release asset", format!("{}", e))));
+    match actual == expected {
+        true => Ok(()),
+        false => Err(fetch_error("release asset checksum mismatch",
+                                 format!("expected {}, got {}", expected, actual))),
+    }
+}
+
+/// Check the signature of the asset at `path` with `openssl dgst`, which
+/// fails for a signature made with any other key or over other content.
+fn verify_signature(path: &Path, signature: &Path, public_key: &str) -> Result<(), CommandError> {
+    let output = try!(Command::new("openssl")
+                          .arg("dgst")
+                          .arg("-sha256")
+                          .arg("-verify")
+                          .arg(public_key)
+                          .arg("-signature")
+                          .arg(signature)
+                          .arg(path)
+                          .output()
+                          .map_err(|e| fetch_error("could not check release asset signature", format!("{}", e))));
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(CommandError {
+            desc: "release asset signature check failed",
+            output: Some(output),
+            detail: None,
+        }),
+    }
+}
+
+/// Unpack a `.zip` with `unzip` and anything else with `tar`, which works
+/// out the compression by itself, into an emptied `dir`.
+fn unpack(archive: &Path, dir: &Path) -> Result<(), CommandError> {
+    let _ = fs::remove_dir_all(dir);
+    try!(fs::create_dir_all(dir).map_err(|e| fetch_error("could not create checkout directory", format!("{}", e))));
+    let mut command = match archive.extension().and_then(|ext| ext.to_str()) {
+        Some("zip") => {
+            let mut command = Command::new("unzip");
+            command.arg("-q").arg(archive).arg("-d").arg(dir);
+            command
+        }
+        _ => {
+            let mut command = Command::new("tar");
+            command.arg("-xf").arg(archive).arg("-C").arg(dir);
+            command
+        }
+    };
+    let output = try!(command.output().map_err(|e| fetch_error("could not unpack release asset", format!("{}", e))));
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(CommandError {
+            desc: "could not unpack release asset",
+            output: Some(output),
+            detail: None,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expected_checksum, for_tag, sha256, unpack, verify_signature, Artifact, DEFAULT_API_URL};
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::process::Command;
+    use tempdir::TempDir;
+
+    fn artifact(pattern: &str) -> Artifact {
+        Artifact {
+            pattern: String::from(pattern),
+            asset: String::from("app-{refstring}.tar.gz"),
+            checksum_asset: String::from("app-{refstring}.tar.gz.sha256"),
+            public_key: None,
+            signature_asset: String::from("app-{refstring}.tar.gz.sig"),
+            api_url: String::from(DEFAULT_API_URL),
+        }
+    }
+
+    #[test]
+    fn test_for_tag() {
+        let artifacts = vec![artifact("v*"), artifact("v2.*"), artifact("v2.0.0")];
+        assert_eq!(for_tag(&artifacts, "v2.0.0").unwrap().pattern, "v2.0.0");
+        assert_eq!(for_tag(&artifacts, "v2.1.0").unwrap().pattern, "v2.*");
+        assert_eq!(for_tag(&artifacts, "v1.0.0").unwrap().pattern, "v*");
+        assert!(for_tag(&artifacts, "release-1").is_none());
+    }
+
+    #[test]
+    fn test_expected_checksum() {
+        let sum = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        assert_eq!(expected_checksum(sum, "app.tar.gz").unwrap(), sum);
+        let zeroes = (0..64).map(|_| '0').collect::<String>();
+        let listing = format!("{}  other.tar.gz\n{}  app.tar.gz\n", zeroes, sum.to_uppercase());
+        assert_eq!(expected_checksum(&listing, "app.tar.gz").unwrap(), sum);
+        assert!(expected_checksum("not a checksum", "app.tar.gz").is_none());
+    }
+
+    #[test]
+    fn test_verify_and_unpack() {
+        let dir = TempDir::new("hookshot-artifact-test").unwrap();
+        let content = dir.path().join("content");
+        fs::create_dir_all(&content).unwrap();
+        File::create(content.join(".hookshot.conf")).unwrap().write_all(b"[default]\n").unwrap();
+        let archive = dir.path().join("app.tar.gz");
+        assert!(Command::new("tar")
+                    .arg("-czf")
+                    .arg(&archive)
+                    .arg("-C")
+                    .arg(&content)
+                    .arg(".")
+                    .status()
+                    .unwrap()
+                    .success());
+        assert_eq!(sha256(&archive).unwrap().len(), 64);
+
+        let checkout = dir.path().join("checkout");
+        fs::create_dir_all(&checkout).unwrap();
+        File::create(checkout.join("stale")).unwrap();
+        unpack(&archive, &checkout).unwrap();
+        assert!(checkout.join(".hookshot.conf").is_file());
+        assert!(!checkout.join("stale").exists());
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let dir = TempDir::new("hookshot-artifact-test").unwrap();
+        let openssl = |args: &[&str]| {
+            assert!(Command::new("openssl").args(args).current_dir(dir.path()).status().unwrap().success());
+        };
+        openssl(&["genrsa", "-out", "private.pem", "2048"]);
+        openssl(&["rsa", "-in", "private.pem", "-pubout", "-out", "public.pem"]);
+        openssl(&["genrsa", "-out", "other.pem", "2048"]);
+        openssl(&["rsa", "-in", "other.pem", "-pubout", "-out", "other.pub.pem"]);
+        File::create(dir.path().join("app.tar.gz")).unwrap().write_all(b"release").unwrap();
+        openssl(&["dgst", "-sha256", "-sign", "private.pem", "-out", "app.tar.gz.sig", "app.tar.gz"]);
+
+        let asset = dir.path().join("app.tar.gz");
+        let signature = dir.path().join("app.tar.gz.sig");
+        let public_key = dir.path().join("public.pem");
+        verify_signature(&asset, &signature, &public_key.to_string_lossy()).unwrap();
+        // Signed with another key
+        let other_key = dir.path().join("other.pub.pem");
+        assert!(verify_signature(&asset, &signature, &other_key.to_string_lossy()).is_err());
+        // Tampered with after signing
+        File::create(&asset).unwrap().write_all(b"tampered").unwrap();
+        assert!(verify_signature(&asset, &signature, &public_key.to_string_lossy()).is_err());
+    }
+}
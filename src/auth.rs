@@ -0,0 +1,116 @@
+//! Token authentication for the endpoints that read task logs and records.
+//!
+//! Task logs often have environment dumps in them, so with `read_token` set
+//! every `GET` request, and `POST /tasks/status`, has to carry the token in
+//! an `Authorization: Bearer <token>` header. `/health` stays open for load
+//! balancers. Webhooks and admin endpoints are signed instead and don't need
+//! the token.
+
+use hyper::method::Method;
+use iron::status;
+use iron::{BeforeMiddleware, IronError, IronResult, Request};
+use reload::SharedConfig;
+use routes::error_response;
+use signature::constant_time_eq;
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fmt;
+
+/// Paths reachable without the token.
+const OPEN_PATHS: &'static [&'static str] = &["health"];
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl StdError for Unauthorized {
+    fn description(&self) -> &str {
+        "missing or invalid read token"
+    }
+}
+impl fmt::Display for Unauthorized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+/// Whether a request reads tasks and so needs the token.
+fn needs_token(method: &Method, path: &[&str]) -> bool {
+    match *method {
+        Method::Get | Method::Head => !(path.len() == 1 && OPEN_PATHS.contains(&path[0])),
+        Method::Post => path == ["tasks", "status"],
+        _ => false,
+    }
+}
+
+/// The token from an `Authorization: Bearer <token>` header.
+fn bearer_token(header: &str) -> Option<&str> {
+    let mut parts = header.trim().splitn(2, ' ');
+    match (parts.next(), parts.next().map(|token| token.trim())) {
+        (Some(scheme), Some(token)) if scheme.to_lowercase() == "bearer" && !token.is_empty() => Some(token),
+        _ => None,
+    }
+}
+
+/// Turns away reads without the config's `read_token`. Looks the token up
+/// for every request so a reload can change it.
+pub struct ReadAuth {
+    config: SharedConfig,
+}
+
+impl ReadAuth {
+    pub fn new(config: SharedConfig) -> ReadAuth {
+        ReadAuth { config: config }
+    }
+}
+
+impl BeforeMiddleware for ReadAuth {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let token = match self.config.read().unwrap().read_token {
+            Some(ref token) => token.clone(),
+            None => return Ok(()),
+        };
+        let path = req.url.path.iter().map(|s| &s[..]).filter(|s| !s.is_empty()).collect::<Vec<_>>();
+        if !needs_token(&req.method, &path) {
+            return Ok(());
+        }
+
+        let given = req.headers
+                       .get_raw("Authorization")
+                       .and_then(|values| values.first())
+                       .and_then(|value| ::std::str::from_utf8(value).ok())
+                       .and_then(bearer_token)
+                       .map(String::from);
+        match given {
+            Some(ref given) if constant_time_eq(given.as_bytes(), token.as_bytes()) => Ok(()),
+            _ => {
+                let mut response = error_response(status::Unauthorized, Unauthorized.description(), BTreeMap::new());
+                response.headers.set_raw("WWW-Authenticate", vec![b"Bearer".to_vec()]);
+                Err(IronError::new(Unauthorized, response))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bearer_token, needs_token};
+    use hyper::method::Method;
+
+    #[test]
+    fn test_needs_token() {
+        assert!(needs_token(&Method::Get, &["tasks", "abc"]));
+        assert!(needs_token(&Method::Get, &["metrics"]));
+        assert!(needs_token(&Method::Post, &["tasks", "status"]));
+        assert!(!needs_token(&Method::Get, &["health"]));
+        assert!(!needs_token(&Method::Post, &["tasks"]));
+        assert!(!needs_token(&Method::Post, &["admin", "reload"]));
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        assert_eq!(bearer_token("Bearer r34d"), Some("r34d"));
+        assert_eq!(bearer_token("bearer  r34d "), Some("r34d"));
+        assert_eq!(bearer_token("Basic dXNlcjpwYXNz"), None);
+        assert_eq!(bearer_token("Bearer"), None);
+    }
+}
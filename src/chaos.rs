@@ -0,0 +1,225 @@
+//! Failure injection, for checking alerting and retries against a staging
+//! hookshot.
+//!
+//! In builds with the `chaos` feature, which is off by default,
+//! `HOOKSHOT_CHAOS` makes tasks fail at set points without touching the
+//! repos they deploy. It is a comma separated list of faults:
+//!
+//! - `clone`: updating the checkout fails, without running git or
+//!   downloading the release asset
+//! - `task`: the task exits with 1 without running, so it's retried like
+//!   any other failed task
+//! - `timeout`: the task fails as if it ran out of time, without an exit
+//!   code, so it isn't retried
+//! - `notify`: every notifier responds with a 500 instead of being sent
+//!   the message
+//!
+//! A fault followed by `=owner/repo` only happens for that repo, like
+//! `HOOKSHOT_CHAOS=clone=brianloveswords/staging,notify`. Builds without
+//! the feature ignore the variable.
+
+use error::CommandError;
+use git;
+use http::Response;
+use hyper::header::Headers;
+use hyper::status::StatusCode;
+use std::env;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Output};
+
+pub const VAR: &'static str = "HOOKSHOT_CHAOS";
+
+const INJECTED: &'static str = "failure injected by HOOKSHOT_CHAOS";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Clone,
+    Task,
+    Timeout,
+    Notify,
+}
+
+impl Fault {
+    pub fn from_str(s: &str) -> Option<Fault> {
+        match s {
+            "clone" => Some(Fault::Clone),
+            "task" => Some(Fault::Task),
+            "timeout" => Some(Fault::Timeout),
+            "notify" => Some(Fault::Notify),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Fault::Clone => "clone",
+            Fault::Task => "task",
+            Fault::Timeout => "timeout",
+            Fault::Notify => "notify",
+        }
+    }
+}
+
+/// One entry of `HOOKSHOT_CHAOS`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Injection {
+    pub fault: Fault,
+    /// The repo, as `owner/name` in lowercase, `None` for every repo.
+    pub repo: Option<String>,
+}
+
+impl Injection {
+    fn applies(&self, fault: Fault, repo: &str) -> bool {
+        self.fault == fault && self.repo.as_ref().map_or(true, |own| own == repo)
+    }
+}
+
+/// Read the value of `HOOKSHOT_CHAOS`.
+pub fn parse(s: &str) -> Result<Vec<Injection>, String> {
+    let mut injections = vec![];
+    for entry in s.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let fault = match Fault::from_str(name) {
+            Some(fault) => fault,
+            None => return Err(format!("unknown fault '{}', expected clone, task, timeout or notify", name)),
+        };
+        let repo = match parts.next() {
+            None => None,
+            Some(repo) => {
+                let names = repo.split('/').collect::<Vec<&str>>();
+                if names.len() != 2 || names.iter().any(|name| name.is_empty()) {
+                    return Err(format!("'{}' isn't a repo, expected owner/name", repo));
+                }
+                Some(git::normalize_name(repo))
+            }
+        };
+        injections.push(Injection {
+            fault: fault,
+            repo: repo,
+        });
+    }
+    Ok(injections)
+}
+
+/// The faults to inject, none when `HOOKSHOT_CHAOS` isn't set or can't be
+/// read.
+#[cfg(feature = "chaos")]
+pub fn injections() -> Vec<Injection> {
+    match env::var(VAR) {
+        Ok(value) => parse(&value).unwrap_or(vec![]),
+        Err(_) => vec![],
+    }
+}
+#[cfg(not(feature = "chaos"))]
+pub fn injections() -> Vec<Injection> {
+    vec![]
+}
+
+fn applies(injections: &[Injection], fault: Fault, owner: &str, name: &str) -> bool {
+    let repo = git::normalize_name(&format!("{}/{}", owner, name));
+    injections.iter().any(|injection| injection.applies(fault, &repo))
+}
+
+/// Whether `fault` should happen to a task for the repo `owner/name`.
+pub fn injects(fault: Fault, owner: &str, name: &str) -> bool {
+    applies(&injections(), fault, owner, name)
+}
+
+/// What updating the checkout gives with `clone` injected.
+pub fn clone_error() -> CommandError {
+    CommandError {
+        desc: INJECTED,
+        output: None,
+        detail: Some(String::from("could not update the checkout")),
+    }
+}
+
+/// What running the task gives with `timeout` or `task` injected for the
+/// repo `owner/name`, `None` to run it for real.
+pub fn task_result(owner: &str, name: &str) -> Option<Result<Output, CommandError>> {
+    let injections = injections();
+    if applies(&injections, Fault::Timeout, owner, name) {
+        return Some(Err(CommandError {
+            desc: INJECTED,
+            output: None,
+            detail: Some(String::from("the task timed out")),
+        }));
+    }
+    if applies(&injections, Fault::Task, owner, name) {
+        return Some(Ok(Output {
+            // A wait status, the exit code is in the second byte
+            status: ExitStatus::from_raw(1 << 8),
+            stdout: vec![],
+            stderr: format!("{}\n", INJECTED).into_bytes(),
+        }));
+    }
+    None
+}
+
+/// What a notifier responds with `notify` injected.
+pub fn server_error() -> Response {
+    Response {
+        status: StatusCode::InternalServerError,
+        headers: Headers::new(),
+        body: String::from(INJECTED),
+    }
+}
+
+/// Log which faults will be injected, or why `HOOKSHOT_CHAOS` is ignored.
+pub fn announce() {
+    let value = match env::var(VAR) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    if !cfg!(feature = "chaos") {
+        return warn!("server", "{} is ignored, hookshot was built without the `chaos` feature", VAR);
+    }
+    match parse(&value) {
+        Ok(injections) => {
+            if injections.is_empty() {
+                return;
+            }
+            let faults = injections.iter()
+                                   .map(|injection| match injection.repo {
+                                       Some(ref repo) => format!("{} for {}", injection.fault.as_str(), repo),
+                                       None => String::from(injection.fault.as_str()),
+                                   })
+                                   .collect::<Vec<String>>();
+            warn!("server", "chaos mode, injecting failures: {}", faults.join(", "));
+        }
+        Err(e) => error!("server", "{} is ignored: {}", VAR, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fault, Injection, applies, parse};
+
+    #[test]
+    fn test_parse() {
+        let injections = parse(" clone=BrianLovesWords/Staging, notify,").unwrap();
+        assert_eq!(injections,
+                   vec![Injection {
+                            fault: Fault::Clone,
+                            repo: Some(String::from("brianloveswords/staging")),
+                        },
+                        Injection {
+                            fault: Fault::Notify,
+                            repo: None,
+                        }]);
+        assert_eq!(parse("").unwrap(), vec![]);
+        assert!(parse("clone,explode").is_err());
+        assert!(parse("task=staging").is_err());
+        assert!(parse("task=owner/").is_err());
+    }
+
+    #[test]
+    fn test_applies() {
+        let injections = parse("clone=brianloveswords/staging,notify").unwrap();
+        assert!(applies(&injections, Fault::Clone, "brianloveswords", "Staging"));
+        assert!(!applies(&injections, Fault::Clone, "brianloveswords", "website"));
+        assert!(applies(&injections, Fault::Notify, "brianloveswords", "website"));
+        assert!(!applies(&injections, Fault::Timeout, "brianloveswords", "staging"));
+    }
+}
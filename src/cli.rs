@@ -1,53 +1,42 @@
-use deploy_task::DeployTask;
+#[cfg(feature = "api_client")]
+use client_cli;
 use getopts::Options;
-use git::GitRepo;
-use iron::headers::{Connection, Location};
-use iron::modifiers::Header;
-use iron::status;
-use iron::{Iron, Request, Response};
-use message::{SimpleMessage, GitHubMessage};
-use router::Router;
-use server_config::{ServerConfig, Error, Environment};
-use signature::Signature;
+use init;
+use migrate;
+use repo_config::{self, DeployMethod, RepoConfig, Warning};
+use server::{self, Server};
+use server_config::{self, ServerConfig, Error};
 use std::env;
-use std::fmt::Display;
 use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
-use std::sync::{Arc, Mutex};
-use task_manager::TaskManager;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
 const ENV_CONFIG_KEY: &'static str = "HOOKSHOT_CONFIG";
-const ENV_INSECURE_KEY: &'static str = "HOOKSHOT_INSECURE";
-
-header! { (XHubSignature, "X-Hub-Signature") => [String] }
-header! { (XSignature, "X-Signature") => [String] }
-
-struct TaskStatusPrinter {
-    task_id: Uuid
-}
-impl TaskStatusPrinter {
-    fn print<T: AsRef<str> + Display>(&self, msg: T) {
-        println!("[{}]: {}", self.task_id, msg);
-    }
-}
-
-fn skip_signature_check() -> bool{
-    match ENV_INSECURE_KEY {
-        "true" | "t" | "1" => true,
-        _ => false,
-    }
-}
 
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [options]", program);
+    let brief = format!("Usage: {0} [options]\n       {0} init [options]\n       {0} check-config [PATH]\n       \
+                         {0} status [TASK] [options]\n       {0} deploy OWNER/REPO REF [options]\n       \
+                         {0} logs TASK [--follow] [options]\n       {0} migrate [--dry-run] [options]",
+                        program);
     print!("{}", opts.usage(&brief));
 }
 
 pub fn main() {
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
+    if args.len() > 1 && args[1] == "init" {
+        return init_main(&program, &args[2..]);
+    }
+    if args.len() > 1 && args[1] == "check-config" {
+        return check_config_main(&args[2..]);
+    }
+    if args.len() > 1 && args[1] == "migrate" {
+        return migrate_main(&program, &args[2..]);
+    }
+    if args.len() > 1 && client_main(&program, &args[1], &args[2..]) {
+        return;
+    }
 
     let mut opts = Options::new();
     opts.optopt("c", "config", "configuration file to use", "FILE");
@@ -56,254 +45,252 @@ pub fn main() {
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
-            println!("[error]: {}", f);
+            error!("cli", "{}", f);
             return print_usage(&program, opts);
         }
     };
     if matches.opt_present("h") {
         return print_usage(&program, opts);
     }
-    if skip_signature_check() {
-        println!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
-        println!("hookshot is running in insecure mode, signatures will not be checked");
-        println!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+    if server::skip_signature_check() {
+        warn!("cli", "hookshot is running in insecure mode, signatures will not be checked");
     }
     let config_file = match matches.opt_str("c") {
         Some(file) => file,
         None => {
-            println!("[warning]: missing --config option, looking up config by environment");
+            warn!("cli", "missing --config option, looking up config by environment");
             match env::var(ENV_CONFIG_KEY) {
                 Ok(file) => file,
                 Err(_) => {
-                    return println!("[error]: Could not load config from environment or command \
-                                     line.\n\nPass --config <FILE> option or set the HOOKSHOT_CONFIG \
-                                     environment variable");
+                    return error!("cli",
+                                  "Could not load config from environment or command line. Pass \
+                                   --config <FILE> option or set the HOOKSHOT_CONFIG environment \
+                                   variable");
                 }
             }
         }
     };
 
     match ServerConfig::from_file(Path::new(&config_file)) {
-        Ok(config) => start_server(config),
+        Ok(config) => Server::new(config).with_config_path(Path::new(&config_file).to_path_buf()).run(),
         Err(e) => match e {
             Error::FileOpenError | Error::FileReadError => {
-                return println!("[error]: Error opening or reading config file {}",
-                                config_file);
+                return error!("cli", "Error opening or reading config file {}", config_file);
             }
             Error::ParseError => {
-                return println!("[error]: Could not parse {}, make sure it is valid TOML",
-                                config_file);
+                return error!("cli", "Could not parse {}, make sure it is valid TOML", config_file);
             }
             _ => {
-                return println!("[error]: Could not validate file: {}", e);
+                return error!("cli", "Could not validate file: {}", e);
             }
         },
     }
 }
 
-// TODO: Note that we always send Connection: close. This is a workaround for a
-// bug in hyper: https://github.com/hyperium/hyper/issues/658 (link is to the
-// one I filed for my specific issue which links to the ticket it's a dupe
-// of). Once this is fixed we can remove the Connection::close() modifiers.
-//
-// In the meantime we should probably implement that Connection::close() thing
-// as Iron middleware, but I don't wanna look up how to do that right now.
-#[allow(unused_must_use)]
-fn start_server(config: ServerConfig) {
-    let mut router = Router::new();
-    let global_manager = Arc::new(Mutex::new(TaskManager::new(config.queue_limit)));
-
-    // Create a healthcheck endpoint.
-    router.get("/health", move |_: &mut Request| {
-        Ok(Response::with((Header(Connection::close()), status::Ok, "okay")))
-    });
-
-    // Show the status of a specific task by UUID. If there is no log file by
-    // that name or if the log file can't be read for any reason return a 404.
-    let config_clone = config.clone();
-    router.get("/tasks/:uuid", move |req: &mut Request| {
-        let file_not_found = Ok(Response::with((Header(Connection::close()),
-                                                status::NotFound,
-                                                "Not Found")));
-
-        let ref uuid = match req.extensions.get::<Router>().unwrap().find("uuid") {
-            Some(query) => query,
-            None => return file_not_found,
-        };
-
-        let logfile_path = Path::new(&config_clone.log_root.to_string())
-            .join(format!("{}.log", uuid.to_string()));
-
-        let mut file = match File::open(&logfile_path) {
-            Ok(file) => file,
-            Err(_) => return file_not_found,
-        };
-
-        let mut content = String::new();
-        if let Err(_) = file.read_to_string(&mut content) {
-            return file_not_found;
-        };
-
-        Ok(Response::with((Header(Connection::close()), status::Ok, content)))
-    });
-
-    // Create Webhook receiver endpoint
-    let shared_manager = global_manager.clone();
-    let checkout_root = config.checkout_root.to_string();
-    let config_clone = config.clone();
-
-    router.post("/tasks", move |req: &mut Request| {
-        let task_id = Uuid::new_v4();
-        let task_status = TaskStatusPrinter { task_id: task_id };
-        let log_root = &config_clone.log_root.to_string();
-
-        task_status.print("request received, processing");
-
-        let mut signature = None;
-        if !skip_signature_check() {
-            task_status.print("looking up signature");
-
-            // Get the signature from the header. We support both `X-Hub-Signature` and
-            // `X-Signature` but they both represent the same type underneath, a
-            // string. It might eventually be better to put this functionality on the
-            // Signature type itself.
-            signature = {
-                let possible_headers = (req.headers.get::<XSignature>(),
-                                        req.headers.get::<XHubSignature>());
-
-                let signature_string = match possible_headers {
-                    (Some(h), None) => h.to_string(),
-                    (None, Some(h)) => h.to_string(),
-                    (None, None) => {
-                        task_status.print("missing signature");
-                        return Ok(Response::with((Header(Connection::close()),
-                                                  status::Unauthorized,
-                                                  "missing signature")));
-                    }
-                    (Some(_), Some(_)) => {
-                        task_status.print("too many signatures");
-                        return Ok(Response::with((Header(Connection::close()),
-                                                  status::Unauthorized,
-                                                  "too many signatures")));
-                    }
-                };
+/// Run a client subcommand, returning false if `command` isn't one.
+#[cfg(feature = "api_client")]
+fn client_main(program: &str, command: &str, args: &[String]) -> bool {
+    match command {
+        "status" => client_cli::status_main(program, args),
+        "deploy" => client_cli::deploy_main(program, args),
+        "logs" => client_cli::logs_main(program, args),
+        _ => return false,
+    }
+    true
+}
 
-                match Signature::from_str(&signature_string) {
-                    Some(signature) => Some(signature),
-                    None => {
-                        task_status.print("could not parse signature");
-                        return Ok(Response::with((Header(Connection::close()),
-                                                  status::Unauthorized,
-                                                  "could not parse signature")));
-                    }
-                }
-            };
-        }
+#[cfg(not(feature = "api_client"))]
+fn client_main(_: &str, _: &str, _: &[String]) -> bool {
+    false
+}
 
-        task_status.print("loading body into string");
-        let mut payload = String::new();
-        if req.body.read_to_string(&mut payload).is_err() {
-            task_status.print("could not read body into string");
-            return Ok(Response::with((Header(Connection::close()), status::InternalServerError)));
-        }
+/// `hookshot init`: write a starter server config and `.hookshot.conf` to the
+/// current directory.
+fn init_main(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("m", "method", "deploy method, makefile (default) or ansible", "METHOD");
+    opts.optmulti("b", "branch", "branch pattern to deploy, can be repeated (default: production)", "PATTERN");
+    opts.optopt("n", "notify", "url to notify when tasks start and finish", "URL");
+    opts.optopt("t", "task", "make task to run (default: deploy)", "TASK");
+    opts.optopt("", "playbook", "ansible playbook (default: ansible/deploy.yml)", "FILE");
+    opts.optopt("", "inventory", "ansible inventory (default: ansible/inventory)", "FILE");
+    opts.optopt("s", "secret", "secret for message verification (default: random)", "SECRET");
+    opts.optopt("", "hostname", "externally accessible hostname (default: 127.0.0.1)", "HOST");
+    opts.optopt("p", "port", "port to listen on (default: 1469)", "PORT");
+    opts.optopt("", "checkout-root", "directory to store checkouts", "DIR");
+    opts.optopt("", "log-root", "directory to store logs", "DIR");
+    opts.optflag("f", "force", "overwrite existing files");
+    opts.optflag("h", "help", "print this help menu");
 
-        if !skip_signature_check() {
-            // Bail out if the signature doesn't match what we're expecting.
-            task_status.print("signature found, verifying");
-            if signature.unwrap().verify(&payload, &config_clone.secret) == false {
-                task_status.print("signature mismatch");
-                return Ok(Response::with((Header(Connection::close()),
-                                          status::Unauthorized,
-                                          "signature doesn't match")));
-            }
+    let usage = |opts: &Options| {
+        let brief = format!("Usage: {} init [options]", program);
+        print!("{}", opts.usage(&brief));
+    };
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => {
+            error!("init", "{}", f);
+            return usage(&opts);
         }
+    };
+    if matches.opt_present("h") {
+        return usage(&opts);
+    }
 
-        // Try to parse the message.
-        // TODO: we can be smarter about this. If we see the XHubSignature
-        // above, we should try to parse as a github message, otherwise go
-        // simple message.
-        task_status.print("attempting to parse message from payload");
-        let repo = match SimpleMessage::from_str(&payload) {
-            Ok(message) => GitRepo::from(message, &checkout_root),
-            Err(_) => match GitHubMessage::from_str(&payload) {
-                Ok(message) => GitRepo::from(message, &checkout_root),
-                Err(_) => {
-                    task_status.print("could not parse message");
-                    return Ok(Response::with((Header(Connection::close()),
-                                              status::BadRequest,
-                                              "could not parse message")));
-                }
-            },
+    let secret = matches.opt_str("s").unwrap_or(format!("{}{}",
+                                                        Uuid::new_v4().to_simple_string(),
+                                                        Uuid::new_v4().to_simple_string()));
+    let checkout_root = matches.opt_str("checkout-root")
+                               .or(server_config::get_default_checkout_dir())
+                               .unwrap_or(String::from("checkouts"));
+    let log_root = matches.opt_str("log-root")
+                          .or(server_config::get_default_log_dir())
+                          .unwrap_or(String::from("logs"));
+    let mut options = init::Options::new(&secret, &checkout_root, &log_root);
+
+    options.method = match matches.opt_str("m") {
+        None => DeployMethod::Makefile,
+        Some(method) => match &method[..] {
+            "makefile" | "make" => DeployMethod::Makefile,
+            "ansible" => DeployMethod::Ansible,
+            _ => return error!("init", "--method must be 'makefile' or 'ansible'"),
+        },
+    };
+    let branches = matches.opt_strs("b");
+    if !branches.is_empty() {
+        options.branches = branches;
+    }
+    options.notify_url = matches.opt_str("n");
+    if let Some(task) = matches.opt_str("t") {
+        options.task = task;
+    }
+    if let Some(playbook) = matches.opt_str("playbook") {
+        options.playbook = playbook;
+    }
+    if let Some(inventory) = matches.opt_str("inventory") {
+        options.inventory = inventory;
+    }
+    if let Some(hostname) = matches.opt_str("hostname") {
+        options.hostname = hostname;
+    }
+    if let Some(port) = matches.opt_str("p") {
+        options.port = match port.parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => return error!("init", "--port must be a port number"),
         };
+    }
 
-        let environment = match config_clone.environment_for(&repo.owner,
-                                                             &repo.name,
-                                                             &repo.refstring) {
-            Ok(environment) => environment,
-            Err(_) => {
-                task_status.print(format!("warning: error loading environment for {}, definition flawed",
-                                          repo.fully_qualified_branch()));
-                Environment::new()
+    let dir = match env::current_dir() {
+        Ok(dir) => dir,
+        Err(e) => return error!("init", "could not find the current directory: {}", e),
+    };
+    match init::run(&options, &dir, matches.opt_present("f")) {
+        Ok((written, problems)) => {
+            for path in written {
+                info!("init", "wrote {}", path.display());
             }
-        };
-
-        // Try to create the log file upfront to make sure we can report
-        // back. If we aren't able to create it we shouldn't accept the task
-        // because we will be unable to report task status.
-        let logfile_path = Path::new(log_root).join(format!("{}.log", task_id.to_string()));
-        let mut logfile = match File::create(&logfile_path) {
-            Ok(file) => file,
-            Err(e) => {
-                task_status.print(format!("could not open logfile for writing: {}", e));
-                return Ok(Response::with((Header(Connection::close()),
-                                          status::InternalServerError)));
+            for problem in problems {
+                warn!("init", "fix before deploying: {}", problem);
             }
-        };
-
-        let task = DeployTask {
-            repo: repo,
-            id: task_id,
-            env: environment,
-            host: format!("{}:{}", &config_clone.hostname, &config_clone.port),
-            logdir: config_clone.log_root.to_string(),
-            secret: config_clone.secret.clone(),
-        };
+            info!("init",
+                  "start the server with `{} --config {}`",
+                  program,
+                  init::SERVER_CONFIG_FILE);
+        }
+        Err(e) => error!("init", "{}", e),
+    }
+}
 
-        task_status.print("acquiring task manager lock");
-        {
-            let mut task_manager = shared_manager.lock().unwrap();
-            let key = task_manager.ensure_queue(task.repo.fully_qualified_branch());
+/// `hookshot check-config [PATH]`: check the `.hookshot.conf` in `PATH`, or
+/// the config at `PATH` for repos with a `config_path`, exiting with 1 if it
+/// doesn't load. Paths in the config are relative to `PATH` if it's a
+/// directory and to the current directory if it's a file.
+fn check_config_main(args: &[String]) {
+    let current_dir = match env::current_dir() {
+        Ok(dir) => dir,
+        Err(e) => return error!("check-config", "could not find the current directory: {}", e),
+    };
+    let (dir, path) = match args.get(0).map(PathBuf::from) {
+        Some(ref path) if path.is_file() => (current_dir, path.clone()),
+        Some(dir) => (dir.clone(), dir.join(repo_config::CONFIG_FILE)),
+        None => (current_dir.clone(), current_dir.join(repo_config::CONFIG_FILE)),
+    };
+    let mut contents = String::new();
+    if let Err(e) = File::open(&path).and_then(|mut file| file.read_to_string(&mut contents)) {
+        error!("check-config", "could not read {}: {}", path.display(), e);
+        ::std::process::exit(1);
+    }
 
-            task_status.print("attempting to schedule");
-            match task_manager.add_task(&key, task) {
-                Ok(_) => task_status.print("scheduled"),
-                Err(_) => {
-                    task_status.print("could not add task to queue");
-                    return Ok(Response::with((Header(Connection::close()),
-                                              status::ServiceUnavailable)));
-                }
-            }
+    let (config, warnings) = RepoConfig::lint(&contents, &dir);
+    for warning in warnings.iter() {
+        match *warning {
+            Warning::Invalid(_) => error!("check-config", "{}", warning),
+            _ => warn!("check-config", "{}", warning),
         }
-        task_status.print("releasing task manager lock");
-        task_status.print("request complete");
+    }
+    match config {
+        Some(_) => info!("check-config", "{} is valid", path.display()),
+        None => ::std::process::exit(1),
+    }
+}
 
-        logfile.write_all(b"task pending");
 
-        // TODO: probably shouldn't hardcode http://, someone might want to run
-        // this behind HTTPS someday.
-        let location = format!("http://{}:{}/tasks/{}",
-                               config_clone.hostname,
-                               config_clone.port,
-                               task_id);
-        let response_body = format!("Location: {}", location);
-        Ok(Response::with((Header(Connection::close()),
-                           Header(Location(location)),
-                           status::Accepted,
-                           response_body)))
-    });
+/// `hookshot migrate`: update the log_root and checkout_root of the server
+/// config to the current layout, see `migrate`.
+fn migrate_main(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("c", "config", "configuration file to use", "FILE");
+    opts.optflag("", "dry-run", "only print what would change");
+    opts.optflag("h", "help", "print this help menu");
 
-    println!("listening on port {}", &config.port);
-    let addr = format!("0.0.0.0:{}", &config.port);
-    Iron::new(router).http(&*addr).unwrap();
-    global_manager.lock().unwrap().shutdown();
+    let usage = |opts: &Options| {
+        let brief = format!("Usage: {} migrate [options]", program);
+        print!("{}", opts.usage(&brief));
+    };
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => {
+            error!("migrate", "{}", f);
+            return usage(&opts);
+        }
+    };
+    if matches.opt_present("h") {
+        return usage(&opts);
+    }
+    let config_file = match matches.opt_str("c").or(env::var(ENV_CONFIG_KEY).ok()) {
+        Some(file) => file,
+        None => return error!("migrate", "pass --config <FILE> or set the HOOKSHOT_CONFIG environment variable"),
+    };
+    let config = match ServerConfig::from_file(Path::new(&config_file)) {
+        Ok(config) => config,
+        Err(e) => return error!("migrate", "could not load {}: {}", config_file, e),
+    };
+
+    let dry_run = matches.opt_present("dry-run");
+    let report = match migrate::migrate(config.log_root.path(), config.checkout_root.path(), dry_run) {
+        Ok(report) => report,
+        Err(e) => {
+            error!("migrate", "{}", e);
+            ::std::process::exit(1);
+        }
+    };
+    let would = match dry_run {
+        true => "would ",
+        false => "",
+    };
+    for id in &report.records_created {
+        info!("migrate", "{}write a record for task {} from its log", would, id);
+    }
+    for id in &report.logs_skipped {
+        warn!("migrate", "the log of task {} doesn't say what it deployed, no record written", id);
+    }
+    for &(ref old, ref new) in &report.checkouts_moved {
+        info!("migrate", "{}move {} to {}", would, old.display(), new.display());
+    }
+    for id in &report.tasks_updated {
+        info!("migrate", "{}point task {} at its new checkout", would, id);
+    }
+    if report == migrate::Report::default() {
+        info!("migrate", "nothing to migrate");
+    }
 }
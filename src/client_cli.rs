@@ -0,0 +1,213 @@
+//! The client subcommands of the binary: `hookshot status`, `hookshot deploy`
+//! and `hookshot logs`.
+//!
+//! They talk to a running server with the api client, so the server url,
+//! secret and read token come from `--server`, `--secret` and `--read-token`
+//! or from the `HOOKSHOT_SERVER`, `HOOKSHOT_SECRET` and `HOOKSHOT_READ_TOKEN`
+//! environment variables. Errors are printed and exit with 1.
+
+use api_client::Client;
+use getopts::{Matches, Options};
+use history::TaskStatus;
+use message::{RefType, SimpleMessage};
+use rustc_serialize::json;
+use std::env;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::process;
+use std::thread;
+
+const ENV_SERVER_KEY: &'static str = "HOOKSHOT_SERVER";
+const ENV_SECRET_KEY: &'static str = "HOOKSHOT_SECRET";
+const ENV_READ_TOKEN_KEY: &'static str = "HOOKSHOT_READ_TOKEN";
+/// How often `hookshot logs --follow` checks for more of the log.
+const FOLLOW_INTERVAL_MS: u32 = 2000;
+
+/// Options every client command takes.
+fn client_options(opts: &mut Options) {
+    opts.optopt("", "server", "url of the hookshot server (default: $HOOKSHOT_SERVER)", "URL");
+    opts.optopt("", "secret", "secret to sign requests with (default: $HOOKSHOT_SECRET)", "SECRET");
+    opts.optopt("", "read-token", "token for reading tasks (default: $HOOKSHOT_READ_TOKEN)", "TOKEN");
+    opts.optflag("h", "help", "print this help menu");
+}
+
+/// Parse the options of a client command, exiting with its usage on
+/// `--help` or bad options.
+fn parse_options(opts: &Options, args: &[String], usage: &str, context: &str) -> Matches {
+    match opts.parse(args) {
+        Ok(ref m) if m.opt_present("h") => {
+            print!("{}", opts.usage(usage));
+            process::exit(0);
+        }
+        Ok(m) => m,
+        Err(f) => {
+            error!(context, "{}", f);
+            print!("{}", opts.usage(usage));
+            process::exit(2);
+        }
+    }
+}
+
+/// The api client for a command's options, falling back to the environment
+/// for anything that isn't on the command line.
+fn client_from(matches: &Matches) -> Result<Client, &'static str> {
+    let server = match matches.opt_str("server").or(env::var(ENV_SERVER_KEY).ok()) {
+        Some(server) => server,
+        None => return Err("missing --server option or HOOKSHOT_SERVER environment variable"),
+    };
+    let mut client = Client::new(&server);
+    if let Some(secret) = matches.opt_str("secret").or(env::var(ENV_SECRET_KEY).ok()) {
+        client = client.with_secret(&secret);
+    }
+    if let Some(token) = matches.opt_str("read-token").or(env::var(ENV_READ_TOKEN_KEY).ok()) {
+        client = client.with_read_token(&token);
+    }
+    Ok(client)
+}
+
+fn fail<E: Display>(context: &str, e: E) -> ! {
+    error!(context, "{}", e);
+    process::exit(1);
+}
+
+/// Split `owner/repo`.
+fn split_repo(repo: &str) -> Option<(&str, &str)> {
+    let parts = repo.split('/').collect::<Vec<&str>>();
+    match parts.len() == 2 && !parts[0].is_empty() && !parts[1].is_empty() {
+        true => Some((parts[0], parts[1])),
+        false => None,
+    }
+}
+
+/// The part of `log` after the first `printed` bytes.
+fn unprinted(log: &str, printed: usize) -> &str {
+    match log.len() > printed && log.is_char_boundary(printed) {
+        true => &log[printed..],
+        false => "",
+    }
+}
+
+/// `hookshot status`: the server's health report, or with a task or
+/// correlation id that task's record. Exits with 1 if the server is
+/// unhealthy.
+pub fn status_main(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    client_options(&mut opts);
+    let usage = format!("Usage: {} status [TASK] [options]", program);
+    let matches = parse_options(&opts, args, &usage, "status");
+    let client = client_from(&matches).unwrap_or_else(|e| fail("status", e));
+
+    match matches.free.get(0) {
+        Some(id) => {
+            let record = client.status(id).unwrap_or_else(|e| fail("status", e));
+            println!("{}", json::as_pretty_json(&record));
+        }
+        None => {
+            let report = client.health().unwrap_or_else(|e| fail("status", e));
+            println!("{}", json::as_pretty_json(&report));
+            if !report.healthy {
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// `hookshot deploy`: send a signed simple message for a ref and print where
+/// to find the task. With `--wait` the task record is printed instead once
+/// the task finishes, and the command exits with 1 unless it succeeded.
+pub fn deploy_main(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    client_options(&mut opts);
+    opts.optopt("", "remote", "remote to clone from (default: git@github.com:OWNER/REPO.git)", "URL");
+    opts.optopt("", "sha", "sha to deploy (default: HEAD, the tip of the ref)", "SHA");
+    opts.optflag("", "tag", "REF is a tag rather than a branch");
+    opts.optopt("w", "wait", "wait up to this many seconds for the task to finish", "SECONDS");
+    let usage = format!("Usage: {} deploy OWNER/REPO REF [options]", program);
+    let matches = parse_options(&opts, args, &usage, "deploy");
+    let client = client_from(&matches).unwrap_or_else(|e| fail("deploy", e));
+
+    let ((owner, name), refstring) = match (matches.free.get(0).and_then(|repo| split_repo(repo)),
+                                            matches.free.get(1)) {
+        (Some(repo), Some(refstring)) => (repo, refstring),
+        _ => fail("deploy", "expected OWNER/REPO and REF"),
+    };
+    // The server checks the upper limit
+    let wait = match matches.opt_str("w").map(|seconds| seconds.parse::<u64>()) {
+        Some(Ok(seconds)) => Some(seconds),
+        Some(Err(_)) => fail("deploy", "--wait must be a number of seconds"),
+        None => None,
+    };
+    let reftype = match matches.opt_present("tag") {
+        true => RefType::tag,
+        false => RefType::branch,
+    };
+    let remote = matches.opt_str("remote").unwrap_or(format!("git@github.com:{}/{}.git", owner, name));
+    let sha = matches.opt_str("sha").unwrap_or(String::from("HEAD"));
+    let message = SimpleMessage::new(owner, name, refstring, reftype, &remote, &sha);
+
+    let triggered = client.deploy(&message, wait).unwrap_or_else(|e| fail("deploy", e));
+    match triggered.record {
+        Some(record) => {
+            println!("{}", json::as_pretty_json(&record));
+            if record.status != TaskStatus::Success {
+                process::exit(1);
+            }
+        }
+        None => println!("{}", triggered.location),
+    }
+}
+
+/// `hookshot logs`: print a task log. With `--follow` keep printing what is
+/// added to it until the task finishes.
+pub fn logs_main(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    client_options(&mut opts);
+    opts.optflag("f", "follow", "keep printing the log until the task finishes");
+    let usage = format!("Usage: {} logs TASK [options]", program);
+    let matches = parse_options(&opts, args, &usage, "logs");
+    let client = client_from(&matches).unwrap_or_else(|e| fail("logs", e));
+    let id = match matches.free.get(0) {
+        Some(id) => id,
+        None => fail("logs", "expected a TASK id"),
+    };
+    let follow = matches.opt_present("f");
+
+    let mut printed = 0;
+    loop {
+        // Checked before reading the log, so everything the task writes
+        // before it finishes is in the last read
+        let finished = !follow ||
+                       client.status(id)
+                             .map(|record| record.status.is_terminal())
+                             .unwrap_or_else(|e| fail("logs", e));
+        let log = client.log(id).unwrap_or_else(|e| fail("logs", e));
+        print!("{}", unprinted(&log, printed));
+        let _ = io::stdout().flush();
+        printed = ::std::cmp::max(printed, log.len());
+        if finished {
+            break;
+        }
+        thread::sleep_ms(FOLLOW_INTERVAL_MS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split_repo, unprinted};
+
+    #[test]
+    fn test_split_repo() {
+        assert_eq!(split_repo("brianloveswords/hookshot"), Some(("brianloveswords", "hookshot")));
+        assert_eq!(split_repo("hookshot"), None);
+        assert_eq!(split_repo("brianloveswords/"), None);
+        assert_eq!(split_repo("a/b/c"), None);
+    }
+
+    #[test]
+    fn test_unprinted() {
+        assert_eq!(unprinted("one\ntwo\n", 0), "one\ntwo\n");
+        assert_eq!(unprinted("one\ntwo\n", 4), "two\n");
+        assert_eq!(unprinted("one\n", 4), "");
+        assert_eq!(unprinted("one\n", 10), "");
+    }
+}
@@ -0,0 +1,174 @@
+//! Comparing two deploys of a ref, for "what changed between these two
+//! deploys".
+//!
+//! `GET /repos/:owner/:repo/:ref/deploys/compare?from=<uuid>&to=<uuid>`
+//! puts the two task records side by side with the commits between their
+//! shas, read from the ref's checkout, how much longer or shorter the second
+//! one waited and ran, and whether its result is better or worse.
+
+use error::CommandError;
+use git::{self, Commit};
+use history::{TaskRecord, TaskStatus};
+use std::path::Path;
+
+/// Most commits listed each way, so comparing deploys far apart doesn't
+/// make for a gigantic response.
+pub const MAX_COMMITS: usize = 250;
+
+/// One of the two deploys, the parts of its record that matter here.
+#[derive(RustcEncodable, Clone, Debug, PartialEq)]
+pub struct Deploy {
+    pub id: String,
+    pub sha: String,
+    pub status: TaskStatus,
+    pub trigger: Option<String>,
+    pub is_rollback: bool,
+    pub queued_at: i64,
+    pub finished_at: Option<i64>,
+    pub wait_seconds: Option<i64>,
+    pub run_seconds: Option<i64>,
+}
+
+impl Deploy {
+    fn from_record(record: &TaskRecord) -> Deploy {
+        Deploy {
+            id: record.id.clone(),
+            sha: record.sha.clone(),
+            status: record.status,
+            trigger: record.trigger.as_ref().map(|trigger| trigger.to_string()),
+            is_rollback: record.is_rollback,
+            queued_at: record.queued_at,
+            finished_at: record.finished_at,
+            wait_seconds: record.wait_seconds,
+            run_seconds: record.run_seconds,
+        }
+    }
+}
+
+#[derive(RustcEncodable, Clone, Debug, PartialEq)]
+pub struct Comparison {
+    pub from: Deploy,
+    pub to: Deploy,
+    pub same_sha: bool,
+    /// Commits `to` deployed that `from` didn't, newest first. `None` when
+    /// they couldn't be read, see `commits_error`.
+    pub commits: Option<Vec<Commit>>,
+    /// Commits `from` deployed that `to` doesn't have, e.g. when `to` rolled
+    /// back.
+    pub reverted: Option<Vec<Commit>>,
+    /// Whether either list stops at `MAX_COMMITS`.
+    pub truncated: bool,
+    pub commits_error: Option<String>,
+    /// `to` minus `from`, for deploys that both have them.
+    pub wait_seconds_delta: Option<i64>,
+    pub run_seconds_delta: Option<i64>,
+    /// How the result changed: "fixed", "broke", "unchanged" or "changed".
+    pub result: &'static str,
+}
+
+fn delta(from: Option<i64>, to: Option<i64>) -> Option<i64> {
+    match (from, to) {
+        (Some(from), Some(to)) => Some(to - from),
+        _ => None,
+    }
+}
+
+/// The commits `to` has over `from` and the other way round, and whether
+/// either list was cut short.
+fn commit_ranges(checkout: &Path, from: &str, to: &str) -> Result<(Vec<Commit>, Vec<Commit>, bool), String> {
+    let (added, more_added) = try!(git::commit_range(checkout, from, to, MAX_COMMITS).map_err(describe));
+    let (reverted, more_reverted) = try!(git::commit_range(checkout, to, from, MAX_COMMITS).map_err(describe));
+    Ok((added, reverted, more_added || more_reverted))
+}
+
+fn describe(error: CommandError) -> String {
+    let detail = match error.output {
+        Some(output) => String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        None => error.detail.unwrap_or(String::new()),
+    };
+    format!("{}: {}", error.desc, detail)
+}
+
+/// How the result of a deploy compares to an earlier one's.
+pub fn result_change(from: TaskStatus, to: TaskStatus) -> &'static str {
+    match (from, to) {
+        (from, to) if from == to => "unchanged",
+        (TaskStatus::Failed, TaskStatus::Success) => "fixed",
+        (TaskStatus::Success, TaskStatus::Failed) => "broke",
+        _ => "changed",
+    }
+}
+
+/// Compare two deploys of the same ref. The commits come from `checkout`,
+/// which has to have both shas. Deploys recorded with a ref instead of a
+/// sha, like scheduled ones, get a `commits_error`.
+pub fn compare(from: &TaskRecord, to: &TaskRecord, checkout: &Path) -> Comparison {
+    let same_sha = from.sha == to.sha;
+    let commits = match (git::is_sha(&from.sha), git::is_sha(&to.sha)) {
+        _ if same_sha => Ok((vec![], vec![], false)),
+        (true, true) => commit_ranges(checkout, &from.sha, &to.sha),
+        _ => Err(String::from("the sha of one of the deploys isn't known, it was recorded as a ref")),
+    };
+    let (commits, reverted, truncated, commits_error) = match commits {
+        Ok((commits, reverted, truncated)) => (Some(commits), Some(reverted), truncated, None),
+        Err(e) => (None, None, false, Some(e)),
+    };
+    Comparison {
+        from: Deploy::from_record(from),
+        to: Deploy::from_record(to),
+        same_sha: same_sha,
+        commits: commits,
+        reverted: reverted,
+        truncated: truncated,
+        commits_error: commits_error,
+        wait_seconds_delta: delta(from.wait_seconds, to.wait_seconds),
+        run_seconds_delta: delta(from.run_seconds, to.run_seconds),
+        result: result_change(from.status, to.status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare, result_change};
+    use history::{TaskRecord, TaskStatus};
+    use std::path::Path;
+
+    fn record(id: &str, sha: &str, status: TaskStatus, run_seconds: i64) -> TaskRecord {
+        let mut record = TaskRecord::test(id, sha, status);
+        record.queued_at = 100;
+        record.started_at = Some(105);
+        record.finished_at = Some(105 + run_seconds);
+        record.wait_seconds = Some(5);
+        record.run_seconds = Some(run_seconds);
+        record
+    }
+
+    #[test]
+    fn test_result_change() {
+        assert_eq!(result_change(TaskStatus::Failed, TaskStatus::Success), "fixed");
+        assert_eq!(result_change(TaskStatus::Success, TaskStatus::Failed), "broke");
+        assert_eq!(result_change(TaskStatus::Success, TaskStatus::Success), "unchanged");
+        assert_eq!(result_change(TaskStatus::Success, TaskStatus::Cancelled), "changed");
+    }
+
+    #[test]
+    fn test_compare() {
+        let from = record("a", "abc123", TaskStatus::Failed, 30);
+        let to = record("b", "abc123", TaskStatus::Success, 45);
+        let comparison = compare(&from, &to, Path::new("/nonexistent"));
+        assert!(comparison.same_sha);
+        assert_eq!(comparison.commits, Some(vec![]));
+        assert_eq!(comparison.run_seconds_delta, Some(15));
+        assert_eq!(comparison.wait_seconds_delta, Some(0));
+        assert_eq!(comparison.result, "fixed");
+
+        let scheduled = record("c", "origin/master", TaskStatus::Success, 20);
+        let comparison = compare(&to, &scheduled, Path::new("/nonexistent"));
+        assert!(comparison.commits.is_none());
+        assert!(comparison.commits_error.is_some());
+        assert_eq!(comparison.run_seconds_delta, Some(-25));
+
+        let missing = record("d", "def456", TaskStatus::Success, 20);
+        assert!(compare(&to, &missing, Path::new("/nonexistent")).commits_error.is_some());
+    }
+}
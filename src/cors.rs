@@ -0,0 +1,127 @@
+//! CORS for the JSON API, so dashboards served from another origin can read
+//! task statuses and history straight from the browser.
+//!
+//! With a `[cors]` section, responses from the routes of its `groups` to
+//! requests whose `Origin` is in `allowed_origins` say that origin can read
+//! them. Preflight requests, `OPTIONS` with an
+//! `Access-Control-Request-Method`, are answered like any `OPTIONS` request
+//! for a known path and also get the allowed methods and headers. They never
+//! need the read token, browsers send it with the request that follows.
+//! Everything is looked up for every response, so a reload changes it right
+//! away.
+
+use hyper::method::Method;
+use iron::{AfterMiddleware, IronError, IronResult, Request, Response};
+use reload::SharedConfig;
+use routes::RouteGroups;
+use server_config::Cors;
+
+/// Whether requests from `origin` may read responses.
+fn allowed(cors: &Cors, origin: &str) -> bool {
+    cors.allowed_origins.iter().any(|allowed| allowed == "*" || allowed.to_lowercase() == origin.to_lowercase())
+}
+
+/// The CORS headers for a response to a request from `origin`, none if the
+/// origin isn't allowed. Preflight requests also get what they can ask for.
+fn cors_headers(cors: &Cors, origin: &str, preflight: bool) -> Vec<(&'static str, String)> {
+    if !allowed(cors, origin) {
+        return vec![];
+    }
+    let mut headers = vec![("Access-Control-Allow-Origin", String::from(origin))];
+    if preflight {
+        headers.push(("Access-Control-Allow-Methods", cors.allowed_methods.join(", ")));
+        headers.push(("Access-Control-Allow-Headers", cors.allowed_headers.join(", ")));
+        headers.push(("Access-Control-Max-Age", cors.max_age.to_string()));
+    }
+    headers
+}
+
+pub struct CorsHeaders {
+    config: SharedConfig,
+    groups: RouteGroups,
+}
+
+impl CorsHeaders {
+    pub fn new(config: SharedConfig, groups: RouteGroups) -> CorsHeaders {
+        CorsHeaders {
+            config: config,
+            groups: groups,
+        }
+    }
+
+    fn add(&self, req: &Request, response: &mut Response) {
+        let config = self.config.read().unwrap();
+        let cors = match config.cors {
+            Some(ref cors) => cors,
+            None => return,
+        };
+        let path = req.url.path.iter().map(|s| &s[..]).filter(|s| !s.is_empty()).collect::<Vec<_>>();
+        match self.groups.find(&req.method, &path) {
+            Some(group) if cors.groups.iter().any(|cors_group| cors_group == group) => (),
+            _ => return,
+        }
+
+        // Whether there are CORS headers depends on the origin, caches
+        // have to know
+        response.headers.set_raw("Vary", vec![b"Origin".to_vec()]);
+        let origin = match req.headers
+                              .get_raw("Origin")
+                              .and_then(|values| values.first())
+                              .and_then(|value| ::std::str::from_utf8(value).ok()) {
+            Some(origin) => String::from(origin),
+            None => return,
+        };
+        let preflight = req.method == Method::Options &&
+                        req.headers.get_raw("Access-Control-Request-Method").is_some();
+        for (name, value) in cors_headers(cors, &origin, preflight) {
+            response.headers.set_raw(name, vec![value.into_bytes()]);
+        }
+    }
+}
+
+impl AfterMiddleware for CorsHeaders {
+    fn after(&self, req: &mut Request, mut response: Response) -> IronResult<Response> {
+        self.add(req, &mut response);
+        Ok(response)
+    }
+
+    // Errors too, so the browser can tell a dashboard it needs the token
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        self.add(req, &mut err.response);
+        Err(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cors_headers;
+    use server_config::Cors;
+
+    fn cors_config(origins: &[&str]) -> Cors {
+        Cors {
+            allowed_origins: origins.iter().map(|origin| String::from(*origin)).collect(),
+            allowed_methods: vec![String::from("GET"), String::from("POST")],
+            allowed_headers: vec![String::from("Authorization"), String::from("Content-Type")],
+            max_age: 600,
+            groups: vec![String::from("history")],
+        }
+    }
+
+    #[test]
+    fn test_cors_headers() {
+        let cors = cors_config(&["https://dashboard.example.org"]);
+        assert_eq!(cors_headers(&cors, "https://Dashboard.example.org", false),
+                   vec![("Access-Control-Allow-Origin", String::from("https://Dashboard.example.org"))]);
+        assert!(cors_headers(&cors, "https://evil.example.org", false).is_empty());
+        assert!(cors_headers(&cors, "https://evil.example.org", true).is_empty());
+
+        assert_eq!(cors_headers(&cors, "https://dashboard.example.org", true),
+                   vec![("Access-Control-Allow-Origin", String::from("https://dashboard.example.org")),
+                        ("Access-Control-Allow-Methods", String::from("GET, POST")),
+                        ("Access-Control-Allow-Headers", String::from("Authorization, Content-Type")),
+                        ("Access-Control-Max-Age", String::from("600"))]);
+
+        let any = cors_config(&["*"]);
+        assert_eq!(cors_headers(&any, "http://localhost:3000", false).len(), 1);
+    }
+}
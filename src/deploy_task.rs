@@ -1,31 +1,163 @@
+use ansi;
+use ansible_task;
+use artifact::Artifact;
+use chaos::{self, Fault};
 use chrono::UTC;
 use chrono::duration::Duration;
+use environment::{self, Source};
+use error::CommandError;
 use git::GitRepo;
+use hook::{self, Hook};
+use history::{self, SharedHistory, StepTiming, TaskStatus, Trigger};
+use http::Timeouts;
+use maintenance::SharedMaintenance;
+use metrics::SharedMetrics;
+#[cfg(feature = "notifiers")]
 use notifier;
+use path_filter::PathFilter;
+use process::ProcessGroups;
+use quarantine::SharedQuarantine;
+use redact::Redactor;
+#[cfg(feature = "pagerduty")]
+use pagerduty;
 use repo_config::{RepoConfig, DeployMethod};
-use server_config::Environment;
+use server_config::{Environment, Project, ServerConfig};
+use std::collections::BTreeMap;
 use std::env;
-use std::error::Error;
 use std::fmt::Display;
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
 use std::io::{Write, Result};
-use std::path::Path;
-use task_manager::Runnable;
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::Instant;
+use storage::{self, SyncPolicy};
+use task_factory::TaskRegistry;
+use task_manager::{LockError, Locks, QueueKey, QueueStrategy, Runnable, TaskOutcome};
+use tempdir::TempDir;
 use users;
 use uuid::Uuid;
+use workspace::{self, CheckoutPath, Quota, QuotaAction};
 
 struct LogWriter {
-    file: File
+    path: PathBuf,
+    file: File,
+    /// Copy of the log with command output left exactly as it was captured,
+    /// and where it is.
+    raw: Option<(PathBuf, File)>,
+    strip_ansi: bool,
+    /// Masks secrets in everything written, the raw log included.
+    redactor: Redactor,
+    sync: SyncPolicy,
+    /// Whether a write failed, so only the first failure is reported.
+    failed: bool,
 }
 
 impl LogWriter {
     fn new(path: &Path) -> Result<LogWriter> {
-        Ok(LogWriter { file: try!(File::create(path)) })
+        let file = try!(storage::retry(|| File::create(path)));
+        Ok(LogWriter::with_file(path, file))
+    }
+
+    /// A writer that adds to the end of an existing log.
+    fn append(path: &Path) -> Result<LogWriter> {
+        let file = try!(storage::retry(|| OpenOptions::new().append(true).open(path)));
+        Ok(LogWriter::with_file(path, file))
+    }
+
+    fn with_file(path: &Path, file: File) -> LogWriter {
+        LogWriter {
+            path: path.to_path_buf(),
+            file: file,
+            raw: None,
+            strip_ansi: false,
+            redactor: Redactor::new(&[]),
+            sync: SyncPolicy::Never,
+            failed: false,
+        }
     }
 
-    #[allow(unused_must_use)]
     fn write<T: AsRef<str> + Display>(&mut self, msg: T) {
-        self.file.write_all(format!("{}\n", msg).as_bytes());
+        let line = self.redactor.redact(&format!("{}\n", msg));
+        self.put(&line, &line);
+    }
+
+    /// Write captured command output, stripping escape sequences from the
+    /// main log if configured to.
+    fn write_output(&mut self, output: &[u8]) {
+        let output = self.redactor.redact(&format!("{}\n", String::from_utf8_lossy(output)));
+        match self.strip_ansi {
+            true => self.put(&ansi::strip(&output), &output),
+            false => self.put(&output, &output),
+        }
+    }
+
+    /// Write to the log and the raw log, retrying on errors from network
+    /// filesystems. The task carries on when a write fails anyway, the
+    /// server log says so once.
+    fn put(&mut self, main: &str, raw: &str) {
+        let sync = self.sync == SyncPolicy::Always;
+        let path = &self.path;
+        let mut result = storage::append(&mut self.file, main.as_bytes(), sync, || {
+            OpenOptions::new().append(true).open(path)
+        });
+        if let (true, Some(&mut (ref raw_path, ref mut raw_file))) = (result.is_ok(), self.raw.as_mut()) {
+            result = storage::append(raw_file, raw.as_bytes(), sync, || {
+                OpenOptions::new().append(true).open(raw_path)
+            });
+        }
+        if let Err(e) = result {
+            if !self.failed {
+                self.failed = true;
+                warn!("logs", "could not write to {}: {}", self.path.display(), storage::describe(&e));
+            }
+        }
+    }
+}
+
+impl Drop for LogWriter {
+    #[allow(unused_must_use)]
+    fn drop(&mut self) {
+        if self.sync != SyncPolicy::Never {
+            self.file.sync_all();
+            if let Some((_, ref raw)) = self.raw {
+                raw.sync_all();
+            }
+        }
+    }
+}
+
+/// How often a task held by maintenance checks whether it can start.
+const MAINTENANCE_POLL_MS: u32 = 1000;
+
+/// A lock from the repo config a task holds until it's done, however it
+/// ends.
+struct HeldLock {
+    locks: Locks,
+    name: String,
+    task: String,
+}
+impl Drop for HeldLock {
+    fn drop(&mut self) {
+        self.locks.release(&self.name, &self.task);
+    }
+}
+
+/// The steps of a task timed so far, for `TaskRecord::steps`.
+#[derive(Default)]
+struct Steps {
+    timings: Vec<StepTiming>,
+}
+impl Steps {
+    /// Record a step that started at `started` and just ended.
+    fn done(&mut self, step: &str, started: Instant) {
+        self.timings.push(StepTiming::new(step, started.elapsed()));
+    }
+
+    fn time<T, F: FnOnce() -> T>(&mut self, step: &str, f: F) -> T {
+        let started = Instant::now();
+        let result = f();
+        self.done(step, started);
+        result
     }
 }
 
@@ -33,105 +165,853 @@ pub struct DeployTask {
     pub repo: GitRepo,
     pub id: Uuid,
     pub env: Environment,
-    pub logdir: String,
+    pub logdir: PathBuf,
     pub host: String,
     pub secret: String,
+    pub is_rollback: bool,
+    pub trigger: Trigger,
+    /// Which attempt at deploying the delivery this task is, starting at 1.
+    pub attempt: u32,
+    /// Seconds to wait before queueing the task again, once it has failed
+    /// and its ref has `retries` left. The attempt then finishes as
+    /// `Queued` instead of `Failed`.
+    pub retry_in: Option<u64>,
+    pub correlation_id: Option<String>,
+    /// Files the push changed, for refs that only deploy when certain
+    /// `paths` change. `None`, or no files, deploys regardless, except for
+    /// the task of a project, where no files means the push changed nothing
+    /// in the project's directory.
+    pub changed_files: Option<Vec<String>>,
+    /// Limit on the size of the checkout once the task is done.
+    pub quota: Option<Quota>,
+    /// Repos, as `owner/name`, whose latest task for the same ref has to
+    /// succeed before this one starts.
+    pub depends_on: Vec<String>,
+    /// Release asset to unpack instead of checking out the tag.
+    pub artifact: Option<Artifact>,
+    /// Where in the checkout to look for the repo config, in order.
+    pub config_paths: Vec<String>,
+    /// The project of a monorepo the task deploys. Its config is looked for
+    /// in, and its commands run from, the project's directory.
+    pub project: Option<Project>,
+    pub history: SharedHistory,
+    pub metrics: SharedMetrics,
+    /// Where the commands the task runs are tracked, so they can be stopped.
+    pub processes: ProcessGroups,
+    /// The task doesn't start while its repo is in maintenance.
+    pub maintenance: SharedMaintenance,
+    /// Failures are allowed while the task's repo or ref is in quarantine.
+    pub quarantine: SharedQuarantine,
+    /// The task manager's named locks, for refs with a `lock`.
+    pub locks: Locks,
+    pub pagerduty_routing_key: Option<String>,
+    /// Timeouts for the notifier and PagerDuty requests about the task.
+    pub http_timeouts: Timeouts,
+    pub strip_ansi_logs: bool,
+    pub raw_logs: bool,
+    /// When the task log is flushed to disk, see `storage`.
+    pub log_sync: SyncPolicy,
+    /// Whether the task log lists the system and hookshot environments.
+    pub log_system_environment: bool,
+    pub log_hookshot_environment: bool,
+    pub allow_env_override: Vec<String>,
+    /// Extra names of variables to mask in the task log, see `redact`.
+    pub redact: Vec<String>,
+    /// The server config's named environments, for repo configs to pick
+    /// from.
+    pub named_environments: BTreeMap<String, Environment>,
+    /// Custom deploy methods repo configs can use.
+    pub methods: TaskRegistry,
+}
+
+/// What every task shares with the server and the other tasks. Cloning gives
+/// another handle to the same state.
+#[derive(Clone)]
+pub struct Shared {
+    pub history: SharedHistory,
+    pub metrics: SharedMetrics,
+    pub processes: ProcessGroups,
+    pub maintenance: SharedMaintenance,
+    pub quarantine: SharedQuarantine,
+    pub locks: Locks,
+    pub methods: TaskRegistry,
+}
+
+impl DeployTask {
+    /// The first attempt at deploying `repo`, with everything else about the
+    /// task coming from `config`. The environment starts out empty, and the
+    /// task isn't a rollback and has no correlation id, changed files or
+    /// project; set those on the task for triggers that have them. Warming a
+    /// checkout deploys nothing, so it has nothing to wait for or unpack.
+    pub fn new(repo: GitRepo, id: Uuid, trigger: Trigger, config: &ServerConfig, shared: &Shared) -> DeployTask {
+        let settings = config.repo_settings(&repo.owner, &repo.name);
+        let quota = settings.and_then(|s| s.quota.clone());
+        let (depends_on, artifact) = match trigger {
+            Trigger::Warm => (vec![], None),
+            _ => (settings.map(|s| s.depends_on.clone()).unwrap_or(vec![]), config.artifact_for(&repo)),
+        };
+        let config_paths = config.config_paths_for(&repo.owner, &repo.name);
+        DeployTask {
+            repo: repo,
+            id: id,
+            env: Environment::new(),
+            logdir: config.log_root.path().to_path_buf(),
+            host: config.authority(),
+            secret: config.secret.clone(),
+            is_rollback: false,
+            trigger: trigger,
+            attempt: 1,
+            retry_in: None,
+            correlation_id: None,
+            changed_files: None,
+            quota: quota,
+            depends_on: depends_on,
+            artifact: artifact,
+            config_paths: config_paths,
+            project: None,
+            history: shared.history.clone(),
+            metrics: shared.metrics.clone(),
+            processes: shared.processes.clone(),
+            maintenance: shared.maintenance.clone(),
+            quarantine: shared.quarantine.clone(),
+            locks: shared.locks.clone(),
+            pagerduty_routing_key: config.pagerduty_routing_key.clone(),
+            http_timeouts: config.http_timeouts,
+            strip_ansi_logs: config.strip_ansi_logs,
+            raw_logs: config.raw_logs,
+            log_sync: config.log_sync,
+            log_system_environment: config.log_system_environment,
+            log_hookshot_environment: config.log_hookshot_environment,
+            allow_env_override: config.allow_env_override.clone(),
+            redact: config.redact.clone(),
+            named_environments: config.named_environments.clone(),
+            methods: shared.methods.clone(),
+        }
+    }
+
+    /// A copy of the task that deploys one project of a monorepo, with an
+    /// id and a checkout under `checkout_root` of its own so the projects
+    /// don't get in each other's way. A correlation id gets `.<project>`
+    /// on the end, to stay unique, and the changed files are narrowed down
+    /// to those in the project's directory.
+    pub fn for_project(&self, project: Project, checkout_root: &Path) -> DeployTask {
+        let mut repo = self.repo.clone();
+        repo.local_path = CheckoutPath::for_project(checkout_root,
+                                                    &repo.owner,
+                                                    &repo.name,
+                                                    &repo.refstring,
+                                                    &project.name)
+                              .into_path_buf();
+        DeployTask {
+            repo: repo,
+            id: Uuid::new_v4(),
+            env: self.env.clone(),
+            logdir: self.logdir.clone(),
+            host: self.host.clone(),
+            secret: self.secret.clone(),
+            is_rollback: self.is_rollback,
+            trigger: self.trigger.clone(),
+            attempt: self.attempt,
+            retry_in: None,
+            correlation_id: self.correlation_id.as_ref().map(|id| format!("{}.{}", id, project.name)),
+            changed_files: self.changed_files.as_ref().and_then(|files| match files.is_empty() {
+                true => None,
+                false => Some(project_files(files, &project.dir)),
+            }),
+            quota: self.quota.clone(),
+            depends_on: self.depends_on.clone(),
+            artifact: self.artifact.clone(),
+            config_paths: self.config_paths.clone(),
+            project: Some(project),
+            history: self.history.clone(),
+            metrics: self.metrics.clone(),
+            processes: self.processes.clone(),
+            maintenance: self.maintenance.clone(),
+            quarantine: self.quarantine.clone(),
+            locks: self.locks.clone(),
+            pagerduty_routing_key: self.pagerduty_routing_key.clone(),
+            http_timeouts: self.http_timeouts,
+            strip_ansi_logs: self.strip_ansi_logs,
+            raw_logs: self.raw_logs,
+            log_sync: self.log_sync,
+            log_system_environment: self.log_system_environment,
+            log_hookshot_environment: self.log_hookshot_environment,
+            allow_env_override: self.allow_env_override.clone(),
+            redact: self.redact.clone(),
+            named_environments: self.named_environments.clone(),
+            methods: self.methods.clone(),
+        }
+    }
+
+    /// Turn a task that failed with retries left into its next attempt,
+    /// returning how many seconds to wait before queueing it. `None` for a
+    /// task that isn't tried again.
+    pub fn next_attempt(&mut self) -> Option<u64> {
+        let delay = match self.retry_in.take() {
+            Some(delay) => delay,
+            None => return None,
+        };
+        self.attempt += 1;
+        Some(delay)
+    }
+
+    /// The queue the task goes in, see `QueueKey`.
+    pub fn queue_key(&self, strategy: QueueStrategy) -> QueueKey {
+        match self.project {
+            Some(ref project) => QueueKey::for_project(&self.repo, &project.name, strategy),
+            None => QueueKey::for_repo(&self.repo, strategy),
+        }
+    }
+
+    /// Prefix for server log lines about this task: the task id, followed by
+    /// the correlation id if there is one.
+    fn log_prefix(&self) -> String {
+        match self.correlation_id {
+            Some(ref correlation_id) => format!("{} {}", self.id, correlation_id),
+            None => self.id.to_string(),
+        }
+    }
+
+    /// Record how much space the checkout and the temporary directory use
+    /// now the task is done, and enforce the repo's quota on the checkout.
+    fn check_workspace(&self, outcome: TaskOutcome, tmp_dir: Option<&Path>) -> TaskOutcome {
+        let task_id = self.id.to_string();
+        let checkout = self.repo.local_path.as_path();
+        let checkout_bytes = workspace::dir_size(checkout);
+        let tmp_bytes = tmp_dir.map(workspace::dir_size);
+        self.history.lock().unwrap().update(&task_id, |record| {
+            record.checkout_bytes = Some(checkout_bytes);
+            record.tmp_bytes = tmp_bytes;
+        });
+
+        let quota = match self.quota {
+            Some(ref quota) if checkout_bytes > quota.bytes => quota,
+            _ => return outcome,
+        };
+        let log_id = self.log_prefix();
+        let logfile_path = self.logdir.join(format!("{}.log", task_id));
+        let mut logger = LogWriter::append(&logfile_path).ok();
+        let msg = format!("checkout is {} bytes, over the quota of {} bytes",
+                          checkout_bytes,
+                          quota.bytes);
+        match quota.action {
+            QuotaAction::Fail => {
+                if let Some(ref mut logger) = logger {
+                    logger.write(format!("{}, failing task", msg));
+                }
+                error!(&log_id, "{}, failing task", msg);
+                match outcome.status {
+                    TaskStatus::Success => TaskOutcome::failed(msg),
+                    _ => outcome,
+                }
+            }
+            QuotaAction::Clean => {
+                if let Some(ref mut logger) = logger {
+                    logger.write(format!("{}, removing checkout", msg));
+                }
+                warn!(&log_id, "{}, removing checkout", msg);
+                if let Err(e) = fs::remove_dir_all(checkout) {
+                    error!(&log_id, "could not remove checkout: {}", e);
+                }
+                outcome
+            }
+        }
+    }
+
+    /// Why the task's repo or ref is in quarantine, if it is.
+    pub fn quarantine_reason(&self) -> Option<String> {
+        let now = history::now();
+        let mut quarantine = self.quarantine.lock().unwrap();
+        quarantine.expire(now);
+        quarantine.lookup(&self.repo.owner, &self.repo.name, &self.repo.refstring, now)
+                  .map(|entry| entry.reason.clone())
+    }
+
+    /// Keep the quarantine the task finished in with its record, noting in
+    /// the task log that it allows a failure.
+    fn record_quarantine(&self, outcome: &TaskOutcome) {
+        let reason = match self.quarantine_reason() {
+            Some(reason) => reason,
+            None => return,
+        };
+        if outcome.status == TaskStatus::Failed {
+            let msg = format!("in quarantine ({}), the failure is allowed", reason);
+            let logfile_path = self.logdir.join(format!("{}.log", self.id));
+            if let Ok(mut logger) = LogWriter::append(&logfile_path) {
+                logger.write(&msg);
+            }
+            info!(&self.log_prefix(), "{}", msg);
+        }
+        self.history.lock().unwrap().update(&self.id.to_string(), |record| record.quarantine = Some(reason));
+    }
+
+    /// Hold the task while its repo is in maintenance. Returns false if
+    /// hookshot started shutting down in the meantime.
+    fn wait_for_maintenance(&self) -> bool {
+        let mut waited = false;
+        while self.maintenance.lock().unwrap().is_active(&self.repo.owner, &self.repo.name) {
+            if self.processes.is_stopped() {
+                return false;
+            }
+            if !waited {
+                info!(&self.log_prefix(), "in maintenance, waiting for it to be lifted");
+                waited = true;
+            }
+            thread::sleep_ms(MAINTENANCE_POLL_MS);
+        }
+        if waited {
+            info!(&self.log_prefix(), "maintenance lifted, starting");
+        }
+        true
+    }
+
+    /// The most recent task for the same ref of each repo the task depends
+    /// on, if there is one, as its id, its status and whether it finished in
+    /// quarantine.
+    fn dependencies(&self) -> Vec<(&str, Option<(String, TaskStatus, bool)>)> {
+        let history = self.history.lock().unwrap();
+        self.depends_on
+            .iter()
+            .map(|dependency| {
+                let mut parts = dependency.splitn(2, '/');
+                let (owner, name) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+                let latest = history.latest(owner, name, &self.repo.refstring)
+                                    .map(|record| (record.id.clone(), record.status, record.quarantine.is_some()));
+                (&dependency[..], latest)
+            })
+            .collect()
+    }
+
+    /// Fails if the most recent task for the same ref of a repo the task
+    /// depends on didn't succeed, unless it failed in quarantine. The task
+    /// manager holds the task until they have finished, see `waits_for`.
+    fn check_dependencies(&self) -> ::std::result::Result<(), String> {
+        for (dependency, latest) in self.dependencies() {
+            match latest {
+                Some((_, TaskStatus::Failed, true)) => {
+                    info!(&self.log_prefix(),
+                          "dependency {} {} failed in quarantine, carrying on",
+                          dependency,
+                          self.repo.refstring);
+                }
+                Some((_, TaskStatus::Failed, false)) | Some((_, TaskStatus::Cancelled, _)) => {
+                    return Err(format!("dependency {} {} did not succeed", dependency, self.repo.refstring));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Hold the task until it has the lock named `name`, behind any task
+    /// that asked for it first. Gives up when the task is cancelled, hookshot
+    /// starts shutting down or the task runs out of time, see `processes`.
+    fn wait_for_lock(&self, name: &str, processes: &ProcessGroups, logger: &mut LogWriter)
+                     -> ::std::result::Result<HeldLock, LockError> {
+        let task = self.id.to_string();
+        if !self.locks.try_acquire(name, &task) {
+            let holder = self.locks.snapshot().get(name).and_then(|state| state.holder.clone());
+            let msg = format!("waiting for lock {} held by task {}",
+                              name,
+                              holder.unwrap_or(String::from("<none>")));
+            logger.write(&msg);
+            info!(&self.log_prefix(), "{}", msg);
+            try!(self.locks.acquire(name,
+                                    &task,
+                                    processes.deadline(),
+                                    || processes.is_stopped() || processes.is_cancelled()));
+        }
+        logger.write(format!("lock: {}", name));
+        Ok(HeldLock {
+            locks: self.locks.clone(),
+            name: String::from(name),
+            task: task,
+        })
+    }
+
+    /// Keep the per-host results from ansible's recap with the task, warning
+    /// about hosts that were unreachable or failed even when ansible exited
+    /// successfully.
+    fn record_hosts(&self, logger: &mut LogWriter, log_id: &str, stdout: &[u8]) {
+        let hosts = match ansible_task::parse_recap(&String::from_utf8_lossy(stdout)) {
+            Some(hosts) => hosts,
+            None => return,
+        };
+        let unhealthy = hosts.iter()
+                             .filter(|&(_, recap)| !recap.is_healthy())
+                             .map(|(host, recap)| {
+                                 format!("{} (unreachable={} failed={})",
+                                         host,
+                                         recap.unreachable,
+                                         recap.failed)
+                             })
+                             .collect::<Vec<String>>();
+        if !unhealthy.is_empty() {
+            let warning = format!("hosts with problems: {}", unhealthy.join(", "));
+            logger.write(format!("\n{}", warning));
+            warn!(log_id, "{}", warning);
+        }
+        self.history.lock().unwrap().update(&self.id.to_string(), |record| record.hosts = Some(hosts));
+    }
+
+    fn set_status(&self, status: TaskStatus) {
+        let id = self.id.to_string();
+        let mut history = self.history.lock().unwrap();
+        history.set_status(&id, status);
+        if status.is_terminal() {
+            if let Some(record) = history.get(&id) {
+                self.metrics.lock().unwrap().observe(record);
+            }
+        }
+    }
+}
+/// The files of `changed_files`, which are relative to the root of the
+/// checkout, that are in the project directory `dir`.
+fn project_files(changed_files: &[String], dir: &str) -> Vec<String> {
+    let dir = Path::new(dir).components().filter(|component| *component != Component::CurDir).collect::<PathBuf>();
+    changed_files.iter().filter(|file| Path::new(file).starts_with(&dir)).cloned().collect()
+}
+
+impl Drop for DeployTask {
+    // If a worker panics mid-task the task gets dropped while unwinding, so
+    // this is where the history finds out nothing is working on it anymore.
+    fn drop(&mut self) {
+        if let Ok(mut history) = self.history.lock() {
+            history.release(&self.id.to_string());
+        }
+        self.processes.for_task(&self.id.to_string()).finish();
+    }
 }
 impl Runnable for DeployTask {
+    fn id(&self) -> Option<String> {
+        Some(self.id.to_string())
+    }
+
     fn cancel(&self) {
+        self.set_status(TaskStatus::Cancelled);
         let task_id = self.id.to_string();
-        let logfile_path = Path::new(&self.logdir).join(format!("{}.log", task_id));
+        let logfile_path = self.logdir.join(format!("{}.log", task_id));
         let mut logger = match LogWriter::new(&logfile_path) {
             Ok(logfile) => logfile,
-            Err(_) => return println!("[{}]: could not open logfile for writing", &task_id),
+            Err(_) => return error!(&task_id, "could not open logfile for writing"),
         };
         logger.write("task cancelled");
     }
 
+    /// The tasks of the repos this one depends on that are still queued or
+    /// running, so the task manager holds it until they're done. Nothing
+    /// once the task can't run anyway.
+    fn waits_for(&self) -> Vec<String> {
+        let processes = self.processes.for_task(&self.id.to_string());
+        if processes.is_stopped() || processes.is_cancelled() {
+            return vec![];
+        }
+        let mut pending = vec![];
+        for (dependency, latest) in self.dependencies() {
+            match latest {
+                Some((id, TaskStatus::Queued, _)) | Some((id, TaskStatus::Running, _)) => {
+                    info!(&self.log_prefix(), "waiting for {} {} (task {})", dependency, self.repo.refstring, id);
+                    pending.push(id);
+                }
+                _ => {}
+            }
+        }
+        pending
+    }
+
+    fn run(&mut self) -> TaskOutcome {
+        let processes = self.processes.for_task(&self.id.to_string());
+        if processes.is_cancelled() || processes.is_stopped() {
+            self.cancel();
+            return TaskOutcome::new(TaskStatus::Cancelled);
+        }
+        if !self.wait_for_maintenance() {
+            self.set_status(TaskStatus::Cancelled);
+            return TaskOutcome::new(TaskStatus::Cancelled);
+        }
+        self.set_status(TaskStatus::Running);
+        let started = Instant::now();
+
+        // A scratch directory for the task, removed when it goes out of scope
+        // at the end of the task however it ends
+        let tmp_dir = TempDir::new("hookshot-task");
+        let mut steps = Steps::default();
+        let outcome = self.deploy(&tmp_dir, &mut steps);
+        let mut outcome = self.check_workspace(outcome, tmp_dir.as_ref().ok().map(|dir| dir.path()))
+                              .with_duration(started.elapsed());
+        // Whatever the commands that were stopped made of it
+        if self.processes.for_task(&self.id.to_string()).is_cancelled() {
+            if let Ok(mut logger) = LogWriter::append(&self.logdir.join(format!("{}.log", self.id))) {
+                logger.write("task cancelled");
+            }
+            outcome.status = TaskStatus::Cancelled;
+            self.retry_in = None;
+        }
+        self.history.lock().unwrap().update(&self.id.to_string(), |record| {
+            record.error = outcome.error.clone();
+            record.steps = Some(steps.timings);
+        });
+        match self.retry_in {
+            // Only the last attempt counts, for observers too
+            Some(_) => {
+                self.set_status(TaskStatus::Queued);
+                TaskOutcome { status: TaskStatus::Queued, ..outcome }
+            }
+            None => {
+                self.record_quarantine(&outcome);
+                self.set_status(outcome.status);
+                outcome
+            }
+        }
+    }
+}
+
+impl DeployTask {
     // TODO: this is a god damn mess and seriously needs to be refactored,
     // especially all of the logging.
-    fn run(&mut self) {
+    fn deploy(&mut self, tmp_dir: &Result<TempDir>, steps: &mut Steps) -> TaskOutcome {
         let task_id = self.id.to_string();
+        let log_id = self.log_prefix();
+        let processes = self.processes.for_task(&task_id);
 
         // Insert the checkout path for the current checkout to the environment
-        self.env.insert("hookshot_checkout_path".to_owned(), self.repo.local_path.clone());
+        let mut injected = Environment::new();
+        injected.insert("hookshot_checkout_path".to_owned(),
+                        self.repo.local_path.to_string_lossy().into_owned());
 
         // Insert git data into the environment
         // TODO: figure out if env type can get away without having to own its
         // keys and values
-        self.env.insert("git_ref".to_owned(), self.repo.refstring.clone());
-        self.env.insert("git_ref_type".to_owned(), self.repo.reftype.to_string());
-        self.env.insert("git_commit_sha".to_owned(), self.repo.sha.clone());
-        self.env.insert("git_repo_name".to_owned(), self.repo.name.clone());
-        self.env.insert("git_repo_owner".to_owned(), self.repo.owner.clone());
-
-        // Truncate the logfile and write "task running..."
-        let logfile_path = Path::new(&self.logdir).join(format!("{}.log", task_id));
-        let mut logger = match LogWriter::new(&logfile_path) {
+        injected.insert("git_ref".to_owned(), self.repo.refstring.clone());
+        injected.insert("git_ref_type".to_owned(), self.repo.reftype.to_string());
+        injected.insert("git_commit_sha".to_owned(), self.repo.sha.clone());
+        injected.insert("git_repo_name".to_owned(), self.repo.name.clone());
+        injected.insert("git_repo_owner".to_owned(), self.repo.owner.clone());
+        injected.insert("hookshot_is_rollback".to_owned(), self.is_rollback.to_string());
+        if let Some(ref project) = self.project {
+            injected.insert("hookshot_project".to_owned(), project.name.clone());
+        }
+
+        // Truncate the logfile and write "task running...". Retries add to
+        // the log of the attempts before them.
+        let logfile_path = self.logdir.join(format!("{}.log", task_id));
+        let logger = match self.attempt {
+            1 => LogWriter::new(&logfile_path),
+            _ => LogWriter::append(&logfile_path),
+        };
+        let mut logger = match logger {
             Ok(logfile) => logfile,
-            Err(_) => return println!("[{}]: could not open logfile for writing", &task_id),
+            Err(e) => {
+                let err = format!("could not open logfile for writing: {}", storage::describe(&e));
+                error!(&log_id, "{}", err);
+                return TaskOutcome::failed(err);
+            }
         };
+        logger.strip_ansi = self.strip_ansi_logs;
+        logger.sync = self.log_sync;
+        logger.redactor = Redactor::new(&self.redact);
+        logger.redactor.learn(env::vars());
+        if self.raw_logs {
+            let raw_path = self.logdir.join(format!("{}.raw.log", task_id));
+            let raw = match self.attempt {
+                1 => storage::retry(|| File::create(&raw_path)),
+                _ => storage::retry(|| OpenOptions::new().append(true).create(true).open(&raw_path)),
+            };
+            match raw {
+                Ok(file) => logger.raw = Some((raw_path, file)),
+                Err(_) => warn!(&log_id, "could not open raw logfile for writing"),
+            }
+        }
+        if self.attempt > 1 {
+            logger.write(format!("\n==attempt {}==\n", self.attempt));
+        }
+        if let Some(ref correlation_id) = self.correlation_id {
+            logger.write(format!("correlation id: {}\n", correlation_id));
+        }
+        logger.write(format!("trigger: {}\n", self.trigger));
+
+        let tmp_dir = match *tmp_dir {
+            Ok(ref tmp_dir) => tmp_dir,
+            Err(ref e) => {
+                let err = format!("could not create temporary directory: {}", e);
+                logger.write(format!("{}", err));
+                error!(&log_id, "{}", err);
+                return TaskOutcome::failed(err);
+            }
+        };
+        injected.insert("hookshot_tmp_dir".to_owned(),
+                        tmp_dir.path().to_string_lossy().into_owned());
+
         // Log the current user
         logger.write(format!("system user: {}\n", users::get_current_username().unwrap_or("<none>".to_owned())));
 
-        // Log the hookshot environment variables
-        logger.write(format!("hookshot environment:\n---------------------\n{}", format_environment(&self.env)));
-
         // Log the system environment variables
-        logger.write(format!("system environment:\n-------------------\n{}", format_os_environment()));
+        // leaving out the ones the server config uses as secrets
+        let secret_vars = self.named_environments
+                              .values()
+                              .chain(Some(&self.env))
+                              .flat_map(|env| env.values())
+                              .filter(|v| v.starts_with("env:"))
+                              .map(|v| String::from(&v["env:".len()..]))
+                              .collect::<Vec<String>>();
+        write_environment(&mut logger,
+                          "system environment",
+                          self.log_system_environment,
+                          || format_os_environment(&secret_vars));
 
         // Log what time the task started.
         let time_task_started = UTC::now();
         logger.write(format!("started: {}", time_task_started));
 
-        if let Err(git_error) = self.repo.get_latest() {
-            let stderr = String::from_utf8(git_error.output.unwrap().stderr).unwrap();
-            let err = format!("{}: {}", git_error.desc, stderr);
+        let warm = self.trigger == Trigger::Warm;
+        let started = Instant::now();
+        let latest = match self.artifact {
+            _ if chaos::injects(Fault::Clone, &self.repo.owner, &self.repo.name) => Err(chaos::clone_error()),
+            Some(ref artifact) if !warm => {
+                logger.write(format!("deploying release asset {} for {}", artifact.asset, self.repo.refstring));
+                artifact.fetch(&self.repo, self.http_timeouts)
+            }
+            _ => self.repo.get_latest(!self.is_rollback && !warm),
+        };
+        steps.done("checkout", started);
+        if let Err(git_error) = latest {
+            let err = format_command_error(git_error);
 
             logger.write(format!("{}", err));
-            return println!("[{}]: {}", task_id, err);
+            error!(&log_id, "{}", err);
+            return TaskOutcome::failed(err);
+        }
+        workspace::touch(&self.repo.local_path);
+        if warm {
+            logger.write("checkout is ready, not running anything");
+            info!(&log_id, "warmed checkout {}", self.repo.local_path.display());
+            return TaskOutcome::new(TaskStatus::Skipped);
         }
 
-        let project_root = Path::new(&self.repo.local_path);
-        let config = match RepoConfig::load(&project_root) {
-            Err(e) => {
-                let err = format!("could not load config for repo {}: {} (branch: {})",
+        // `${VAR}` in the config can use the ref's variables from the server
+        // config and hookshot's own, but not secrets, which would end up in
+        // the task record along with the rest of the config
+        let mut config_vars = self.env
+                                  .iter()
+                                  .filter(|&(_, value)| !environment::is_secret_reference(value))
+                                  .map(|(key, value)| (key.clone(), String::from(environment::unescape(value))))
+                                  .collect::<Environment>();
+        config_vars.extend(injected.clone());
+
+        let project_root = match self.project {
+            Some(ref project) => self.repo.local_path.join(&project.dir),
+            None => self.repo.local_path.clone(),
+        };
+        let started = Instant::now();
+        let loaded = RepoConfig::load_from(&project_root,
+                                           &self.config_paths,
+                                           &self.methods.methods(),
+                                           Some(&config_vars));
+        steps.done("config", started);
+        let config = match loaded {
+            Err(errors) => {
+                let err = format!("could not load config for repo {}: {}",
                                   self.repo.remote_path,
-                                  e.description(),
-                                  e.related_branch().unwrap_or("None"));
+                                  errors);
 
                 logger.write(format!("{}", err));
-                return println!("[{}]: {}", task_id, err);
+                error!(&log_id, "{}", err);
+                return TaskOutcome::failed(err);
             }
             Ok(config) => config,
         };
-
-        notifier::started(&self, &config);
+        for warning in config.unreachable_patterns() {
+            logger.write(format!("config warning: {}", warning));
+            warn!(&log_id, "config warning: {}", warning);
+        }
 
         let ref_config = match config.lookup(self.repo.reftype, &self.repo.refstring) {
             None => {
                 let err = format!("No config for ref '{}'", &self.repo.refstring);
 
                 logger.write(format!("{}", err));
-                return println!("[{}]: {}", task_id, err);
+                error!(&log_id, "{}", err);
+                return TaskOutcome::failed(err);
             }
             Some(config) => config,
         };
+        let resolved = ref_config.resolved();
+        self.history.lock().unwrap().update(&self.id.to_string(), |record| record.config = Some(resolved));
+        // The time a task has counts from here, waiting for its lock included
+        let processes = match ref_config.timeout {
+            Some(secs) => processes.with_timeout(secs),
+            None => processes,
+        };
+
+        // No changed files at all means nobody knows what changed, unless
+        // they were narrowed down to a project's
+        let changed_files = self.changed_files.as_ref().and_then(|files| match files.is_empty() &&
+                                                                              self.project.is_none() {
+            true => None,
+            false => Some(files),
+        });
+        if let (Some(patterns), Some(changed_files)) = (ref_config.paths.as_ref(), changed_files) {
+            // Checked when the config was loaded
+            let filter = PathFilter::new(patterns).unwrap();
+            if !filter.matches_any(changed_files) {
+                logger.write(format!("skipping: none of the {} changed files match `paths`",
+                                     changed_files.len()));
+                info!(&log_id, "skipped, no changed files match `paths`");
+                return TaskOutcome::new(TaskStatus::Skipped);
+            }
+        }
+
+        if let Err(err) = self.check_dependencies() {
+            logger.write(format!("{}", err));
+            error!(&log_id, "{}", err);
+            steps.time("notify_finished", || report_finished(&self, &config, false));
+            return TaskOutcome::failed(err);
+        }
+
+        // Held until the task is done
+        let _lock = match ref_config.lock {
+            None => None,
+            Some(ref name) => {
+                let started = Instant::now();
+                let lock = self.wait_for_lock(name, &processes, &mut logger);
+                steps.done("lock", started);
+                match lock {
+                    Ok(lock) => Some(lock),
+                    Err(LockError::Cancelled) => return TaskOutcome::new(TaskStatus::Cancelled),
+                    Err(LockError::TimedOut) => {
+                        let err = format!("the task timed out waiting for lock {}", name);
+
+                        logger.write(format!("{}", err));
+                        error!(&log_id, "{}", err);
+                        steps.time("notify_finished", || report_finished(&self, &config, false));
+                        return TaskOutcome::failed(err);
+                    }
+                }
+            }
+        };
+
+        steps.time("notify_started", || report_started(&self, &config));
 
-        // TODO: refactor this, use a trait or something.
-        let output_result = {
-            match ref_config.method {
+        // Merge our variables with the ones from the server config, hookshot's
+        // own values win unless the server config explicitly overrides them.
+        // The ref's named environment comes first so the `env.*` section for
+        // the branch can override its shared values.
+        let mut layers = vec![(Source::Server, self.env.clone())];
+        if let Some(ref name) = ref_config.environment {
+            match self.named_environments.get(name) {
+                Some(named) => layers.push((Source::Named, named.clone())),
+                None => {
+                    let err = format!("unknown environment '{}' for ref '{}'", name, &self.repo.refstring);
+
+                    logger.write(format!("{}", err));
+                    error!(&log_id, "{}", err);
+                    return TaskOutcome::failed(err);
+                }
+            }
+        }
+        // Only values from the server config can be secret references, the
+        // injected values come from the webhook
+        let mut secrets = vec![];
+        for &mut (_, ref mut layer) in layers.iter_mut() {
+            match environment::resolve_secrets(layer) {
+                Ok(references) => {
+                    for (key, reference) in references {
+                        secrets.push((layer[&key].clone(), key, reference));
+                    }
+                }
+                Err(e) => {
+                    let err = format!("invalid environment: {}", e);
+                    logger.write(format!("{}", err));
+                    error!(&log_id, "{}", err);
+                    return TaskOutcome::failed(err);
+                }
+            }
+        }
+        layers.push((Source::Hookshot, injected));
+        let (env, problems) = environment::merge(layers, &self.allow_env_override);
+        if !problems.is_empty() {
+            let warnings = problems.iter().fold(String::new(), |s, p| s + &format!("{}\n", p));
+            logger.write(format!("environment warnings:\n---------------------\n{}", warnings));
+        }
+        if let Some(problem) = problems.iter().find(|p| p.is_fatal()) {
+            let err = format!("invalid environment: {}", problem);
+            logger.write(format!("{}", err));
+            error!(&log_id, "{}", err);
+            return TaskOutcome::failed(err);
+        }
+
+        // Log the hookshot environment variables
+        logger.redactor.learn(env.iter());
+        if let Some(ref name) = ref_config.environment {
+            logger.write(format!("environment: {}\n", name));
+        }
+        // with the references in place of the secrets they point at
+        let mut logged_env = env.clone();
+        for (secret, key, reference) in secrets {
+            if env.get(&key) == Some(&secret) {
+                logged_env.insert(key, format!("<{}>", reference));
+            }
+        }
+        write_environment(&mut logger,
+                          "hookshot environment",
+                          self.log_hookshot_environment,
+                          || format_environment(&logged_env));
+
+        // Submodules enabled on the server side were already updated as part
+        // of `get_latest`.
+        if ref_config.submodules && !self.repo.submodules {
+            if let Err(git_error) = steps.time("submodules", || self.repo.update_submodules()) {
+                let err = format_command_error(git_error);
+
+                logger.write(format!("{}", err));
+                error!(&log_id, "{}", err);
+                return TaskOutcome::failed(err);
+            }
+        }
+
+        if ref_config.clean_checkout {
+            logger.write("cleaning checkout\n");
+            let cleaned = steps.time("clean", || self.repo.clean(self.repo.submodules || ref_config.submodules));
+            if let Err(git_error) = cleaned {
+                let err = format_command_error(git_error);
+
+                logger.write(format!("{}", err));
+                error!(&log_id, "{}", err);
+                return TaskOutcome::failed(err);
+            }
+        }
+
+        if let Some(ref hook) = ref_config.before_task {
+            if !run_hook(&mut logger, steps, &log_id, "before_task", hook, &project_root, &env, &processes) {
+                if let Some(ref hook) = ref_config.on_failure {
+                    run_hook(&mut logger, steps, &log_id, "on_failure", hook, &project_root, &env, &processes);
+                }
+                steps.time("notify_finished", || report_finished(&self, &config, false));
+                return TaskOutcome::failed(String::from("before_task failed"));
+            }
+        }
+
+        let started = Instant::now();
+        let output_result = match chaos::task_result(&self.repo.owner, &self.repo.name) {
+            Some(result) => result,
+            None => match ref_config.method {
                 DeployMethod::Ansible => match ref_config.ansible_task() {
                     None => {
                         let err = format!("No task for ref '{}'", &self.repo.refstring);
 
                         logger.write(format!("{}", err));
-                        return println!("[{}]: {}", &task_id, err);
+                        error!(&log_id, "{}", err);
+                        return TaskOutcome::failed(err);
                     }
                     Some(task) => {
-                        println!("[{}]: {:?}", &task_id, task);
-                        println!("[{}]: with environment {:?}", &task_id, &self.env);
-                        task.run(&self.env)
+                        if task.check {
+                            logger.write("check mode: running with --check --diff, nothing will be changed\n");
+                        }
+                        debug!(&log_id, "{:?}", task);
+                        if self.log_hookshot_environment {
+                            debug!(&log_id, "with environment {:?}", &logged_env);
+                        }
+                        task.run(&env, &processes)
                     }
                 },
                 DeployMethod::Makefile => match ref_config.make_task() {
@@ -139,16 +1019,42 @@ impl Runnable for DeployTask {
                         let err = format!("No task for ref '{}'", &self.repo.refstring);
 
                         logger.write(format!("{}", err));
-                        return println!("[{}]: {}", &task_id, err);
+                        error!(&log_id, "{}", err);
+                        return TaskOutcome::failed(err);
                     }
                     Some(task) => {
-                        println!("[{}]: {:?}", self.id, task);
-                        println!("[{}]: with environment {:?}", self.id, &self.env);
-                        task.run(&self.env)
+                        debug!(&log_id, "{:?}", task);
+                        if self.log_hookshot_environment {
+                            debug!(&log_id, "with environment {:?}", &logged_env);
+                        }
+                        task.run(&env, &processes)
                     }
                 },
-            }
+                DeployMethod::Custom(ref name) => {
+                    let built = match self.methods.get(name) {
+                        Some(factory) => factory.build(&self.repo, ref_config, &project_root),
+                        None => Err(format!("no task factory registered for method '{}'", name)),
+                    };
+                    match built {
+                        Err(err) => {
+                            let err = format!("No task for ref '{}': {}", &self.repo.refstring, err);
+
+                            logger.write(format!("{}", err));
+                            error!(&log_id, "{}", err);
+                            return TaskOutcome::failed(err);
+                        }
+                        Ok(task) => {
+                            debug!(&log_id, "{:?}", task);
+                            if self.log_hookshot_environment {
+                                debug!(&log_id, "with environment {:?}", &logged_env);
+                            }
+                            task.run(&env, &processes)
+                        }
+                    }
+                }
+            },
         };
+        steps.done("task", started);
 
         let output = match output_result {
             Ok(output) => output,
@@ -157,7 +1063,12 @@ impl Runnable for DeployTask {
                                   e.desc,
                                   e.detail.unwrap_or(String::from("")));
                 logger.write(format!("{}", err));
-                return println!("[{}]: {}", &task_id, err);
+                error!(&log_id, "{}", err);
+                if let Some(ref hook) = ref_config.on_failure {
+                    run_hook(&mut logger, steps, &log_id, "on_failure", hook, &project_root, &env, &processes);
+                }
+                steps.time("notify_finished", || report_finished(&self, &config, false));
+                return TaskOutcome::failed(err);
             }
         };
 
@@ -166,18 +1077,6 @@ impl Runnable for DeployTask {
             Some(code) => format!("{}", code),
         };
 
-        let exit_status = match output.status.success() {
-            true => {
-                notifier::success(&self, &config);
-                "successful"
-            }
-            false => {
-                notifier::failed(&self, &config);
-                "failed"
-            }
-        };
-        println!("[{}]: run {}", self.id, exit_status);
-
         // Log what time the task ended and how long it took
         let time_task_ended = UTC::now();
         let duration = time_task_ended - time_task_started;
@@ -188,12 +1087,143 @@ impl Runnable for DeployTask {
         // Log the exit code and the standard streams
         logger.write(format!("exit code: {}", exit_code));
         logger.write("\n==stdout==");
-        logger.write(String::from_utf8_lossy(&output.stdout));
+        logger.write_output(&output.stdout);
         logger.write("\n==stderr==");
-        logger.write(String::from_utf8_lossy(&output.stderr));
+        logger.write_output(&output.stderr);
+
+        if let DeployMethod::Ansible = ref_config.method {
+            self.record_hosts(&mut logger, &log_id, &output.stdout);
+        }
+
+        // Exit codes other than 0 can be configured to count as success
+        let exit_allowed = match output.status.code() {
+            Some(code) => ref_config.allowed_exit_codes.contains(&code),
+            None => false,
+        };
+        let mut success = exit_allowed;
+        if success && !output.status.success() {
+            logger.write(format!("exit code {} is allowed, treating as success", exit_code));
+        }
+        if let (true, &Some(ref hook)) = (success, &ref_config.after_task) {
+            success = run_hook(&mut logger, steps, &log_id, "after_task", hook, &project_root, &env, &processes);
+        }
+        if let (false, &Some(ref hook)) = (success, &ref_config.on_failure) {
+            run_hook(&mut logger, steps, &log_id, "on_failure", hook, &project_root, &env, &processes);
+        }
+
+        let (exit_status, outcome) = match (success, exit_allowed) {
+            (true, _) => ("successful", TaskOutcome::new(TaskStatus::Success)),
+            (false, true) => ("failed", TaskOutcome::failed(String::from("after_task failed"))),
+            (false, false) => ("failed", TaskOutcome::failed(format!("exit code: {}", exit_code))),
+        };
+        info!(&log_id, "run {}", exit_status);
+
+        // Only failures of the task itself are retried, and the notifiers
+        // only hear about the last attempt
+        let retried = (self.attempt - 1) as usize;
+        if !exit_allowed && output.status.code().is_some() && retried < ref_config.retries {
+            let msg = format!("attempt {} of {} failed, retrying in {} seconds",
+                              self.attempt,
+                              ref_config.retries + 1,
+                              ref_config.retry_delay);
+            logger.write(format!("\n{}", msg));
+            warn!(&log_id, "{}", msg);
+            self.retry_in = Some(ref_config.retry_delay);
+            return outcome;
+        }
+        steps.time("notify_finished", || report_finished(&self, &config, success));
+
+        outcome
     }
 }
 
+/// Run one of the repo config's hooks, logging its output and timing it as
+/// a step by its name. Returns whether it succeeded, or failed and is
+/// allowed to.
+fn run_hook(logger: &mut LogWriter,
+            steps: &mut Steps,
+            log_id: &str,
+            name: &str,
+            hook: &Hook,
+            project_root: &Path,
+            env: &Environment,
+            groups: &ProcessGroups)
+            -> bool {
+    logger.write(format!("\n=={}==\n$ {}", name, hook.command));
+    let started = Instant::now();
+    let success = match hook::run(&hook.command, project_root, env, groups) {
+        Ok(output) => {
+            logger.write_output(&output.stdout);
+            logger.write_output(&output.stderr);
+            match output.status.code() {
+                Some(0) => true,
+                Some(code) => {
+                    logger.write(format!("{} exited with {}", name, code));
+                    false
+                }
+                None => {
+                    logger.write(format!("{} was killed", name));
+                    false
+                }
+            }
+        }
+        Err(e) => {
+            let err = format!("{} failed: {}", name, format_command_error(e));
+            logger.write(format!("{}", err));
+            error!(log_id, "{}", err);
+            false
+        }
+    };
+    steps.done(name, started);
+    if !success && hook.allow_failure {
+        logger.write(format!("{} is allowed to fail, carrying on", name));
+        return true;
+    }
+    success
+}
+
+
+// Tell the notifiers that are compiled in about a task starting and finishing.
+
+#[cfg(feature = "notifiers")]
+fn report_started(task: &DeployTask, config: &RepoConfig) {
+    notifier::started(task, config);
+}
+#[cfg(not(feature = "notifiers"))]
+fn report_started(_: &DeployTask, _: &RepoConfig) {}
+
+fn report_finished(task: &DeployTask, config: &RepoConfig, success: bool) {
+    notify_finished(task, config, success);
+    page_finished(task, config, success);
+}
+
+#[cfg(feature = "notifiers")]
+fn notify_finished(task: &DeployTask, config: &RepoConfig, success: bool) {
+    match success {
+        true => notifier::success(task, config),
+        false => notifier::failed(task, config),
+    }
+}
+#[cfg(not(feature = "notifiers"))]
+fn notify_finished(_: &DeployTask, _: &RepoConfig, _: bool) {}
+
+#[cfg(feature = "pagerduty")]
+fn page_finished(task: &DeployTask, config: &RepoConfig, success: bool) {
+    match success {
+        true => pagerduty::success(task, config),
+        false => pagerduty::failed(task, config),
+    }
+}
+#[cfg(not(feature = "pagerduty"))]
+fn page_finished(_: &DeployTask, _: &RepoConfig, _: bool) {}
+
+fn format_command_error(error: CommandError) -> String {
+    let detail = match error.output {
+        Some(output) => String::from_utf8_lossy(&output.stderr).into_owned(),
+        None => error.detail.unwrap_or(String::new()),
+    };
+    format!("{}: {}", error.desc, detail)
+}
 
 fn format_duration(duration: Duration) -> String {
     let mut minutes = 0i64;
@@ -212,6 +1242,18 @@ fn format_duration(duration: Duration) -> String {
     }
 }
 
+/// List an environment in the task log under `title`, or only say that it
+/// isn't logged. `list` isn't called then.
+fn write_environment<F: FnOnce() -> String>(logger: &mut LogWriter, title: &str, logged: bool, list: F) {
+    match logged {
+        true => {
+            let underline = title.chars().chain(Some(':')).map(|_| '-').collect::<String>();
+            logger.write(format!("{}:\n{}\n{}", title, underline, list()))
+        }
+        false => logger.write(format!("{}: not logged\n", title)),
+    }
+}
+
 fn format_environment(env: &Environment) -> String {
     let mut env_string = String::new();
     for (k, v) in env.iter() {
@@ -220,10 +1262,51 @@ fn format_environment(env: &Environment) -> String {
     env_string
 }
 
-fn format_os_environment() -> String {
+fn format_os_environment(secret_vars: &[String]) -> String {
     let mut env_string = String::new();
     for (k, v) in env::vars() {
-        env_string.push_str(&format!("{}: {}\n", k, v))
+        match secret_vars.contains(&k) {
+            true => env_string.push_str(&format!("{}: <secret>\n", k)),
+            false => env_string.push_str(&format!("{}: {}\n", k, v)),
+        }
     }
     env_string
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{LogWriter, format_environment, project_files, write_environment};
+    use std::collections::BTreeMap;
+    use std::fs::File;
+    use std::io::Read;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_write_environment() {
+        let dir = TempDir::new("hookshot-deploy-task-test").unwrap();
+        let path = dir.path().join("task.log");
+        let mut env = BTreeMap::new();
+        env.insert(String::from("API_TOKEN"), String::from("hunter2"));
+        {
+            let mut logger = LogWriter::new(&path).unwrap();
+            write_environment(&mut logger, "hookshot environment", true, || format_environment(&env));
+            write_environment(&mut logger, "system environment", false, || panic!("listed anyway"));
+        }
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents,
+                   "hookshot environment:\n---------------------\nAPI_TOKEN: hunter2\n\n\
+                    system environment: not logged\n\n");
+    }
+
+    #[test]
+    fn test_project_files() {
+        let changed_files = vec![String::from("services/api/main.rs"),
+                                 String::from("services/api-docs/index.md"),
+                                 String::from("README.md")];
+        assert_eq!(project_files(&changed_files, "services/api"), vec!["services/api/main.rs"]);
+        assert_eq!(project_files(&changed_files, "./services/api"), vec!["services/api/main.rs"]);
+        assert_eq!(project_files(&changed_files, "."), changed_files);
+        assert!(project_files(&changed_files, "services/web").is_empty());
+    }
+}
@@ -0,0 +1,380 @@
+//! Building and checking the environment a deploy task runs with.
+//!
+//! A task's environment is merged from several sources: the named
+//! environment the repo config picks, the `env.*` sections of the server
+//! config and the variables hookshot injects itself (checkout path, git ref
+//! and sha, ...). When two sources set the same key the one with
+//! the higher precedence wins and the collision is reported so it can go in
+//! the task log, rather than one value silently replacing the other.
+//!
+//! Variables starting with `hookshot_` or `git_` are reserved for hookshot, so
+//! deploy scripts can trust things like the checkout path and the sha. The
+//! server config can only set them if they are listed in
+//! `allow_env_override`, in which case its value wins over hookshot's.
+//!
+//! Values in the server config can point at a secret instead of holding it:
+//! `file:/path/to/secret` is replaced by the contents of the file and
+//! `env:VAR_NAME` by the variable from hookshot's own environment. They are
+//! read when the task runs, so rotating a secret doesn't need a reload.
+
+use server_config::Environment;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+/// Upper bound on the combined size of every key and value. The kernel limits
+/// the size of the environment plus arguments of a process, going over it
+/// makes the exec fail with an unhelpful "argument list too long".
+pub const MAX_ENVIRONMENT_BYTES: usize = 128 * 1024;
+
+/// Prefixes of the variables hookshot sets itself.
+pub const RESERVED_PREFIXES: [&'static str; 2] = ["hookshot_", "git_"];
+
+/// Where a variable came from, lowest precedence first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    /// An `[environment.<name>]` section of the server config.
+    Named,
+    Server,
+    Hookshot,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Named => write!(f, "named environment"),
+            Source::Server => write!(f, "server config"),
+            Source::Hookshot => write!(f, "hookshot"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The key isn't a valid variable name and was dropped.
+    InvalidKey(Source, String),
+    /// The key is in the reserved namespace and wasn't allowed to be
+    /// overridden, so it was dropped.
+    Reserved(Source, String),
+    /// The key was set by more than one source. The value from `kept` was
+    /// used.
+    Collision {
+        key: String,
+        kept: Source,
+        overridden: Source,
+    },
+    /// The merged environment is larger than `MAX_ENVIRONMENT_BYTES`.
+    TooLarge(usize),
+}
+
+impl Problem {
+    /// Whether the task can't run with this environment.
+    pub fn is_fatal(&self) -> bool {
+        match *self {
+            Problem::TooLarge(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::InvalidKey(source, ref key) =>
+                write!(f, "ignoring invalid variable name '{}' from {}", key, source),
+            Problem::Reserved(source, ref key) =>
+                write!(f, "ignoring '{}' from {}, it is reserved for hookshot. Add it to \
+                           `allow_env_override` to override it",
+                       key, source),
+            Problem::Collision { ref key, kept, overridden } =>
+                write!(f, "'{}' is set by both {} and {}, using the value from {}",
+                       key, overridden, kept, kept),
+            Problem::TooLarge(size) =>
+                write!(f, "environment is {} bytes, the limit is {} bytes",
+                       size, MAX_ENVIRONMENT_BYTES),
+        }
+    }
+}
+
+/// A secret reference that couldn't be resolved.
+#[derive(Debug)]
+pub enum SecretError {
+    File(String, PathBuf, io::Error),
+    Var(String, String),
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SecretError::File(ref key, ref path, ref e) =>
+                write!(f, "could not read '{}' from file:{}: {}", key, path.display(), e),
+            SecretError::Var(ref key, ref var) =>
+                write!(f, "could not read '{}' from env:{}: variable is not set", key, var),
+        }
+    }
+}
+
+/// Whether `value` points at a secret instead of holding it.
+pub fn is_secret_reference(value: &str) -> bool {
+    value.starts_with("file:") || value.starts_with("env:")
+}
+
+/// `value` without its `literal:` prefix, the escape for a value that
+/// really starts with `file:`, `env:` or `literal:`.
+pub fn unescape(value: &str) -> &str {
+    if value.starts_with("literal:") {
+        &value["literal:".len()..]
+    } else {
+        value
+    }
+}
+
+/// Replace every `file:` and `env:` value with the secret it points at, and
+/// take the prefix off `literal:` values. Returns the references that were
+/// replaced, by key, so the secrets don't have to be logged.
+pub fn resolve_secrets(vars: &mut Environment) -> Result<BTreeMap<String, String>, SecretError> {
+    let mut references = BTreeMap::new();
+    for (key, value) in vars.iter_mut() {
+        if value.starts_with("literal:") {
+            let literal = String::from(unescape(value));
+            *value = literal;
+            continue;
+        }
+        let secret = if value.starts_with("file:") {
+            let path = PathBuf::from(&value["file:".len()..]);
+            let mut contents = String::new();
+            if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)) {
+                return Err(SecretError::File(key.clone(), path, e));
+            }
+            // Files usually end with a newline that isn't part of the secret
+            String::from(contents.trim_right_matches(|c| c == '\n' || c == '\r'))
+        } else if value.starts_with("env:") {
+            let var = &value["env:".len()..];
+            match env::var(var) {
+                Ok(secret) => secret,
+                Err(_) => return Err(SecretError::Var(key.clone(), String::from(var))),
+            }
+        } else {
+            continue;
+        };
+        references.insert(key.clone(), value.clone());
+        *value = secret;
+    }
+    Ok(references)
+}
+
+/// Whether `key` can be used as an environment variable (and as an ansible
+/// extra var): ascii letters, digits and underscores, not starting with a
+/// digit.
+pub fn valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    let first_valid = match chars.next() {
+        Some(c) => (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_',
+        None => false,
+    };
+    first_valid &&
+    chars.all(|c| (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9') ||
+                  c == '_')
+}
+
+/// Whether `key` is in the namespace reserved for hookshot's own variables.
+pub fn is_reserved(key: &str) -> bool {
+    RESERVED_PREFIXES.iter().any(|prefix| key.starts_with(prefix))
+}
+
+/// Merge the environments from each source. Keys in `allow_override` can be
+/// set by the server config even though they are reserved. Returns the merged
+/// environment and everything that was wrong with it.
+pub fn merge(mut layers: Vec<(Source, Environment)>,
+             allow_override: &[String])
+             -> (Environment, Vec<Problem>) {
+    layers.sort_by(|a, b| a.0.cmp(&b.0));
+    let allowed = |key: &str| allow_override.iter().any(|k| k == key);
+
+    let mut merged = Environment::new();
+    let mut sources = BTreeMap::new();
+    let mut problems = vec![];
+    for (source, env) in layers {
+        for (key, value) in env {
+            if !valid_key(&key) {
+                problems.push(Problem::InvalidKey(source, key));
+                continue;
+            }
+            if source != Source::Hookshot && is_reserved(&key) && !allowed(&key) {
+                problems.push(Problem::Reserved(source, key));
+                continue;
+            }
+            // An allowed override beats hookshot's own value
+            let (kept, overridden) = match sources.get(&key).cloned() {
+                Some(previous) if previous != Source::Hookshot && source == Source::Hookshot &&
+                                  allowed(&key) => (previous, Source::Hookshot),
+                Some(previous) => (source, previous),
+                None => {
+                    sources.insert(key.clone(), source);
+                    merged.insert(key, value);
+                    continue;
+                }
+            };
+            if merged.get(&key) != Some(&value) {
+                problems.push(Problem::Collision {
+                    key: key.clone(),
+                    kept: kept,
+                    overridden: overridden,
+                });
+            }
+            if kept == source {
+                sources.insert(key.clone(), source);
+                merged.insert(key, value);
+            }
+        }
+    }
+
+    let size = merged.iter().fold(0, |size, (k, v)| size + k.len() + v.len() + 2);
+    if size > MAX_ENVIRONMENT_BYTES {
+        problems.push(Problem::TooLarge(size));
+    }
+    (merged, problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server_config::Environment;
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    fn env(pairs: &[(&str, &str)]) -> Environment {
+        pairs.iter().map(|&(k, v)| (String::from(k), String::from(v))).collect()
+    }
+
+    #[test]
+    fn test_valid_key() {
+        assert!(valid_key("git_ref"));
+        assert!(valid_key("_PRIVATE"));
+        assert!(valid_key("api_key_2"));
+        assert!(!valid_key(""));
+        assert!(!valid_key("2fast"));
+        assert!(!valid_key("has-dash"));
+        assert!(!valid_key("has space"));
+        assert!(!valid_key("a=b"));
+    }
+
+    #[test]
+    fn test_is_reserved() {
+        assert!(is_reserved("hookshot_checkout_path"));
+        assert!(is_reserved("git_commit_sha"));
+        assert!(!is_reserved("github_token"));
+        assert!(!is_reserved("api_key"));
+    }
+
+    #[test]
+    fn test_merge_reserved() {
+        let server = env(&[("git_ref", "from-server"),
+                           ("hookshot_extra", "x"),
+                           ("api_key", "s3cret")]);
+        let hookshot = env(&[("git_ref", "master"), ("git_commit_sha", "abc123")]);
+        let (merged, problems) = merge(vec![(Source::Hookshot, hookshot),
+                                            (Source::Server, server)],
+                                       &[]);
+        assert_eq!(merged["git_ref"], "master");
+        assert_eq!(merged["api_key"], "s3cret");
+        assert_eq!(merged["git_commit_sha"], "abc123");
+        assert!(!merged.contains_key("hookshot_extra"));
+        assert_eq!(problems,
+                   vec![Problem::Reserved(Source::Server, String::from("git_ref")),
+                        Problem::Reserved(Source::Server, String::from("hookshot_extra"))]);
+    }
+
+    #[test]
+    fn test_merge_allow_override() {
+        let server = env(&[("git_ref", "from-server")]);
+        let hookshot = env(&[("git_ref", "master")]);
+        let (merged, problems) = merge(vec![(Source::Server, server), (Source::Hookshot, hookshot)],
+                                       &[String::from("git_ref")]);
+        assert_eq!(merged["git_ref"], "from-server");
+        assert_eq!(problems,
+                   vec![Problem::Collision {
+                            key: String::from("git_ref"),
+                            kept: Source::Server,
+                            overridden: Source::Hookshot,
+                        }]);
+    }
+
+    #[test]
+    fn test_merge_named_environment() {
+        let named = env(&[("api_url", "https://api.example.org"), ("api_key", "shared")]);
+        let server = env(&[("api_key", "just-this-branch")]);
+        let (merged, problems) = merge(vec![(Source::Server, server), (Source::Named, named)], &[]);
+        assert_eq!(merged["api_url"], "https://api.example.org");
+        assert_eq!(merged["api_key"], "just-this-branch");
+        assert_eq!(problems,
+                   vec![Problem::Collision {
+                            key: String::from("api_key"),
+                            kept: Source::Server,
+                            overridden: Source::Named,
+                        }]);
+    }
+
+    #[test]
+    fn test_resolve_secrets() {
+        let dir = TempDir::new("hookshot-environment-test").unwrap();
+        let path = dir.path().join("api_key");
+        File::create(&path).unwrap().write_all(b"correct horse\n").unwrap();
+        env::set_var("HOOKSHOT_TEST_SECRET", "battery staple");
+
+        let mut server = env(&[("api_key", &format!("file:{}", path.display())[..]),
+                               ("password", "env:HOOKSHOT_TEST_SECRET"),
+                               ("hostname", "website.biz"),
+                               ("greeting", "literal:env:HOME is where the heart is")]);
+        let references = resolve_secrets(&mut server).unwrap();
+        assert_eq!(server["api_key"], "correct horse");
+        assert_eq!(server["password"], "battery staple");
+        assert_eq!(server["hostname"], "website.biz");
+        assert_eq!(server["greeting"], "env:HOME is where the heart is");
+        assert_eq!(references["password"], "env:HOOKSHOT_TEST_SECRET");
+        assert!(!references.contains_key("hostname"));
+        assert!(!references.contains_key("greeting"));
+
+        assert_eq!(unescape("literal:literal:x"), "literal:x");
+        assert_eq!(unescape("file:x"), "file:x");
+    }
+
+    #[test]
+    fn test_resolve_secrets_errors() {
+        let mut server = env(&[("api_key", "file:/does/not/exist")]);
+        match resolve_secrets(&mut server) {
+            Err(SecretError::File(ref key, _, _)) => assert_eq!(key, "api_key"),
+            other => panic!("expected File error, got {:?}", other),
+        }
+
+        let mut server = env(&[("password", "env:HOOKSHOT_TEST_NOT_SET")]);
+        let err = resolve_secrets(&mut server).err().unwrap();
+        assert_eq!(err.to_string(),
+                   "could not read 'password' from env:HOOKSHOT_TEST_NOT_SET: variable is not set");
+    }
+
+    #[test]
+    fn test_merge_same_value_is_not_a_collision() {
+        let allow = [String::from("git_ref")];
+        let (_, problems) = merge(vec![(Source::Server, env(&[("git_ref", "master")])),
+                                       (Source::Hookshot, env(&[("git_ref", "master")]))],
+                                  &allow);
+        assert!(problems.is_empty());
+    }
+
+    #[test]
+    fn test_merge_invalid_keys_and_size() {
+        let big = (0..MAX_ENVIRONMENT_BYTES).map(|_| "x").collect::<String>();
+        let server = env(&[("bad-key", "value"), ("big", &big[..])]);
+        let (merged, problems) = merge(vec![(Source::Server, server)], &[]);
+        assert!(!merged.contains_key("bad-key"));
+        assert_eq!(problems[0],
+                   Problem::InvalidKey(Source::Server, String::from("bad-key")));
+        assert!(problems[1].is_fatal());
+    }
+}
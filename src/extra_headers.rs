@@ -0,0 +1,48 @@
+//! Extra headers for responses, from the server config's `[headers]`
+//! section.
+//!
+//! The proxy in front of hookshot can't always be changed, so headers like
+//! `Strict-Transport-Security` or `Cache-Control` can be set here instead,
+//! for every response or for the responses of one of the groups of routes of
+//! `[endpoints]`. They go on error responses too, and replace any header
+//! hookshot set itself under the same name. They're looked up for every
+//! response, so a reload changes them right away.
+
+use iron::{AfterMiddleware, IronError, IronResult, Request, Response};
+use reload::SharedConfig;
+use routes::RouteGroups;
+
+pub struct ExtraHeaders {
+    config: SharedConfig,
+    groups: RouteGroups,
+}
+
+impl ExtraHeaders {
+    pub fn new(config: SharedConfig, groups: RouteGroups) -> ExtraHeaders {
+        ExtraHeaders {
+            config: config,
+            groups: groups,
+        }
+    }
+
+    fn add(&self, req: &Request, response: &mut Response) {
+        let path = req.url.path.iter().map(|s| &s[..]).filter(|s| !s.is_empty()).collect::<Vec<_>>();
+        let group = self.groups.find(&req.method, &path);
+        let config = self.config.read().unwrap();
+        for (name, value) in config.headers.for_group(group) {
+            response.headers.set_raw(String::from(name), vec![value.as_bytes().to_vec()]);
+        }
+    }
+}
+
+impl AfterMiddleware for ExtraHeaders {
+    fn after(&self, req: &mut Request, mut response: Response) -> IronResult<Response> {
+        self.add(req, &mut response);
+        Ok(response)
+    }
+
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        self.add(req, &mut err.response);
+        Err(err)
+    }
+}
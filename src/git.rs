@@ -4,12 +4,121 @@
 //! not intended to provide a 1-1 interface to the git cli but instead provide a
 //! minimal interface to create the smallest checkout for a specific sha.
 
+use chrono::{Timelike, UTC};
 use error::CommandError;
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
+use tempdir::TempDir;
+use unicode_normalization::UnicodeNormalization;
 use verified_path::directory_exists;
 use message::RefType;
 
+/// Environment variable the askpass helper reads the token from. The token is
+/// handed to git through the environment so it never shows up in process
+/// arguments or in the remote url stored in the checkout.
+const TOKEN_ENV_KEY: &'static str = "HOOKSHOT_GIT_TOKEN";
+/// How many commits to fetch when deepening a shallow checkout to find a sha.
+const DEEPEN_DEPTH: u32 = 50;
+/// What a task fails with when the sha it was asked to deploy isn't in the
+/// history of its ref, usually because the ref was force pushed after the
+/// delivery was sent.
+pub const SHA_NOT_ON_REF: &'static str = "sha not found on ref, see detail";
+const ASKPASS_SCRIPT: &'static str = "#!/bin/sh\nexec printf '%s\\n' \"$HOOKSHOT_GIT_TOKEN\"\n";
+/// Program network operations are run under to limit their bandwidth.
+pub const THROTTLE_PROGRAM: &'static str = "trickle";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloneProtocol {
+    /// Use the remote exactly as it was given, usually an ssh url.
+    Ssh,
+    /// Rewrite the remote to an https url, authenticating with a token if
+    /// one is set.
+    Https,
+}
+
+/// How much of a repository to transfer and how fast, from the
+/// `depth`, `partial_clone` and `bandwidth_limit*` repo settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    /// Commits of history to clone and fetch, 0 for all of it. Without it
+    /// clones are `--depth=1` and fetches bring in whatever is new.
+    pub depth: Option<u32>,
+    /// Clone with `--filter=blob:none`, leaving file contents to be fetched
+    /// when a checkout needs them.
+    pub partial_clone: bool,
+    /// Limit, in KB/s, on how fast clones and fetches download.
+    pub bandwidth_limit: Option<u32>,
+    /// Hours of the day, in UTC, during which the limit applies, as
+    /// `(start, end)` with `end` excluded. Ranges can wrap around midnight.
+    /// The limit always applies without them.
+    pub bandwidth_limit_hours: Option<(u32, u32)>,
+}
+
+impl Default for Transfer {
+    fn default() -> Transfer {
+        Transfer {
+            depth: None,
+            partial_clone: false,
+            bandwidth_limit: None,
+            bandwidth_limit_hours: None,
+        }
+    }
+}
+
+impl Transfer {
+    /// Parse hours like `"9-17"`.
+    pub fn parse_hours(hours: &str) -> Option<(u32, u32)> {
+        let parts = hours.split('-').map(|part| part.trim().parse::<u32>().ok()).collect::<Vec<_>>();
+        if parts.len() != 2 {
+            return None;
+        }
+        match (parts[0], parts[1]) {
+            (Some(start), Some(end)) if start < 24 && end <= 24 && start != end => Some((start, end)),
+            _ => None,
+        }
+    }
+
+    /// The bandwidth limit in effect at `hour` (UTC), if any.
+    pub fn limit_at(&self, hour: u32) -> Option<u32> {
+        let in_hours = match self.bandwidth_limit_hours {
+            None => true,
+            Some((start, end)) if start < end => start <= hour && hour < end,
+            Some((start, end)) => hour >= start || hour < end,
+        };
+        if in_hours {
+            self.bandwidth_limit
+        } else {
+            None
+        }
+    }
+
+    /// Arguments to `git clone` for the depth and filter.
+    fn clone_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        match self.depth {
+            None => args.push(String::from("--depth=1")),
+            Some(0) => {}
+            Some(depth) => args.push(format!("--depth={}", depth)),
+        }
+        if self.partial_clone {
+            args.push(String::from("--filter=blob:none"));
+        }
+        args
+    }
+
+    /// Arguments to `git fetch` for the depth.
+    fn fetch_args(&self) -> Vec<String> {
+        match self.depth {
+            Some(depth) if depth > 0 => vec![format!("--depth={}", depth)],
+            _ => vec![],
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct GitRepo {
     /// Owner of the repository
     pub owner: String,
@@ -32,16 +141,144 @@ pub struct GitRepo {
     /// `file://` protocol is used.
     pub remote_path: String,
 
-    /// Local path of where to clone the repository.
-    pub local_path: String,
+    /// Local path of where to clone the repository, see
+    /// `workspace::CheckoutPath`.
+    pub local_path: PathBuf,
+
+    /// How to talk to the remote.
+    pub clone_protocol: CloneProtocol,
+
+    /// Access token for https remotes.
+    pub token: Option<String>,
+
+    /// Whether to initialize and update submodules after checking out.
+    pub submodules: bool,
+
+    /// Depth, filter and bandwidth limit for clones and fetches.
+    pub transfer: Transfer,
+}
+
+/// Convert a git remote to an https url with a placeholder user for token
+/// authentication, e.g. `git@github.com:owner/repo.git` becomes
+/// `https://x-access-token@github.com/owner/repo.git`. Returns `None` for
+/// remotes that aren't network urls.
+pub fn https_remote(remote: &str, with_user: bool) -> Option<String> {
+    let user = if with_user { "x-access-token@" } else { "" };
+    let host_and_path = if remote.starts_with("https://") || remote.starts_with("http://") ||
+                           remote.starts_with("ssh://") || remote.starts_with("git://") {
+        let without_scheme = &remote[remote.find("://").unwrap() + 3..];
+        // Drop any existing user info
+        match without_scheme.find('@') {
+            Some(at) if at < without_scheme.find('/').unwrap_or(without_scheme.len()) =>
+                String::from(&without_scheme[at + 1..]),
+            _ => String::from(without_scheme),
+        }
+    } else {
+        // scp-like syntax: [user@]host:path
+        let colon = match remote.find(':') {
+            Some(colon) => colon,
+            None => return None,
+        };
+        let (host, path) = (&remote[..colon], &remote[colon + 1..]);
+        let host = match host.find('@') {
+            Some(at) => &host[at + 1..],
+            None => host,
+        };
+        if host.is_empty() || host.contains('/') {
+            return None;
+        }
+        format!("{}/{}", host, path.trim_left_matches('/'))
+    };
+    Some(format!("https://{}{}", user, host_and_path))
+}
+
+/// An owner or repo name the way hookshot compares them: GitHub ignores
+/// case in them, so `Org/App` and `org/app` are the same repo.
+pub fn normalize_name(name: &str) -> String {
+    name.nfc().collect::<String>().to_lowercase()
+}
+
+/// Whether two owner or repo names, or two `owner/name`s, are the same.
+pub fn names_match(a: &str, b: &str) -> bool {
+    a == b || normalize_name(a) == normalize_name(b)
+}
+
+/// A commit, as `commit_range` lists it.
+#[derive(RustcEncodable, Clone, Debug, PartialEq, Eq)]
+pub struct Commit {
+    pub sha: String,
+    pub author: String,
+    /// Unix timestamp of when the commit was made.
+    pub timestamp: i64,
+    pub subject: String,
+}
+
+/// Whether `s` is a commit sha, full or abbreviated, rather than a ref like
+/// the `origin/<branch>` scheduled deploys record.
+pub fn is_sha(s: &str) -> bool {
+    s.len() >= 4 && s.len() <= 40 && s.chars().all(|c| c.is_digit(16))
+}
+
+/// The commits of the checkout at `checkout` that `to` has and `from`
+/// doesn't, newest first, like `git log from..to`. At most `max` of them,
+/// along with whether there were more. Fails when either commit isn't in
+/// the checkout, which happens with shallow clones.
+pub fn commit_range(checkout: &Path, from: &str, to: &str, max: usize) -> Result<(Vec<Commit>, bool), CommandError> {
+    let output = Command::new("git")
+                     .current_dir(checkout)
+                     .arg("log")
+                     .arg("--format=%H%x1f%an%x1f%at%x1f%s")
+                     .arg(format!("--max-count={}", max + 1))
+                     .arg(format!("{}..{}", from, to))
+                     .arg("--")
+                     .output();
+
+    let result = match output {
+        Ok(r) => r,
+        Err(e) => return Err(CommandError {
+            desc: "failed to execute process, see detail",
+            output: None,
+            detail: Some(format!("{}", e)),
+        }),
+    };
+
+    if !result.status.success() {
+        return Err(CommandError {
+            desc: "git log failed",
+            output: Some(result),
+            detail: None,
+        });
+    }
+
+    let mut commits = String::from_utf8_lossy(&result.stdout)
+                          .lines()
+                          .filter_map(parse_commit)
+                          .collect::<Vec<Commit>>();
+    let truncated = commits.len() > max;
+    commits.truncate(max);
+    Ok((commits, truncated))
+}
+
+/// A line of `commit_range`'s `git log`, fields separated by `\x1f`.
+fn parse_commit(line: &str) -> Option<Commit> {
+    let fields = line.splitn(4, '\u{1f}').collect::<Vec<&str>>();
+    if fields.len() != 4 {
+        return None;
+    }
+    Some(Commit {
+        sha: String::from(fields[0]),
+        author: String::from(fields[1]),
+        timestamp: fields[2].parse().unwrap_or(0),
+        subject: String::from(fields[3]),
+    })
 }
 
 pub trait ToGitRepo {
-    fn to_git_repo(self, root: &str) -> GitRepo;
+    fn to_git_repo(self, root: &Path) -> GitRepo;
 }
 
 impl GitRepo {
-    pub fn from<T: ToGitRepo>(other: T, root: &str) -> GitRepo {
+    pub fn from<T: ToGitRepo>(other: T, root: &Path) -> GitRepo {
         other.to_git_repo(root)
     }
 
@@ -49,16 +286,67 @@ impl GitRepo {
         format!("{}.{}.{}", &self.owner, &self.name, &self.refstring)
     }
 
+    /// The url git should clone from.
+    pub fn remote_url(&self) -> String {
+        match self.clone_protocol {
+            CloneProtocol::Ssh => self.remote_path.clone(),
+            CloneProtocol::Https => https_remote(&self.remote_path, self.token.is_some())
+                                        .unwrap_or(self.remote_path.clone()),
+        }
+    }
+
+    /// A git command, run under `THROTTLE_PROGRAM` if it talks to the remote
+    /// and a bandwidth limit is in effect right now.
+    fn git(&self, network: bool) -> Command {
+        match self.transfer.limit_at(UTC::now().hour()) {
+            Some(limit) if network => {
+                let mut command = Command::new(THROTTLE_PROGRAM);
+                command.arg("-s").arg("-d").arg(limit.to_string()).arg("git");
+                command
+            }
+            _ => Command::new("git"),
+        }
+    }
+
+    /// Set up token authentication for a git command. The returned directory
+    /// holds the askpass helper and must be kept alive until the command
+    /// finishes.
+    fn authenticate(&self, command: &mut Command) -> Result<Option<TempDir>, CommandError> {
+        let token = match (self.clone_protocol, &self.token) {
+            (CloneProtocol::Https, &Some(ref token)) => token,
+            _ => return Ok(None),
+        };
+        let askpass_error = |e: ::std::io::Error| CommandError {
+            desc: "could not create git askpass helper, see detail",
+            output: None,
+            detail: Some(format!("{}", e)),
+        };
+        let dir = try!(TempDir::new("hookshot-askpass").map_err(&askpass_error));
+        let script_path = dir.path().join("askpass.sh");
+        {
+            let mut script = try!(File::create(&script_path).map_err(&askpass_error));
+            try!(script.write_all(ASKPASS_SCRIPT.as_bytes()).map_err(&askpass_error));
+        }
+        try!(fs::set_permissions(&script_path, fs::Permissions::from_mode(0o700))
+                 .map_err(&askpass_error));
+
+        command.env("GIT_ASKPASS", &script_path);
+        command.env("GIT_TERMINAL_PROMPT", "0");
+        command.env(TOKEN_ENV_KEY, token);
+        Ok(Some(dir))
+    }
+
     fn clone(&self) -> Result<Output, CommandError> {
-        let output = Command::new("git")
-                         .arg("clone")
-                         .arg("--depth=1")
-                         .arg("--single-branch")
-                         .arg("-b")
-                         .arg(&self.refstring)
-                         .arg(&self.remote_path)
-                         .arg(&self.local_path)
-                         .output();
+        let mut command = self.git(true);
+        command.arg("clone")
+               .args(&self.transfer.clone_args())
+               .arg("--single-branch")
+               .arg("-b")
+               .arg(&self.refstring)
+               .arg(&self.remote_url())
+               .arg(&self.local_path);
+        let _askpass = try!(self.authenticate(&mut command));
+        let output = command.output();
 
         let result = match output {
             Ok(r) => r,
@@ -79,7 +367,7 @@ impl GitRepo {
         }
     }
     fn ensure_cloned(&self) -> Result<bool, CommandError> {
-        if !directory_exists(&Path::new(&self.local_path)) {
+        if !directory_exists(&self.local_path) {
             return match self.clone() {
                 Ok(_) => Ok(true),
                 Err(e) => Err(e),
@@ -93,11 +381,13 @@ impl GitRepo {
             return Err(e);
         }
 
-        let output = Command::new("git")
-                         .current_dir(&self.local_path)
-                         .arg("fetch")
-                         .arg("--tags")
-                         .output();
+        let mut command = self.git(true);
+        command.current_dir(&self.local_path)
+               .arg("fetch")
+               .arg("--tags")
+               .args(&self.transfer.fetch_args());
+        let _askpass = try!(self.authenticate(&mut command));
+        let output = command.output();
 
         let result = match output {
             Ok(r) => r,
@@ -118,26 +408,195 @@ impl GitRepo {
         }
     }
 
+    /// Run git in the checkout with token authentication set up, failing with
+    /// `desc` if git exits unsuccessfully.
+    fn run_in_checkout(&self, args: &[String], desc: &'static str) -> Result<Output, CommandError> {
+        let network = args.first().map_or(false, |command| command == "fetch");
+        let mut command = self.git(network);
+        command.current_dir(&self.local_path).args(args);
+        let _askpass = try!(self.authenticate(&mut command));
+        let output = command.output();
+
+        let result = match output {
+            Ok(r) => r,
+            Err(e) => return Err(CommandError {
+                desc: "failed to execute process, see detail",
+                output: None,
+                detail: Some(format!("{}", e)),
+            }),
+        };
+
+        match result.status.success() {
+            true => Ok(result),
+            false => Err(CommandError {
+                desc: desc,
+                output: Some(result),
+                detail: None,
+            }),
+        }
+    }
+
+    /// Whether the commit for `sha` is available in the checkout.
+    fn has_commit(&self) -> bool {
+        Command::new("git")
+            .current_dir(&self.local_path)
+            .arg("cat-file")
+            .arg("-e")
+            .arg(format!("{}^{{commit}}", &self.sha))
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false)
+    }
+
+    /// Fetches to try, in order, when the commit we want isn't in the shallow
+    /// checkout yet: the sha itself (only works if the server allows fetching
+    /// unadvertised objects, which GitHub does), then a deeper history of the
+    /// ref, then the full history of the ref.
+    fn fetch_attempts(&self) -> Vec<Vec<String>> {
+        vec![vec![String::from("fetch"),
+                  String::from("--depth=1"),
+                  String::from("origin"),
+                  self.sha.clone()],
+             vec![String::from("fetch"),
+                  format!("--depth={}", ::std::cmp::max(DEEPEN_DEPTH, self.transfer.depth.unwrap_or(0))),
+                  String::from("origin"),
+                  self.refstring.clone()],
+             vec![String::from("fetch"),
+                  String::from("--unshallow"),
+                  String::from("origin"),
+                  self.refstring.clone()]]
+    }
+
+    /// Make sure the commit for `sha` is in the checkout, fetching it if the
+    /// shallow clone or fetch didn't bring it in, e.g. because more commits
+    /// were pushed to the ref after the one we were asked to deploy.
+    fn fetch_sha(&self) -> Result<(), CommandError> {
+        if self.has_commit() {
+            return Ok(());
+        }
+        let mut last_error = None;
+        for args in self.fetch_attempts() {
+            if let Err(e) = self.run_in_checkout(&args, "git fetch failed") {
+                last_error = Some(e);
+            }
+            if self.has_commit() {
+                return Ok(());
+            }
+        }
+        Err(match last_error {
+            Some(e) => e,
+            None => CommandError {
+                desc: "commit not found on remote, see detail",
+                output: None,
+                detail: Some(format!("could not find {} in the history of {}",
+                                     &self.sha,
+                                     &self.refstring)),
+            },
+        })
+    }
+
+    /// The ref as git knows it in the checkout after a fetch.
+    fn ref_tip(&self) -> String {
+        match self.reftype {
+            RefType::branch => format!("origin/{}", &self.refstring),
+            RefType::tag => format!("refs/tags/{}", &self.refstring),
+        }
+    }
+
+    /// Whether `sha` is the tip of the ref or one of its ancestors.
+    fn is_on_ref(&self) -> Result<bool, CommandError> {
+        let output = Command::new("git")
+                         .current_dir(&self.local_path)
+                         .arg("merge-base")
+                         .arg("--is-ancestor")
+                         .arg(&self.sha)
+                         .arg(&self.ref_tip())
+                         .output();
+        match output {
+            Ok(ref result) if result.status.success() => Ok(true),
+            Ok(ref result) if result.status.code() == Some(1) => Ok(false),
+            Ok(result) => Err(CommandError {
+                desc: "git merge-base failed",
+                output: Some(result),
+                detail: None,
+            }),
+            Err(e) => Err(CommandError {
+                desc: "failed to execute process, see detail",
+                output: None,
+                detail: Some(format!("{}", e)),
+            }),
+        }
+    }
+
+    /// Make sure `sha` is part of the ref's history, so a delivery that
+    /// raced with a force push doesn't deploy a commit the ref no longer
+    /// has. A shallow checkout can hide how the sha connects to the ref, so
+    /// the history is deepened before giving up.
+    fn verify_on_ref(&self) -> Result<(), CommandError> {
+        if try!(self.is_on_ref()) {
+            return Ok(());
+        }
+        for args in self.fetch_attempts().into_iter().skip(1) {
+            // Unshallowing a complete checkout fails, and that's fine
+            let _ = self.run_in_checkout(&args, "git fetch failed");
+            if try!(self.is_on_ref()) {
+                return Ok(());
+            }
+        }
+        Err(CommandError {
+            desc: SHA_NOT_ON_REF,
+            output: None,
+            detail: Some(format!("{} is not in the history of {}, it may have been force pushed away",
+                                 &self.sha,
+                                 &self.refstring)),
+        })
+    }
+
     /// If a repo exists, fetch && reset it. If it doesn't, clone it
     ///
     /// This is currently very dumb in the sense that it only checks if
     /// a directory exists, not whether it's the git repo represented
     /// by `self` or even whether it's a git repository at all.
     ///
+    /// Clones and fetches are shallow, so if the sha isn't at the tip of the
+    /// ref (say, someone pushed again before this task ran) it is fetched
+    /// explicitly, deepening or unshallowing the checkout as a last resort.
+    /// This way we always deploy the exact commit we were asked to.
+    ///
+    /// With `verify_ref` the checkout also fails with `SHA_NOT_ON_REF` if the
+    /// sha isn't in the history of the ref. Rollbacks skip this since the
+    /// sha they go back to may since have been force pushed away.
+    ///
+    /// If `submodules` is set they are updated once the reset is done.
+    ///
+    /// `transfer` can change the depth of the clone and fetch, make the clone
+    /// partial, and run network operations under `trickle` to limit their
+    /// bandwidth.
+    ///
     /// This is the equivalent of doing:
     ///
     /// ```text
     /// (test -d <local_path> && \
     ///   cd <local_path> && \
     ///   git fetch && \
-    ///   git reset --hard <ref>) || \
+    ///   (git cat-file -e <sha> || git fetch --depth=1 origin <sha>) && \
+    ///   git merge-base --is-ancestor <sha> origin/<ref> && \
+    ///   git reset --hard <sha>) || \
     /// git clone --depth=1 --single-branch -b <ref> <remote_path> <local_path>
     /// ```
-    pub fn get_latest(&self) -> Result<Output, CommandError> {
+    pub fn get_latest(&self, verify_ref: bool) -> Result<Output, CommandError> {
         if let Err(e) = self.fetch() {
             return Err(e);
         }
 
+        if let Err(e) = self.fetch_sha() {
+            return Err(e);
+        }
+
+        if verify_ref {
+            try!(self.verify_on_ref());
+        }
+
         let output = Command::new("git")
                          .current_dir(&self.local_path)
                          .arg("reset")
@@ -154,10 +613,61 @@ impl GitRepo {
             }),
         };
 
+        if !result.status.success() {
+            return Err(CommandError {
+                desc: "git reset failed",
+                output: Some(result),
+                detail: None,
+            });
+        }
+
+        match self.submodules {
+            true => self.update_submodules(),
+            false => Ok(result),
+        }
+    }
+
+    /// Remove everything in the checkout that isn't tracked, including ignored
+    /// files like build artifacts, so the next task starts from a pristine
+    /// tree. With `submodules` every submodule is cleaned the same way.
+    pub fn clean(&self, submodules: bool) -> Result<Output, CommandError> {
+        let clean = vec![String::from("clean"), String::from("-ffdx")];
+        let result = try!(self.run_in_checkout(&clean, "git clean failed"));
+        if !submodules {
+            return Ok(result);
+        }
+        let clean_submodules = vec![String::from("submodule"),
+                                    String::from("foreach"),
+                                    String::from("--recursive"),
+                                    String::from("git clean -ffdx")];
+        self.run_in_checkout(&clean_submodules, "git clean failed for submodules")
+    }
+
+    /// Equivalent of `git submodule update --init --recursive` in the
+    /// checkout.
+    pub fn update_submodules(&self) -> Result<Output, CommandError> {
+        let mut command = self.git(true);
+        command.current_dir(&self.local_path)
+               .arg("submodule")
+               .arg("update")
+               .arg("--init")
+               .arg("--recursive");
+        let _askpass = try!(self.authenticate(&mut command));
+        let output = command.output();
+
+        let result = match output {
+            Ok(r) => r,
+            Err(e) => return Err(CommandError {
+                desc: "failed to execute process, see detail",
+                output: None,
+                detail: Some(format!("{}", e)),
+            }),
+        };
+
         match result.status.success() {
             true => Ok(result),
             false => Err(CommandError {
-                desc: "git reset failed",
+                desc: "git submodule update failed",
                 output: Some(result),
                 detail: None,
             }),
@@ -167,8 +677,10 @@ impl GitRepo {
 
 #[cfg(test)]
 mod tests {
-    use super::GitRepo;
+    use super::{GitRepo, CloneProtocol, Transfer, commit_range, https_remote, is_sha, parse_commit};
     use message::RefType;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
     use tempdir::TempDir;
     use verified_path::directory_exists;
 
@@ -182,7 +694,11 @@ mod tests {
             reftype: RefType::branch,
             sha: String::from("HEAD"),
             remote_path: String::from("src/test/test_repo"),
-            local_path: String::from(local_path.to_str().unwrap()),
+            local_path: local_path.clone(),
+            clone_protocol: CloneProtocol::Ssh,
+            token: None,
+            submodules: false,
+            transfer: Transfer::default(),
         };
         assert!(git.clone().is_ok());
         assert!(directory_exists(&local_path));
@@ -199,7 +715,11 @@ mod tests {
             reftype: RefType::branch,
             sha: String::from("HEAD"),
             remote_path: String::from("src/test/test_repo"),
-            local_path: String::from(local_path.to_str().unwrap()),
+            local_path: local_path.clone(),
+            clone_protocol: CloneProtocol::Ssh,
+            token: None,
+            submodules: false,
+            transfer: Transfer::default(),
         };
 
         let first_run = git.ensure_cloned();
@@ -226,9 +746,16 @@ mod tests {
             reftype: RefType::branch,
             sha: String::from("HEAD"),
             remote_path: String::from("src/test/test_repo"),
-            local_path: String::from(local_path.to_str().unwrap()),
+            local_path: local_path.clone(),
+            clone_protocol: CloneProtocol::Ssh,
+            token: None,
+            submodules: false,
+            transfer: Transfer::default(),
         };
-        assert!(git.get_latest().is_ok());
+        assert!(git.get_latest(true).is_ok());
+        assert!(git.is_on_ref().unwrap());
+        assert!(git.update_submodules().is_ok());
+        assert!(git.clean(true).is_ok());
     }
 
     #[test]
@@ -240,8 +767,201 @@ mod tests {
             reftype: RefType::branch,
             sha: String::from("HEAD"),
             remote_path: String::from("doesn't matter"),
-            local_path: String::from("irrelevant"),
+            local_path: PathBuf::from("irrelevant"),
+            clone_protocol: CloneProtocol::Ssh,
+            token: None,
+            submodules: false,
+            transfer: Transfer::default(),
         };
         assert_eq!(git.fully_qualified_branch(), "owner.name.branch");
     }
+
+    #[test]
+    fn test_fetch_attempts() {
+        let git = GitRepo {
+            owner: String::from("owner"),
+            name: String::from("name"),
+            refstring: String::from("branch"),
+            reftype: RefType::branch,
+            sha: String::from("abc123"),
+            remote_path: String::from("doesn't matter"),
+            local_path: PathBuf::from("irrelevant"),
+            clone_protocol: CloneProtocol::Ssh,
+            token: None,
+            submodules: false,
+            transfer: Transfer::default(),
+        };
+        let attempts = git.fetch_attempts();
+        assert_eq!(attempts.len(), 3);
+        assert_eq!(attempts[0], vec!["fetch", "--depth=1", "origin", "abc123"]);
+        assert_eq!(attempts[1], vec!["fetch", "--depth=50", "origin", "branch"]);
+        assert_eq!(attempts[2], vec!["fetch", "--unshallow", "origin", "branch"]);
+    }
+
+    #[test]
+    fn test_get_latest_older_sha() {
+        let origin = TempDir::new("hookshot-git-test").unwrap();
+        git_in(origin.path(), &["init", "-q"]);
+        git_in(origin.path(), &["symbolic-ref", "HEAD", "refs/heads/master"]);
+        git_in(origin.path(), &["commit", "-q", "--allow-empty", "-m", "first"]);
+        let first = git_in(origin.path(), &["rev-parse", "HEAD"]);
+        git_in(origin.path(), &["commit", "-q", "--allow-empty", "-m", "second"]);
+        git_in(origin.path(), &["commit", "-q", "--allow-empty", "-m", "third"]);
+
+        let checkouts = TempDir::new("hookshot-git-test").unwrap();
+        let mut git = GitRepo {
+            owner: String::from("test"),
+            name: String::from("test"),
+            refstring: String::from("master"),
+            reftype: RefType::branch,
+            sha: first.clone(),
+            // A file:// url, since git ignores the depth of local clones
+            remote_path: format!("file://{}", origin.path().display()),
+            local_path: checkouts.path().join("test_repo"),
+            clone_protocol: CloneProtocol::Ssh,
+            token: None,
+            submodules: false,
+            transfer: Transfer::default(),
+        };
+        // The shallow clone only has the tip, the commit pushed first has to
+        // be fetched
+        assert!(git.get_latest(true).is_ok());
+        assert_eq!(git_in(&git.local_path, &["rev-parse", "HEAD"]), first);
+
+        // A sha the remote doesn't have fails, rather than deploying the tip
+        git.sha = String::from("0000000000000000000000000000000000000000");
+        assert!(git.get_latest(false).is_err());
+        assert_eq!(git_in(&git.local_path, &["rev-parse", "HEAD"]), first);
+    }
+
+    #[test]
+    fn test_ref_tip() {
+        let mut git = GitRepo {
+            owner: String::from("owner"),
+            name: String::from("name"),
+            refstring: String::from("branch"),
+            reftype: RefType::branch,
+            sha: String::from("abc123"),
+            remote_path: String::from("doesn't matter"),
+            local_path: PathBuf::from("irrelevant"),
+            clone_protocol: CloneProtocol::Ssh,
+            token: None,
+            submodules: false,
+            transfer: Transfer::default(),
+        };
+        assert_eq!(git.ref_tip(), "origin/branch");
+        git.reftype = RefType::tag;
+        git.refstring = String::from("v1.0.0");
+        assert_eq!(git.ref_tip(), "refs/tags/v1.0.0");
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut transfer = Transfer::default();
+        assert_eq!(transfer.clone_args(), vec!["--depth=1"]);
+        assert!(transfer.fetch_args().is_empty());
+        assert_eq!(transfer.limit_at(12), None);
+
+        transfer.depth = Some(0);
+        transfer.partial_clone = true;
+        assert_eq!(transfer.clone_args(), vec!["--filter=blob:none"]);
+        transfer.depth = Some(20);
+        assert_eq!(transfer.clone_args(), vec!["--depth=20", "--filter=blob:none"]);
+        assert_eq!(transfer.fetch_args(), vec!["--depth=20"]);
+
+        transfer.bandwidth_limit = Some(500);
+        assert_eq!(transfer.limit_at(3), Some(500));
+        transfer.bandwidth_limit_hours = Transfer::parse_hours("9-17");
+        assert_eq!(transfer.limit_at(9), Some(500));
+        assert_eq!(transfer.limit_at(17), None);
+        transfer.bandwidth_limit_hours = Transfer::parse_hours("22-6");
+        assert_eq!(transfer.limit_at(23), Some(500));
+        assert_eq!(transfer.limit_at(5), Some(500));
+        assert_eq!(transfer.limit_at(12), None);
+
+        assert_eq!(Transfer::parse_hours("9-9"), None);
+        assert_eq!(Transfer::parse_hours("9-25"), None);
+        assert_eq!(Transfer::parse_hours("nine-five"), None);
+    }
+
+    #[test]
+    fn test_https_remote() {
+        assert_eq!(https_remote("git@github.com:owner/repo.git", true).unwrap(),
+                   "https://x-access-token@github.com/owner/repo.git");
+        assert_eq!(https_remote("ssh://git@github.com/owner/repo.git", false).unwrap(),
+                   "https://github.com/owner/repo.git");
+        assert_eq!(https_remote("https://github.com/owner/repo.git", true).unwrap(),
+                   "https://x-access-token@github.com/owner/repo.git");
+        assert_eq!(https_remote("git://github.com/owner/repo.git", false).unwrap(),
+                   "https://github.com/owner/repo.git");
+        assert!(https_remote("src/test/test_repo", true).is_none());
+    }
+
+    #[test]
+    fn test_remote_url_with_token() {
+        let git = GitRepo {
+            owner: String::from("owner"),
+            name: String::from("name"),
+            refstring: String::from("branch"),
+            reftype: RefType::branch,
+            sha: String::from("HEAD"),
+            remote_path: String::from("git@github.com:owner/name.git"),
+            local_path: PathBuf::from("irrelevant"),
+            clone_protocol: CloneProtocol::Https,
+            token: Some(String::from("s3cret")),
+            submodules: false,
+            transfer: Transfer::default(),
+        };
+        let url = git.remote_url();
+        assert_eq!(url, "https://x-access-token@github.com/owner/name.git");
+        assert!(!url.contains("s3cret"));
+    }
+
+    fn git_in(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+                         .current_dir(dir)
+                         .env("GIT_AUTHOR_NAME", "Brian")
+                         .env("GIT_AUTHOR_EMAIL", "brian@example.com")
+                         .env("GIT_COMMITTER_NAME", "Brian")
+                         .env("GIT_COMMITTER_EMAIL", "brian@example.com")
+                         .args(args)
+                         .output()
+                         .unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).trim().to_owned()
+    }
+
+    #[test]
+    fn test_commit_range() {
+        let dir = TempDir::new("hookshot-git-test").unwrap();
+        git_in(dir.path(), &["init", "-q"]);
+        git_in(dir.path(), &["commit", "-q", "--allow-empty", "-m", "first"]);
+        let first = git_in(dir.path(), &["rev-parse", "HEAD"]);
+        git_in(dir.path(), &["commit", "-q", "--allow-empty", "-m", "second"]);
+        git_in(dir.path(), &["commit", "-q", "--allow-empty", "-m", "third"]);
+        let third = git_in(dir.path(), &["rev-parse", "HEAD"]);
+
+        let (commits, truncated) = commit_range(dir.path(), &first, &third, 10).unwrap();
+        assert_eq!(commits.iter().map(|c| &c.subject[..]).collect::<Vec<&str>>(), vec!["third", "second"]);
+        assert_eq!(commits[0].sha, third);
+        assert_eq!(commits[0].author, "Brian");
+        assert!(!truncated);
+
+        let (commits, truncated) = commit_range(dir.path(), &first, &third, 1).unwrap();
+        assert_eq!(commits.len(), 1);
+        assert!(truncated);
+        assert!(commit_range(dir.path(), &third, &first, 10).unwrap().0.is_empty());
+        assert!(commit_range(dir.path(), &first, "0000000000", 10).is_err());
+    }
+
+    #[test]
+    fn test_parse_commit() {
+        let commit = parse_commit("abc123\u{1f}Brian\u{1f}1452902400\u{1f}Fix the \u{1f} thing").unwrap();
+        assert_eq!(commit.timestamp, 1452902400);
+        assert_eq!(commit.subject, "Fix the \u{1f} thing");
+        assert!(parse_commit("abc123").is_none());
+        assert!(is_sha("abc123"));
+        assert!(!is_sha("origin/master"));
+        assert!(!is_sha("-n"));
+    }
 }
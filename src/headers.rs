@@ -0,0 +1,10 @@
+//! Custom headers used by the server and the API client.
+
+header! { (XHubSignature, "X-Hub-Signature") => [String] }
+header! { (XHubSignature256, "X-Hub-Signature-256") => [String] }
+header! { (XSignature, "X-Signature") => [String] }
+header! { (XCorrelationId, "X-Correlation-Id") => [String] }
+header! { (XGitHubDelivery, "X-GitHub-Delivery") => [String] }
+header! { (XHookshotTimestamp, "X-Hookshot-Timestamp") => [String] }
+header! { (Prefer, "Prefer") => [String] }
+header! { (RetryAfter, "Retry-After") => [u64] }
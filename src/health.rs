@@ -0,0 +1,146 @@
+//! Checks behind the `/health` endpoint.
+//!
+//! A hookshot that can't write logs, can't check out code or can't find the
+//! tools it deploys with will accept tasks and fail every one of them, so
+//! health means more than "the server is up".
+//!
+//! Checks that fail make hookshot unhealthy. A root on a network filesystem
+//! that is slow or has stale handles makes it degraded instead: tasks may
+//! still work, or fail with I/O errors halfway.
+
+use std::env;
+use std::os::unix::fs::PermissionsExt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use storage;
+use tempdir::TempDir;
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    /// What went wrong, if anything.
+    pub detail: Option<String>,
+    /// Whether failing makes hookshot unhealthy rather than degraded.
+    pub critical: bool,
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    pub healthy: bool,
+    /// Whether a check that isn't critical failed.
+    pub degraded: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Check {
+    fn new(name: String, result: Result<(), String>) -> Check {
+        Check {
+            name: name,
+            ok: result.is_ok(),
+            detail: result.err(),
+            critical: true,
+        }
+    }
+
+    fn warning(name: String, result: Result<(), String>) -> Check {
+        Check { critical: false, ..Check::new(name, result) }
+    }
+}
+
+/// Check that we can create files in `path`.
+pub fn writable(name: &str, path: &Path) -> Check {
+    let result = TempDir::new_in(path, "hookshot-health")
+                     .map(|_| ())
+                     .map_err(|e| format!("{} is not writable: {}", path.display(), e));
+    Check::new(format!("{} writable", name), result)
+}
+
+/// For a root on a network filesystem, check that it responds, see
+/// `storage::probe`. `None` for roots on local filesystems.
+pub fn network_storage(name: &str, path: &Path) -> Option<Check> {
+    storage::network_filesystem(path).map(|fstype| {
+        let result = storage::probe(path).map_err(|e| format!("{} mount: {}", fstype, e));
+        Check::warning(format!("{} storage", name), result)
+    })
+}
+
+/// Find an executable by name in `PATH`.
+pub fn find_executable(program: &str) -> Option<PathBuf> {
+    let path = match env::var_os("PATH") {
+        Some(path) => path,
+        None => return None,
+    };
+    env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| {
+            match fs::metadata(candidate) {
+                Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+                Err(_) => false,
+            }
+        })
+}
+
+/// Check that `program` is on the `PATH`.
+pub fn on_path(program: &str) -> Check {
+    let result = match find_executable(program) {
+        Some(_) => Ok(()),
+        None => Err(format!("could not find {} on the PATH", program)),
+    };
+    Check::new(format!("{} installed", program), result)
+}
+
+/// Run every check.
+pub fn check(checkout_root: &Path,
+             log_root: &Path,
+             tools: &[String],
+             task_manager_running: bool)
+             -> HealthReport {
+    let mut checks = vec![writable("checkout_root", checkout_root), writable("log_root", log_root)];
+    checks.extend(network_storage("checkout_root", checkout_root));
+    checks.extend(network_storage("log_root", log_root));
+    for tool in tools {
+        checks.push(on_path(tool));
+    }
+    checks.push(Check::new(String::from("task manager running"),
+                           match task_manager_running {
+                               true => Ok(()),
+                               false => Err(String::from("task manager is shut down")),
+                           }));
+    HealthReport {
+        healthy: checks.iter().all(|c| c.ok || !c.critical),
+        degraded: checks.iter().any(|c| !c.ok && !c.critical),
+        checks: checks,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_healthy() {
+        let dir = TempDir::new("hookshot-health-test").unwrap();
+        let report = check(dir.path(), dir.path(), &[String::from("sh")], true);
+        assert!(report.healthy);
+        assert!(!report.degraded);
+        assert_eq!(report.checks.len(), 4);
+    }
+
+    #[test]
+    fn test_unhealthy() {
+        let dir = TempDir::new("hookshot-health-test").unwrap();
+        let report = check(Path::new("/does/not/exist"),
+                           dir.path(),
+                           &[String::from("definitely-not-a-real-program")],
+                           false);
+        assert!(!report.healthy);
+        let failed = report.checks.iter().filter(|c| !c.ok).map(|c| &c.name[..]).collect::<Vec<_>>();
+        assert_eq!(failed,
+                   vec!["checkout_root writable",
+                        "definitely-not-a-real-program installed",
+                        "task manager running"]);
+    }
+}
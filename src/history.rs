@@ -0,0 +1,845 @@
+//! A record of every task hookshot has accepted.
+//!
+//! Each task gets a `TaskRecord` written next to its log file as
+//! `<log_root>/<task_id>.json`. The records are loaded back into memory on
+//! startup so questions like "what was the last sha that deployed
+//! successfully to production" survive a restart.
+
+use ansible_task::HostRecap;
+use chrono::UTC;
+use git;
+use message::RefType;
+use payloads;
+use repo_config::ResolvedConfig;
+use rustc_serialize::{json, Decodable, Decoder, Encodable, Encoder};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use storage::{self, SyncPolicy};
+use uuid::Uuid;
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskStatus {
+    Queued,
+    Running,
+    Success,
+    Failed,
+    Cancelled,
+    /// Nothing to deploy, e.g. none of the files the ref has `paths` for
+    /// changed.
+    Skipped,
+}
+
+impl TaskStatus {
+    /// Whether a task in this state will ever change state again.
+    pub fn is_terminal(&self) -> bool {
+        match *self {
+            TaskStatus::Queued | TaskStatus::Running => false,
+            TaskStatus::Success | TaskStatus::Failed | TaskStatus::Cancelled |
+            TaskStatus::Skipped => true,
+        }
+    }
+}
+
+/// How a task came to be, for telling who or what started a deploy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// A GitHub push webhook to `POST /tasks`.
+    GitHubWebhook,
+    /// A simple message to `POST /tasks`, usually from a script or CI.
+    SimpleMessage,
+    /// A `[[schedule]]` entry.
+    Schedule,
+    /// `POST /tasks/:uuid/replay` of the task with this id.
+    ReplayOf(String),
+    /// `POST /rollback/...`, back to the sha of the task with this id.
+    RollbackOf(String),
+    /// `POST /repos/:owner/:repo/warm`, only getting the checkout ready.
+    Warm,
+}
+
+impl Trigger {
+    pub fn from_str(s: &str) -> Option<Trigger> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("github-webhook"), None) => Some(Trigger::GitHubWebhook),
+            (Some("simple-message"), None) => Some(Trigger::SimpleMessage),
+            (Some("schedule"), None) => Some(Trigger::Schedule),
+            (Some("warm"), None) => Some(Trigger::Warm),
+            (Some("replay-of"), Some(id)) => Some(Trigger::ReplayOf(String::from(id))),
+            (Some("rollback-of"), Some(id)) => Some(Trigger::RollbackOf(String::from(id))),
+            _ => None,
+        }
+    }
+
+    /// The trigger without the task it refers to, e.g. `rollback-of`.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Trigger::GitHubWebhook => "github-webhook",
+            Trigger::SimpleMessage => "simple-message",
+            Trigger::Schedule => "schedule",
+            Trigger::ReplayOf(_) => "replay-of",
+            Trigger::RollbackOf(_) => "rollback-of",
+            Trigger::Warm => "warm",
+        }
+    }
+
+    /// Whether `filter` names this trigger, either exactly or by kind.
+    pub fn matches(&self, filter: &str) -> bool {
+        self.kind() == filter || self.to_string() == filter
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Trigger::ReplayOf(ref id) | Trigger::RollbackOf(ref id) => write!(f, "{}:{}", self.kind(), id),
+            _ => write!(f, "{}", self.kind()),
+        }
+    }
+}
+
+impl Encodable for Trigger {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(&self.to_string())
+    }
+}
+
+impl Decodable for Trigger {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Trigger, D::Error> {
+        let s = try!(d.read_str());
+        match Trigger::from_str(&s) {
+            Some(trigger) => Ok(trigger),
+            None => Err(d.error(&format!("unknown trigger '{}'", s))),
+        }
+    }
+}
+
+/// How long one step of a task took, see `TaskRecord::steps`.
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, PartialEq, Eq)]
+pub struct StepTiming {
+    /// `checkout`, `config`, `lock`, `submodules`, `clean`, the name of a
+    /// hook, `task`, `notify_started` or `notify_finished`.
+    pub step: String,
+    pub milliseconds: u64,
+}
+
+impl StepTiming {
+    pub fn new(step: &str, elapsed: ::std::time::Duration) -> StepTiming {
+        StepTiming {
+            step: String::from(step),
+            milliseconds: elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64,
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+pub struct TaskRecord {
+    pub id: String,
+    pub owner: String,
+    pub repo: String,
+    pub refstring: String,
+    pub reftype: RefType,
+    pub sha: String,
+    pub remote_path: String,
+    pub local_path: String,
+    pub status: TaskStatus,
+    pub is_rollback: bool,
+    /// The project of a monorepo the task deploys, see `projects` in the
+    /// server config.
+    pub project: Option<String>,
+    /// How the task was started. `None` for tasks recorded before triggers
+    /// were.
+    pub trigger: Option<Trigger>,
+
+    /// Identifier supplied by the client that triggered the task, so external
+    /// systems can look tasks up by their own ids.
+    pub correlation_id: Option<String>,
+
+    /// Unix timestamps (seconds) for when the task was accepted, when a
+    /// worker picked it up and when it reached a terminal state.
+    pub queued_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+
+    /// Seconds spent waiting in the queue and seconds spent running. Kept
+    /// separately so slow deploys can be blamed on queue contention or on
+    /// the task itself.
+    pub wait_seconds: Option<i64>,
+    pub run_seconds: Option<i64>,
+
+    /// Bytes used by the checkout and by the task's temporary directory when
+    /// the task finished.
+    pub checkout_bytes: Option<u64>,
+    pub tmp_bytes: Option<u64>,
+
+    /// How long each step of the task's last attempt took, in the order they
+    /// ran, so a slow deploy can be blamed on fetching the repo, waiting for
+    /// a lock or the task itself. Steps that didn't run aren't listed.
+    pub steps: Option<Vec<StepTiming>>,
+
+    /// Per-host results from ansible's play recap, for ansible tasks that
+    /// got as far as printing one.
+    pub hosts: Option<BTreeMap<String, HostRecap>>,
+
+    /// What went wrong, for tasks that failed.
+    pub error: Option<String>,
+    /// Why the task's repo or ref was in quarantine when the task finished,
+    /// see `quarantine`. A failure is allowed for tasks that have one.
+    pub quarantine: Option<String>,
+
+    /// The parts of the repo config the task ran with, once it was read.
+    pub config: Option<ResolvedConfig>,
+}
+#[cfg(test)]
+impl TaskRecord {
+    /// A queued task for `owner/repo` on `master`, with everything that isn't
+    /// an argument left out, for tests to change what they need.
+    pub fn test(id: &str, sha: &str, status: TaskStatus) -> TaskRecord {
+        TaskRecord {
+            id: String::from(id),
+            owner: String::from("owner"),
+            repo: String::from("repo"),
+            refstring: String::from("master"),
+            reftype: RefType::branch,
+            sha: String::from(sha),
+            remote_path: String::from("remote"),
+            local_path: String::from("local"),
+            status: status,
+            is_rollback: false,
+            project: None,
+            trigger: None,
+            correlation_id: None,
+            queued_at: 0,
+            started_at: None,
+            finished_at: None,
+            wait_seconds: None,
+            run_seconds: None,
+            checkout_bytes: None,
+            tmp_bytes: None,
+            steps: None,
+            hosts: None,
+            config: None,
+            error: None,
+            quarantine: None,
+        }
+    }
+}
+
+pub type SharedHistory = Arc<Mutex<TaskHistory>>;
+
+/// How many of a ref's most recent deploys `RefStats` looks at.
+pub const STATS_WINDOW: usize = 20;
+
+/// How a ref's recent deploys went, for notifications to give a task some
+/// context. Only deploys that succeeded or failed count.
+#[derive(RustcEncodable, Clone, Debug, PartialEq)]
+pub struct RefStats {
+    /// Deploys looked at, at most `STATS_WINDOW`.
+    pub deploys: usize,
+    /// Fraction of those that succeeded, `None` without any.
+    pub success_rate: Option<f64>,
+    pub average_run_seconds: Option<f64>,
+    /// Seconds since the last successful deploy finished, looking past the
+    /// window too.
+    pub seconds_since_success: Option<i64>,
+    /// How many of the most recent deploys in a row succeeded, looking past
+    /// the window too.
+    pub success_streak: usize,
+}
+
+pub struct TaskHistory {
+    root: PathBuf,
+    records: BTreeMap<String, TaskRecord>,
+
+    /// Tasks that are owned by a live `DeployTask` in this process. Anything
+    /// queued or running that isn't in here belonged to a worker that died.
+    active: BTreeSet<String>,
+
+    /// Whether records are flushed to disk after they are written.
+    sync: SyncPolicy,
+}
+
+pub fn now() -> i64 {
+    UTC::now().timestamp()
+}
+
+/// How often `wait_for` checks whether a task has finished.
+const WAIT_POLL_MS: u32 = 250;
+
+/// Block until the task for `id` (a task or correlation id) reaches a terminal
+/// state or `timeout` seconds have passed, then return its record as of that
+/// point. Returns `None` if there is no such task. The history lock is only
+/// held while checking, never while sleeping.
+pub fn wait_for(history: &SharedHistory, id: &str, timeout: u64) -> Option<TaskRecord> {
+    let deadline = now() + timeout as i64;
+    loop {
+        let record = match history.lock().unwrap().resolve(id) {
+            Some(record) => record.clone(),
+            None => return None,
+        };
+        if record.status.is_terminal() || now() >= deadline {
+            return Some(record);
+        }
+        thread::sleep_ms(WAIT_POLL_MS);
+    }
+}
+
+impl TaskHistory {
+    /// Create an empty history that persists records to `root`.
+    pub fn new(root: &Path) -> TaskHistory {
+        TaskHistory {
+            root: root.to_path_buf(),
+            records: BTreeMap::new(),
+            active: BTreeSet::new(),
+            sync: SyncPolicy::Never,
+        }
+    }
+
+    /// Create a history from any records already stored in `root`. Files
+    /// that can't be read or decoded are skipped.
+    pub fn load(root: &Path) -> TaskHistory {
+        let mut history = TaskHistory::new(root);
+        let entries = match fs::read_dir(root) {
+            Ok(entries) => entries,
+            Err(_) => return history,
+        };
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(_) => continue,
+            };
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            // Only `<task_id>.json` is a record, other JSON files like
+            // `quarantine.json` live in the log root too
+            let id = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(stem) if Uuid::parse_str(stem).is_ok() => String::from(stem),
+                _ => continue,
+            };
+            let mut contents = String::new();
+            match File::open(&path) {
+                Ok(mut file) => {
+                    if file.read_to_string(&mut contents).is_err() {
+                        continue;
+                    }
+                }
+                Err(_) => continue,
+            }
+            match json::decode::<TaskRecord>(&contents) {
+                Ok(ref record) if record.id != id => continue,
+                Ok(record) => {
+                    history.records.insert(id, record);
+                }
+                Err(_) => continue,
+            }
+        }
+        history
+    }
+
+    /// Flush records to disk after writing them unless `sync` is `Never`.
+    pub fn set_sync(&mut self, sync: SyncPolicy) {
+        self.sync = sync;
+    }
+
+    pub fn get(&self, id: &str) -> Option<&TaskRecord> {
+        self.records.get(id)
+    }
+
+    /// Look up a record by task id, falling back to correlation id.
+    pub fn resolve(&self, id: &str) -> Option<&TaskRecord> {
+        match self.records.get(id) {
+            Some(record) => Some(record),
+            None => self.records
+                        .values()
+                        .find(|r| r.correlation_id.as_ref().map(|c| &c[..]) == Some(id)),
+        }
+    }
+
+    /// Look up a batch of tasks by id or correlation id. Ids that don't match
+    /// a task map to `None`.
+    pub fn resolve_all(&self, ids: &[String]) -> BTreeMap<String, Option<TaskRecord>> {
+        ids.iter()
+           .map(|id| (id.clone(), self.resolve(id).cloned()))
+           .collect()
+    }
+
+    /// Every record, in no particular order.
+    pub fn records(&self) -> Vec<&TaskRecord> {
+        self.records.values().collect()
+    }
+
+    /// Checkout paths of every task that is queued or running.
+    pub fn busy_checkouts(&self) -> BTreeSet<PathBuf> {
+        self.records
+            .values()
+            .filter(|r| !r.status.is_terminal())
+            .map(|r| PathBuf::from(&r.local_path))
+            .collect()
+    }
+
+    /// Add a new record for a task owned by this process and persist it.
+    pub fn insert(&mut self, record: TaskRecord) {
+        self.persist(&record);
+        self.active.insert(record.id.clone());
+        self.records.insert(record.id.clone(), record);
+    }
+
+    /// `insert`, unless the record's correlation id already names a task.
+    /// Checking and inserting under one lock of the history keeps two
+    /// requests with the same correlation id from both getting through.
+    /// False if the id is taken, and nothing is inserted then.
+    pub fn insert_unique(&mut self, record: TaskRecord) -> bool {
+        let taken = match record.correlation_id {
+            Some(ref correlation_id) => self.resolve(correlation_id).is_some(),
+            None => false,
+        };
+        if taken {
+            return false;
+        }
+        self.insert(record);
+        true
+    }
+
+    /// Take over a task a previous process left queued, so `reconcile`
+    /// doesn't fail it. False if there is no queued task for `id`.
+    pub fn claim(&mut self, id: &str) -> bool {
+        match self.records.get(id) {
+            Some(record) if record.status == TaskStatus::Queued => {
+                self.active.insert(String::from(id));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Note that the task for `id` no longer exists in this process.
+    pub fn release(&mut self, id: &str) {
+        self.active.remove(id);
+    }
+
+    /// Modify an existing record in place and persist the result. Does
+    /// nothing if there is no record for `id`.
+    pub fn update<F>(&mut self, id: &str, f: F)
+        where F: FnOnce(&mut TaskRecord)
+    {
+        let record = match self.records.get_mut(id) {
+            Some(record) => {
+                f(record);
+                record.clone()
+            }
+            None => return,
+        };
+        self.persist(&record);
+    }
+
+    pub fn set_status(&mut self, id: &str, status: TaskStatus) {
+        self.update(id, |record| {
+            let now = now();
+            match status {
+                TaskStatus::Running => {
+                    record.started_at = Some(now);
+                    record.wait_seconds = Some(now - record.queued_at);
+                }
+                _ if status.is_terminal() => {
+                    record.finished_at = Some(now);
+                    match record.started_at {
+                        Some(started_at) => record.run_seconds = Some(now - started_at),
+                        // Cancelled before it ever ran, all of its time was
+                        // spent waiting.
+                        None => record.wait_seconds = Some(now - record.queued_at),
+                    }
+                }
+                _ => {}
+            }
+            record.status = status;
+        });
+    }
+
+    /// All records for a ref, oldest first.
+    pub fn for_ref(&self, owner: &str, repo: &str, refstring: &str) -> Vec<&TaskRecord> {
+        let mut records = self.records
+                              .values()
+                              .filter(|r| git::names_match(&r.owner, owner) &&
+                                          git::names_match(&r.repo, repo) &&
+                                          r.refstring == refstring)
+                              .collect::<Vec<_>>();
+        records.sort_by(|a, b| a.queued_at.cmp(&b.queued_at));
+        records
+    }
+
+    /// The most recently queued task for a ref.
+    pub fn latest(&self, owner: &str, repo: &str, refstring: &str) -> Option<&TaskRecord> {
+        self.for_ref(owner, repo, refstring).into_iter().last()
+    }
+
+    /// The most recently queued task for any ref of a repo.
+    pub fn latest_for_repo(&self, owner: &str, repo: &str) -> Option<&TaskRecord> {
+        self.records
+            .values()
+            .filter(|r| git::names_match(&r.owner, owner) && git::names_match(&r.repo, repo))
+            .max_by_key(|r| r.queued_at)
+    }
+
+    /// The most recently finished successful task for a ref, of one project
+    /// for repos that have them.
+    pub fn last_successful(&self,
+                           owner: &str,
+                           repo: &str,
+                           refstring: &str,
+                           project: Option<&str>)
+                           -> Option<&TaskRecord> {
+        self.for_ref(owner, repo, refstring)
+            .into_iter()
+            .filter(|r| r.status == TaskStatus::Success && r.project.as_ref().map(|p| &p[..]) == project)
+            .max_by_key(|r| r.finished_at.unwrap_or(r.queued_at))
+    }
+
+    /// The project of the task for `id`, so the deploys compared with it
+    /// are of the same project.
+    fn project_of(&self, id: &str) -> Option<String> {
+        self.records.get(id).and_then(|r| r.project.clone())
+    }
+
+    /// Statistics over the last `STATS_WINDOW` deploys of a ref that
+    /// succeeded or failed, leaving out the task for `id`. Only deploys of
+    /// the same project count.
+    pub fn stats(&self, id: &str, owner: &str, repo: &str, refstring: &str) -> RefStats {
+        let project = self.project_of(id);
+        let mut finished = self.for_ref(owner, repo, refstring)
+                               .into_iter()
+                               .filter(|r| r.id != id && r.finished_at.is_some() && r.project == project)
+                               .filter(|r| r.status == TaskStatus::Success || r.status == TaskStatus::Failed)
+                               .collect::<Vec<_>>();
+        finished.sort_by(|a, b| b.finished_at.cmp(&a.finished_at));
+        let seconds_since_success = finished.iter()
+                                            .find(|r| r.status == TaskStatus::Success)
+                                            .map(|r| now() - r.finished_at.unwrap());
+        let success_streak = finished.iter().take_while(|r| r.status == TaskStatus::Success).count();
+        finished.truncate(STATS_WINDOW);
+
+        let successes = finished.iter().filter(|r| r.status == TaskStatus::Success).count();
+        let run_seconds = finished.iter().filter_map(|r| r.run_seconds).collect::<Vec<i64>>();
+        RefStats {
+            deploys: finished.len(),
+            success_rate: match finished.len() {
+                0 => None,
+                n => Some(successes as f64 / n as f64),
+            },
+            average_run_seconds: match run_seconds.len() {
+                0 => None,
+                n => Some(run_seconds.iter().fold(0, |sum, s| sum + s) as f64 / n as f64),
+            },
+            seconds_since_success: seconds_since_success,
+            success_streak: success_streak,
+        }
+    }
+
+    /// The most recently finished task for a ref and project other than
+    /// `id`, regardless of whether it succeeded.
+    pub fn previous_finished(&self,
+                             id: &str,
+                             owner: &str,
+                             repo: &str,
+                             refstring: &str)
+                             -> Option<&TaskRecord> {
+        let project = self.project_of(id);
+        self.for_ref(owner, repo, refstring)
+            .into_iter()
+            .filter(|r| r.id != id && r.finished_at.is_some() && r.project == project)
+            .filter(|r| r.status == TaskStatus::Success || r.status == TaskStatus::Failed)
+            .max_by_key(|r| r.finished_at.unwrap())
+    }
+
+    /// Bring the index back in line with reality: tasks that are queued or
+    /// running but no longer owned by anything in this process are marked as
+    /// failed, and finished tasks older than `retention` seconds are removed.
+    /// Returns the number of (failed, pruned) records.
+    #[allow(unused_must_use)]
+    pub fn reconcile(&mut self, retention: Option<i64>) -> (usize, usize) {
+        let now = now();
+        let orphaned = self.records
+                           .values()
+                           .filter(|r| !r.status.is_terminal() && !self.active.contains(&r.id))
+                           .map(|r| r.id.clone())
+                           .collect::<Vec<_>>();
+        for id in &orphaned {
+            self.set_status(id, TaskStatus::Failed);
+        }
+
+        let expired = match retention {
+            None => vec![],
+            Some(retention) => {
+                self.records
+                    .values()
+                    .filter(|r| r.status.is_terminal())
+                    .filter(|r| now - r.finished_at.unwrap_or(r.queued_at) > retention)
+                    .map(|r| r.id.clone())
+                    .collect::<Vec<_>>()
+            }
+        };
+        for id in &expired {
+            self.records.remove(id);
+            fs::remove_file(self.root.join(format!("{}.json", id)));
+            payloads::remove(&self.root, id);
+        }
+
+        (orphaned.len(), expired.len())
+    }
+
+    fn persist(&self, record: &TaskRecord) {
+        let path = self.root.join(format!("{}.json", record.id));
+        let encoded = match json::encode(record) {
+            Ok(encoded) => encoded,
+            Err(_) => return,
+        };
+        if let Err(e) = storage::write_file(&path, encoded.as_bytes(), self.sync) {
+            warn!(&record.id, "could not save the task record: {}", storage::describe(&e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::json;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use tempdir::TempDir;
+
+    fn record(id: &str, sha: &str, status: TaskStatus, finished_at: i64) -> TaskRecord {
+        let mut record = TaskRecord::test(id, sha, status);
+        record.queued_at = finished_at - 10;
+        record.started_at = Some(finished_at - 5);
+        record.finished_at = Some(finished_at);
+        record.wait_seconds = Some(5);
+        record.run_seconds = Some(5);
+        record
+    }
+
+    #[test]
+    fn test_last_successful() {
+        let dir = TempDir::new("hookshot-history-test").unwrap();
+        let mut history = TaskHistory::new(dir.path());
+        history.insert(record("a", "sha-a", TaskStatus::Success, 100));
+        history.insert(record("b", "sha-b", TaskStatus::Success, 200));
+        history.insert(record("c", "sha-c", TaskStatus::Failed, 300));
+
+        let last = history.last_successful("owner", "repo", "master", None).unwrap();
+        assert_eq!(last.sha, "sha-b");
+        assert!(history.last_successful("owner", "repo", "other", None).is_none());
+        assert_eq!(history.latest("owner", "repo", "master").unwrap().sha, "sha-c");
+        // Payloads can spell the repo differently
+        assert_eq!(history.latest("Owner", "REPO", "master").unwrap().sha, "sha-c");
+        assert!(history.latest("owner", "repo", "Master").is_none());
+
+        // Projects of a monorepo deploy on their own
+        let mut api = record("d", "sha-d", TaskStatus::Success, 400);
+        api.project = Some(String::from("api"));
+        history.insert(api);
+        assert_eq!(history.last_successful("owner", "repo", "master", None).unwrap().sha, "sha-b");
+        assert_eq!(history.last_successful("owner", "repo", "master", Some("api")).unwrap().sha, "sha-d");
+        assert!(history.last_successful("owner", "repo", "master", Some("web")).is_none());
+    }
+
+    #[test]
+    fn test_trigger() {
+        let rollback = Trigger::RollbackOf(String::from("a"));
+        assert_eq!(rollback.to_string(), "rollback-of:a");
+        assert_eq!(Trigger::from_str("rollback-of:a"), Some(rollback.clone()));
+        assert_eq!(Trigger::from_str("schedule"), Some(Trigger::Schedule));
+        assert_eq!(Trigger::from_str("rollback-of"), None);
+        assert_eq!(Trigger::from_str("carrier-pigeon"), None);
+        assert!(rollback.matches("rollback-of"));
+        assert!(rollback.matches("rollback-of:a"));
+        assert!(!rollback.matches("rollback-of:b"));
+
+        let mut triggered = record("a", "sha-a", TaskStatus::Success, 100);
+        triggered.trigger = Some(rollback);
+        let decoded = json::decode::<TaskRecord>(&json::encode(&triggered).unwrap()).unwrap();
+        assert_eq!(decoded.trigger, triggered.trigger);
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = TempDir::new("hookshot-history-test").unwrap();
+        let mut history = TaskHistory::new(dir.path());
+        let stats = history.stats("current", "owner", "repo", "master");
+        assert_eq!(stats.deploys, 0);
+        assert_eq!(stats.success_rate, None);
+        assert_eq!(stats.seconds_since_success, None);
+
+        history.insert(record("a", "sha-a", TaskStatus::Failed, 100));
+        for i in 0..24 {
+            let id = format!("ok-{}", i);
+            history.insert(record(&id, "sha", TaskStatus::Success, 200 + i));
+        }
+        let mut slow = record("slow", "sha-slow", TaskStatus::Success, 300);
+        slow.run_seconds = Some(45);
+        history.insert(slow);
+        history.insert(record("cancelled", "sha-c", TaskStatus::Cancelled, 400));
+        history.insert(record("current", "sha-d", TaskStatus::Running, 500));
+
+        let stats = history.stats("current", "owner", "repo", "master");
+        assert_eq!(stats.deploys, STATS_WINDOW);
+        assert_eq!(stats.success_rate, Some(1.0));
+        assert_eq!(stats.average_run_seconds, Some(7.0));
+        assert_eq!(stats.success_streak, 25);
+        assert!(stats.seconds_since_success.unwrap() >= now() - 300);
+
+        history.insert(record("b", "sha-b", TaskStatus::Failed, 450));
+        let stats = history.stats("current", "owner", "repo", "master");
+        assert_eq!(stats.success_rate, Some(0.95));
+        assert_eq!(stats.success_streak, 0);
+    }
+
+    #[test]
+    fn test_resolve_correlation_id() {
+        let dir = TempDir::new("hookshot-history-test").unwrap();
+        let mut history = TaskHistory::new(dir.path());
+        let mut correlated = record("a", "sha-a", TaskStatus::Queued, 100);
+        correlated.correlation_id = Some(String::from("build-1234"));
+        history.insert(correlated);
+
+        assert_eq!(history.resolve("a").unwrap().id, "a");
+        assert_eq!(history.resolve("build-1234").unwrap().id, "a");
+        assert!(history.resolve("build-5678").is_none());
+
+        let mut duplicate = record("b", "sha-b", TaskStatus::Queued, 200);
+        duplicate.correlation_id = Some(String::from("build-1234"));
+        assert!(!history.insert_unique(duplicate.clone()));
+        assert!(history.get("b").is_none());
+        duplicate.correlation_id = Some(String::from("build-5678"));
+        assert!(history.insert_unique(duplicate));
+        assert_eq!(history.resolve("build-5678").unwrap().id, "b");
+    }
+
+    #[test]
+    fn test_resolve_all() {
+        let dir = TempDir::new("hookshot-history-test").unwrap();
+        let mut history = TaskHistory::new(dir.path());
+        history.insert(record("a", "sha-a", TaskStatus::Success, 100));
+        history.insert(record("b", "sha-b", TaskStatus::Running, 200));
+
+        let ids = vec![String::from("a"), String::from("b"), String::from("nope")];
+        let statuses = history.resolve_all(&ids);
+        assert_eq!(statuses.len(), 3);
+        assert_eq!(statuses["a"].as_ref().unwrap().status, TaskStatus::Success);
+        assert_eq!(statuses["b"].as_ref().unwrap().status, TaskStatus::Running);
+        assert!(statuses["nope"].is_none());
+    }
+
+    #[test]
+    fn test_wait_for() {
+        let dir = TempDir::new("hookshot-history-test").unwrap();
+        let history = Arc::new(Mutex::new(TaskHistory::new(dir.path())));
+        history.lock().unwrap().insert(record("a", "sha-a", TaskStatus::Running, 100));
+        history.lock().unwrap().insert(record("b", "sha-b", TaskStatus::Running, 100));
+
+        let finisher = history.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep_ms(300);
+            finisher.lock().unwrap().set_status("a", TaskStatus::Success);
+        });
+        assert_eq!(wait_for(&history, "a", 10).unwrap().status, TaskStatus::Success);
+        handle.join().unwrap();
+
+        // Times out with the task still running
+        assert_eq!(wait_for(&history, "b", 0).unwrap().status, TaskStatus::Running);
+        assert!(wait_for(&history, "nope", 0).is_none());
+    }
+
+    #[test]
+    fn test_set_status_timings() {
+        let dir = TempDir::new("hookshot-history-test").unwrap();
+        let mut history = TaskHistory::new(dir.path());
+        let mut queued = record("a", "sha-a", TaskStatus::Queued, now());
+        queued.started_at = None;
+        queued.finished_at = None;
+        queued.wait_seconds = None;
+        queued.run_seconds = None;
+        history.insert(queued);
+
+        history.set_status("a", TaskStatus::Running);
+        assert!(history.get("a").unwrap().wait_seconds.unwrap() >= 10);
+        assert!(history.get("a").unwrap().run_seconds.is_none());
+
+        history.set_status("a", TaskStatus::Success);
+        assert!(history.get("a").unwrap().run_seconds.unwrap() <= 1);
+    }
+
+    // Records are only loaded from files named after a uuid
+    const GHOST: &'static str = "5b1f3c2e-0d4a-4c8e-9a51-0f6e2d7b8c91";
+    const QUEUED: &'static str = "8e2a6d14-3b7f-4f0c-b1d9-4c5e7a2f9b03";
+    const RUNNING: &'static str = "c47d9e31-6a2b-4d8f-8e15-2b9c0f4a7d62";
+    const A: &'static str = "1f8b3e6c-9d2a-4b7e-a3c5-7e0d4f1b9a28";
+
+    #[test]
+    fn test_reconcile() {
+        let dir = TempDir::new("hookshot-history-test").unwrap();
+        {
+            let mut history = TaskHistory::new(dir.path());
+            history.insert(record(GHOST, "sha", TaskStatus::Running, now()));
+        }
+        let mut history = TaskHistory::load(dir.path());
+        history.insert(record("live", "sha", TaskStatus::Running, now()));
+        history.insert(record("old", "sha", TaskStatus::Success, now() - 1000));
+
+        assert_eq!(history.reconcile(Some(500)), (1, 1));
+        assert_eq!(history.get(GHOST).unwrap().status, TaskStatus::Failed);
+        assert_eq!(history.get("live").unwrap().status, TaskStatus::Running);
+        assert!(history.get("old").is_none());
+        assert!(!dir.path().join("old.json").exists());
+
+        history.release("live");
+        assert_eq!(history.reconcile(None), (1, 0));
+        assert_eq!(history.get("live").unwrap().status, TaskStatus::Failed);
+    }
+
+    #[test]
+    fn test_claim() {
+        let dir = TempDir::new("hookshot-history-test").unwrap();
+        {
+            let mut history = TaskHistory::new(dir.path());
+            history.insert(record(QUEUED, "sha", TaskStatus::Queued, now()));
+            history.insert(record(RUNNING, "sha", TaskStatus::Running, now()));
+        }
+        let mut history = TaskHistory::load(dir.path());
+        assert!(history.claim(QUEUED));
+        assert!(!history.claim(RUNNING));
+        assert!(!history.claim("missing"));
+        assert_eq!(history.reconcile(None), (1, 0));
+        assert_eq!(history.get(QUEUED).unwrap().status, TaskStatus::Queued);
+        assert_eq!(history.get(RUNNING).unwrap().status, TaskStatus::Failed);
+    }
+
+    #[test]
+    fn test_load_roundtrip() {
+        let dir = TempDir::new("hookshot-history-test").unwrap();
+        {
+            let mut history = TaskHistory::new(dir.path());
+            history.insert(record(A, "sha-a", TaskStatus::Queued, 100));
+            history.set_status(A, TaskStatus::Success);
+        }
+        let history = TaskHistory::load(dir.path());
+        let loaded = history.get(A).unwrap();
+        assert_eq!(loaded.status, TaskStatus::Success);
+        assert_eq!(loaded.sha, "sha-a");
+
+        // Other JSON files in the log root aren't records, even if they
+        // decode as one
+        {
+            let mut history = TaskHistory::new(dir.path());
+            history.insert(record("not-a-uuid", "sha-b", TaskStatus::Success, 100));
+        }
+        fs::copy(dir.path().join(format!("{}.json", A)), dir.path().join("quarantine.json")).unwrap();
+        let history = TaskHistory::load(dir.path());
+        assert_eq!(history.records().len(), 1);
+        assert!(history.get("not-a-uuid").is_none());
+    }
+}
@@ -0,0 +1,85 @@
+//! Commands from the repo config that run around the deploy task.
+//!
+//! `before_task` runs first and the task doesn't run if it fails,
+//! `after_task` runs after the task succeeds and `on_failure` after the task
+//! or either of the other hooks fails. They run with `sh -c` in the project
+//! root, with the same environment as the task.
+//!
+//! A hook can be a command or a table with a `command` and
+//! `allow_failure = true`, for hooks whose failure should be logged but not
+//! fail the task.
+
+use error::CommandError;
+use process::{CommandLine, ProcessGroups};
+use server_config::Environment;
+use std::ascii::AsciiExt;
+use std::path::Path;
+use std::process::Output;
+
+#[derive(Debug, Clone, PartialEq, Eq, RustcEncodable)]
+pub struct Hook {
+    pub command: String,
+    pub allow_failure: bool,
+}
+
+impl Hook {
+    pub fn new(command: &str) -> Hook {
+        Hook {
+            command: String::from(command),
+            allow_failure: false,
+        }
+    }
+}
+
+pub fn run(command: &str,
+           project_root: &Path,
+           env: &Environment,
+           groups: &ProcessGroups)
+           -> Result<Output, CommandError> {
+    let mut cmd = CommandLine::new("sh");
+    cmd.current_dir(project_root);
+    cmd.arg("-c").arg(command);
+
+    for (k, v) in env {
+        let uppercase_key = k.chars().map(|c| c.to_ascii_uppercase()).collect::<String>();
+        cmd.env(uppercase_key, v);
+    }
+
+    match groups.output(&cmd) {
+        Ok(r) => Ok(r),
+        Err(e) => Err(CommandError {
+            desc: "failed to execute `sh`, see detail",
+            output: None,
+            detail: Some(format!("{}", e)),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run;
+    use process::ProcessGroups;
+    use server_config::Environment;
+    use std::path::Path;
+
+    #[test]
+    fn test_run_hook() {
+        let mut env = Environment::new();
+        env.insert(String::from("git_ref"), String::from("production"));
+        let output = run("echo \"deploying $GIT_REF from $(basename $PWD)\"",
+                         Path::new("./src/test/make_task"),
+                         &env,
+                         &ProcessGroups::new())
+                         .ok()
+                         .unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(),
+                   "deploying production from make_task\n");
+    }
+
+    #[test]
+    fn test_failing_hook() {
+        let output = run("exit 3", Path::new("."), &Environment::new(), &ProcessGroups::new()).ok().unwrap();
+        assert_eq!(output.status.code(), Some(3));
+    }
+}
@@ -0,0 +1,207 @@
+//! The HTTP requests hookshot makes itself: notifier messages, PagerDuty
+//! events, release asset downloads and the api client.
+//!
+//! Every request runs on its own thread with the server's `http_*` timeouts.
+//! Reading or writing the connection gives up after `read`, and a request
+//! that hasn't finished after `deadline`, body included, is abandoned no
+//! matter what it was waiting on. hyper has no connect timeout of its own,
+//! so connecting counts against the deadline.
+
+use hyper;
+use hyper::client::{Client, RedirectPolicy};
+use hyper::header::{Header, HeaderFormat, Headers};
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_READ_TIMEOUT: u64 = 10;
+pub const DEFAULT_DEADLINE: u64 = 30;
+
+/// How often to check whether a request has finished.
+const POLL_MS: u32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub read: Duration,
+    pub deadline: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts::from_secs(DEFAULT_READ_TIMEOUT, DEFAULT_DEADLINE)
+    }
+}
+
+impl Timeouts {
+    pub fn from_secs(read: u64, deadline: u64) -> Timeouts {
+        Timeouts {
+            read: Duration::from_secs(read),
+            deadline: Duration::from_secs(deadline),
+        }
+    }
+
+    /// The timeouts for a request the server holds on to for up to `seconds`
+    /// on purpose, like `POST /tasks?wait=`.
+    pub fn extended_by(&self, seconds: u64) -> Timeouts {
+        Timeouts {
+            read: self.read + Duration::from_secs(seconds),
+            deadline: self.deadline + Duration::from_secs(seconds),
+        }
+    }
+}
+
+pub struct Request {
+    pub method: Method,
+    pub url: String,
+    pub headers: Headers,
+    pub body: Option<String>,
+    /// Redirects are followed unless this is turned off.
+    pub follow_redirects: bool,
+}
+
+impl Request {
+    pub fn new(method: Method, url: &str) -> Request {
+        Request {
+            method: method,
+            url: String::from(url),
+            headers: Headers::new(),
+            body: None,
+            follow_redirects: true,
+        }
+    }
+
+    pub fn get(url: &str) -> Request {
+        Request::new(Method::Get, url)
+    }
+
+    pub fn post(url: &str, body: &str) -> Request {
+        let mut request = Request::new(Method::Post, url);
+        request.body = Some(String::from(body));
+        request
+    }
+
+    pub fn header<H: Header + HeaderFormat>(mut self, header: H) -> Request {
+        self.headers.set(header);
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: String,
+}
+
+/// Send a request and read the whole response, within `timeouts`.
+pub fn send(request: Request, timeouts: Timeouts) -> hyper::Result<Response> {
+    within(timeouts.deadline, move || {
+        let mut client = Client::new();
+        client.set_read_timeout(Some(timeouts.read));
+        client.set_write_timeout(Some(timeouts.read));
+        if !request.follow_redirects {
+            client.set_redirect_policy(RedirectPolicy::FollowNone);
+        }
+        let mut builder = client.request(request.method, &request.url[..]).headers(request.headers);
+        if let Some(ref body) = request.body {
+            builder = builder.body(&body[..]);
+        }
+        let mut response = try!(builder.send());
+        let mut body = String::new();
+        try!(response.read_to_string(&mut body));
+        Ok(Response {
+            status: response.status,
+            headers: response.headers.clone(),
+            body: body,
+        })
+    })
+}
+
+/// Send a request and, if it succeeds, write the response body to `path` as
+/// it comes in, within `timeouts`. Other responses are read like `send`
+/// reads them, for their status, headers and body.
+pub fn download(request: Request, path: PathBuf, timeouts: Timeouts) -> hyper::Result<Response> {
+    within(timeouts.deadline, move || {
+        let mut client = Client::new();
+        client.set_read_timeout(Some(timeouts.read));
+        client.set_write_timeout(Some(timeouts.read));
+        if !request.follow_redirects {
+            client.set_redirect_policy(RedirectPolicy::FollowNone);
+        }
+        let mut response = try!(client.request(request.method, &request.url[..]).headers(request.headers).send());
+        let mut body = String::new();
+        match response.status.is_success() {
+            true => {
+                let mut file = try!(File::create(&path));
+                try!(io::copy(&mut response, &mut file));
+            }
+            false => {
+                try!(response.read_to_string(&mut body));
+            }
+        }
+        Ok(Response {
+            status: response.status,
+            headers: response.headers.clone(),
+            body: body,
+        })
+    })
+}
+
+/// Run `f` on a new thread, giving up on it after `deadline`. The thread is
+/// left to finish by itself.
+fn within<T, F>(deadline: Duration, f: F) -> hyper::Result<T>
+    where T: 'static + Send,
+          F: 'static + FnOnce() -> hyper::Result<T> + Send
+{
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(f());
+    });
+    let started = Instant::now();
+    loop {
+        match rx.try_recv() {
+            Ok(result) => return result,
+            Err(TryRecvError::Disconnected) => {
+                return Err(hyper::Error::Io(io::Error::new(io::ErrorKind::Other, "request thread panicked")))
+            }
+            Err(TryRecvError::Empty) if started.elapsed() >= deadline => {
+                return Err(hyper::Error::Io(io::Error::new(io::ErrorKind::TimedOut, "deadline exceeded")))
+            }
+            Err(TryRecvError::Empty) => thread::sleep_ms(POLL_MS),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{within, Timeouts};
+    use hyper;
+    use std::io;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_within() {
+        assert_eq!(within(Duration::from_secs(5), || Ok(1)).unwrap(), 1);
+
+        let result = within(Duration::from_millis(100), || {
+            thread::sleep_ms(2000);
+            Ok(1)
+        });
+        match result {
+            Err(hyper::Error::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => {}
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_extended_by() {
+        let timeouts = Timeouts::from_secs(10, 30).extended_by(60);
+        assert_eq!(timeouts, Timeouts::from_secs(70, 90));
+    }
+}
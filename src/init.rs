@@ -0,0 +1,329 @@
+//! `hookshot init`: write a starter server config and `.hookshot.conf`.
+//!
+//! The files only depend on the options, so the same options always produce
+//! the same files, which makes them usable as fixtures in tests and docs.
+//! Both are checked with the same code that loads them at runtime before
+//! anything is written.
+
+use repo_config::{self, DeployMethod, RepoConfig};
+use rustc_serialize::json;
+use server_config::{self, ServerConfig};
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub const SERVER_CONFIG_FILE: &'static str = "hookshot.toml";
+pub const REPO_CONFIG_FILE: &'static str = repo_config::CONFIG_FILE;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub secret: String,
+    pub hostname: String,
+    pub port: u16,
+    pub checkout_root: String,
+    pub log_root: String,
+    pub method: DeployMethod,
+    /// Branch patterns to deploy, e.g. `production` or `release-*`.
+    pub branches: Vec<String>,
+    /// Make task to run for `method = "makefile"`.
+    pub task: String,
+    pub playbook: String,
+    pub inventory: String,
+    pub notify_url: Option<String>,
+}
+
+impl Options {
+    pub fn new(secret: &str, checkout_root: &str, log_root: &str) -> Options {
+        Options {
+            secret: String::from(secret),
+            hostname: String::from("127.0.0.1"),
+            port: 1469,
+            checkout_root: String::from(checkout_root),
+            log_root: String::from(log_root),
+            method: DeployMethod::Makefile,
+            branches: vec![String::from("production")],
+            task: String::from("deploy"),
+            playbook: String::from("ansible/deploy.yml"),
+            inventory: String::from("ansible/inventory"),
+            notify_url: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The file is already there, pass `--force` to replace it.
+    Exists(PathBuf),
+    Io(io::Error),
+    /// The generated server config doesn't load.
+    ServerConfig(server_config::Error),
+    /// The generated `.hookshot.conf` doesn't load, for a reason other than
+    /// the files it points at not existing yet.
+    RepoConfig(repo_config::Errors),
+}
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Exists(_) => "file already exists, use --force to overwrite it",
+            Error::Io(_) => "could not write file",
+            Error::ServerConfig(_) => "generated server config is invalid",
+            Error::RepoConfig(_) => "generated .hookshot.conf is invalid",
+        }
+    }
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Exists(ref path) => write!(f, "{}: {}", path.display(), self.description()),
+            Error::Io(ref e) => write!(f, "{}: {}", self.description(), e),
+            Error::ServerConfig(ref e) => write!(f, "{}: {}", self.description(), e),
+            Error::RepoConfig(ref e) => write!(f, "{}: {}", self.description(), e),
+        }
+    }
+}
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// A TOML string, quoted and escaped. JSON strings are valid TOML strings.
+fn quote(s: &str) -> String {
+    json::encode(&s).unwrap()
+}
+
+pub fn server_config_toml(options: &Options) -> String {
+    format!(r#"## Generated by `hookshot init`. See the README for every option.
+[config]
+## Port to run hookshot server.
+port = {port}
+
+## Key for message verification. Webhooks must be signed with it.
+secret = {secret}
+
+## Directory to store checkouts. Must be writeable.
+checkout_root = {checkout_root}
+
+## Directory to store logs. Must be writeable.
+log_root = {log_root}
+
+## Externally accessible hostname or IP, used in the links hookshot sends back.
+hostname = {hostname}
+
+## Extra variables for tasks, keyed by [env.<user>.<repo>.<branch>].
+# [env.owner.repo.production]
+# api_key = "it's a secret to everyone"
+"#,
+            port = options.port,
+            secret = quote(&options.secret),
+            checkout_root = quote(&options.checkout_root),
+            log_root = quote(&options.log_root),
+            hostname = quote(&options.hostname))
+}
+
+pub fn repo_config_toml(options: &Options) -> String {
+    let mut out = String::from("## Generated by `hookshot init`. Paths are relative to the repository root.\n\
+                                [default]\n");
+    match options.method {
+        DeployMethod::Makefile => {
+            out.push_str("## Run `make <task>` to deploy.\n");
+            out.push_str("method = \"makefile\"\n");
+            out.push_str(&format!("task = {}\n", quote(&options.task)));
+        }
+        DeployMethod::Ansible => {
+            out.push_str("## Run ansible-playbook with this playbook and inventory to deploy.\n");
+            out.push_str("method = \"ansible\"\n");
+            out.push_str(&format!("playbook = {}\n", quote(&options.playbook)));
+            out.push_str(&format!("inventory = {}\n", quote(&options.inventory)));
+        }
+        DeployMethod::Custom(ref name) => {
+            out.push_str("## A method the server registered, configured under [default.settings].\n");
+            out.push_str(&format!("method = {}\n", quote(name)));
+        }
+    }
+    if let Some(ref url) = options.notify_url {
+        out.push_str("## Sent a message when a task starts and when it finishes.\n");
+        out.push_str(&format!("notifiers = [{}]\n", quote(url)));
+    }
+    for branch in &options.branches {
+        out.push_str(&format!("\n## Deploy pushes to {} with the defaults above.\n", branch));
+        out.push_str(&format!("[branch.{}]\n", quote(branch)));
+    }
+    out
+}
+
+/// Errors that only mean a file the config points at doesn't exist yet,
+/// e.g. the Makefile in a repository that hasn't got one.
+fn missing_file(error: &repo_config::Error) -> bool {
+    match *error {
+        repo_config::Error::UnknownDefaultMakeTask(_) |
+        repo_config::Error::InvalidDefaultPlaybook |
+        repo_config::Error::InvalidDefaultInventory |
+        repo_config::Error::UnknownMakeTask(..) |
+        repo_config::Error::InvalidPlaybook(_) |
+        repo_config::Error::InvalidInventory(_) |
+        repo_config::Error::InvalidAnsibleConfig |
+        repo_config::Error::InvalidMakeTaskConfig => true,
+        _ => false,
+    }
+}
+
+/// Check both configs load. Returns the `.hookshot.conf` problems that
+/// need fixing in the repository before a deploy can work, like a make task
+/// that doesn't exist yet.
+pub fn check(options: &Options, project_root: &Path) -> Result<Vec<repo_config::Error>, Error> {
+    if let Err(e) = ServerConfig::from(&server_config_toml(options)) {
+        return Err(Error::ServerConfig(e));
+    }
+    let methods = match options.method {
+        DeployMethod::Custom(ref name) => vec![name.clone()],
+        _ => vec![],
+    };
+    match RepoConfig::from_str_with_methods(&repo_config_toml(options), project_root, &methods) {
+        Ok(_) => Ok(vec![]),
+        Err(errors) => {
+            if errors.0.iter().all(missing_file) {
+                Ok(errors.0)
+            } else {
+                Err(Error::RepoConfig(errors))
+            }
+        }
+    }
+}
+
+/// Create the checkout and log roots, check the configs and write them to
+/// `dir`. Existing files are only replaced if `force` is set. Returns the
+/// paths written and the problems from `check`.
+pub fn run(options: &Options,
+           dir: &Path,
+           force: bool)
+           -> Result<(Vec<PathBuf>, Vec<repo_config::Error>), Error> {
+    let server_path = dir.join(SERVER_CONFIG_FILE);
+    let repo_path = dir.join(REPO_CONFIG_FILE);
+    if !force {
+        for path in &[&server_path, &repo_path] {
+            if fs::metadata(path).is_ok() {
+                return Err(Error::Exists(path.to_path_buf()));
+            }
+        }
+    }
+
+    try!(fs::create_dir_all(&options.checkout_root));
+    try!(fs::create_dir_all(&options.log_root));
+    let problems = try!(check(options, dir));
+
+    try!(try!(File::create(&server_path)).write_all(server_config_toml(options).as_bytes()));
+    try!(try!(File::create(&repo_path)).write_all(repo_config_toml(options).as_bytes()));
+    Ok((vec![server_path, repo_path], problems))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repo_config::{self, DeployMethod, RepoConfig};
+    use server_config::ServerConfig;
+    use std::fs::File;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_deterministic() {
+        let mut options = Options::new("s3cret", "/var/lib/hookshot/checkouts", "/var/log/hookshot");
+        options.branches = vec![String::from("production"), String::from("release-*")];
+        options.notify_url = Some(String::from("http://example.org/hook"));
+        assert_eq!(server_config_toml(&options),
+                   r#"## Generated by `hookshot init`. See the README for every option.
+[config]
+## Port to run hookshot server.
+port = 1469
+
+## Key for message verification. Webhooks must be signed with it.
+secret = "s3cret"
+
+## Directory to store checkouts. Must be writeable.
+checkout_root = "/var/lib/hookshot/checkouts"
+
+## Directory to store logs. Must be writeable.
+log_root = "/var/log/hookshot"
+
+## Externally accessible hostname or IP, used in the links hookshot sends back.
+hostname = "127.0.0.1"
+
+## Extra variables for tasks, keyed by [env.<user>.<repo>.<branch>].
+# [env.owner.repo.production]
+# api_key = "it's a secret to everyone"
+"#);
+        assert_eq!(repo_config_toml(&options),
+                   r#"## Generated by `hookshot init`. Paths are relative to the repository root.
+[default]
+## Run `make <task>` to deploy.
+method = "makefile"
+task = "deploy"
+## Sent a message when a task starts and when it finishes.
+notifiers = ["http://example.org/hook"]
+
+## Deploy pushes to production with the defaults above.
+[branch."production"]
+
+## Deploy pushes to release-* with the defaults above.
+[branch."release-*"]
+"#);
+    }
+
+    #[test]
+    fn test_server_config_loads() {
+        let mut options = Options::new("it's \"quoted\"", "/tmp", "/tmp");
+        options.port = 8080;
+        let config = ServerConfig::from(&server_config_toml(&options)).unwrap();
+        assert_eq!(config.secret, "it's \"quoted\"");
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.hostname, "127.0.0.1");
+    }
+
+    #[test]
+    fn test_makefile_repo_config() {
+        let dir = TempDir::new("hookshot-init-test").unwrap();
+        File::create(dir.path().join("Makefile")).unwrap().write_all(b"deploy:\n\techo hi\n").unwrap();
+
+        let mut options = Options::new("s3cret", "/tmp", "/tmp");
+        options.branches = vec![String::from("production"), String::from("release-*")];
+        options.notify_url = Some(String::from("http://example.org/hook"));
+        assert!(check(&options, dir.path()).unwrap().is_empty());
+
+        let config = RepoConfig::from_str(&repo_config_toml(&options), dir.path()).unwrap();
+        let production = config.lookup_branch("production").unwrap();
+        assert_eq!(production.method, DeployMethod::Makefile);
+        assert_eq!(production.notifiers, Some(vec![String::from("http://example.org/hook")]));
+        assert!(config.lookup_branch("release-2").is_some());
+        assert!(config.lookup_branch("staging").is_none());
+    }
+
+    #[test]
+    fn test_missing_files_are_problems_not_errors() {
+        let dir = TempDir::new("hookshot-init-test").unwrap();
+        let options = Options::new("s3cret", "/tmp", "/tmp");
+        let problems = check(&options, dir.path()).unwrap();
+        assert_eq!(problems.len(), 2);
+        match problems[0] {
+            repo_config::Error::UnknownDefaultMakeTask(_) => {}
+            ref other => panic!("expected UnknownDefaultMakeTask, got {:?}", other),
+        }
+        assert_eq!(problems[1], repo_config::Error::InvalidMakeTaskConfig);
+    }
+
+    #[test]
+    fn test_run_refuses_to_overwrite() {
+        let dir = TempDir::new("hookshot-init-test").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let options = Options::new("s3cret", root, root);
+        let (written, _) = run(&options, dir.path(), false).unwrap();
+        assert_eq!(written.len(), 2);
+        match run(&options, dir.path(), false) {
+            Err(Error::Exists(_)) => {}
+            other => panic!("expected Exists, got {:?}", other),
+        }
+        assert!(run(&options, dir.path(), true).is_ok());
+    }
+}
@@ -0,0 +1,83 @@
+//! Periodic housekeeping that runs on its own thread.
+//!
+//! The janitor wakes up every `interval` seconds and reconciles the task
+//! history: tasks left queued or running by a worker that died (or by a
+//! previous hookshot process that crashed) are marked as failed, and finished
+//! tasks past the retention period are dropped from the index. It also cleans
+//! up checkouts that haven't been used recently or that don't fit in the
+//! checkout quota, see the `workspace` module.
+
+use history::SharedHistory;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use task_manager::Changes;
+use workspace::{self, CleanupReport};
+
+pub struct Janitor {
+    pub history: SharedHistory,
+    pub interval: u64,
+    pub retention: Option<i64>,
+    pub checkout_root: PathBuf,
+    /// Seconds a checkout can go unused before it is removed.
+    pub checkout_retention: Option<i64>,
+    /// Bytes all checkouts together may use.
+    pub checkout_quota: Option<u64>,
+    /// The task manager's count of finished tasks, see `TaskManager::changes`.
+    pub changes: Changes,
+}
+
+impl Janitor {
+    /// Run one round of cleanup.
+    pub fn sweep(&self) {
+        let (failed, pruned) = self.history.lock().unwrap().reconcile(self.retention);
+        if failed > 0 || pruned > 0 {
+            info!("janitor",
+                  "marked {} stale tasks as failed, pruned {} old tasks",
+                  failed,
+                  pruned);
+        }
+        // Tasks held up by one of the stale tasks find out it's over
+        if failed > 0 {
+            self.changes.notify();
+        }
+        self.clean_checkouts();
+    }
+
+    /// Remove stale checkouts, skipping any that a queued or running task is
+    /// going to use.
+    pub fn clean_checkouts(&self) -> CleanupReport {
+        if self.checkout_retention.is_none() && self.checkout_quota.is_none() {
+            return CleanupReport::default();
+        }
+        let history = &self.history;
+        let report = workspace::cleanup(&self.checkout_root,
+                                        self.checkout_retention,
+                                        self.checkout_quota,
+                                        |path| {
+                                            // Tasks are in the history before they touch their
+                                            // checkout, so none can start using it while it goes
+                                            let history = history.lock().unwrap();
+                                            workspace::remove_unless_busy(path, &history.busy_checkouts())
+                                        });
+        if !report.removed.is_empty() {
+            info!("janitor",
+                  "removed {} checkouts, freed {} bytes",
+                  report.removed.len(),
+                  report.freed_bytes);
+        }
+        report
+    }
+
+    /// Sweep every `interval` seconds on a new thread. The janitor is shared
+    /// so cleanup can also be triggered on demand.
+    pub fn start(janitor: Arc<Janitor>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            loop {
+                janitor.sweep();
+                thread::sleep(Duration::from_secs(janitor.interval));
+            }
+        })
+    }
+}
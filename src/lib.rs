@@ -2,25 +2,73 @@ extern crate chrono;
 #[macro_use] extern crate hyper;
 extern crate getopts;
 extern crate iron;
+extern crate libc;
 extern crate openssl;
 extern crate regex;
 extern crate router;
 extern crate rustc_serialize;
+extern crate semver;
 extern crate tempdir;
 extern crate toml;
+extern crate unicode_normalization;
 extern crate users;
 extern crate uuid;
+#[macro_use]
+pub mod logging;
+pub mod ansi;
+pub mod artifact;
+pub mod auth;
+pub mod chaos;
+#[cfg(feature = "api_client")]
+pub mod api_client;
 pub mod cli;
+#[cfg(feature = "api_client")]
+pub mod client_cli;
+pub mod compare;
 pub mod config;
+pub mod cors;
+pub mod environment;
 pub mod error;
+pub mod extra_headers;
 pub mod git;
+pub mod headers;
+pub mod health;
+pub mod history;
+pub mod http;
+pub mod hook;
+pub mod init;
+pub mod janitor;
+pub mod maintenance;
 pub mod make_task;
 pub mod message;
+pub mod metrics;
+pub mod migrate;
+pub mod path_filter;
+pub mod payloads;
+pub mod percent;
+pub mod process;
+pub mod queue_graph;
+pub mod quarantine;
+pub mod queue_store;
+pub mod redact;
+pub mod reload;
+pub mod replay;
 pub mod repo_config;
+pub mod routes;
+pub mod schedule;
+pub mod server;
 pub mod server_config;
 pub mod signature;
+pub mod storage;
+pub mod task_factory;
 pub mod task_manager;
+pub mod template;
 pub mod verified_path;
+pub mod versions;
+pub mod workspace;
 pub mod ansible_task;
+#[cfg(feature = "notifiers")]
 pub mod notifier;
+#[cfg(feature = "pagerduty")]
+pub mod pagerduty;
 pub mod deploy_task;
@@ -0,0 +1,199 @@
+//! Server logging.
+//!
+//! Every line has a timestamp, a level and a context, usually the id of the
+//! task the line is about. Lines are written to stdout either as text,
+//!
+//! ```text
+//! 2015-11-02T21:12:20Z INFO [67e55044-10b1-426f-9247-bb680e5fe0c8]: scheduled
+//! ```
+//!
+//! or, with `log_format = "json"`, as one JSON object per line so logs can be
+//! shipped to something like journald or ELK as is. Use the `debug!`,
+//! `info!`, `warn!` and `error!` macros, which take the context followed by
+//! `format!` style arguments.
+
+use chrono::UTC;
+use rustc_serialize::json;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub fn from_str(s: &str) -> Option<Level> {
+        match s {
+            "debug" => Some(Level::Debug),
+            "info" => Some(Level::Info),
+            "warn" | "warning" => Some(Level::Warn),
+            "error" => Some(Level::Error),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+
+    fn from_usize(n: usize) -> Level {
+        match n {
+            0 => Level::Debug,
+            1 => Level::Info,
+            2 => Level::Warn,
+            _ => Level::Error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn from_str(s: &str) -> Option<Format> {
+        match s {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Format::Text => "text",
+            Format::Json => "json",
+        }
+    }
+}
+
+// Both start out as zero before `init` is called. The level is stored off by
+// one so zero can mean the default, `Info`.
+static FORMAT: AtomicUsize = ATOMIC_USIZE_INIT;
+static LEVEL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Set the output format and the lowest level that gets logged. Until this
+/// is called lines are logged as text at `Info` and above.
+pub fn init(format: Format, level: Level) {
+    FORMAT.store(match format {
+                     Format::Text => 0,
+                     Format::Json => 1,
+                 },
+                 Ordering::SeqCst);
+    LEVEL.store(level as usize + 1, Ordering::SeqCst);
+}
+
+fn format() -> Format {
+    match FORMAT.load(Ordering::SeqCst) {
+        0 => Format::Text,
+        _ => Format::Json,
+    }
+}
+
+fn max_level() -> Level {
+    match LEVEL.load(Ordering::SeqCst) {
+        0 => Level::Info,
+        n => Level::from_usize(n - 1),
+    }
+}
+
+#[derive(RustcEncodable)]
+struct JsonLine<'a> {
+    timestamp: &'a str,
+    level: &'static str,
+    context: &'a str,
+    message: &'a str,
+}
+
+fn format_line(format: Format, timestamp: &str, level: Level, context: &str, message: &str) -> String {
+    match format {
+        Format::Text => format!("{} {} [{}]: {}",
+                                timestamp,
+                                level.as_str().to_uppercase(),
+                                context,
+                                message),
+        Format::Json => {
+            let line = JsonLine {
+                timestamp: timestamp,
+                level: level.as_str(),
+                context: context,
+                message: message,
+            };
+            json::encode(&line).unwrap_or(String::new())
+        }
+    }
+}
+
+/// Log a line. The macros are more convenient.
+pub fn log<C: Display>(level: Level, context: C, message: &str) {
+    if level < max_level() {
+        return;
+    }
+    let timestamp = UTC::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    println!("{}",
+             format_line(format(), &timestamp, level, &context.to_string(), message));
+}
+
+macro_rules! log_at {
+    ($level:expr, $context:expr, $($arg:tt)+) => (
+        ::logging::log($level, $context, &format!($($arg)+))
+    )
+}
+
+macro_rules! debug {
+    ($context:expr, $($arg:tt)+) => (log_at!(::logging::Level::Debug, $context, $($arg)+))
+}
+
+macro_rules! info {
+    ($context:expr, $($arg:tt)+) => (log_at!(::logging::Level::Info, $context, $($arg)+))
+}
+
+macro_rules! warn {
+    ($context:expr, $($arg:tt)+) => (log_at!(::logging::Level::Warn, $context, $($arg)+))
+}
+
+macro_rules! error {
+    ($context:expr, $($arg:tt)+) => (log_at!(::logging::Level::Error, $context, $($arg)+))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, Level, format_line};
+
+    #[test]
+    fn test_format_text() {
+        let line = format_line(Format::Text, "2015-11-02T21:12:20Z", Level::Warn, "janitor", "hi");
+        assert_eq!(line, "2015-11-02T21:12:20Z WARN [janitor]: hi");
+    }
+
+    #[test]
+    fn test_format_json() {
+        let line = format_line(Format::Json,
+                               "2015-11-02T21:12:20Z",
+                               Level::Info,
+                               "abc",
+                               "said \"hi\"");
+        assert_eq!(line,
+                   "{\"timestamp\":\"2015-11-02T21:12:20Z\",\"level\":\"info\",\
+                    \"context\":\"abc\",\"message\":\"said \\\"hi\\\"\"}");
+    }
+
+    #[test]
+    fn test_levels() {
+        assert!(Level::Debug < Level::Info);
+        assert!(Level::Warn < Level::Error);
+        assert_eq!(Level::from_str("warning"), Some(Level::Warn));
+        assert_eq!(Level::from_str("loud"), None);
+    }
+}
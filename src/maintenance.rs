@@ -0,0 +1,180 @@
+//! Maintenance mode, for holding off deploys while something else is going
+//! on, globally or for single repos.
+//!
+//! The `maintenance` settings in the server config are what hookshot starts
+//! with, `POST /admin/maintenance` turns it on and off while it runs. What
+//! happens to deliveries meanwhile depends on `maintenance_mode`: with
+//! `queue` they are accepted and queued but don't start until maintenance is
+//! lifted, with `reject` they get a `503` and a `Retry-After` header.
+
+use git;
+use server_config::ServerConfig;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+pub type SharedMaintenance = Arc<Mutex<Maintenance>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaintenanceMode {
+    /// Queue tasks and start them once maintenance is lifted.
+    Queue,
+    /// Turn deliveries away with a `503`.
+    Reject,
+}
+
+impl MaintenanceMode {
+    pub fn from_str(s: &str) -> Option<MaintenanceMode> {
+        match s {
+            "queue" => Some(MaintenanceMode::Queue),
+            "reject" => Some(MaintenanceMode::Reject),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            MaintenanceMode::Queue => "queue",
+            MaintenanceMode::Reject => "reject",
+        }
+    }
+}
+
+/// Body of `POST /admin/maintenance`. Without a `repo` the change is
+/// global.
+#[derive(Debug, RustcDecodable)]
+pub struct MaintenanceChange {
+    pub enabled: bool,
+    pub repo: Option<String>,
+}
+
+/// Which repos are in maintenance right now.
+#[derive(Debug, Clone, Default, PartialEq, Eq, RustcEncodable)]
+pub struct Maintenance {
+    /// Every repo is in maintenance.
+    pub global: bool,
+    /// Repos in maintenance, as `owner/name` in lowercase.
+    pub repos: BTreeSet<String>,
+    /// Global maintenance was turned on by hookshot itself, until the
+    /// orphaned processes it adopted have exited. See `hold_for_orphans`.
+    pub orphans: bool,
+}
+
+impl Maintenance {
+    /// The maintenance the server config starts with.
+    pub fn from_config(config: &ServerConfig) -> Maintenance {
+        Maintenance {
+            global: config.maintenance,
+            repos: config.repos
+                         .iter()
+                         .filter(|&(_, settings)| settings.maintenance)
+                         .map(|(repo, _)| git::normalize_name(repo))
+                         .collect(),
+            orphans: false,
+        }
+    }
+
+    pub fn is_active(&self, owner: &str, name: &str) -> bool {
+        self.global || self.repos.contains(&git::normalize_name(&format!("{}/{}", owner, name)))
+    }
+
+    /// Turn maintenance on or off, for one repo or globally. Lifting global
+    /// maintenance doesn't lift it for repos that are in maintenance by
+    /// themselves.
+    pub fn set(&mut self, repo: Option<&str>, enabled: bool) {
+        match (repo, enabled) {
+            (None, enabled) => {
+                self.global = enabled;
+                self.orphans = false;
+            }
+            (Some(repo), true) => {
+                self.repos.insert(git::normalize_name(repo));
+            }
+            (Some(repo), false) => {
+                self.repos.remove(&git::normalize_name(repo));
+            }
+        }
+    }
+
+    /// Turn global maintenance on until `release_orphans`, unless it is on
+    /// already.
+    pub fn hold_for_orphans(&mut self) {
+        if !self.global {
+            self.global = true;
+            self.orphans = true;
+        }
+    }
+
+    /// Turn global maintenance off again if `hold_for_orphans` turned it on
+    /// and nobody changed it since.
+    pub fn release_orphans(&mut self) -> bool {
+        if !self.orphans {
+            return false;
+        }
+        self.global = false;
+        self.orphans = false;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Maintenance;
+    use server_config::ServerConfig;
+
+    #[test]
+    fn test_maintenance() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.hookshot]
+            maintenance = true
+
+            [repo.brianloveswords.website]
+            submodules = true
+        "#;
+        let mut maintenance = Maintenance::from_config(&ServerConfig::from(&toml).unwrap());
+        assert!(!maintenance.global);
+        assert!(maintenance.is_active("brianloveswords", "hookshot"));
+        assert!(!maintenance.is_active("brianloveswords", "website"));
+
+        maintenance.set(None, true);
+        assert!(maintenance.is_active("brianloveswords", "website"));
+        maintenance.set(None, false);
+        assert!(!maintenance.is_active("brianloveswords", "website"));
+        assert!(maintenance.is_active("brianloveswords", "hookshot"));
+
+        maintenance.set(Some("brianloveswords/hookshot"), false);
+        assert!(!maintenance.is_active("brianloveswords", "hookshot"));
+
+        maintenance.set(Some("BrianLovesWords/HookShot"), true);
+        assert!(maintenance.is_active("brianloveswords", "hookshot"));
+        maintenance.set(Some("brianloveswords/hookshot"), false);
+        assert!(!maintenance.is_active("BrianLovesWords", "HookShot"));
+    }
+
+    #[test]
+    fn test_hold_for_orphans() {
+        let mut maintenance = Maintenance::default();
+        maintenance.hold_for_orphans();
+        assert!(maintenance.global);
+        assert!(maintenance.release_orphans());
+        assert!(!maintenance.global);
+
+        // Maintenance that was on already, or that someone turned on or
+        // off in the meantime, is left as it is
+        maintenance.set(None, true);
+        maintenance.hold_for_orphans();
+        assert!(!maintenance.release_orphans());
+        assert!(maintenance.global);
+
+        maintenance.set(None, false);
+        maintenance.hold_for_orphans();
+        maintenance.set(None, true);
+        assert!(!maintenance.release_orphans());
+        assert!(maintenance.global);
+    }
+}
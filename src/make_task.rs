@@ -1,56 +1,144 @@
 use error::{Error, CommandError};
+use process::{CommandLine, ProcessGroups};
 use server_config::Environment;
-use std::fs::File;
-use std::io::Read;
 use std::ascii::AsciiExt;
-use std::path::Path;
-use std::process::{Command, Output};
+use std::path::{Path, PathBuf};
+use std::process::Output;
+
+/// Where the Makefile is, relative to the project root or the task's
+/// `workdir`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    /// `Makefile` in the project root.
+    Root,
+    /// The default Makefile in a subdirectory, make runs in that directory.
+    Directory(PathBuf),
+    /// A Makefile with another name or in a subdirectory, make runs in the
+    /// project root with `-f`.
+    File(PathBuf),
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MakeTask<'a> {
     task: String,
     path: &'a Path,
+    location: Location,
+    /// Subdirectory of the project to run from, see `with_workdir`.
+    workdir: Option<PathBuf>,
+    /// Extra arguments for make, see `with_args`.
+    args: Vec<String>,
 }
 
 impl<'a> MakeTask<'a> {
+    /// A make task from the Makefile in `directory`.
     pub fn new(directory: &'a Path, task: &str) -> Result<MakeTask<'a>, Error> {
-        let path_to_makefile = directory.join("Makefile");
-        let makefile_contents = {
-            let mut f = match File::open(&path_to_makefile) {
-                Ok(f) => f,
-                Err(_) => return Err(Error {
-                    desc: "can't open Makefile",
-                    subject: Some(String::from(path_to_makefile.to_str().unwrap())),
-                }),
-            };
-
-            let mut contents = String::new();
-            f.read_to_string(&mut contents).unwrap();
-            contents
-        };
+        MakeTask::locate(directory, Location::Root, task)
+    }
 
-        let mut task_header = task.to_string();
-        task_header.push(':');
+    /// A make task that `make` knows how to build. Asks `make -qn <task>`
+    /// rather than reading the Makefile, so targets from includes, variables
+    /// and pattern rules count too. Nothing is run. When make can't build
+    /// the task the error subject is what make printed.
+    pub fn locate(directory: &'a Path, location: Location, task: &str) -> Result<MakeTask<'a>, Error> {
+        MakeTask::unchecked(directory, location, task).check()
+    }
 
-        let has_task = makefile_contents.lines().any(|line| line.starts_with(&task_header));
+    /// The same check as `locate`, for a task that was made `unchecked`,
+    /// e.g. to give it a `workdir` first.
+    pub fn check(self) -> Result<MakeTask<'a>, Error> {
+        let output = match self.command().arg("-qn").arg(&self.task).to_command().output() {
+            Ok(output) => output,
+            Err(e) => return Err(Error {
+                desc: "failed to execute `make`",
+                subject: Some(format!("{}", e)),
+            }),
+        };
 
+        // `make -q` exits with 1 when the target exists but isn't up to
+        // date, which is what a deploy task usually is. 2 is an error.
+        match output.status.code() {
+            Some(0) | Some(1) => Ok(self),
+            _ => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                Err(Error {
+                    desc: "Makefile does not have specified task",
+                    subject: Some(String::from(stderr.trim())),
+                })
+            }
+        }
+    }
 
-        match has_task {
-            true => Ok(MakeTask {
-                task: task.to_string(),
-                path: directory,
-            }),
-            false => Err(Error {
-                desc: "Makefile does not have specified task",
-                subject: Some(task.to_string()),
-            }),
+    /// A make task that isn't checked until it runs, for Makefiles that
+    /// can't be evaluated before the deploy, like ones that need variables
+    /// from the task environment.
+    pub fn unchecked(directory: &'a Path, location: Location, task: &str) -> MakeTask<'a> {
+        MakeTask {
+            task: task.to_string(),
+            path: directory,
+            location: location,
+            workdir: None,
+            args: vec![],
         }
     }
 
-    pub fn run(&self, env: &Environment) -> Result<Output, CommandError> {
-        let mut cmd = Command::new("make");
+    /// Run make from `workdir`, a subdirectory of the project, instead of
+    /// the project root. The location of the Makefile is relative to it.
+    pub fn with_workdir(mut self, workdir: PathBuf) -> MakeTask<'a> {
+        self.workdir = Some(workdir);
+        self
+    }
+
+    /// Pass `args`, like `ENV=prod` or `-j4`, to make before the task, when
+    /// it's checked too. Each one is a single argument, there's no shell in
+    /// between to split or expand them.
+    pub fn with_args(mut self, args: Vec<String>) -> MakeTask<'a> {
+        self.args = args;
+        self
+    }
+
+    /// The target make builds.
+    pub fn task(&self) -> &str {
+        &self.task
+    }
+
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+
+    pub fn workdir(&self) -> Option<&Path> {
+        self.workdir.as_ref().map(|workdir| workdir.as_path())
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
 
-        cmd.current_dir(&self.path);
+    /// `make`, running in the right directory with the right Makefile.
+    fn command(&self) -> CommandLine {
+        let root = match self.workdir {
+            Some(ref workdir) => self.path.join(workdir),
+            None => self.path.to_path_buf(),
+        };
+        let mut cmd = CommandLine::new("make");
+        match self.location {
+            Location::Root => {
+                cmd.current_dir(&root);
+            }
+            Location::Directory(ref dir) => {
+                cmd.current_dir(root.join(dir));
+            }
+            Location::File(ref makefile) => {
+                cmd.current_dir(&root).arg("-f").arg(makefile);
+            }
+        }
+        for arg in &self.args {
+            cmd.arg(arg);
+        }
+        cmd
+    }
+
+    pub fn run(&self, env: &Environment, groups: &ProcessGroups) -> Result<Output, CommandError> {
+        let mut cmd = self.command();
         cmd.arg(&self.task);
 
         for (k, v) in env {
@@ -58,7 +146,7 @@ impl<'a> MakeTask<'a> {
             cmd.env(uppercase_key, v);
         }
 
-        match cmd.output() {
+        match groups.output(&cmd) {
             Ok(r) => Ok(r),
             Err(e) => return Err(CommandError {
                 desc: "failed to execute `make`, see detail",
@@ -76,8 +164,9 @@ impl<'a> ToString for MakeTask<'a> {
 
 #[cfg(test)]
 mod tests {
-    use super::MakeTask;
-    use std::path::Path;
+    use super::{Location, MakeTask};
+    use process::ProcessGroups;
+    use std::path::{Path, PathBuf};
     use server_config::Environment;
 
     #[test]
@@ -87,7 +176,7 @@ mod tests {
             Ok(maketask) => maketask,
             Err(_) => panic!("should have constructed make task"),
         };
-        let result = match maketask.run(&Environment::new()) {
+        let result = match maketask.run(&Environment::new(), &ProcessGroups::new()) {
             Ok(result) => result,
             Err(_) => panic!("should have run successfully"),
         };
@@ -95,6 +184,74 @@ mod tests {
         assert_eq!(stdout, "this passes the test\n");
     }
 
+    #[test]
+    fn test_missing_task() {
+        let test_dir = Path::new("./src/test/make_task");
+        match MakeTask::new(test_dir, "does-not-exist") {
+            Ok(_) => panic!("should not have constructed make task"),
+            Err(e) => assert!(e.subject().unwrap().contains("does-not-exist")),
+        }
+    }
+
+    #[test]
+    fn test_pattern_rule_task() {
+        let test_dir = Path::new("./src/test/make_task");
+        assert!(MakeTask::new(test_dir, "deploy-production").is_ok());
+    }
+
+    #[test]
+    fn test_makefile_location() {
+        let test_dir = Path::new("./src/test");
+        let in_dir = MakeTask::locate(test_dir, Location::Directory(PathBuf::from("make_task")), "echo")
+                         .unwrap();
+        let stdout = String::from_utf8(in_dir.run(&Environment::new(), &ProcessGroups::new()).unwrap().stdout).unwrap();
+        assert_eq!(stdout, "this passes the test\n");
+
+        let with_file = MakeTask::locate(test_dir, Location::File(PathBuf::from("make_task/Makefile")), "echo")
+                            .unwrap();
+        let stdout = String::from_utf8(with_file.run(&Environment::new(), &ProcessGroups::new()).unwrap().stdout).unwrap();
+        assert_eq!(stdout, "this passes the test\n");
+
+        assert!(MakeTask::locate(test_dir, Location::Root, "echo").is_err());
+    }
+
+    #[test]
+    fn test_workdir() {
+        let test_dir = Path::new("./src/test");
+        let maketask = MakeTask::unchecked(test_dir, Location::Root, "echo")
+                           .with_workdir(PathBuf::from("make_task"))
+                           .check()
+                           .unwrap();
+        let stdout = String::from_utf8(maketask.run(&Environment::new(), &ProcessGroups::new()).unwrap().stdout).unwrap();
+        assert_eq!(stdout, "this passes the test\n");
+
+        let groups = ProcessGroups::recording();
+        let maketask = MakeTask::unchecked(test_dir, Location::Directory(PathBuf::from("deploy")), "deploy")
+                           .with_workdir(PathBuf::from("services/api"));
+        maketask.run(&Environment::new(), &groups).unwrap();
+        assert_eq!(groups.recorded()[0].cwd.as_ref().unwrap(), &test_dir.join("services/api/deploy"));
+    }
+
+    #[test]
+    fn test_args() {
+        let test_dir = Path::new("./src/test/make_task");
+        let maketask = MakeTask::unchecked(test_dir, Location::File(PathBuf::from("Makefile")), "deploy")
+                           .with_args(vec![String::from("ENV=prod"), String::from("-j4"),
+                                           String::from("MESSAGE=it's $(deployed)")]);
+        let groups = ProcessGroups::recording();
+        maketask.run(&Environment::new(), &groups).unwrap();
+        assert_eq!(groups.recorded()[0].args,
+                   vec!["-f", "Makefile", "ENV=prod", "-j4", "MESSAGE=it's $(deployed)", "deploy"]);
+
+        // Checked with the arguments too, and variables set with them win
+        let maketask = MakeTask::unchecked(test_dir, Location::Root, "env")
+                           .with_args(vec![String::from("ENV=from-args")])
+                           .check()
+                           .unwrap();
+        let stdout = String::from_utf8(maketask.run(&Environment::new(), &ProcessGroups::new()).unwrap().stdout).unwrap();
+        assert_eq!(stdout, "from-args\n");
+    }
+
     #[test]
     fn test_run_task_with_env() {
         let mut env = Environment::new();
@@ -105,11 +262,29 @@ mod tests {
             Ok(maketask) => maketask,
             Err(_) => panic!("should have constructed make task"),
         };
-        let result = match maketask.run(&env) {
+        let result = match maketask.run(&env, &ProcessGroups::new()) {
             Ok(result) => result,
             Err(_) => panic!("should have run successfully"),
         };
         let stdout = String::from_utf8(result.stdout).unwrap();
         assert_eq!(stdout, "this is from the environment\n");
     }
+
+    #[test]
+    fn test_recorded_run() {
+        let mut env = Environment::new();
+        env.insert(String::from("git_ref"), String::from("master"));
+        // Never checked or run, so there doesn't need to be a Makefile
+        let test_dir = Path::new("./src/test");
+        let maketask = MakeTask::unchecked(test_dir, Location::File(PathBuf::from("deploy/Makefile")), "deploy");
+        let groups = ProcessGroups::recording();
+        assert!(maketask.run(&env, &groups).unwrap().status.success());
+
+        let recorded = groups.recorded();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].program, "make");
+        assert_eq!(recorded[0].args, vec!["-f", "deploy/Makefile", "deploy"]);
+        assert_eq!(recorded[0].env.get("GIT_REF").unwrap(), "master");
+        assert_eq!(recorded[0].cwd.as_ref().unwrap(), test_dir);
+    }
 }
@@ -1,7 +1,12 @@
-use git::{GitRepo, ToGitRepo};
+use git::{GitRepo, ToGitRepo, CloneProtocol, Transfer};
+use std::path::Path;
 use std::string::ToString;
+use workspace::CheckoutPath;
 use rustc_serialize::json::{self, Json};
 
+/// The most commits GitHub lists in a push event.
+const GITHUB_COMMIT_LIMIT: usize = 20;
+
 // We allow non-camel case types here so we can use RustcDecodable and
 // RustcEncodable and have it be able to read and emit lowercase strings
 #[allow(non_camel_case_types)]
@@ -20,6 +25,64 @@ impl ToString for RefType {
     }
 }
 
+/// The first of `directives` the commit message contains, if any.
+pub fn skip_directive<'a>(commit_message: &str, directives: &'a [String]) -> Option<&'a str> {
+    directives.iter()
+              .find(|directive| !directive.is_empty() && commit_message.contains(&directive[..]))
+              .map(|directive| &directive[..])
+}
+
+/// Correlation ids are used in urls and log lines, so they are limited to 1-64
+/// characters of ascii letters, digits, `.`, `_` and `-`. UUIDs fit.
+pub fn valid_correlation_id(id: &str) -> bool {
+    id.len() > 0 && id.len() <= 64 &&
+    id.chars().all(|c| {
+        (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9') ||
+        c == '.' || c == '_' || c == '-'
+    })
+}
+
+/// Body of `POST /repos/:owner/:repo/warm`, which ref to get a checkout of
+/// ready.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WarmRequest {
+    pub refstring: String,
+    pub reftype: RefType,
+    /// Where to clone from. Without it the remote of the repo's last task is
+    /// used.
+    pub remote: Option<String>,
+}
+
+impl WarmRequest {
+    /// Parse `{"ref": "master", "ref_type": "branch", "remote": "..."}`,
+    /// where only `ref` is required and `ref_type` defaults to `branch`.
+    pub fn from_str(s: &str) -> Result<WarmRequest, &'static str> {
+        let json = match Json::from_str(s) {
+            Ok(json) => json,
+            Err(_) => return Err("could not parse body as json"),
+        };
+        let refstring = match json.find("ref").and_then(|v| v.as_string()) {
+            Some(refstring) if !refstring.is_empty() => String::from(refstring),
+            _ => return Err("missing `ref`"),
+        };
+        let reftype = match json.find("ref_type").map(|v| v.as_string()) {
+            None | Some(Some("branch")) => RefType::branch,
+            Some(Some("tag")) => RefType::tag,
+            Some(_) => return Err("`ref_type` must be \"branch\" or \"tag\""),
+        };
+        let remote = match json.find("remote").map(|v| v.as_string()) {
+            None => None,
+            Some(Some(remote)) if !remote.is_empty() => Some(String::from(remote)),
+            Some(_) => return Err("`remote` must be a string"),
+        };
+        Ok(WarmRequest {
+            refstring: refstring,
+            reftype: reftype,
+            remote: remote,
+        })
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct GitHubMessage {
     reftype: RefType,
@@ -28,19 +91,13 @@ pub struct GitHubMessage {
     owner: String,
     git_url: String,
     sha: String,
+    changed_files: Option<Vec<String>>,
+    head_commit_message: Option<String>,
 }
 
 impl ToGitRepo for GitHubMessage {
-    fn to_git_repo(self, root: &str) -> GitRepo {
-        // Make the end part of the local_path. Do some very basic safety on the
-        // string so it can't escape the container directory. This is intended
-        // to prevent accidents, not malicious behavior -- that's what the
-        // signature is (hopefully) for.
-        let local_path_component = {
-            let prefix = &self.owner;
-            let path = format!("{}.{}.{}", prefix, self.repo_name, self.refstring);
-            path.replace("/", "!").replace("\\", "!")
-        };
+    fn to_git_repo(self, root: &Path) -> GitRepo {
+        let local_path = CheckoutPath::new(root, &self.owner, &self.repo_name, &self.refstring);
 
         GitRepo {
             owner: self.owner,
@@ -48,15 +105,28 @@ impl ToGitRepo for GitHubMessage {
             refstring: self.refstring,
             reftype: self.reftype,
             sha: self.sha,
-
-            // TODO: fix this, use paths & path.join or something
-            local_path: format!("{}/{}", root, local_path_component),
+            local_path: local_path.into_path_buf(),
             remote_path: self.git_url,
+            clone_protocol: CloneProtocol::Ssh,
+            token: None,
+            submodules: false,
+            transfer: Transfer::default(),
         }
     }
 }
 
 impl GitHubMessage {
+    /// Every file added, removed or modified by the pushed commits, `None`
+    /// if the payload doesn't list the commits.
+    pub fn changed_files(&self) -> Option<Vec<String>> {
+        self.changed_files.clone()
+    }
+
+    /// The message of the commit the push points at, if there is one.
+    pub fn head_commit_message(&self) -> Option<String> {
+        self.head_commit_message.clone()
+    }
+
     pub fn from_str(json: &str) -> Result<GitHubMessage, &'static str> {
 
         let data = match Json::from_str(&json) {
@@ -118,6 +188,41 @@ impl GitHubMessage {
             None => return Err("missing `repository.ssh_url`"),
         };
 
+        // GitHub sends no commits for new branches, tags and some force
+        // pushes, and only the first 20 of bigger pushes, so those could
+        // have changed anything.
+        let forced = root_obj.find("forced").and_then(|f| f.as_boolean()).unwrap_or(false);
+        let changed_files = match root_obj.find("commits").and_then(|c| c.as_array()) {
+            None => None,
+            Some(commits) if forced || commits.is_empty() || commits.len() >= GITHUB_COMMIT_LIMIT => None,
+            Some(commits) => {
+                let mut files = vec![];
+                for commit in commits {
+                    for key in ["added", "removed", "modified"].iter() {
+                        let paths = commit.find(key).and_then(|p| p.as_array());
+                        for path in paths.into_iter().flat_map(|p| p.iter()) {
+                            match path.as_string() {
+                                Some(path) => files.push(path.to_string()),
+                                None => return Err("couldn't read changed files in `commits` as strings"),
+                            }
+                        }
+                    }
+                }
+                files.sort();
+                files.dedup();
+                Some(files)
+            }
+        };
+
+        let head_commit_message = match root_obj.find_path(&["head_commit", "message"]) {
+            None => None,
+            Some(v) => match v.as_string() {
+                Some(v) => Some(v.to_string()),
+                None if v.is_null() => None,
+                None => return Err("couldn't read `head_commit.message` as a string"),
+            },
+        };
+
         Ok(GitHubMessage {
             reftype: reftype,
             refstring: refstring,
@@ -125,11 +230,13 @@ impl GitHubMessage {
             owner: owner,
             sha: sha,
             git_url: git_url,
+            changed_files: changed_files,
+            head_commit_message: head_commit_message,
         })
     }
 }
 
-#[derive(RustcDecodable, Clone, Debug)]
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
 pub struct SimpleMessage {
     /// The prefix to differentiate this deployment from another with
     /// possibly the same name.
@@ -150,9 +257,42 @@ pub struct SimpleMessage {
     /// Name of the repository. Used to construct the local path where
     /// the clone will be stored
     pub repo_name: String,
+
+    /// Optional client supplied id for the task. See
+    /// [`valid_correlation_id`](fn.valid_correlation_id.html).
+    pub correlation_id: Option<String>,
+
+    /// Files changed since the last deploy, for repo configs with `paths`.
+    /// Without them every push is deployed.
+    pub changed_files: Option<Vec<String>>,
+
+    /// Message of the commit being deployed, checked for skip directives.
+    pub commit_message: Option<String>,
 }
 
 impl SimpleMessage {
+    /// A message to deploy `refstring` of `owner/repo_name`, with nothing
+    /// optional set.
+    pub fn new(owner: &str,
+               repo_name: &str,
+               refstring: &str,
+               reftype: RefType,
+               remote: &str,
+               sha: &str)
+               -> SimpleMessage {
+        SimpleMessage {
+            prefix: Some(String::from(owner)),
+            reftype: reftype,
+            refstring: String::from(refstring),
+            remote: String::from(remote),
+            sha: String::from(sha),
+            repo_name: String::from(repo_name),
+            correlation_id: None,
+            changed_files: None,
+            commit_message: None,
+        }
+    }
+
     pub fn from_str(json: &str) -> Result<SimpleMessage, &'static str> {
         match json::decode::<SimpleMessage>(json) {
             Ok(msg) => Ok(msg),
@@ -161,18 +301,9 @@ impl SimpleMessage {
     }
 }
 impl ToGitRepo for SimpleMessage {
-    fn to_git_repo(self, root: &str) -> GitRepo {
+    fn to_git_repo(self, root: &Path) -> GitRepo {
         let owner = self.prefix.unwrap_or("$".to_owned());
-
-        // Make the end part of the local_path. Do some very basic safety on the
-        // string so it can't escape the container directory. This is intended
-        // to prevent accidents, not malicious behavior -- that's what the
-        // signature is (hopefully) for.
-        let local_path_component = {
-            let prefix = owner.replace(".", "!");
-            let path = format!("{}.{}.{}", prefix, self.repo_name, self.refstring);
-            path.replace("/", "!").replace("\\", "!")
-        };
+        let local_path = CheckoutPath::new(root, &owner, &self.repo_name, &self.refstring);
 
         GitRepo {
             name: self.repo_name,
@@ -180,8 +311,12 @@ impl ToGitRepo for SimpleMessage {
             refstring: self.refstring,
             reftype: self.reftype,
             sha: self.sha,
-            local_path: format!("{}/{}", root, local_path_component),
+            local_path: local_path.into_path_buf(),
             remote_path: self.remote,
+            clone_protocol: CloneProtocol::Ssh,
+            token: None,
+            submodules: false,
+            transfer: Transfer::default(),
         }
     }
 }
@@ -189,6 +324,8 @@ impl ToGitRepo for SimpleMessage {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use git::GitRepo;
+    use std::path::Path;
 
     #[test]
     fn test_simple_message() {
@@ -214,5 +351,129 @@ mod tests {
         assert_eq!(msg.remote, "the internet");
         assert_eq!(msg.sha, "HEAD");
         assert_eq!(msg.repo_name, "stuff");
+        assert_eq!(msg.correlation_id, None);
+        assert_eq!(msg.changed_files, None);
+    }
+
+    #[test]
+    fn test_simple_message_new() {
+        let msg = SimpleMessage::new("brian", "stuff", "master", RefType::branch, "the internet", "HEAD");
+        let decoded = SimpleMessage::from_str(&json::encode(&msg).unwrap()).unwrap();
+        assert_eq!(decoded.prefix, Some("brian".to_owned()));
+        assert_eq!(decoded.repo_name, "stuff");
+        assert_eq!(decoded.refstring, "master");
+        assert_eq!(decoded.reftype, RefType::branch);
+        assert_eq!(decoded.remote, "the internet");
+    }
+
+    #[test]
+    fn test_github_changed_files() {
+        let json = r#"
+        {
+          "ref": "refs/heads/production",
+          "after": "abc123",
+          "repository": {
+            "name": "cool-website",
+            "ssh_url": "git@github.com:brian/cool-website.git",
+            "owner": {"name": "brian"}
+          },
+          "commits": [
+            {"added": ["services/api/new.rs"], "removed": [], "modified": ["README.md"]},
+            {"added": [], "removed": ["docs/old.md"], "modified": ["README.md"]}
+          ]
+        }
+        "#;
+        let msg = GitHubMessage::from_str(json).unwrap();
+        assert_eq!(msg.changed_files(),
+                   Some(vec![String::from("README.md"),
+                             String::from("docs/old.md"),
+                             String::from("services/api/new.rs")]));
+
+        assert_eq!(msg.head_commit_message(), None);
+
+        let with_head_commit = json.replace("\"commits\"",
+                                            "\"head_commit\": {\"message\": \"Add the api\"}, \"commits\"");
+        assert_eq!(GitHubMessage::from_str(&with_head_commit).unwrap().head_commit_message(),
+                   Some(String::from("Add the api")));
+
+        let without_commits = json.replace("\"commits\"", "\"not_commits\"");
+        assert_eq!(GitHubMessage::from_str(&without_commits).unwrap().changed_files(), None);
+    }
+
+    #[test]
+    fn test_github_changed_files_unknown() {
+        let json = r#"
+        {
+          "ref": "refs/heads/production",
+          "after": "abc123",
+          "forced": false,
+          "repository": {
+            "name": "cool-website",
+            "ssh_url": "git@github.com:brian/cool-website.git",
+            "owner": {"name": "brian"}
+          },
+          "commits": COMMITS
+        }
+        "#;
+        // New branches, tags and force pushes
+        let empty = json.replace("COMMITS", "[]");
+        assert_eq!(GitHubMessage::from_str(&empty).unwrap().changed_files(), None);
+        let forced = json.replace("COMMITS", r#"[{"added": [], "removed": [], "modified": ["README.md"]}]"#)
+                         .replace("\"forced\": false", "\"forced\": true");
+        assert_eq!(GitHubMessage::from_str(&forced).unwrap().changed_files(), None);
+
+        // GitHub leaves out everything after the 20th commit
+        let commit = r#"{"added": [], "removed": [], "modified": ["README.md"]}"#;
+        let truncated = json.replace("COMMITS", &format!("[{}]", vec![commit; 20].join(",")));
+        assert_eq!(GitHubMessage::from_str(&truncated).unwrap().changed_files(), None);
+        let complete = json.replace("COMMITS", &format!("[{}]", vec![commit; 19].join(",")));
+        assert_eq!(GitHubMessage::from_str(&complete).unwrap().changed_files(),
+                   Some(vec![String::from("README.md")]));
+    }
+
+    #[test]
+    fn test_skip_directive() {
+        let directives = vec![String::from("[skip deploy]"), String::from("[hookshot skip]")];
+        assert_eq!(skip_directive("Fix typo in docs [skip deploy]", &directives),
+                   Some("[skip deploy]"));
+        assert_eq!(skip_directive("Fix typo\n\n[hookshot skip]", &directives),
+                   Some("[hookshot skip]"));
+        assert_eq!(skip_directive("Deploy the new api", &directives), None);
+        assert_eq!(skip_directive("anything", &[String::new()]), None);
+    }
+
+    #[test]
+    fn test_valid_correlation_id() {
+        assert!(valid_correlation_id("build-1234"));
+        assert!(valid_correlation_id("67e55044-10b1-426f-9247-bb680e5fe0c8"));
+        assert!(!valid_correlation_id(""));
+        assert!(!valid_correlation_id("../../etc/passwd"));
+        assert!(!valid_correlation_id("has spaces"));
+        assert!(!valid_correlation_id(&(0..65).map(|_| "x").collect::<String>()));
+    }
+
+    #[test]
+    fn test_warm_request() {
+        assert_eq!(WarmRequest::from_str(r#"{"ref": "master"}"#).unwrap(),
+                   WarmRequest {
+                       refstring: String::from("master"),
+                       reftype: RefType::branch,
+                       remote: None,
+                   });
+        let request = WarmRequest::from_str(r#"{"ref": "v1.0.0", "ref_type": "tag", "remote": "git@github.com:o/r.git"}"#)
+                          .unwrap();
+        assert_eq!(request.reftype, RefType::tag);
+        assert_eq!(request.remote, Some(String::from("git@github.com:o/r.git")));
+
+        assert!(WarmRequest::from_str(r#"{"ref_type": "tag"}"#).is_err());
+        assert!(WarmRequest::from_str(r#"{"ref": "master", "ref_type": "commit"}"#).is_err());
+        assert!(WarmRequest::from_str("nope").is_err());
+    }
+
+    #[test]
+    fn test_local_path() {
+        let message = SimpleMessage::new("Owner", "repo", "feature/thing", RefType::branch, "remote", "sha");
+        let repo = GitRepo::from(message, Path::new("/tmp"));
+        assert_eq!(repo.local_path, Path::new("/tmp/owner.repo.feature!thing"));
     }
 }
@@ -0,0 +1,307 @@
+//! Counters for finished tasks, rendered in the Prometheus text format by
+//! the `/metrics` endpoint. They are counted in builds without the `metrics`
+//! feature too, there is just no endpoint to read them.
+//!
+//! The counters, and when each ref last deployed successfully, are written
+//! to `metrics/counters.json` under the log root every time a task
+//! finishes, so they carry on from where they were after a restart. Without
+//! that file, like on the first start after an upgrade, they are rebuilt
+//! from the finished tasks still in the history.
+
+use git;
+use history::{TaskHistory, TaskRecord, TaskStatus};
+use rustc_serialize::json;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use storage::{self, SyncPolicy};
+use std::sync::{Arc, Mutex};
+
+pub type SharedMetrics = Arc<Mutex<Metrics>>;
+
+#[derive(Debug, Clone, Default, RustcEncodable, RustcDecodable)]
+struct Counters {
+    /// Number of finished tasks keyed by final status.
+    tasks: BTreeMap<String, u64>,
+    wait_seconds_sum: i64,
+    wait_seconds_count: u64,
+    run_seconds_sum: i64,
+    run_seconds_count: u64,
+    /// Time spent in each step of finished tasks, keyed by step. `None` for
+    /// counters saved before steps were timed.
+    steps: Option<BTreeMap<String, StepCounter>>,
+    /// When the last successful task of each ref finished, keyed by
+    /// `owner/repo` and then ref. `None` for counters saved before it was
+    /// kept.
+    last_success: Option<BTreeMap<String, BTreeMap<String, i64>>>,
+}
+
+#[derive(Debug, Clone, Default, RustcEncodable, RustcDecodable)]
+struct StepCounter {
+    milliseconds_sum: u64,
+    count: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    counters: Counters,
+    /// Where the counters are saved, `None` to keep them in memory only.
+    path: Option<PathBuf>,
+}
+
+fn counters_path(log_root: &Path) -> PathBuf {
+    log_root.join("metrics").join("counters.json")
+}
+
+fn status_label(status: TaskStatus) -> &'static str {
+    match status {
+        TaskStatus::Queued => "queued",
+        TaskStatus::Running => "running",
+        TaskStatus::Success => "success",
+        TaskStatus::Failed => "failed",
+        TaskStatus::Cancelled => "cancelled",
+        TaskStatus::Skipped => "skipped",
+    }
+}
+
+impl Metrics {
+    /// Metrics that start at zero and are only kept in memory.
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Metrics saved under `log_root`, starting from the saved counters or,
+    /// if there are none that can be read, from the finished tasks in
+    /// `history`.
+    pub fn load(log_root: &Path, history: &TaskHistory) -> Metrics {
+        let path = counters_path(log_root);
+        let mut contents = String::new();
+        let saved = File::open(&path)
+                        .and_then(|mut file| file.read_to_string(&mut contents))
+                        .ok()
+                        .and_then(|_| json::decode::<Counters>(&contents).ok());
+        let mut metrics = Metrics::new();
+        match saved {
+            Some(counters) => {
+                metrics.counters = counters;
+                if metrics.counters.last_success.is_none() {
+                    for record in history.records() {
+                        metrics.note_success(record);
+                    }
+                }
+            }
+            None => {
+                for record in history.records() {
+                    metrics.count(record);
+                }
+            }
+        }
+        metrics.path = Some(path);
+        metrics.persist();
+        metrics
+    }
+
+    /// Count a task that has reached a terminal state.
+    pub fn observe(&mut self, record: &TaskRecord) {
+        if self.count(record) {
+            self.persist();
+        }
+    }
+
+    /// Whether `record` was counted.
+    fn count(&mut self, record: &TaskRecord) -> bool {
+        if !record.status.is_terminal() {
+            return false;
+        }
+        let counters = &mut self.counters;
+        *counters.tasks.entry(String::from(status_label(record.status))).or_insert(0) += 1;
+        if let Some(wait) = record.wait_seconds {
+            counters.wait_seconds_sum += wait;
+            counters.wait_seconds_count += 1;
+        }
+        if let Some(run) = record.run_seconds {
+            counters.run_seconds_sum += run;
+            counters.run_seconds_count += 1;
+        }
+        if let Some(ref steps) = record.steps {
+            let mut by_step = counters.steps.take().unwrap_or(BTreeMap::new());
+            for timing in steps {
+                let counter = by_step.entry(timing.step.clone()).or_insert(StepCounter::default());
+                counter.milliseconds_sum += timing.milliseconds;
+                counter.count += 1;
+            }
+            counters.steps = Some(by_step);
+        }
+        self.note_success(record);
+        true
+    }
+
+    /// Keep when `record` finished if it's the latest success of its ref.
+    fn note_success(&mut self, record: &TaskRecord) {
+        let finished_at = match (record.status, record.finished_at) {
+            (TaskStatus::Success, Some(finished_at)) => finished_at,
+            _ => return,
+        };
+        let repo = format!("{}/{}", git::normalize_name(&record.owner), git::normalize_name(&record.repo));
+        let mut by_repo = self.counters.last_success.take().unwrap_or(BTreeMap::new());
+        {
+            let last = by_repo.entry(repo)
+                              .or_insert(BTreeMap::new())
+                              .entry(record.refstring.clone())
+                              .or_insert(finished_at);
+            if *last < finished_at {
+                *last = finished_at;
+            }
+        }
+        self.counters.last_success = Some(by_repo);
+    }
+
+    #[allow(unused_must_use)]
+    fn persist(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let encoded = match json::encode(&self.counters) {
+            Ok(encoded) => encoded,
+            Err(_) => return,
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir);
+        }
+        // Written next to the counters and renamed over them, so a crash
+        // halfway through leaves the last complete counters behind
+        let tmp = path.with_extension("json.tmp");
+        if storage::write_file(&tmp, encoded.as_bytes(), SyncPolicy::Close).is_ok() {
+            storage::retry(|| fs::rename(&tmp, path));
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn render(&self) -> String {
+        let counters = &self.counters;
+        let mut out = String::new();
+        out.push_str("# HELP hookshot_tasks_total Finished tasks by final status.\n");
+        out.push_str("# TYPE hookshot_tasks_total counter\n");
+        for (status, count) in counters.tasks.iter() {
+            out.push_str(&format!("hookshot_tasks_total{{status=\"{}\"}} {}\n", status, count));
+        }
+        out.push_str("# HELP hookshot_task_wait_seconds Time tasks spent queued.\n");
+        out.push_str("# TYPE hookshot_task_wait_seconds summary\n");
+        out.push_str(&format!("hookshot_task_wait_seconds_sum {}\n", counters.wait_seconds_sum));
+        out.push_str(&format!("hookshot_task_wait_seconds_count {}\n", counters.wait_seconds_count));
+        out.push_str("# HELP hookshot_task_run_seconds Time tasks spent running.\n");
+        out.push_str("# TYPE hookshot_task_run_seconds summary\n");
+        out.push_str(&format!("hookshot_task_run_seconds_sum {}\n", counters.run_seconds_sum));
+        out.push_str(&format!("hookshot_task_run_seconds_count {}\n", counters.run_seconds_count));
+        out.push_str("# HELP hookshot_task_step_seconds Time tasks spent in each step.\n");
+        out.push_str("# TYPE hookshot_task_step_seconds summary\n");
+        for (step, counter) in counters.steps.iter().flat_map(|steps| steps.iter()) {
+            out.push_str(&format!("hookshot_task_step_seconds_sum{{step=\"{}\"}} {}.{:03}\n",
+                                  step,
+                                  counter.milliseconds_sum / 1000,
+                                  counter.milliseconds_sum % 1000));
+            out.push_str(&format!("hookshot_task_step_seconds_count{{step=\"{}\"}} {}\n", step, counter.count));
+        }
+        out.push_str("# HELP hookshot_last_success_timestamp_seconds When the last successful task of a ref \
+                      finished.\n");
+        out.push_str("# TYPE hookshot_last_success_timestamp_seconds gauge\n");
+        for (repo, refs) in counters.last_success.iter().flat_map(|repos| repos.iter()) {
+            for (refstring, finished_at) in refs.iter() {
+                out.push_str(&format!("hookshot_last_success_timestamp_seconds{{repo=\"{}\",ref=\"{}\"}} {}\n",
+                                      repo,
+                                      refstring,
+                                      finished_at));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use history::{StepTiming, TaskHistory, TaskRecord, TaskStatus};
+    use std::fs::File;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    fn record(status: TaskStatus, wait: Option<i64>, run: Option<i64>) -> TaskRecord {
+        let mut record = TaskRecord::test("id", "HEAD", status);
+        record.wait_seconds = wait;
+        record.run_seconds = run;
+        record
+    }
+
+    #[test]
+    fn test_observe_and_render() {
+        let mut metrics = Metrics::new();
+        let mut success = record(TaskStatus::Success, Some(2), Some(10));
+        success.steps = Some(vec![StepTiming { step: String::from("checkout"), milliseconds: 1500 },
+                                  StepTiming { step: String::from("task"), milliseconds: 8000 }]);
+        metrics.observe(&success);
+        let mut failed = record(TaskStatus::Failed, Some(4), Some(20));
+        failed.steps = Some(vec![StepTiming { step: String::from("checkout"), milliseconds: 20 }]);
+        metrics.observe(&failed);
+        metrics.observe(&record(TaskStatus::Cancelled, Some(6), None));
+        metrics.observe(&record(TaskStatus::Running, Some(100), None));
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("hookshot_tasks_total{status=\"success\"} 1\n"));
+        assert!(rendered.contains("hookshot_tasks_total{status=\"failed\"} 1\n"));
+        assert!(rendered.contains("hookshot_tasks_total{status=\"cancelled\"} 1\n"));
+        assert!(rendered.contains("hookshot_task_wait_seconds_sum 12\n"));
+        assert!(rendered.contains("hookshot_task_wait_seconds_count 3\n"));
+        assert!(rendered.contains("hookshot_task_run_seconds_sum 30\n"));
+        assert!(rendered.contains("hookshot_task_run_seconds_count 2\n"));
+        assert!(rendered.contains("hookshot_task_step_seconds_sum{step=\"checkout\"} 1.520\n"));
+        assert!(rendered.contains("hookshot_task_step_seconds_count{step=\"checkout\"} 2\n"));
+        assert!(rendered.contains("hookshot_task_step_seconds_sum{step=\"task\"} 8.000\n"));
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = TempDir::new("hookshot-metrics-test").unwrap();
+        let root = dir.path();
+        let mut history = TaskHistory::new(root);
+        let mut finished = record(TaskStatus::Success, Some(2), Some(10));
+        finished.id = String::from("finished");
+        finished.finished_at = Some(1500000000);
+        history.insert(finished);
+        let mut running = record(TaskStatus::Running, Some(1), None);
+        running.id = String::from("running");
+        history.insert(running);
+
+        // Nothing saved yet, so the counters come from the history
+        let mut metrics = Metrics::load(root, &history);
+        assert!(metrics.render().contains("hookshot_tasks_total{status=\"success\"} 1\n"));
+        metrics.observe(&record(TaskStatus::Failed, Some(4), Some(20)));
+
+        // After a restart they pick up where they were, even with the
+        // history gone
+        let metrics = Metrics::load(root, &TaskHistory::new(root));
+        let rendered = metrics.render();
+        assert!(rendered.contains("hookshot_tasks_total{status=\"success\"} 1\n"));
+        assert!(rendered.contains("hookshot_tasks_total{status=\"failed\"} 1\n"));
+        assert!(rendered.contains("hookshot_task_run_seconds_sum 30\n"));
+        assert!(rendered.contains("hookshot_last_success_timestamp_seconds{repo=\"owner/repo\",ref=\"master\"} \
+                                   1500000000\n"));
+        assert!(!root.join("metrics").join("counters.json.tmp").exists());
+
+        // Counters saved before steps were timed still load
+        let saved = "{\"tasks\":{\"success\":3},\"wait_seconds_sum\":0,\"wait_seconds_count\":0,\
+                     \"run_seconds_sum\":0,\"run_seconds_count\":0}";
+        File::create(root.join("metrics").join("counters.json")).unwrap().write_all(saved.as_bytes()).unwrap();
+        let metrics = Metrics::load(root, &TaskHistory::new(root));
+        assert!(metrics.render().contains("hookshot_tasks_total{status=\"success\"} 3\n"));
+
+        // and the last successes of those come from the history, without
+        // counting its tasks again
+        File::create(root.join("metrics").join("counters.json")).unwrap().write_all(saved.as_bytes()).unwrap();
+        let rendered = Metrics::load(root, &history).render();
+        assert!(rendered.contains("hookshot_tasks_total{status=\"success\"} 3\n"));
+        assert!(rendered.contains("hookshot_last_success_timestamp_seconds{repo=\"owner/repo\",ref=\"master\"} \
+                                   1500000000\n"));
+    }
+}
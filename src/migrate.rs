@@ -0,0 +1,362 @@
+//! `hookshot migrate`: bring a log_root and checkout_root written by an
+//! older hookshot up to date.
+//!
+//! Two things have changed in how hookshot lays out what it keeps on disk:
+//!
+//! - Tasks get a record (`<log_root>/<task_id>.json`) next to their log.
+//!   Tasks that ran before records existed only have the log, so they are
+//!   missing from `/tasks`, rollbacks and stats. For those a record is
+//!   written from what the log says: the repo, ref and sha from its
+//!   "hookshot environment", the start and finish times and the result.
+//! - Checkout directory names escape the dots in owner and repo names,
+//!   lowercase owner and repo names and shorten names that are too long,
+//!   see `workspace::CheckoutPath`. Checkouts under an older name are
+//!   renamed, and the task records and queued tasks that point at them are
+//!   updated, so the next deploy reuses the checkout instead of cloning
+//!   again.
+//!
+//! Run it while hookshot is stopped. Everything it does can be done again,
+//! a second run finds nothing left to do.
+
+use chrono::{TimeZone, UTC};
+use history::{TaskHistory, TaskRecord, TaskStatus};
+use message::RefType;
+use queue_store;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use uuid::Uuid;
+use workspace::CheckoutPath;
+
+/// What `migrate` changed, or would have changed when it's a dry run.
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    /// Ids of the tasks that got a record from their log.
+    pub records_created: Vec<String>,
+    /// Ids of the tasks whose log doesn't say what they deployed, so no
+    /// record could be written for them.
+    pub logs_skipped: Vec<String>,
+    /// Checkouts renamed, from their old path to their new one.
+    pub checkouts_moved: Vec<(PathBuf, PathBuf)>,
+    /// Ids of the task records and queued tasks now pointing at a renamed
+    /// checkout.
+    pub tasks_updated: Vec<String>,
+}
+
+/// Migrate `log_root` and `checkout_root`. With `dry_run` nothing is
+/// written, the report says what would have been.
+pub fn migrate(log_root: &Path, checkout_root: &Path, dry_run: bool) -> io::Result<Report> {
+    let mut report = Report::default();
+    let mut history = TaskHistory::load(log_root);
+
+    let mut created = vec![];
+    for (id, log, modified) in try!(logs_without_records(log_root, &history)) {
+        match record_from_log(&id, &log, modified) {
+            Some(record) => {
+                report.records_created.push(id);
+                created.push(record);
+            }
+            None => report.logs_skipped.push(id),
+        }
+    }
+    let mut records = history.records().into_iter().cloned().collect::<Vec<TaskRecord>>();
+    records.extend(created.iter().cloned());
+    records.sort_by(|a, b| (a.queued_at, &a.id).cmp(&(b.queued_at, &b.id)));
+    if !dry_run {
+        for record in created {
+            history.insert(record);
+        }
+    }
+
+    let mut moved: BTreeMap<PathBuf, PathBuf> = BTreeMap::new();
+    for record in &records {
+        let old = PathBuf::from(&record.local_path);
+        let new = match record.project {
+            Some(ref project) => {
+                CheckoutPath::for_project(checkout_root, &record.owner, &record.repo, &record.refstring, project)
+            }
+            None => CheckoutPath::new(checkout_root, &record.owner, &record.repo, &record.refstring),
+        };
+        let new = new.into_path_buf();
+        if old == new {
+            continue;
+        }
+        // On a case insensitive filesystem a checkout whose name only
+        // differs in case already "exists" under the new name
+        let free = !new.exists() || same_file(&old, &new);
+        if !moved.contains_key(&old) && old.parent() == Some(checkout_root) && old.is_dir() && free {
+            if !dry_run {
+                try!(fs::rename(&old, &new));
+            }
+            report.checkouts_moved.push((old.clone(), new.clone()));
+            moved.insert(old.clone(), new.clone());
+        }
+        // Records of a checkout that was renamed, or that is gone and has
+        // been cloned again under the new name, point at the new one
+        let target = match moved.get(&old) {
+            Some(target) => target.clone(),
+            None if !old.exists() && new.exists() => new,
+            None => continue,
+        };
+        if !dry_run {
+            history.update(&record.id,
+                           |record| record.local_path = target.to_string_lossy().into_owned());
+        }
+        report.tasks_updated.push(record.id.clone());
+    }
+
+    for mut task in queue_store::load(log_root) {
+        let target = match moved.get(&PathBuf::from(&task.local_path)) {
+            Some(target) => target.clone(),
+            None => continue,
+        };
+        task.local_path = target.to_string_lossy().into_owned();
+        if !dry_run {
+            try!(queue_store::save(log_root, &task));
+        }
+        if !report.tasks_updated.contains(&task.id) {
+            report.tasks_updated.push(task.id);
+        }
+    }
+
+    Ok(report)
+}
+
+/// Whether two paths are the same file or directory.
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// The id, contents and modification time of every task log in `log_root`
+/// that has no record.
+fn logs_without_records(log_root: &Path, history: &TaskHistory) -> io::Result<Vec<(String, String, i64)>> {
+    let entries = match fs::read_dir(log_root) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut logs = vec![];
+    for entry in entries {
+        let path = try!(entry).path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("log") {
+            continue;
+        }
+        // `<id>.raw.log` has a stem that isn't a uuid
+        let id = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(stem) if Uuid::parse_str(stem).is_ok() => String::from(stem),
+            _ => continue,
+        };
+        if history.get(&id).is_some() {
+            continue;
+        }
+        let mut log = String::new();
+        try!(try!(File::open(&path)).read_to_string(&mut log));
+        let modified = try!(fs::metadata(&path))
+                           .modified()
+                           .ok()
+                           .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                           .map(|since| since.as_secs() as i64)
+                           .unwrap_or(0);
+        logs.push((id, log, modified));
+    }
+    logs.sort();
+    Ok(logs)
+}
+
+/// A record for the task that wrote `log`, which was last modified at
+/// `modified`. `None` if the log has no "hookshot environment" to say what
+/// the task deployed.
+fn record_from_log(id: &str, log: &str, modified: i64) -> Option<TaskRecord> {
+    let mut env = BTreeMap::new();
+    let mut in_env = false;
+    let mut correlation_id = None;
+    let mut started_at = None;
+    let mut finished_at = None;
+    let mut exit_code = None;
+    let mut allowed = false;
+    let mut cancelled = false;
+    let mut skipped = false;
+    for line in log.lines() {
+        if in_env {
+            match line.find(": ") {
+                Some(i) => {
+                    env.insert(&line[..i], &line[i + 2..]);
+                    continue;
+                }
+                None if line.starts_with("---") => continue,
+                None => in_env = false,
+            }
+        }
+        if line == "hookshot environment:" {
+            in_env = true;
+        } else if line.starts_with("correlation id: ") {
+            correlation_id = Some(String::from(&line["correlation id: ".len()..]));
+        } else if line.starts_with("started: ") {
+            started_at = parse_time(&line["started: ".len()..]);
+        } else if line.starts_with("task finished: ") {
+            finished_at = parse_time(&line["task finished: ".len()..]);
+        } else if line.starts_with("exit code: ") {
+            exit_code = Some(String::from(&line["exit code: ".len()..]));
+        } else if line.ends_with("is allowed, treating as success") {
+            allowed = true;
+        } else if line == "task cancelled" {
+            cancelled = true;
+        } else if line.starts_with("skipping:") || line.starts_with("checkout is ready") {
+            skipped = true;
+        }
+    }
+
+    let get = |key: &str| env.get(key).map(|value| String::from(*value));
+    let (owner, repo, refstring) = match (get("git_repo_owner"), get("git_repo_name"), get("git_ref")) {
+        (Some(owner), Some(repo), Some(refstring)) => (owner, repo, refstring),
+        _ => return None,
+    };
+    let (status, error) = match exit_code {
+        _ if cancelled => (TaskStatus::Cancelled, None),
+        _ if skipped => (TaskStatus::Skipped, None),
+        Some(ref code) if code == "0" || allowed => (TaskStatus::Success, None),
+        Some(code) => (TaskStatus::Failed, Some(format!("exit code: {}", code))),
+        None => (TaskStatus::Failed, Some(String::from("the task log has no result"))),
+    };
+    let queued_at = started_at.unwrap_or(modified);
+    Some(TaskRecord {
+        id: String::from(id),
+        owner: owner,
+        repo: repo,
+        refstring: refstring,
+        reftype: match get("git_ref_type") {
+            Some(ref reftype) if reftype == "tag" => RefType::tag,
+            _ => RefType::branch,
+        },
+        sha: get("git_commit_sha").unwrap_or(String::new()),
+        remote_path: String::new(),
+        local_path: get("hookshot_checkout_path").unwrap_or(String::new()),
+        status: status,
+        is_rollback: get("hookshot_is_rollback").map_or(false, |value| value == "true"),
+        project: get("hookshot_project"),
+        trigger: None,
+        correlation_id: correlation_id,
+        queued_at: queued_at,
+        started_at: started_at,
+        finished_at: Some(finished_at.unwrap_or(modified)),
+        wait_seconds: started_at.map(|_| 0),
+        run_seconds: match (started_at, finished_at) {
+            (Some(started), Some(finished)) => Some(finished - started),
+            _ => None,
+        },
+        checkout_bytes: None,
+        tmp_bytes: None,
+        steps: None,
+        hosts: None,
+        config: None,
+        error: error,
+        quarantine: None,
+    })
+}
+
+/// A timestamp as the task log writes it, `2016-01-02 03:04:05.678 UTC`.
+fn parse_time(s: &str) -> Option<i64> {
+    UTC.datetime_from_str(s.trim_right_matches(" UTC"), "%Y-%m-%d %H:%M:%S%.f")
+       .ok()
+       .map(|time| time.timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{migrate, record_from_log};
+    use history::{TaskHistory, TaskStatus};
+    use std::fs::{self, File};
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    fn log(checkout: &str) -> String {
+        format!("correlation id: build-7\n\ntrigger: push\n\nsystem user: deploy\n\n\
+                 started: 2016-01-02 03:04:05.678 UTC\n\
+                 hookshot environment:\n---------------------\n\
+                 git_commit_sha: abc\ngit_ref: master\ngit_ref_type: branch\n\
+                 git_repo_name: c.d\ngit_repo_owner: a.b\n\
+                 hookshot_checkout_path: {}\nhookshot_is_rollback: false\n\n\
+                 task finished: 2016-01-02 03:05:10.001 UTC\nduration: 1m 4s...\n\n\
+                 exit code: 2\n\n==stdout==\n",
+                checkout)
+    }
+
+    #[test]
+    fn test_record_from_log() {
+        let record = record_from_log("id", &log("/checkouts/a.b.c.d.master"), 99).unwrap();
+        assert_eq!((&record.owner[..], &record.repo[..], &record.refstring[..]), ("a.b", "c.d", "master"));
+        assert_eq!(record.sha, "abc");
+        assert_eq!(record.local_path, "/checkouts/a.b.c.d.master");
+        assert_eq!(record.correlation_id, Some(String::from("build-7")));
+        assert_eq!(record.status, TaskStatus::Failed);
+        assert_eq!(record.error, Some(String::from("exit code: 2")));
+        assert_eq!(record.started_at, Some(1451703845));
+        assert_eq!(record.run_seconds, Some(65));
+
+        let allowed = log("/c").replace("exit code: 2\n", "exit code: 2\nexit code 2 is allowed, treating as success\n");
+        assert_eq!(record_from_log("id", &allowed, 99).unwrap().status, TaskStatus::Success);
+        let unfinished = log("/c").replace("exit code: 2\n", "");
+        let record = record_from_log("id", &unfinished, 99).unwrap();
+        assert_eq!(record.error, Some(String::from("the task log has no result")));
+        assert!(record_from_log("id", "hookshot environment: not logged\n", 99).is_none());
+    }
+
+    #[test]
+    fn test_migrate() {
+        let logs = TempDir::new("hookshot-migrate-logs").unwrap();
+        let checkouts = TempDir::new("hookshot-migrate-checkouts").unwrap();
+        let old = checkouts.path().join("a.b.c.d.master");
+        let new = checkouts.path().join("a!b.c!d.master");
+        fs::create_dir(&old).unwrap();
+        let id = "2cd9e8a0-8e6e-4e3b-9f5a-6c1b1c3a5c3e";
+        File::create(logs.path().join(format!("{}.log", id)))
+            .unwrap()
+            .write_all(log(&old.to_string_lossy()).as_bytes())
+            .unwrap();
+
+        let report = migrate(logs.path(), checkouts.path(), true).unwrap();
+        assert_eq!(report.records_created, vec![id]);
+        assert_eq!(report.checkouts_moved, vec![(old.clone(), new.clone())]);
+        assert_eq!(report.tasks_updated, vec![id]);
+        assert!(old.exists());
+        assert!(TaskHistory::load(logs.path()).get(id).is_none());
+
+        assert_eq!(migrate(logs.path(), checkouts.path(), false).unwrap(), report);
+        assert!(!old.exists() && new.is_dir());
+        let history = TaskHistory::load(logs.path());
+        assert_eq!(history.get(id).unwrap().local_path, new.to_string_lossy());
+
+        assert_eq!(migrate(logs.path(), checkouts.path(), false).unwrap(),
+                   Default::default());
+    }
+
+    #[test]
+    fn test_migrate_mixed_case() {
+        let logs = TempDir::new("hookshot-migrate-logs").unwrap();
+        let checkouts = TempDir::new("hookshot-migrate-checkouts").unwrap();
+        let old = checkouts.path().join("Org.App.Master");
+        let new = checkouts.path().join("org.app.Master");
+        fs::create_dir(&old).unwrap();
+        let id = "7f0c3b8e-5d2a-4c1e-8b9f-0a6d2e4c1b3a";
+        let contents = log(&old.to_string_lossy())
+                           .replace("git_ref: master", "git_ref: Master")
+                           .replace("git_repo_name: c.d", "git_repo_name: App")
+                           .replace("git_repo_owner: a.b", "git_repo_owner: Org");
+        File::create(logs.path().join(format!("{}.log", id)))
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap();
+
+        let report = migrate(logs.path(), checkouts.path(), false).unwrap();
+        assert_eq!(report.checkouts_moved, vec![(old.clone(), new.clone())]);
+        assert!(new.is_dir());
+        let history = TaskHistory::load(logs.path());
+        assert_eq!(history.get(id).unwrap().local_path, new.to_string_lossy());
+    }
+}
@@ -1,26 +1,50 @@
+use ansi;
+use ansible_task::HostRecap;
+use chaos::{self, Fault};
 use deploy_task::DeployTask;
+use history;
 use message::RefType;
-use hyper::client::Client;
+use http::{self, Request};
 use hyper::header::ContentType;
-use repo_config::RepoConfig;
+use hyper::mime::Mime;
+use repo_config::{Config, PayloadFormat, RepoConfig};
 use rustc_serialize::json::{self, ToJson, Json};
 use signature::{Signature, HashType};
+use std::collections::BTreeMap;
 use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::Read;
 use std::thread;
+use template;
 
 header! { (XHookshotSignature, "X-Hookshot-Signature") => [String] }
+header! { (XHookshotIdempotencyKey, "X-Hookshot-Idempotency-Key") => [String] }
+
+const FORM_CONTENT_TYPE: &'static str = "application/x-www-form-urlencoded";
 
 #[derive(RustcEncodable)]
 struct Message<'a> {
     status: TaskState,
     failed: bool,
+    quarantined: bool,
     task_id: &'a String,
     task_url: &'a String,
     owner: &'a String,
     reftype: RefType,
     refstring: &'a String,
     repo: &'a String,
+    project: Option<&'a String>,
     sha: &'a String,
+    is_rollback: bool,
+    trigger: String,
+    attempt: u32,
+    idempotency_key: String,
+    correlation_id: Option<String>,
+    wait_seconds: Option<i64>,
+    run_seconds: Option<i64>,
+    hosts: Option<BTreeMap<String, HostRecap>>,
+    stats: history::RefStats,
+    log_tail: Option<String>,
 }
 
 #[derive(RustcEncodable, Clone)]
@@ -58,12 +82,43 @@ pub fn failed(task: &DeployTask, config: &RepoConfig) {
     send_message(task, config, TaskState::Failed);
 }
 
+/// The last `lines` lines of `log`.
+fn tail(log: &str, lines: usize) -> &str {
+    let log = log.trim_right_matches('\n');
+    match log.rmatch_indices('\n').nth(lines.saturating_sub(1)) {
+        Some((index, _)) if lines > 0 => &log[index + 1..],
+        _ if lines > 0 => log,
+        _ => "",
+    }
+}
+
+/// The end of a failed task's log, without escape sequences. `None` for
+/// other tasks, when no lines are wanted or when the log can't be read.
+fn log_tail(task: &DeployTask, status: &TaskState, lines: usize) -> Option<String> {
+    match *status {
+        TaskState::Failed if lines > 0 => {}
+        _ => return None,
+    }
+    let mut log = String::new();
+    let path = task.logdir.join(format!("{}.log", task.id));
+    match File::open(&path).and_then(|mut file| file.read_to_string(&mut log)) {
+        Ok(_) => Some(String::from(tail(&ansi::strip(&log), lines))),
+        Err(_) => None,
+    }
+}
+
+/// Identifies a message for receivers: the same task, state and attempt
+/// always get the same key, however many times the message is sent.
+fn idempotency_key(task: &DeployTask, status: &TaskState) -> String {
+    format!("{}:{}:{}", task.id, status, task.attempt)
+}
+
 fn send_message(task: &DeployTask, config: &RepoConfig, status: TaskState) {
-    println!("[{}]: notifier: looking up notify url", &task.id);
-    let notifiers = match get_notifiers(task, config) {
-        Some(url) => url,
+    debug!(&task.id, "notifier: looking up notify url");
+    let (notifiers, refconfig) = match get_notifiers(task, config) {
+        Some(found) => found,
         None => {
-            println!("[{}]: notifier: could not find notify url", &task.id);
+            debug!(&task.id, "notifier: could not find notify url");
             return;
         }
     };
@@ -75,10 +130,31 @@ fn send_message(task: &DeployTask, config: &RepoConfig, status: TaskState) {
         TaskState::Failed => true,
         _ => false,
     };
+    let quarantined = task.quarantine_reason().is_some();
+
+    // The task hasn't been marked finished yet when the final message goes
+    // out, so its run time so far is as good as it gets.
+    let (wait_seconds, run_seconds, hosts, stats) = {
+        let history = task.history.lock().unwrap();
+        let stats = history.stats(&task.id.to_string(), &task.repo.owner, &task.repo.name, &task.repo.refstring);
+        match history.get(&task.id.to_string()) {
+            None => (None, None, None, stats),
+            Some(record) => {
+                let run_seconds = match (&status, record.started_at) {
+                    (&TaskState::Started, _) | (_, None) => None,
+                    (_, Some(started_at)) => Some(history::now() - started_at),
+                };
+                (record.wait_seconds, run_seconds, record.hosts.clone(), stats)
+            }
+        }
+    };
 
+    let idempotency_key = idempotency_key(task, &status);
+    let log_tail = log_tail(task, &status, refconfig.notify_log_lines);
     let message = Message {
         status: status.clone(),
         failed: failed,
+        quarantined: quarantined,
         task_id: &format!("{}", task.id),
         task_url: &task_url,
         sha: &repo.sha,
@@ -86,48 +162,127 @@ fn send_message(task: &DeployTask, config: &RepoConfig, status: TaskState) {
         refstring: &repo.refstring,
         reftype: repo.reftype,
         repo: &repo.name,
+        project: task.project.as_ref().map(|project| &project.name),
+        is_rollback: task.is_rollback,
+        trigger: task.trigger.to_string(),
+        attempt: task.attempt,
+        idempotency_key: idempotency_key.clone(),
+        correlation_id: task.correlation_id.clone(),
+        wait_seconds: wait_seconds,
+        run_seconds: run_seconds,
+        hosts: hosts,
+        stats: stats,
+        log_tail: log_tail.clone(),
     };
 
-    let request_body = match json::encode(&message) {
-        Ok(body) => body.to_owned(),
-        Err(_) => return,
-    };
+    // The placeholders are everything in `repo_config::PAYLOAD_PLACEHOLDERS`
+    let (notifiers, request_body, content_type) = {
+        let id = format!("{}", task.id);
+        let status_name = status.to_string();
+        let reftype = repo.reftype.to_string();
+        let trigger = task.trigger.to_string();
+        let attempt = task.attempt.to_string();
+        let (failed, is_rollback) = (failed.to_string(), task.is_rollback.to_string());
+        let quarantined = quarantined.to_string();
+        let correlation_id = task.correlation_id.clone().unwrap_or(String::new());
+        let log_tail = log_tail.unwrap_or(String::new());
+        let vars = [("owner", &repo.owner[..]),
+                    ("repo", &repo.name[..]),
+                    ("refstring", &repo.refstring[..]),
+                    ("reftype", &reftype[..]),
+                    ("sha", &repo.sha[..]),
+                    ("status", &status_name[..]),
+                    ("failed", &failed[..]),
+                    ("quarantined", &quarantined[..]),
+                    ("task_id", &id[..]),
+                    ("task_url", &task_url[..]),
+                    ("trigger", &trigger[..]),
+                    ("attempt", &attempt[..]),
+                    ("is_rollback", &is_rollback[..]),
+                    ("idempotency_key", &idempotency_key[..]),
+                    ("correlation_id", &correlation_id[..]),
+                    ("log_tail", &log_tail[..])];
+        let urls = notifiers.iter()
+                            .map(|url| template::expand(url, &vars, template::url_escape))
+                            .collect::<Vec<String>>();
 
-    let client = Client::new();
+        let (request_body, content_type) = match (refconfig.payload_template.as_ref(), refconfig.payload_format) {
+            (Some(payload), PayloadFormat::Json) =>
+                (template::expand(payload, &vars, template::json_escape), ContentType::json()),
+            (Some(payload), PayloadFormat::Form) =>
+                (template::expand(payload, &vars, template::url_escape),
+                 ContentType(FORM_CONTENT_TYPE.parse::<Mime>().unwrap())),
+            (None, _) => match json::encode(&message) {
+                Ok(body) => (body.to_owned(), ContentType::json()),
+                Err(_) => return,
+            },
+        };
+        (urls, request_body, content_type)
+    };
 
     // Spawn a new thread to send the message so we don't block the task
     let task_id = task.id.clone();
-    let notifiers = notifiers.clone();
     let secret = task.secret.clone();
+    let timeouts = task.http_timeouts;
+    let inject_error = chaos::injects(Fault::Notify, &repo.owner, &repo.name);
 
     thread::spawn(move || {
         let sig = Signature::create(HashType::SHA256, &request_body, &secret);
 
         for notifiers in &notifiers {
-            println!("[{}]: notifier: sending {} message to {}",
-                     &task_id,
-                     &status,
-                     &notifiers);
-            let request = client.post(notifiers)
+            info!(&task_id,
+                  "notifier: sending {} message to {}",
+                  &status,
+                  &notifiers);
+            let request = Request::post(notifiers, &request_body)
                 .header(XHookshotSignature(sig.to_string()))
-                .header(ContentType::json())
-                .body(&request_body)
-                .send();
-
-            if request.is_err() {
-                println!("[{}]: notifier: could not send message {}",
-                         &task_id,
-                         &request.unwrap_err());
+                .header(XHookshotIdempotencyKey(idempotency_key.clone()))
+                .header(content_type.clone());
+            let response = match inject_error {
+                true => Ok(chaos::server_error()),
+                false => http::send(request, timeouts),
+            };
+
+            match response {
+                Ok(ref response) if !response.status.is_success() => {
+                    warn!(&task_id,
+                          "notifier: {} responded with {}",
+                          &notifiers,
+                          response.status)
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!(&task_id,
+                          "notifier: could not send message {}",
+                          &e)
+                }
             }
         }
     });
 }
 
-fn get_notifiers<'a>(task: &DeployTask, config: &'a RepoConfig) -> Option<&'a Vec<String>> {
+/// The notifiers for the task's ref and the config they're from.
+fn get_notifiers<'a, 'b>(task: &DeployTask, config: &'b RepoConfig<'a>) -> Option<(&'b Vec<String>, &'b Config<'a>)> {
     let refstring = &task.repo.refstring;
     let reftype = task.repo.reftype;
     match config.lookup(reftype, refstring) {
-        Some(refconfig) => refconfig.notifiers.as_ref(),
+        Some(refconfig) => refconfig.notifiers.as_ref().map(|notifiers| (notifiers, refconfig)),
         None => None,
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::tail;
+
+    #[test]
+    fn test_tail() {
+        let log = "one\ntwo\nthree\n";
+        assert_eq!(tail(log, 2), "two\nthree");
+        assert_eq!(tail(log, 3), "one\ntwo\nthree");
+        assert_eq!(tail(log, 10), "one\ntwo\nthree");
+        assert_eq!(tail(log, 0), "");
+        assert_eq!(tail("", 5), "");
+    }
+}
@@ -0,0 +1,268 @@
+//! Trigger and resolve PagerDuty incidents for failed deploys.
+//!
+//! Only refs that have a `pagerduty_severity` in their repository config are
+//! reported, and only if the server config has a `pagerduty_routing_key`.
+//! Events for a ref share a dedup key, so the first success after a failure
+//! resolves the incident that failure opened.
+
+use deploy_task::DeployTask;
+use history::TaskStatus;
+use http::{self, Request};
+use hyper::header::ContentType;
+use repo_config::{RepoConfig, Severity};
+use rustc_serialize::json;
+use std::thread;
+
+const EVENTS_URL: &'static str = "https://events.pagerduty.com/v2/enqueue";
+
+#[derive(RustcEncodable)]
+struct TriggerEvent {
+    routing_key: String,
+    event_action: &'static str,
+    dedup_key: String,
+    payload: Payload,
+}
+
+#[derive(RustcEncodable)]
+struct ResolveEvent {
+    routing_key: String,
+    event_action: &'static str,
+    dedup_key: String,
+}
+
+#[derive(RustcEncodable)]
+struct Payload {
+    summary: String,
+    source: String,
+    severity: String,
+}
+
+fn dedup_key(task: &DeployTask) -> String {
+    match task.project {
+        Some(ref project) => format!("hookshot.{}~{}", task.repo.fully_qualified_branch(), project.name),
+        None => format!("hookshot.{}", task.repo.fully_qualified_branch()),
+    }
+}
+
+fn severity_for(task: &DeployTask, config: &RepoConfig) -> Option<Severity> {
+    match config.lookup(task.repo.reftype, &task.repo.refstring) {
+        Some(refconfig) => refconfig.pagerduty_severity,
+        None => None,
+    }
+}
+
+/// The severity of the incident for a failed task, `info` while the task's
+/// target is in quarantine.
+fn trigger_severity(task: &DeployTask, config: &RepoConfig) -> Option<Severity> {
+    match (severity_for(task, config), task.quarantine_reason()) {
+        (Some(_), Some(_)) => Some(Severity::Info),
+        (severity, _) => severity,
+    }
+}
+
+/// Whether the last task that finished for the task's ref failed, so its
+/// incident is still open.
+fn previously_failed(task: &DeployTask) -> bool {
+    let history = task.history.lock().unwrap();
+    match history.previous_finished(&task.id.to_string(),
+                                    &task.repo.owner,
+                                    &task.repo.name,
+                                    &task.repo.refstring) {
+        Some(record) => record.status == TaskStatus::Failed,
+        None => false,
+    }
+}
+
+/// Trigger an incident for a failed task, with severity `info` while the
+/// task's target is in quarantine.
+pub fn failed(task: &DeployTask, config: &RepoConfig) {
+    let severity = match trigger_severity(task, config) {
+        Some(severity) => severity,
+        None => return,
+    };
+    let routing_key = match task.pagerduty_routing_key {
+        Some(ref key) => key.clone(),
+        None => return,
+    };
+
+    let event = TriggerEvent {
+        routing_key: routing_key,
+        event_action: "trigger",
+        dedup_key: dedup_key(task),
+        payload: Payload {
+            summary: format!("hookshot deploy of {} failed at {} (task {})",
+                             task.repo.fully_qualified_branch(),
+                             task.repo.sha,
+                             task.id),
+            source: task.host.clone(),
+            severity: severity.to_string(),
+        },
+    };
+    match json::encode(&event) {
+        Ok(body) => send_event(task, "trigger", body),
+        Err(_) => return,
+    }
+}
+
+/// Resolve the incident for a ref if the last task for it failed.
+pub fn success(task: &DeployTask, config: &RepoConfig) {
+    if severity_for(task, config).is_none() {
+        return;
+    }
+    let routing_key = match task.pagerduty_routing_key {
+        Some(ref key) => key.clone(),
+        None => return,
+    };
+
+    if !previously_failed(task) {
+        return;
+    }
+
+    let event = ResolveEvent {
+        routing_key: routing_key,
+        event_action: "resolve",
+        dedup_key: dedup_key(task),
+    };
+    match json::encode(&event) {
+        Ok(body) => send_event(task, "resolve", body),
+        Err(_) => return,
+    }
+}
+
+fn send_event(task: &DeployTask, action: &'static str, request_body: String) {
+    let task_id = task.id.clone();
+    let timeouts = task.http_timeouts;
+    thread::spawn(move || {
+        info!(&task_id, "pagerduty: sending {} event", action);
+        let request = Request::post(EVENTS_URL, &request_body).header(ContentType::json());
+        let request = http::send(request, timeouts);
+        if request.is_err() {
+            warn!(&task_id,
+                  "pagerduty: could not send event {}",
+                  &request.unwrap_err());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dedup_key, previously_failed, severity_for, trigger_severity};
+    use deploy_task::{DeployTask, Shared};
+    use git::GitRepo;
+    use history::{TaskHistory, TaskRecord, TaskStatus, Trigger};
+    use maintenance::Maintenance;
+    use message::{RefType, SimpleMessage};
+    use metrics::Metrics;
+    use process::ProcessGroups;
+    use quarantine::Quarantine;
+    use repo_config::{RepoConfig, Severity};
+    use server_config::{Project, ServerConfig};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use task_factory::TaskRegistry;
+    use task_manager::Locks;
+    use tempdir::TempDir;
+    use uuid::Uuid;
+
+    fn task(root: &TempDir, refstring: &str) -> DeployTask {
+        let toml = format!(r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "{root}"
+            log_root = "{root}"
+            pagerduty_routing_key = "routing-key"
+        "#,
+                           root = root.path().display());
+        let config = ServerConfig::from(&toml).unwrap();
+        let message = SimpleMessage::new("owner",
+                                         "repo",
+                                         refstring,
+                                         RefType::branch,
+                                         "git@github.com:owner/repo.git",
+                                         "HEAD");
+        let shared = Shared {
+            history: Arc::new(Mutex::new(TaskHistory::new(config.log_root.path()))),
+            metrics: Arc::new(Mutex::new(Metrics::new())),
+            processes: ProcessGroups::new(),
+            maintenance: Arc::new(Mutex::new(Maintenance::default())),
+            quarantine: Arc::new(Mutex::new(Quarantine::default())),
+            locks: Locks::new(),
+            methods: TaskRegistry::new(),
+        };
+        DeployTask::new(GitRepo::from(message, config.checkout_root.path()),
+                        Uuid::new_v4(),
+                        Trigger::SimpleMessage,
+                        &config,
+                        &shared)
+    }
+
+    fn repo_config() -> RepoConfig<'static> {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "build"
+
+            [branch.production]
+            pagerduty_severity = "critical"
+
+            [branch.staging]
+        "#;
+        RepoConfig::from_str(toml, Path::new("./src/test/repo_config")).unwrap()
+    }
+
+    fn finished(id: &str, status: TaskStatus, finished_at: i64) -> TaskRecord {
+        let mut record = TaskRecord::test(id, "HEAD", status);
+        record.refstring = String::from("production");
+        record.queued_at = finished_at - 10;
+        record.finished_at = Some(finished_at);
+        record
+    }
+
+    #[test]
+    fn test_dedup_key() {
+        let root = TempDir::new("hookshot-pagerduty-test").unwrap();
+        let mut task = task(&root, "production");
+        assert_eq!(dedup_key(&task), "hookshot.owner.repo.production");
+
+        task.project = Some(Project { name: String::from("api"), dir: String::from("services/api") });
+        assert_eq!(dedup_key(&task), "hookshot.owner.repo.production~api");
+    }
+
+    #[test]
+    fn test_severity() {
+        let root = TempDir::new("hookshot-pagerduty-test").unwrap();
+        let config = repo_config();
+        let production = task(&root, "production");
+        assert_eq!(severity_for(&production, &config), Some(Severity::Critical));
+        assert_eq!(trigger_severity(&production, &config), Some(Severity::Critical));
+        // Refs without a severity aren't reported, not even as `info`
+        let staging = task(&root, "staging");
+        assert_eq!(severity_for(&staging, &config), None);
+        assert_eq!(trigger_severity(&staging, &config), None);
+        assert_eq!(severity_for(&task(&root, "feature"), &config), None);
+
+        // Failures in quarantine are allowed, so they only page as `info`
+        production.quarantine.lock().unwrap().add("owner/repo", Some("production"), "flaky", i64::max_value());
+        assert_eq!(trigger_severity(&production, &config), Some(Severity::Info));
+        staging.quarantine.lock().unwrap().add("owner/repo", None, "flaky", i64::max_value());
+        assert_eq!(trigger_severity(&staging, &config), None);
+    }
+
+    #[test]
+    fn test_previously_failed() {
+        let root = TempDir::new("hookshot-pagerduty-test").unwrap();
+        let task = task(&root, "production");
+        assert!(!previously_failed(&task));
+
+        task.history.lock().unwrap().insert(finished("a", TaskStatus::Failed, 100));
+        assert!(previously_failed(&task));
+
+        // Only the last task that finished counts, and cancelled ones don't
+        task.history.lock().unwrap().insert(finished("b", TaskStatus::Success, 200));
+        assert!(!previously_failed(&task));
+        task.history.lock().unwrap().insert(finished("c", TaskStatus::Failed, 300));
+        task.history.lock().unwrap().insert(finished("d", TaskStatus::Cancelled, 400));
+        assert!(previously_failed(&task));
+    }
+}
@@ -0,0 +1,125 @@
+//! Glob patterns for the files a push has to touch to be deployed.
+//!
+//! `*` matches within a path segment, `**` across segments and `?` a single
+//! character. Patterns starting with `!` exclude files. A file matches when
+//! it matches an include pattern and no exclude pattern; a filter with only
+//! exclude patterns includes everything else.
+
+use regex::Regex;
+
+#[derive(Debug)]
+pub struct PathFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl PathFilter {
+    /// Compile the patterns, returning the first one that isn't valid.
+    pub fn new(patterns: &[String]) -> Result<PathFilter, String> {
+        let mut filter = PathFilter {
+            include: vec![],
+            exclude: vec![],
+        };
+        for pattern in patterns {
+            let (excluded, glob) = match pattern.starts_with('!') {
+                true => (true, &pattern[1..]),
+                false => (false, &pattern[..]),
+            };
+            if glob.is_empty() {
+                return Err(pattern.clone());
+            }
+            let regex = match Regex::new(&glob_to_regex(glob)) {
+                Ok(regex) => regex,
+                Err(_) => return Err(pattern.clone()),
+            };
+            match excluded {
+                true => filter.exclude.push(regex),
+                false => filter.include.push(regex),
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|r| r.is_match(path));
+        included && !self.exclude.iter().any(|r| r.is_match(path))
+    }
+
+    /// Whether any of the files match.
+    pub fn matches_any(&self, paths: &[String]) -> bool {
+        paths.iter().any(|path| self.matches(path))
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` also matches no directories at all
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '.' | '+' | '(' | ')' | '|' | '^' | '$' | '[' | ']' | '{' | '}' | '\\' => {
+                regex.push('\\');
+                regex.push(c);
+            }
+            _ => regex.push(c),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PathFilter;
+
+    fn filter(patterns: &[&str]) -> PathFilter {
+        let patterns = patterns.iter().map(|p| String::from(*p)).collect::<Vec<String>>();
+        PathFilter::new(&patterns).unwrap()
+    }
+
+    #[test]
+    fn test_globs() {
+        let api = filter(&["services/api/**", "*.toml"]);
+        assert!(api.matches("services/api/main.rs"));
+        assert!(api.matches("services/api/src/deep/lib.rs"));
+        assert!(api.matches("Cargo.toml"));
+        assert!(!api.matches("config/Cargo.toml"));
+        assert!(!api.matches("services/web/main.rs"));
+
+        let any_depth = filter(&["**/Makefile", "src/?.rs"]);
+        assert!(any_depth.matches("Makefile"));
+        assert!(any_depth.matches("deploy/Makefile"));
+        assert!(any_depth.matches("src/a.rs"));
+        assert!(!any_depth.matches("src/ab.rs"));
+    }
+
+    #[test]
+    fn test_excludes() {
+        let not_docs = filter(&["!docs/**"]);
+        assert!(not_docs.matches("src/main.rs"));
+        assert!(!not_docs.matches("docs/index.md"));
+
+        let api = filter(&["services/api/**", "!**/*.md"]);
+        assert!(api.matches("services/api/main.rs"));
+        assert!(!api.matches("services/api/README.md"));
+        assert!(api.matches_any(&[String::from("services/api/README.md"),
+                                  String::from("services/api/main.rs")]));
+        assert!(!api.matches_any(&[String::from("docs/index.md")]));
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert_eq!(PathFilter::new(&[String::from("!")]).err(), Some(String::from("!")));
+    }
+}
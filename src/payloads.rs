@@ -0,0 +1,110 @@
+//! An archive of the webhook payloads tasks were created from.
+//!
+//! Every accepted delivery is written to `<log_root>/payloads/<task_id>.json`
+//! with its headers, the body exactly as it came in and what hookshot made of
+//! it, so a deploy of the wrong ref or sha can be traced back to what was
+//! actually sent. `GET /tasks/:uuid/payload` serves it back.
+
+use message::RefType;
+use rustc_serialize::json;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// What hookshot read out of a payload.
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, PartialEq)]
+pub struct ParseResult {
+    /// `simple` or `github`, whichever message format the body parsed as.
+    pub format: String,
+    pub owner: String,
+    pub repo: String,
+    pub refstring: String,
+    pub reftype: RefType,
+    pub sha: String,
+    pub remote_path: String,
+    pub correlation_id: Option<String>,
+    pub changed_files: Option<Vec<String>>,
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, PartialEq)]
+pub struct Payload {
+    pub task_id: String,
+    /// Unix timestamp of when the delivery came in.
+    pub received_at: i64,
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+    pub parsed: ParseResult,
+}
+
+fn path(log_root: &Path, task_id: &str) -> PathBuf {
+    log_root.join("payloads").join(format!("{}.json", task_id))
+}
+
+/// Write a payload to the archive, creating the `payloads` directory if
+/// it doesn't exist yet.
+pub fn save(log_root: &Path, payload: &Payload) -> io::Result<()> {
+    try!(fs::create_dir_all(log_root.join("payloads")));
+    let encoded = match json::encode(payload) {
+        Ok(encoded) => encoded,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+    };
+    let mut file = try!(File::create(path(log_root, &payload.task_id)));
+    file.write_all(encoded.as_bytes())
+}
+
+/// The archived payload of a task, unparsed. `None` if there isn't one,
+/// e.g. for rollbacks and scheduled deploys.
+pub fn read(log_root: &Path, task_id: &str) -> Option<String> {
+    let mut contents = String::new();
+    match File::open(path(log_root, task_id)) {
+        Ok(mut file) => file.read_to_string(&mut contents).ok().map(|_| contents),
+        Err(_) => None,
+    }
+}
+
+/// Drop a task's payload from the archive, if it has one.
+#[allow(unused_must_use)]
+pub fn remove(log_root: &Path, task_id: &str) {
+    fs::remove_file(path(log_root, task_id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::RefType;
+    use rustc_serialize::json;
+    use std::collections::BTreeMap;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_save_and_read() {
+        let dir = TempDir::new("hookshot-payloads-test").unwrap();
+        let mut headers = BTreeMap::new();
+        headers.insert(String::from("X-GitHub-Event"), String::from("push"));
+        let payload = Payload {
+            task_id: String::from("id"),
+            received_at: 100,
+            headers: headers,
+            body: String::from("{\"ref\": \"refs/heads/master\"}"),
+            parsed: ParseResult {
+                format: String::from("github"),
+                owner: String::from("owner"),
+                repo: String::from("repo"),
+                refstring: String::from("master"),
+                reftype: RefType::branch,
+                sha: String::from("abc"),
+                remote_path: String::from("git@github.com:owner/repo.git"),
+                correlation_id: None,
+                changed_files: Some(vec![String::from("README.md")]),
+            },
+        };
+        assert!(read(dir.path(), "id").is_none());
+        save(dir.path(), &payload).unwrap();
+        let archived = read(dir.path(), "id").unwrap();
+        assert_eq!(json::decode::<Payload>(&archived).unwrap(), payload);
+
+        remove(dir.path(), "id");
+        assert!(read(dir.path(), "id").is_none());
+    }
+}
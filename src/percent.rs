@@ -0,0 +1,74 @@
+//! Percent-encoding for owner, repo and ref names in urls.
+//!
+//! Refs can have slashes, spaces and any unicode in them, like
+//! `feature/añadir soporte`. In a path like `/history/:owner/:repo/:ref` a
+//! ref only fits in its segment with everything but the unreserved
+//! characters of RFC 3986 encoded, slashes included, so that is what
+//! `encode` does and what the server decodes.
+
+/// Percent-encode every byte of `s` except ASCII letters, digits and
+/// `-._~`.
+pub fn encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Decode the escapes in `s`. `None` if an escape isn't two hex digits or
+/// what they decode to isn't UTF-8.
+pub fn decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        if i + 2 >= bytes.len() {
+            return None;
+        }
+        match ::std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) => decoded.push(byte),
+            None => return None,
+        }
+        i += 3;
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode};
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode("master"), "master");
+        assert_eq!(encode("v1.0-rc_2"), "v1.0-rc_2");
+        assert_eq!(encode("feature/añadir soporte"), "feature%2Fa%C3%B1adir%20soporte");
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode("feature%2Fa%C3%B1adir%20soporte").unwrap(), "feature/añadir soporte");
+        assert_eq!(decode("feature%2fthing").unwrap(), "feature/thing");
+        assert_eq!(decode("already/decoded").unwrap(), "already/decoded");
+        assert!(decode("bad%2").is_none());
+        assert!(decode("bad%zz").is_none());
+        // Not UTF-8
+        assert!(decode("%FF").is_none());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for s in ["master", "feature/añadir soporte", "release/2016/q1", "100%", "日本語", "a+b&c=d?"].iter() {
+            assert_eq!(decode(&encode(s)).unwrap(), *s);
+        }
+    }
+}
@@ -0,0 +1,712 @@
+//! Running task commands in process groups of their own.
+//!
+//! make and ansible start children of their own, which keep running if only
+//! the process hookshot started is killed. Every command a task runs gets a
+//! new process group instead, so stopping it means signalling the whole
+//! group: `SIGTERM` first, then `SIGKILL` for whatever is still around after
+//! a grace period.
+//!
+//! On `SIGTERM` or `SIGINT` hookshot stops every running group this way
+//! before it exits, so nothing a task started outlives the server. A task
+//! that is cancelled gets its groups stopped the same way, see `cancel`,
+//! and so does a command that runs past the task's `timeout`, see
+//! `with_timeout`.
+//!
+//! That doesn't help when hookshot is killed outright or crashes, so groups
+//! made `with_markers` also leave a marker file for every group while it
+//! runs, `<log_root>/processes/<pgid>.json`. On startup `orphans` finds the
+//! groups whose markers a previous process left behind and are still
+//! running, and `orphan_processes` in the server config decides what happens
+//! to them, see `OrphanAction`: otherwise a long playbook could still be
+//! running against production when the restarted hookshot starts it again.
+//!
+//! Groups made with `ProcessGroups::recording` run nothing at all. They keep
+//! the command lines they are given, with their environment and working
+//! directory, and answer each one with a successful, empty output. They
+//! are for tests, which can check the commands of make and ansible tasks
+//! without either installed. Nothing in the server uses them.
+
+use libc;
+use rustc_serialize::json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How long a group gets to exit after `SIGTERM` before it is killed.
+pub const TERMINATE_GRACE_MS: u32 = 10000;
+/// How often to check whether a group has exited.
+const EXIT_POLL_MS: u32 = 100;
+/// How often the signal watcher checks whether a shutdown signal came in.
+const SHUTDOWN_POLL_MS: u32 = 500;
+/// How often to check whether adopted groups have exited.
+const ADOPTED_POLL_MS: u32 = 1000;
+
+static SHUTDOWN: AtomicBool = ATOMIC_BOOL_INIT;
+
+#[derive(Debug, Default)]
+struct Groups {
+    /// The running groups, with the task each one belongs to.
+    running: BTreeMap<libc::pid_t, Option<String>>,
+    /// Tasks that were cancelled. Their commands are stopped and they can't
+    /// start new ones.
+    cancelled: BTreeSet<String>,
+    /// Groups that were stopped for running past their task's timeout.
+    timed_out: BTreeSet<libc::pid_t>,
+    stopped: bool,
+}
+
+/// A command to run, kept as plain strings so it can be recorded and
+/// compared, which a `Command` can't be.
+#[derive(Debug, Clone, PartialEq, Eq, RustcEncodable)]
+pub struct CommandLine {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Variables set on top of hookshot's own environment.
+    pub env: BTreeMap<String, String>,
+    /// `None` to run in hookshot's working directory.
+    pub cwd: Option<PathBuf>,
+}
+
+impl CommandLine {
+    pub fn new(program: &str) -> CommandLine {
+        CommandLine {
+            program: String::from(program),
+            args: vec![],
+            env: BTreeMap::new(),
+            cwd: None,
+        }
+    }
+
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut CommandLine {
+        self.args.push(arg.as_ref().to_string_lossy().into_owned());
+        self
+    }
+
+    pub fn env<K: AsRef<str>, V: AsRef<str>>(&mut self, key: K, value: V) -> &mut CommandLine {
+        self.env.insert(String::from(key.as_ref()), String::from(value.as_ref()));
+        self
+    }
+
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut CommandLine {
+        self.cwd = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// The `Command` that runs this, for running it outside of any group.
+    pub fn to_command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        for (k, v) in &self.env {
+            command.env(k, v);
+        }
+        if let Some(ref cwd) = self.cwd {
+            command.current_dir(cwd);
+        }
+        command
+    }
+}
+
+/// What to do on startup with groups a previous hookshot process left
+/// running, from `config.orphan_processes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanAction {
+    /// Stop them like a shutdown would, before any task starts. The
+    /// default.
+    Terminate,
+    /// Leave them running and hold off every task until they have exited,
+    /// like maintenance does. A shutdown stops them too.
+    Adopt,
+    /// Leave them alone.
+    Ignore,
+}
+
+impl OrphanAction {
+    pub fn from_str(s: &str) -> Option<OrphanAction> {
+        match s {
+            "terminate" => Some(OrphanAction::Terminate),
+            "adopt" => Some(OrphanAction::Adopt),
+            "ignore" => Some(OrphanAction::Ignore),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            OrphanAction::Terminate => "terminate",
+            OrphanAction::Adopt => "adopt",
+            OrphanAction::Ignore => "ignore",
+        }
+    }
+}
+
+/// The marker file of a running group.
+#[derive(Debug, Clone, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct Marker {
+    pub pgid: libc::pid_t,
+    /// The task the command belongs to, for groups made `for_task`.
+    pub task: Option<String>,
+    pub program: String,
+    /// The hookshot process that started the group, and when it started.
+    pub hookshot_pid: u32,
+    pub hookshot_started: Option<u64>,
+    /// When the group leader started, in clock ticks since boot, so a
+    /// process that got the same id later isn't mistaken for it.
+    pub started: Option<u64>,
+}
+
+/// The process groups of the commands tasks are running. Cloning gives
+/// another handle to the same set.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessGroups {
+    groups: Arc<Mutex<Groups>>,
+    /// What has been run, for groups that only record commands.
+    recorded: Option<Arc<Mutex<Vec<CommandLine>>>>,
+    /// Where marker files go, see `with_markers`.
+    markers: Option<PathBuf>,
+    /// The task commands run through this handle belong to.
+    task: Option<String>,
+    /// When the task runs out of time, and how long it had, see
+    /// `with_timeout`.
+    deadline: Option<(Instant, u64)>,
+}
+
+fn alive(pgid: libc::pid_t) -> bool {
+    unsafe { libc::killpg(pgid, 0) == 0 }
+}
+
+/// When process `pid` started, in clock ticks since boot, from
+/// `/proc/<pid>/stat`. `None` where there's no `/proc`.
+fn start_time(pid: libc::pid_t) -> Option<u64> {
+    let mut stat = String::new();
+    if File::open(format!("/proc/{}/stat", pid)).and_then(|mut file| file.read_to_string(&mut stat)).is_err() {
+        return None;
+    }
+    // The command name is in parentheses and can contain spaces, the start
+    // time is the 22nd field and the 20th after the name
+    stat.rfind(')')
+        .and_then(|end| stat[end + 1..].split_whitespace().nth(19))
+        .and_then(|started| started.parse::<u64>().ok())
+}
+
+/// Whether process `pid` is the one that started at `started`, rather than
+/// one that got its id once that one was gone. Without a start time to
+/// compare with it's taken to be.
+fn started_at(pid: libc::pid_t, started: Option<u64>) -> bool {
+    match started {
+        Some(started) => start_time(pid) == Some(started),
+        None => true,
+    }
+}
+
+/// Where groups `with_markers` for `log_root` leave their markers.
+pub fn marker_dir(log_root: &Path) -> PathBuf {
+    log_root.join("processes")
+}
+
+fn marker_path(dir: &Path, pgid: libc::pid_t) -> PathBuf {
+    dir.join(format!("{}.json", pgid))
+}
+
+impl ProcessGroups {
+    pub fn new() -> ProcessGroups {
+        ProcessGroups::default()
+    }
+
+    /// Groups that record the commands they're given instead of running
+    /// them, see `recorded`.
+    pub fn recording() -> ProcessGroups {
+        ProcessGroups {
+            groups: Arc::new(Mutex::new(Groups::default())),
+            recorded: Some(Arc::new(Mutex::new(vec![]))),
+            markers: None,
+            task: None,
+            deadline: None,
+        }
+    }
+
+    /// Leave a marker file in `dir` for every group while it runs, see the
+    /// module documentation.
+    pub fn with_markers(mut self, dir: PathBuf) -> ProcessGroups {
+        self.markers = Some(dir);
+        self
+    }
+
+    /// Another handle to the same groups, whose markers say the commands
+    /// belong to `task_id`.
+    pub fn for_task(&self, task_id: &str) -> ProcessGroups {
+        let mut groups = self.clone();
+        groups.task = Some(String::from(task_id));
+        groups
+    }
+
+    /// Another handle to the same groups that stops every command still
+    /// running `secs` seconds from now, and refuses to start new ones
+    /// after that. The command then fails with a `TimedOut` error.
+    pub fn with_timeout(&self, secs: u64) -> ProcessGroups {
+        let mut groups = self.clone();
+        groups.deadline = Some((Instant::now() + Duration::from_secs(secs), secs));
+        groups
+    }
+
+    /// When the task this handle is for runs out of time, if it has a
+    /// timeout.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline.map(|(deadline, _)| deadline)
+    }
+
+    fn timed_out(&self) -> io::Error {
+        let secs = self.deadline.map_or(0, |(_, secs)| secs);
+        io::Error::new(io::ErrorKind::TimedOut, format!("the task timed out after {} seconds", secs))
+    }
+
+    /// Why a command can't start now: hookshot is shutting down, or the
+    /// task was cancelled or is out of time.
+    fn refusal(&self, groups: &Groups) -> Option<io::Error> {
+        if groups.stopped {
+            return Some(io::Error::new(io::ErrorKind::Other, "hookshot is shutting down"));
+        }
+        if self.task.as_ref().map_or(false, |task| groups.cancelled.contains(task)) {
+            return Some(io::Error::new(io::ErrorKind::Other, "the task was cancelled"));
+        }
+        if self.deadline.map_or(false, |(deadline, _)| Instant::now() >= deadline) {
+            return Some(self.timed_out());
+        }
+        None
+    }
+
+    /// The commands recorded so far, oldest first. Always empty for groups
+    /// that really run commands.
+    pub fn recorded(&self) -> Vec<CommandLine> {
+        match self.recorded {
+            Some(ref recorded) => recorded.lock().unwrap().clone(),
+            None => vec![],
+        }
+    }
+
+    /// Run `command` to completion in a new process group, capturing its
+    /// output like `Command::output`. Fails without running anything once
+    /// the groups have been stopped, or the task was cancelled or ran out of
+    /// time.
+    pub fn output(&self, command: &CommandLine) -> io::Result<Output> {
+        if let Some(ref recorded) = self.recorded {
+            if let Some(e) = self.refusal(&self.groups.lock().unwrap()) {
+                return Err(e);
+            }
+            recorded.lock().unwrap().push(command.clone());
+            return Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout: vec![],
+                stderr: vec![],
+            });
+        }
+
+        let program = command.program.clone();
+        let mut command = command.to_command();
+        unsafe {
+            command.before_exec(|| {
+                libc::setpgid(0, 0);
+                Ok(())
+            });
+        }
+        command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+
+        let child = {
+            // Spawn while holding the lock so a stop can't miss the group
+            let mut groups = self.groups.lock().unwrap();
+            if let Some(e) = self.refusal(&groups) {
+                return Err(e);
+            }
+            let child = try!(command.spawn());
+            groups.running.insert(child.id() as libc::pid_t, self.task.clone());
+            child
+        };
+        let pgid = child.id() as libc::pid_t;
+        self.write_marker(pgid, &program);
+        let watchdog = self.deadline.map(|(deadline, _)| self.watch(pgid, deadline));
+        let result = child.wait_with_output();
+        self.groups.lock().unwrap().running.remove(&pgid);
+        if let Some(watchdog) = watchdog {
+            let _ = watchdog.join();
+        }
+        self.remove_marker(pgid);
+        match self.groups.lock().unwrap().timed_out.remove(&pgid) {
+            true => Err(self.timed_out()),
+            false => result,
+        }
+    }
+
+    /// Stop group `pgid` if it's still running at `deadline`.
+    fn watch(&self, pgid: libc::pid_t, deadline: Instant) -> JoinHandle<()> {
+        let groups = self.groups.clone();
+        thread::spawn(move || {
+            loop {
+                if !groups.lock().unwrap().running.contains_key(&pgid) {
+                    return;
+                }
+                if Instant::now() >= deadline {
+                    break;
+                }
+                thread::sleep_ms(EXIT_POLL_MS);
+            }
+            groups.lock().unwrap().timed_out.insert(pgid);
+            terminate(&Some(pgid).into_iter().collect(), TERMINATE_GRACE_MS);
+        })
+    }
+
+    /// A marker failing to be written is no reason not to run the command,
+    /// it only means a crash would leave the group unaccounted for.
+    #[allow(unused_must_use)]
+    fn write_marker(&self, pgid: libc::pid_t, program: &str) {
+        let dir = match self.markers {
+            Some(ref dir) => dir,
+            None => return,
+        };
+        let hookshot_pid = unsafe { libc::getpid() };
+        let marker = Marker {
+            pgid: pgid,
+            task: self.task.clone(),
+            program: String::from(program),
+            hookshot_pid: hookshot_pid as u32,
+            hookshot_started: start_time(hookshot_pid),
+            started: start_time(pgid),
+        };
+        let encoded = match json::encode(&marker) {
+            Ok(encoded) => encoded,
+            Err(_) => return,
+        };
+        fs::create_dir_all(dir);
+        File::create(marker_path(dir, pgid)).and_then(|mut file| file.write_all(encoded.as_bytes()));
+    }
+
+    #[allow(unused_must_use)]
+    fn remove_marker(&self, pgid: libc::pid_t) {
+        if let Some(ref dir) = self.markers {
+            fs::remove_file(marker_path(dir, pgid));
+        }
+    }
+
+    /// Whether the groups have been stopped, i.e. hookshot is shutting down.
+    pub fn is_stopped(&self) -> bool {
+        self.groups.lock().unwrap().stopped
+    }
+
+    /// Whether the task this handle is for was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        match self.task {
+            Some(ref task) => self.groups.lock().unwrap().cancelled.contains(task),
+            None => false,
+        }
+    }
+
+    /// Cancel task `task_id`: stop its running commands like `stop` does
+    /// and refuse to start new ones for it. Works for tasks that haven't
+    /// started yet too, they're cancelled as soon as they try to run
+    /// anything.
+    pub fn cancel(&self, task_id: &str, grace_ms: u32) {
+        let running = {
+            let mut groups = self.groups.lock().unwrap();
+            groups.cancelled.insert(String::from(task_id));
+            groups.running
+                  .iter()
+                  .filter(|&(_, task)| task.as_ref().map(|task| &task[..]) == Some(task_id))
+                  .map(|(pgid, _)| *pgid)
+                  .collect()
+        };
+        terminate(&running, grace_ms);
+    }
+
+    /// Forget that the task this handle is for was cancelled, once it's
+    /// done.
+    pub fn finish(&self) {
+        if let Some(ref task) = self.task {
+            self.groups.lock().unwrap().cancelled.remove(task);
+        }
+    }
+
+    /// How many commands are running.
+    pub fn running(&self) -> usize {
+        self.groups.lock().unwrap().running.len()
+    }
+
+    /// Track groups a previous process left running as if they had been
+    /// started here, so `running` counts them and `stop` stops them, until
+    /// they exit. Their markers stay until then.
+    pub fn adopt(&self, orphans: &[Marker]) -> JoinHandle<()> {
+        let mut adopted = orphans.iter().map(|orphan| orphan.pgid).collect::<BTreeSet<libc::pid_t>>();
+        self.groups.lock().unwrap().running.extend(orphans.iter().map(|orphan| (orphan.pgid, orphan.task.clone())));
+        let groups = self.clone();
+        thread::spawn(move || {
+            while !adopted.is_empty() {
+                thread::sleep_ms(ADOPTED_POLL_MS);
+                let exited = adopted.iter().cloned().filter(|pgid| !alive(*pgid)).collect::<Vec<libc::pid_t>>();
+                for pgid in exited {
+                    adopted.remove(&pgid);
+                    groups.groups.lock().unwrap().running.remove(&pgid);
+                    groups.remove_marker(pgid);
+                }
+            }
+        })
+    }
+
+    /// Stop every running group, waiting up to `grace_ms` after `SIGTERM`
+    /// before sending `SIGKILL`, and refuse to run anything new.
+    pub fn stop(&self, grace_ms: u32) {
+        let running = {
+            let mut groups = self.groups.lock().unwrap();
+            groups.stopped = true;
+            groups.running.keys().cloned().collect()
+        };
+        terminate(&running, grace_ms);
+    }
+}
+
+/// The groups whose markers in `dir` were left by a hookshot process that
+/// is gone, and that are still running. Markers of groups that have exited
+/// are removed, markers of another hookshot process that is still running
+/// against the same `log_root` are left alone.
+#[allow(unused_must_use)]
+pub fn orphans(dir: &Path) -> Vec<Marker> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let own_pid = unsafe { libc::getpid() } as u32;
+    let mut orphans = vec![];
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(_) => continue,
+        };
+        let mut contents = String::new();
+        if File::open(&path).and_then(|mut file| file.read_to_string(&mut contents)).is_err() {
+            continue;
+        }
+        let marker = match json::decode::<Marker>(&contents) {
+            Ok(marker) => marker,
+            Err(_) => {
+                fs::remove_file(&path);
+                continue;
+            }
+        };
+        let hookshot_pid = marker.hookshot_pid as libc::pid_t;
+        let hookshot_running = unsafe { libc::kill(hookshot_pid, 0) == 0 } &&
+                               started_at(hookshot_pid, marker.hookshot_started);
+        if marker.hookshot_pid != own_pid && hookshot_running {
+            continue;
+        }
+        match alive(marker.pgid) && started_at(marker.pgid, marker.started) {
+            true => orphans.push(marker),
+            false => {
+                fs::remove_file(&path);
+            }
+        }
+    }
+    orphans.sort_by(|a, b| a.pgid.cmp(&b.pgid));
+    orphans
+}
+
+/// Stop groups `orphans` found and remove their markers.
+#[allow(unused_must_use)]
+pub fn terminate_orphans(dir: &Path, orphans: &[Marker], grace_ms: u32) {
+    terminate(&orphans.iter().map(|orphan| orphan.pgid).collect(), grace_ms);
+    for orphan in orphans {
+        fs::remove_file(marker_path(dir, orphan.pgid));
+    }
+}
+
+/// Send `SIGTERM` to every group, then `SIGKILL` to the ones still alive
+/// after `grace_ms`.
+pub fn terminate(groups: &BTreeSet<libc::pid_t>, grace_ms: u32) {
+    for pgid in groups {
+        unsafe {
+            libc::killpg(*pgid, libc::SIGTERM);
+        }
+    }
+    let mut waited = 0;
+    while waited < grace_ms && groups.iter().any(|pgid| alive(*pgid)) {
+        thread::sleep_ms(EXIT_POLL_MS);
+        waited += EXIT_POLL_MS;
+    }
+    for pgid in groups.iter().filter(|pgid| alive(**pgid)) {
+        unsafe {
+            libc::killpg(*pgid, libc::SIGKILL);
+        }
+    }
+}
+
+extern "C" fn on_shutdown_signal(_: libc::c_int) {
+    // Only async-signal-safe things can happen in here, the watcher thread
+    // does the actual shutdown
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Call `on_shutdown` when the process gets a `SIGTERM` or `SIGINT`.
+pub fn watch_shutdown<F>(on_shutdown: F) -> JoinHandle<()>
+    where F: Fn() + Send + 'static
+{
+    unsafe {
+        let handler = on_shutdown_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+    thread::spawn(move || {
+        loop {
+            thread::sleep_ms(SHUTDOWN_POLL_MS);
+            if SHUTDOWN.swap(false, Ordering::SeqCst) {
+                on_shutdown();
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommandLine, Marker, ProcessGroups, orphans, terminate_orphans};
+    use rustc_serialize::json;
+    use std::fs::{self, File};
+    use std::io::{self, Write};
+    use std::path::Path;
+    use std::process::Command;
+    use std::thread;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_output() {
+        let groups = ProcessGroups::new();
+        let output = groups.output(CommandLine::new("sh").arg("-c").arg("echo $$ && ps -o pgid= -p $$"))
+                           .unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let lines = stdout.lines().map(|line| line.trim()).collect::<Vec<&str>>();
+        // The shell leads its own group
+        assert_eq!(lines[0], lines[1]);
+        assert_eq!(groups.running(), 0);
+    }
+
+    #[test]
+    fn test_stop() {
+        let groups = ProcessGroups::new();
+        let handle = {
+            let groups = groups.clone();
+            // The child ignores SIGTERM, so it takes a SIGKILL
+            thread::spawn(move || groups.output(CommandLine::new("sh").arg("-c").arg("trap '' TERM; sleep 30")))
+        };
+        while groups.running() == 0 {
+            thread::sleep_ms(10);
+        }
+        groups.stop(200);
+        let output = handle.join().unwrap().unwrap();
+        assert!(!output.status.success());
+        assert!(groups.output(&CommandLine::new("true")).is_err());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let groups = ProcessGroups::new();
+        let task = groups.for_task("task-id");
+        let other = groups.for_task("other-task-id");
+        let handle = {
+            let task = task.clone();
+            thread::spawn(move || task.output(CommandLine::new("sh").arg("-c").arg("trap '' TERM; sleep 30")))
+        };
+        while groups.running() == 0 {
+            thread::sleep_ms(10);
+        }
+        groups.cancel("task-id", 200);
+        assert!(!handle.join().unwrap().unwrap().status.success());
+        assert!(task.is_cancelled());
+        assert!(task.output(&CommandLine::new("true")).is_err());
+        // Other tasks carry on
+        assert!(!other.is_cancelled());
+        assert!(other.output(&CommandLine::new("true")).unwrap().status.success());
+
+        task.finish();
+        assert!(!task.is_cancelled());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let groups = ProcessGroups::new().for_task("task-id").with_timeout(1);
+        let error = groups.output(CommandLine::new("sleep").arg("30")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(format!("{}", error), "the task timed out after 1 seconds");
+        assert_eq!(groups.running(), 0);
+        // The task is out of time, nothing else starts
+        assert_eq!(groups.output(&CommandLine::new("true")).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(ProcessGroups::new().with_timeout(60).output(&CommandLine::new("true")).is_ok());
+    }
+
+    #[test]
+    fn test_recording() {
+        let groups = ProcessGroups::recording();
+        let output = groups.output(CommandLine::new("make")
+                                       .arg("deploy")
+                                       .env("GIT_REF", "master")
+                                       .current_dir("/nowhere"))
+                           .unwrap();
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty());
+        assert_eq!(groups.running(), 0);
+
+        let recorded = groups.recorded();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].program, "make");
+        assert_eq!(recorded[0].args, vec!["deploy"]);
+        assert_eq!(recorded[0].env.get("GIT_REF").unwrap(), "master");
+        assert_eq!(recorded[0].cwd.as_ref().unwrap(), Path::new("/nowhere"));
+        assert!(ProcessGroups::new().recorded().is_empty());
+
+        groups.stop(0);
+        assert!(groups.output(&CommandLine::new("make")).is_err());
+    }
+
+    #[test]
+    fn test_orphans() {
+        let dir = TempDir::new("hookshot-process-test").unwrap();
+        let markers = dir.path().join("processes");
+        let groups = ProcessGroups::new().with_markers(markers.clone()).for_task("task-id");
+        let handle = {
+            let groups = groups.clone();
+            thread::spawn(move || groups.output(CommandLine::new("sleep").arg("30")))
+        };
+        // The marker names this process, so the group counts as an orphan
+        // as if hookshot had restarted
+        let mut found = orphans(&markers);
+        while found.is_empty() {
+            thread::sleep_ms(10);
+            found = orphans(&markers);
+        }
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].task, Some(String::from("task-id")));
+        assert_eq!(found[0].program, "sleep");
+
+        terminate_orphans(&markers, &found, 200);
+        assert!(!handle.join().unwrap().unwrap().status.success());
+        assert_eq!(fs::read_dir(&markers).unwrap().count(), 0);
+
+        // Markers of groups that are gone are cleaned up
+        let mut child = Command::new("true").spawn().unwrap();
+        let pgid = child.id() as i32;
+        child.wait().unwrap();
+        let stale = Marker {
+            pgid: pgid,
+            task: None,
+            program: String::from("make"),
+            hookshot_pid: pgid as u32,
+            hookshot_started: None,
+            started: None,
+        };
+        let path = markers.join(format!("{}.json", pgid));
+        File::create(&path).unwrap().write_all(json::encode(&stale).unwrap().as_bytes()).unwrap();
+        assert!(orphans(&markers).is_empty());
+        assert!(!path.exists());
+        assert!(orphans(&dir.path().join("missing")).is_empty());
+    }
+}
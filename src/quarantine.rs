@@ -0,0 +1,185 @@
+//! Quarantine, for deploy targets that are known to be flaky.
+//!
+//! `POST /admin/quarantine` puts a repo, or one ref of it, in quarantine for
+//! a while. Its tasks still run and are recorded as usual, with the reason
+//! for the quarantine in their record, but they are allowed to fail: tasks
+//! that depend on the repo aren't held back by a failure, notifiers get
+//! `"quarantined": true` with it and PagerDuty incidents are opened with
+//! severity `info`, whatever the ref's `pagerduty_severity`. Quarantine
+//! ends by itself once its time is up. What is in quarantine is saved to
+//! `<log_root>/quarantine.json` whenever it changes, and read back on
+//! startup, so a restart doesn't bring the alerts back.
+
+use git;
+use rustc_serialize::json;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use storage::{self, SyncPolicy};
+
+pub type SharedQuarantine = Arc<Mutex<Quarantine>>;
+
+/// Longest a target can be put in quarantine for at once, a week.
+pub const MAX_SECONDS: i64 = 7 * 24 * 60 * 60;
+
+/// Body of `POST /admin/quarantine`. Without a `refstring` the change is for
+/// every ref of the repo. Putting a target in quarantine takes `seconds`
+/// and a `reason`, lifting it doesn't.
+#[derive(Debug, RustcDecodable)]
+pub struct QuarantineChange {
+    pub enabled: bool,
+    pub repo: String,
+    pub refstring: Option<String>,
+    pub seconds: Option<i64>,
+    pub reason: Option<String>,
+}
+
+/// A target in quarantine.
+#[derive(Debug, Clone, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct Entry {
+    /// The repo, as `owner/name` in lowercase.
+    pub repo: String,
+    /// The ref, `None` for every ref of the repo.
+    pub refstring: Option<String>,
+    pub reason: String,
+    /// Unix timestamp of when the quarantine ends.
+    pub until: i64,
+}
+
+impl Entry {
+    fn is_for(&self, repo: &str, refstring: Option<&str>) -> bool {
+        self.repo == repo && self.refstring.as_ref().map(|refstring| &refstring[..]) == refstring
+    }
+}
+
+/// Which targets are in quarantine, in the order they were put in it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct Quarantine {
+    pub entries: Vec<Entry>,
+}
+
+fn path(log_root: &Path) -> PathBuf {
+    log_root.join("quarantine.json")
+}
+
+impl Quarantine {
+    /// What was in quarantine when it was last saved under `log_root`, less
+    /// what has expired since. Nothing if there is no saved quarantine that
+    /// can be read.
+    pub fn load(log_root: &Path, now: i64) -> Quarantine {
+        let mut contents = String::new();
+        let mut quarantine = File::open(path(log_root))
+                                 .and_then(|mut file| file.read_to_string(&mut contents))
+                                 .ok()
+                                 .and_then(|_| json::decode::<Quarantine>(&contents).ok())
+                                 .unwrap_or(Quarantine::default());
+        quarantine.expire(now);
+        quarantine
+    }
+
+    /// Save what is in quarantine under `log_root`. It is written to a
+    /// temporary file first and renamed over the old one, so a crash halfway
+    /// doesn't lose it.
+    pub fn save(&self, log_root: &Path) -> io::Result<()> {
+        let encoded = match json::encode(self) {
+            Ok(encoded) => encoded,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+        };
+        let tmp = log_root.join("quarantine.json.tmp");
+        try!(storage::write_file(&tmp, encoded.as_bytes(), SyncPolicy::Close));
+        storage::retry(|| fs::rename(&tmp, path(log_root)))
+    }
+
+    /// Put a target in quarantine until `until`, replacing the quarantine
+    /// it was already in.
+    pub fn add(&mut self, repo: &str, refstring: Option<&str>, reason: &str, until: i64) {
+        self.remove(repo, refstring);
+        self.entries.push(Entry {
+            repo: git::normalize_name(repo),
+            refstring: refstring.map(String::from),
+            reason: String::from(reason),
+            until: until,
+        });
+    }
+
+    /// Lift the quarantine of a target, returning whether it was in one.
+    /// Lifting it for a repo doesn't lift it for refs that are in
+    /// quarantine by themselves.
+    pub fn remove(&mut self, repo: &str, refstring: Option<&str>) -> bool {
+        let repo = git::normalize_name(repo);
+        let before = self.entries.len();
+        self.entries.retain(|entry| !entry.is_for(&repo, refstring));
+        self.entries.len() != before
+    }
+
+    /// Forget the quarantines whose time is up at `now`.
+    pub fn expire(&mut self, now: i64) {
+        self.entries.retain(|entry| entry.until > now);
+    }
+
+    /// The quarantine a ref is in at `now`, its own if it has one and its
+    /// repo's otherwise.
+    pub fn lookup(&self, owner: &str, name: &str, refstring: &str, now: i64) -> Option<&Entry> {
+        let repo = git::normalize_name(&format!("{}/{}", owner, name));
+        self.find(&repo, Some(refstring), now).or_else(|| self.find(&repo, None, now))
+    }
+
+    fn find(&self, repo: &str, refstring: Option<&str>, now: i64) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.until > now && entry.is_for(repo, refstring))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Quarantine;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_quarantine() {
+        let mut quarantine = Quarantine::default();
+        quarantine.add("BrianLovesWords/Website", None, "staging is flaky", 200);
+        quarantine.add("brianloveswords/hookshot", Some("staging"), "shared database", 100);
+        assert!(quarantine.lookup("brianloveswords", "hookshot", "master", 50).is_none());
+        assert_eq!(quarantine.lookup("brianloveswords", "hookshot", "staging", 50).unwrap().reason,
+                   "shared database");
+        assert_eq!(quarantine.lookup("brianloveswords", "website", "master", 50).unwrap().reason,
+                   "staging is flaky");
+
+        // A ref's own quarantine comes before its repo's, and outlives it
+        quarantine.add("brianloveswords/website", Some("staging"), "load balancer", 300);
+        assert_eq!(quarantine.lookup("brianloveswords", "website", "staging", 50).unwrap().reason,
+                   "load balancer");
+        assert!(quarantine.remove("brianloveswords/website", None));
+        assert!(!quarantine.remove("brianloveswords/website", None));
+        assert!(quarantine.lookup("brianloveswords", "website", "master", 50).is_none());
+        assert!(quarantine.lookup("brianloveswords", "website", "staging", 50).is_some());
+
+        // Putting a target in quarantine again replaces its old one
+        quarantine.add("brianloveswords/hookshot", Some("staging"), "still flaky", 400);
+        assert_eq!(quarantine.entries.len(), 2);
+        assert!(quarantine.lookup("brianloveswords", "hookshot", "staging", 350).is_some());
+
+        assert!(quarantine.lookup("brianloveswords", "website", "staging", 300).is_none());
+        quarantine.expire(300);
+        assert_eq!(quarantine.entries.len(), 1);
+        assert_eq!(quarantine.entries[0].reason, "still flaky");
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let root = TempDir::new("hookshot-quarantine-test").unwrap();
+        assert_eq!(Quarantine::load(root.path(), 0), Quarantine::default());
+
+        let mut quarantine = Quarantine::default();
+        quarantine.add("brianloveswords/website", None, "staging is flaky", 200);
+        quarantine.add("brianloveswords/hookshot", Some("staging"), "shared database", 100);
+        quarantine.save(root.path()).unwrap();
+        assert_eq!(Quarantine::load(root.path(), 50), quarantine);
+
+        // What has expired since it was saved is left out
+        let loaded = Quarantine::load(root.path(), 150);
+        assert_eq!(loaded.entries.len(), 1);
+        assert_eq!(loaded.entries[0].reason, "staging is flaky");
+    }
+}
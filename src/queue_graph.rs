@@ -0,0 +1,98 @@
+//! The scheduling state behind `/admin/queues/graph`.
+//!
+//! When a deploy doesn't start it's usually stuck behind another task in its
+//! queue or behind whichever task holds its `lock`. The graph puts both in
+//! one picture: every queue with the task it's running and the ones waiting
+//! in it, and every lock with its holder and the tasks in line for it. It
+//! encodes to JSON, and `to_dot` renders it for graphviz.
+
+use std::collections::BTreeMap;
+use task_manager::{LockState, QueueKey, QueueState};
+
+#[derive(RustcEncodable, Debug, Clone, PartialEq, Eq)]
+pub struct QueueGraph {
+    pub queues: BTreeMap<String, QueueState>,
+    pub locks: BTreeMap<String, LockState>,
+}
+
+impl QueueGraph {
+    pub fn new(queues: BTreeMap<QueueKey, QueueState>, locks: BTreeMap<String, LockState>) -> QueueGraph {
+        QueueGraph {
+            queues: queues.into_iter().map(|(key, state)| (String::from(key.as_str()), state)).collect(),
+            locks: locks,
+        }
+    }
+
+    /// The graph in graphviz's DOT language. Queues are boxes, locks are
+    /// diamonds and tasks are ellipses, with dashed edges for waiting.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph hookshot {\n    rankdir=LR;\n");
+        for (queue, state) in &self.queues {
+            let node = quote(&format!("queue:{}", queue));
+            dot.push_str(&format!("    {} [shape=box, label={}];\n", node, quote(queue)));
+            if let Some(ref task) = state.running {
+                dot.push_str(&format!("    {} -> {} [label=\"running\"];\n", node, quote(task)));
+            }
+            for (position, task) in state.waiting.iter().enumerate() {
+                dot.push_str(&format!("    {} -> {} [label=\"waiting {}\", style=dashed];\n",
+                                      node,
+                                      quote(task),
+                                      position + 1));
+            }
+        }
+        for (lock, state) in &self.locks {
+            let node = quote(&format!("lock:{}", lock));
+            dot.push_str(&format!("    {} [shape=diamond, label={}];\n", node, quote(lock)));
+            if let Some(ref task) = state.holder {
+                dot.push_str(&format!("    {} -> {} [label=\"held by\"];\n", node, quote(task)));
+            }
+            for task in &state.waiting {
+                dot.push_str(&format!("    {} -> {} [label=\"waiting for\", style=dashed];\n", quote(task), node));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// A DOT id for any string.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace("\\", "\\\\").replace("\"", "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QueueGraph;
+    use std::collections::BTreeMap;
+    use task_manager::{LockState, QueueKey, QueueState};
+
+    #[test]
+    fn test_to_dot() {
+        let mut queues = BTreeMap::new();
+        queues.insert(QueueKey::from(String::from("brianloveswords/hookshot/master")),
+                      QueueState {
+                          running: Some(String::from("a")),
+                          waiting: vec![String::from("b")],
+                      });
+        let mut locks = BTreeMap::new();
+        locks.insert(String::from("production-db"),
+                     LockState {
+                         holder: Some(String::from("a")),
+                         waiting: vec![String::from("c")],
+                     });
+        let graph = QueueGraph::new(queues, locks);
+        assert_eq!(graph.to_dot(),
+                   "digraph hookshot {\n    rankdir=LR;\n    \
+                    \"queue:brianloveswords/hookshot/master\" [shape=box, label=\"brianloveswords/hookshot/master\"];\n    \
+                    \"queue:brianloveswords/hookshot/master\" -> \"a\" [label=\"running\"];\n    \
+                    \"queue:brianloveswords/hookshot/master\" -> \"b\" [label=\"waiting 1\", style=dashed];\n    \
+                    \"lock:production-db\" [shape=diamond, label=\"production-db\"];\n    \
+                    \"lock:production-db\" -> \"a\" [label=\"held by\"];\n    \
+                    \"c\" -> \"lock:production-db\" [label=\"waiting for\", style=dashed];\n}\n");
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(super::quote(r#"say "hi"\"#), r#""say \"hi\"\\""#);
+    }
+}
@@ -0,0 +1,176 @@
+//! Queued tasks saved to disk, so a restart doesn't lose them.
+//!
+//! Unless `persist_queue = false`, every task that is queued is written to
+//! `<log_root>/queue/<task_id>.json` with what it was queued with: the
+//! repo, ref and sha and how it was triggered. The file goes away once a
+//! worker starts the task or the queue drops it. On startup the tasks still
+//! on disk are queued again, oldest first, with everything else about them,
+//! like the environment, clone settings, quotas and the directory of their
+//! project, coming from the config hookshot started with. Failed tasks
+//! waiting to be tried again are saved with when that is, and wait out the
+//! rest of it. The environment is left out so its secrets never end up on
+//! disk.
+
+use deploy_task::DeployTask;
+use git::{CloneProtocol, GitRepo, Transfer};
+use history::Trigger;
+use message::RefType;
+use rustc_serialize::json;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use storage::{self, SyncPolicy};
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, PartialEq)]
+pub struct QueuedTask {
+    pub id: String,
+    pub owner: String,
+    pub repo: String,
+    pub refstring: String,
+    pub reftype: RefType,
+    pub sha: String,
+    pub remote_path: String,
+    pub local_path: String,
+    pub is_rollback: bool,
+    pub trigger: Trigger,
+    pub attempt: u32,
+    pub correlation_id: Option<String>,
+    pub changed_files: Option<Vec<String>>,
+    /// The project of a monorepo the task deploys, by name.
+    pub project: Option<String>,
+    /// When a failed task waiting to be tried again is queued, as a unix
+    /// timestamp. `None` for tasks that are in a queue already.
+    pub retry_at: Option<i64>,
+}
+
+impl QueuedTask {
+    pub fn from_task(task: &DeployTask) -> QueuedTask {
+        QueuedTask {
+            id: task.id.to_string(),
+            owner: task.repo.owner.clone(),
+            repo: task.repo.name.clone(),
+            refstring: task.repo.refstring.clone(),
+            reftype: task.repo.reftype,
+            sha: task.repo.sha.clone(),
+            remote_path: task.repo.remote_path.clone(),
+            local_path: task.repo.local_path.to_string_lossy().into_owned(),
+            is_rollback: task.is_rollback,
+            trigger: task.trigger.clone(),
+            attempt: task.attempt,
+            correlation_id: task.correlation_id.clone(),
+            changed_files: task.changed_files.clone(),
+            project: task.project.as_ref().map(|project| project.name.clone()),
+            retry_at: None,
+        }
+    }
+
+    /// The repo the task deploys, with the clone settings a repo without
+    /// `repo.*` settings gets.
+    pub fn git_repo(&self) -> GitRepo {
+        GitRepo {
+            owner: self.owner.clone(),
+            name: self.repo.clone(),
+            refstring: self.refstring.clone(),
+            reftype: self.reftype,
+            sha: self.sha.clone(),
+            remote_path: self.remote_path.clone(),
+            local_path: PathBuf::from(&self.local_path),
+            clone_protocol: CloneProtocol::Ssh,
+            token: None,
+            submodules: false,
+            transfer: Transfer::default(),
+        }
+    }
+}
+
+fn dir(log_root: &Path) -> PathBuf {
+    log_root.join("queue")
+}
+
+fn path(log_root: &Path, task_id: &str) -> PathBuf {
+    dir(log_root).join(format!("{}.json", task_id))
+}
+
+/// Write a queued task, creating the `queue` directory if it doesn't exist
+/// yet. Errors from network filesystems are retried, see `storage`.
+pub fn save(log_root: &Path, task: &QueuedTask) -> io::Result<()> {
+    try!(fs::create_dir_all(dir(log_root)));
+    let encoded = match json::encode(task) {
+        Ok(encoded) => encoded,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+    };
+    storage::write_file(&path(log_root, &task.id), encoded.as_bytes(), SyncPolicy::Never)
+}
+
+/// Forget a task once it has started or was dropped.
+#[allow(unused_must_use)]
+pub fn remove(log_root: &Path, task_id: &str) {
+    fs::remove_file(path(log_root, task_id));
+}
+
+/// Every task left on disk. Files that can't be read or decoded are
+/// skipped.
+pub fn load(log_root: &Path) -> Vec<QueuedTask> {
+    let entries = match fs::read_dir(dir(log_root)) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let mut tasks = vec![];
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(_) => continue,
+        };
+        let mut contents = String::new();
+        match File::open(&path) {
+            Ok(mut file) => {
+                if file.read_to_string(&mut contents).is_err() {
+                    continue;
+                }
+            }
+            Err(_) => continue,
+        }
+        if let Ok(task) = json::decode::<QueuedTask>(&contents) {
+            tasks.push(task);
+        }
+    }
+    tasks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use history::Trigger;
+    use message::RefType;
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = TempDir::new("hookshot-queue-test").unwrap();
+        let task = QueuedTask {
+            id: String::from("id"),
+            owner: String::from("owner"),
+            repo: String::from("repo"),
+            refstring: String::from("master"),
+            reftype: RefType::branch,
+            sha: String::from("abc"),
+            remote_path: String::from("git@github.com:owner/repo.git"),
+            local_path: String::from("/tmp/owner.repo.master"),
+            is_rollback: false,
+            trigger: Trigger::ReplayOf(String::from("other")),
+            attempt: 1,
+            correlation_id: Some(String::from("build-1")),
+            changed_files: None,
+            project: Some(String::from("api")),
+            retry_at: Some(1500000000),
+        };
+        assert!(load(dir.path()).is_empty());
+        save(dir.path(), &task).unwrap();
+        assert_eq!(load(dir.path()), vec![task.clone()]);
+        assert_eq!(task.git_repo().local_path, PathBuf::from("/tmp/owner.repo.master"));
+
+        remove(dir.path(), "id");
+        assert!(load(dir.path()).is_empty());
+    }
+}
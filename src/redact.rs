@@ -0,0 +1,124 @@
+//! Masking secrets in task logs.
+//!
+//! Task logs list the system and hookshot environments, and commands often
+//! echo them back. Variables whose names match one of `DEFAULT_PATTERNS` or
+//! a pattern in the server's `redact` setting are treated as secrets: their
+//! values are replaced with `<redacted>` everywhere in the log, command
+//! output included. Patterns can use `*` as a wildcard and are matched
+//! ignoring case.
+
+/// Names that are always treated as secrets.
+pub const DEFAULT_PATTERNS: &'static [&'static str] = &["*_TOKEN", "*_SECRET", "*PASSWORD"];
+
+/// Values shorter than this are left alone, masking them would garble the
+/// log without hiding anything worth hiding.
+pub const MIN_SECRET_LEN: usize = 4;
+
+const MASK: &'static str = "<redacted>";
+
+/// Whether `name` matches `pattern`, ignoring case.
+fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_uppercase();
+    let name = name.to_uppercase();
+    let parts = pattern.split('*').collect::<Vec<&str>>();
+    if parts.len() == 1 {
+        return pattern == name;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !name.starts_with(first) || name.len() < first.len() + last.len() {
+        return false;
+    }
+    let mut rest = &name[first.len()..];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    patterns: Vec<String>,
+    /// Secret values seen so far, longest first so a secret that contains
+    /// another is masked whole.
+    values: Vec<String>,
+}
+
+impl Redactor {
+    /// A redactor for the default patterns and `extra`.
+    pub fn new(extra: &[String]) -> Redactor {
+        let mut patterns = DEFAULT_PATTERNS.iter().map(|p| String::from(*p)).collect::<Vec<String>>();
+        patterns.extend(extra.iter().cloned());
+        Redactor {
+            patterns: patterns,
+            values: vec![],
+        }
+    }
+
+    pub fn is_secret(&self, name: &str) -> bool {
+        self.patterns.iter().any(|pattern| matches(pattern, name))
+    }
+
+    /// Remember the values of the secrets among `vars`, to mask them from
+    /// then on.
+    pub fn learn<I, K, V>(&mut self, vars: I)
+        where I: IntoIterator<Item = (K, V)>,
+              K: AsRef<str>,
+              V: AsRef<str>
+    {
+        for (name, value) in vars {
+            let value = value.as_ref();
+            if value.len() >= MIN_SECRET_LEN && self.is_secret(name.as_ref()) &&
+               !self.values.iter().any(|known| known == value) {
+                self.values.push(String::from(value));
+            }
+        }
+        self.values.sort_by(|a, b| b.len().cmp(&a.len()));
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = String::from(text);
+        for value in &self.values {
+            if text.contains(&value[..]) {
+                text = text.replace(&value[..], MASK);
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{matches, Redactor};
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("*_TOKEN", "GITHUB_TOKEN"));
+        assert!(matches("*_TOKEN", "github_token"));
+        assert!(!matches("*_TOKEN", "TOKEN"));
+        assert!(matches("*PASSWORD", "PASSWORD"));
+        assert!(matches("*PASSWORD", "DB_PASSWORD"));
+        assert!(!matches("*PASSWORD", "PASSWORD_FILE"));
+        assert!(matches("AWS_*_KEY", "AWS_SECRET_ACCESS_KEY"));
+        assert!(matches("DSN", "dsn"));
+        assert!(!matches("DSN", "SENTRY_DSN"));
+    }
+
+    #[test]
+    fn test_redact() {
+        let mut redactor = Redactor::new(&[String::from("sentry_dsn")]);
+        redactor.learn(vec![("GITHUB_TOKEN", "t0k3n"),
+                            ("DB_PASSWORD", "hunter2-longer"),
+                            ("SENTRY_DSN", "https://key@sentry.io/1"),
+                            ("API_SECRET", "abc"),
+                            ("HOME", "/home/deploy")]);
+        assert_eq!(redactor.redact("GITHUB_TOKEN: t0k3n\nHOME: /home/deploy\n"),
+                   "GITHUB_TOKEN: <redacted>\nHOME: /home/deploy\n");
+        assert_eq!(redactor.redact("connecting with hunter2-longer to https://key@sentry.io/1"),
+                   "connecting with <redacted> to <redacted>");
+        // Too short to be worth masking
+        assert_eq!(redactor.redact("API_SECRET: abc"), "API_SECRET: abc");
+    }
+}
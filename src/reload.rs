@@ -0,0 +1,142 @@
+//! Reloading the server config while the server is running.
+//!
+//! A `SIGHUP` or a signed `POST /admin/reload` re-reads the config file and
+//! swaps it in. Request handlers take a copy of the config when a request
+//! comes in, so a reload never changes the config in the middle of a request.
+//!
+//! Secrets, environments, repo settings, queue limits and logging take effect
+//! right away. Settings the server only looks at on startup, like `listen`
+//! and `[endpoints]`, keep their old values until it is restarted, and the
+//! reload reports them.
+
+use libc;
+use logging;
+use server_config::{self, ServerConfig};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use task_manager::{Runnable, TaskManager};
+
+pub type SharedConfig = Arc<RwLock<ServerConfig>>;
+
+/// How often the signal watcher checks whether a `SIGHUP` came in.
+const HANGUP_POLL_MS: u32 = 500;
+
+static HANGUP: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Keep the running value of every setting that needs a restart, returning
+/// the names of the ones that changed.
+fn keep_restart_only(old: &ServerConfig, new: &mut ServerConfig) -> Vec<&'static str> {
+    let mut changed = vec![];
+    macro_rules! keep {
+        ($field:ident) => (
+            if new.$field != old.$field {
+                changed.push(stringify!($field));
+                new.$field = old.$field.clone();
+            }
+        )
+    }
+    keep!(port);
+    keep!(listen);
+    keep!(janitor_interval);
+    keep!(history_retention);
+    keep!(checkout_retention);
+    keep!(checkout_quota);
+    keep!(endpoints);
+    keep!(log_sync);
+    keep!(orphan_processes);
+    if new.checkout_root.path() != old.checkout_root.path() {
+        changed.push("checkout_root");
+        new.checkout_root = old.checkout_root.clone();
+    }
+    if new.log_root.path() != old.log_root.path() {
+        changed.push("log_root");
+        new.log_root = old.log_root.clone();
+    }
+    changed
+}
+
+/// Re-read the config file and swap it in. Returns the settings that changed
+/// but won't take effect until a restart. If the file can't be loaded the
+/// running config is left alone.
+pub fn reload<T>(path: &Path,
+                 config: &SharedConfig,
+                 manager: &Arc<Mutex<TaskManager<T>>>)
+                 -> Result<Vec<&'static str>, server_config::Error>
+    where T: 'static + Runnable + Send
+{
+    let mut new = try!(ServerConfig::from_file(path));
+    let mut config = config.write().unwrap();
+    let restart_required = keep_restart_only(&config, &mut new);
+
+    logging::init(new.log_format, new.log_level);
+    manager.lock().unwrap().set_limit(new.queue_limit);
+    *config = new;
+    Ok(restart_required)
+}
+
+extern "C" fn on_hangup(_: libc::c_int) {
+    // Only async-signal-safe things can happen in here, the watcher thread
+    // does the actual reload
+    HANGUP.store(true, Ordering::SeqCst);
+}
+
+/// Call `on_reload` whenever the process gets a `SIGHUP`.
+pub fn watch_hangup<F>(on_reload: F) -> JoinHandle<()>
+    where F: Fn() + Send + 'static
+{
+    unsafe {
+        libc::signal(libc::SIGHUP,
+                     on_hangup as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+    thread::spawn(move || {
+        loop {
+            thread::sleep_ms(HANGUP_POLL_MS);
+            if HANGUP.swap(false, Ordering::SeqCst) {
+                on_reload();
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::keep_restart_only;
+    use server_config::ServerConfig;
+
+    fn config(extra: &str) -> ServerConfig {
+        let toml = format!(r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            {}
+        "#,
+                           extra);
+        ServerConfig::from(&toml).unwrap()
+    }
+
+    #[test]
+    fn test_keep_restart_only() {
+        let old = config("port = 1469\nqueue_limit = 1");
+        let mut new = config("port = 8080\nqueue_limit = 5\ncheckout_quota = 100");
+        new.secret = String::from("new secret");
+
+        let changed = keep_restart_only(&old, &mut new);
+        assert_eq!(changed, vec!["port", "checkout_quota"]);
+        assert_eq!(new.port, 1469);
+        assert_eq!(new.checkout_quota, None);
+        // Everything else takes the new value
+        assert_eq!(new.queue_limit, Some(5));
+        assert_eq!(new.secret, "new secret");
+    }
+
+    #[test]
+    fn test_nothing_to_restart() {
+        let old = config("");
+        let mut new = config("queue_limit = 5");
+        assert!(keep_restart_only(&old, &mut new).is_empty());
+    }
+}
@@ -0,0 +1,86 @@
+//! Turning away signed requests that were captured and sent again.
+//!
+//! With `replay_window` set, requests signed with `X-Signature` have to carry
+//! an `X-Hookshot-Timestamp` that is part of what is signed and no further
+//! than `replay_window` seconds from the server's clock. Every signed request
+//! is also remembered by its delivery id for that long, so the same request
+//! can't be sent twice inside the window. GitHub deliveries are identified by
+//! their `X-GitHub-Delivery` header, everything else by its signature.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+pub type SharedReplayGuard = Arc<Mutex<ReplayGuard>>;
+
+/// Most delivery ids remembered at once. Past this the oldest are forgotten
+/// even if they are still inside the window.
+pub const SEEN_LIMIT: usize = 1000;
+
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    /// Delivery ids and when they were seen, oldest first.
+    seen: VecDeque<(String, i64)>,
+}
+
+/// Whether a request signed at `timestamp` is recent enough at `now`.
+/// Clocks drift both ways, so timestamps from the future count too.
+pub fn is_fresh(timestamp: i64, now: i64, window: u64) -> bool {
+    (now - timestamp).abs() <= window as i64
+}
+
+/// What signed requests with `X-Hookshot-Timestamp` sign: the timestamp
+/// and the body.
+pub fn signed_data(timestamp: &str, body: &str) -> String {
+    format!("{}.{}", timestamp, body)
+}
+
+impl ReplayGuard {
+    pub fn new() -> ReplayGuard {
+        ReplayGuard::default()
+    }
+
+    /// Remember a delivery id, returning false if it was already seen in the
+    /// last `window` seconds.
+    pub fn check(&mut self, id: &str, now: i64, window: u64) -> bool {
+        while self.seen.front().map_or(false, |&(_, seen_at)| now - seen_at > window as i64) {
+            self.seen.pop_front();
+        }
+        if self.seen.iter().any(|&(ref seen, _)| seen == id) {
+            return false;
+        }
+        if self.seen.len() >= SEEN_LIMIT {
+            self.seen.pop_front();
+        }
+        self.seen.push_back((String::from(id), now));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_fresh() {
+        assert!(is_fresh(1000, 1100, 300));
+        assert!(is_fresh(1100, 1000, 300));
+        assert!(!is_fresh(1000, 1301, 300));
+    }
+
+    #[test]
+    fn test_check() {
+        let mut guard = ReplayGuard::new();
+        assert!(guard.check("a", 1000, 300));
+        assert!(guard.check("b", 1001, 300));
+        assert!(!guard.check("a", 1200, 300));
+        // Forgotten once outside the window
+        assert!(guard.check("a", 1301, 300));
+
+        let mut guard = ReplayGuard::new();
+        for i in 0..SEEN_LIMIT + 1 {
+            assert!(guard.check(&i.to_string(), 1000, 300));
+        }
+        assert!(guard.check("0", 1000, 300));
+        assert!(!guard.check("2", 1000, 300));
+    }
+}
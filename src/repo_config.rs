@@ -1,41 +1,307 @@
 use ansible_task::AnsibleTask;
+use environment;
+use hook::Hook;
 use message::RefType;
-use make_task::MakeTask;
+use path_filter::PathFilter;
+use make_task::{Location, MakeTask};
 use std::collections::BTreeMap;
 use std::error::Error as StdError;
 use std::fmt;
 use std::cmp::{Ordering, Ord, PartialOrd};
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use std::string::ToString;
-use regex::Regex;
+use regex::{self, Regex};
+use rustc_serialize::{Encodable, Encoder};
+use semver::VersionReq;
+use server_config::Environment;
+use template;
 use toml::{self, Table};
 use verified_path::VerifiedPath;
+use versions;
 
-#[derive(Debug, Clone, PartialEq, Eq, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DeployMethod {
     Ansible,
     Makefile,
+    /// A method the server has a `TaskFactory` registered for, see
+    /// `task_factory`.
+    Custom(String),
 }
 impl ToString for DeployMethod {
     fn to_string(&self) -> String {
         match *self {
             DeployMethod::Ansible => String::from("ansible"),
             DeployMethod::Makefile => String::from("makefile"),
+            DeployMethod::Custom(ref name) => name.clone(),
+        }
+    }
+}
+impl Encodable for DeployMethod {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(&self.to_string())
+    }
+}
+
+/// PagerDuty event severities. Refs with a severity configured will trigger
+/// a PagerDuty event when a deploy fails.
+#[derive(Debug, Clone, PartialEq, Eq, Copy)]
+pub enum Severity {
+    Critical,
+    Error,
+    Warning,
+    Info,
+}
+impl Severity {
+    pub fn from_str(s: &str) -> Option<Severity> {
+        match s {
+            "critical" => Some(Severity::Critical),
+            "error" => Some(Severity::Error),
+            "warning" => Some(Severity::Warning),
+            "info" => Some(Severity::Info),
+            _ => None,
+        }
+    }
+}
+impl ToString for Severity {
+    fn to_string(&self) -> String {
+        match *self {
+            Severity::Critical => String::from("critical"),
+            Severity::Error => String::from("error"),
+            Severity::Warning => String::from("warning"),
+            Severity::Info => String::from("info"),
+        }
+    }
+}
+impl Encodable for Severity {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(&self.to_string())
+    }
+}
+
+/// How a `payload_template` is encoded, and so how the values filled into it
+/// are escaped.
+#[derive(Debug, Clone, PartialEq, Eq, Copy)]
+pub enum PayloadFormat {
+    Json,
+    Form,
+}
+impl PayloadFormat {
+    pub fn from_str(s: &str) -> Option<PayloadFormat> {
+        match s {
+            "json" => Some(PayloadFormat::Json),
+            "form" => Some(PayloadFormat::Form),
+            _ => None,
         }
     }
 }
+impl ToString for PayloadFormat {
+    fn to_string(&self) -> String {
+        match *self {
+            PayloadFormat::Json => String::from("json"),
+            PayloadFormat::Form => String::from("form"),
+        }
+    }
+}
+impl Encodable for PayloadFormat {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(&self.to_string())
+    }
+}
+
+/// Whether a template renders to a valid body for `format`, whatever the
+/// placeholders are filled with. Only the ones in
+/// `PAYLOAD_SCALAR_PLACEHOLDERS` can go outside of quotes in JSON.
+fn valid_payload_template(template: &str, format: PayloadFormat) -> bool {
+    match format {
+        PayloadFormat::Form => true,
+        PayloadFormat::Json => template::renders_json(template, PAYLOAD_PLACEHOLDERS, PAYLOAD_SCALAR_PLACEHOLDERS),
+    }
+}
+
+/// How many lines from the end of the task log go in the notifications
+/// about a failed task, unless configured.
+pub const DEFAULT_NOTIFY_LOG_LINES: usize = 20;
+
+/// Seconds a failed task waits to be queued again, unless configured.
+pub const DEFAULT_RETRY_DELAY: u64 = 30;
+
+/// The longest a failed task can be configured to wait, a day.
+pub const MAX_RETRY_DELAY: u64 = 24 * 60 * 60;
+
+/// The longest `timeout` a task can have, a week.
+pub const MAX_TIMEOUT: u64 = 7 * 24 * 60 * 60;
+
+/// What can be filled into notifier urls and payload templates.
+pub const PAYLOAD_PLACEHOLDERS: &'static [&'static str] = &["owner",
+                                                            "repo",
+                                                            "refstring",
+                                                            "reftype",
+                                                            "sha",
+                                                            "status",
+                                                            "failed",
+                                                            "quarantined",
+                                                            "task_id",
+                                                            "task_url",
+                                                            "trigger",
+                                                            "attempt",
+                                                            "is_rollback",
+                                                            "idempotency_key",
+                                                            "correlation_id",
+                                                            "log_tail"];
+
+/// The placeholders that are numbers or booleans, so they can go outside of
+/// quotes in JSON templates.
+pub const PAYLOAD_SCALAR_PLACEHOLDERS: &'static [&'static str] = &["failed", "quarantined", "attempt", "is_rollback"];
+
+/// Patterns starting with this are regular expressions instead of globs.
+/// Ref names can't contain `:`, so no branch or tag is ever named like one.
+pub const REGEX_PREFIX: &'static str = "re:";
+
+fn is_regex(pattern: &str) -> bool {
+    pattern.starts_with(REGEX_PREFIX)
+}
+
+/// A section's pattern, compiled when the config is loaded. In globs `*`
+/// matches anything and everything else only itself. `re:` patterns match
+/// anywhere in the ref unless they are anchored with `^` and `$`.
+#[derive(Debug, Clone)]
+pub struct Matcher(Regex);
+
+impl Matcher {
+    /// The matcher for `pattern`, or what is wrong with its regex.
+    pub fn new(pattern: &str) -> Result<Matcher, String> {
+        let regex = match is_regex(pattern) {
+            true => Regex::new(&pattern[REGEX_PREFIX.len()..]),
+            false => {
+                let parts = pattern.split('*').map(regex::quote).collect::<Vec<String>>();
+                Regex::new(&format!("^{}$", parts.join(".*?")))
+            }
+        };
+        regex.map(Matcher).map_err(|e| e.to_string())
+    }
+
+    pub fn is_match(&self, name: &str) -> bool {
+        self.0.is_match(name)
+    }
+}
+
+impl PartialEq for Matcher {
+    fn eq(&self, other: &Matcher) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Matcher {}
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Config<'a> {
     pub pattern: String,
+    /// `pattern` compiled, `None` if it was given to `make` or `ansible`
+    /// with a regex that doesn't compile.
+    matcher: Option<Matcher>,
+    /// For tag sections, the versions the section is for. When it's set
+    /// the section matches tags by version instead of by `pattern`, see
+    /// `semver`.
+    pub semver: Option<VersionReq>,
     pub method: DeployMethod,
     pub notifiers: Option<Vec<URL>>,
+    /// The body to send the notifiers instead of the usual message, see
+    /// `template`.
+    pub payload_template: Option<String>,
+    pub payload_format: PayloadFormat,
+    /// Lines from the end of the task log to send the notifiers when the
+    /// task fails, 0 for none.
+    pub notify_log_lines: usize,
+    /// How many times a task that exits with a code that isn't allowed is
+    /// queued again, `retry_delay` seconds after it failed.
+    pub retries: usize,
+    pub retry_delay: u64,
+    /// Seconds the task's commands have, counting from when the config is
+    /// read. Commands still running then are stopped and the task fails.
+    /// `None` for no limit.
+    pub timeout: Option<u64>,
+    pub pagerduty_severity: Option<Severity>,
+    pub submodules: bool,
+    pub clean_checkout: bool,
+    /// Named environment from the server config to run with.
+    pub environment: Option<String>,
+    /// Tasks with the same lock never run at the same time, whatever queue
+    /// they're in. See `task_manager::Locks`.
+    pub lock: Option<String>,
+    /// Shell commands to run before the task, after it succeeds and after
+    /// anything fails. See `hook`.
+    pub before_task: Option<Hook>,
+    pub after_task: Option<Hook>,
+    pub on_failure: Option<Hook>,
+    /// Exit codes of the task that count as success, `[0]` unless
+    /// configured.
+    pub allowed_exit_codes: Vec<i32>,
+    /// Glob patterns, see `path_filter`. When set the ref is only deployed
+    /// if the push changed a matching file.
+    pub paths: Option<Vec<String>>,
+    /// The `settings` table, for custom methods to read what they run from.
+    /// Keys in the branch's table win over the same keys in the default's.
+    pub settings: BTreeMap<String, String>,
+    /// Subdirectory of the project the make or ansible task runs from, its
+    /// Makefile, playbook and inventory relative to it.
+    pub workdir: Option<PathBuf>,
     make_task: Option<MakeTask<'a>>,
     ansible_task: Option<AnsibleTask<'a>>,
 }
 impl<'a> Config<'a> {
+    /// A config for refs matching `pattern`, a glob or a `re:` regex, that
+    /// runs a make task, with everything else as it is when the repo config
+    /// leaves it out.
+    pub fn make(pattern: &str, task: MakeTask<'a>) -> Config<'a> {
+        let mut config = Config::empty(pattern, DeployMethod::Makefile);
+        config.make_task = Some(task);
+        config
+    }
+
+    /// A config for refs matching `pattern` that runs a playbook.
+    pub fn ansible(pattern: &str, task: AnsibleTask<'a>) -> Config<'a> {
+        let mut config = Config::empty(pattern, DeployMethod::Ansible);
+        config.ansible_task = Some(task);
+        config
+    }
+
+    fn empty(pattern: &str, method: DeployMethod) -> Config<'a> {
+        Config {
+            pattern: String::from(pattern),
+            matcher: Matcher::new(pattern).ok(),
+            semver: None,
+            method: method,
+            notifiers: None,
+            payload_template: None,
+            payload_format: PayloadFormat::Json,
+            notify_log_lines: DEFAULT_NOTIFY_LOG_LINES,
+            retries: 0,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            timeout: None,
+            pagerduty_severity: None,
+            submodules: false,
+            clean_checkout: false,
+            environment: None,
+            lock: None,
+            before_task: None,
+            after_task: None,
+            on_failure: None,
+            allowed_exit_codes: vec![0],
+            paths: None,
+            settings: BTreeMap::new(),
+            workdir: None,
+            make_task: None,
+            ansible_task: None,
+        }
+    }
+
+    /// Whether the pattern matches `name`.
+    pub fn matches(&self, name: &str) -> bool {
+        self.matcher.as_ref().map_or(false, |matcher| matcher.is_match(name))
+    }
+
     pub fn make_task(&self) -> Option<&MakeTask<'a>> {
         match self.make_task {
             Some(ref t) => Some(t),
@@ -48,10 +314,116 @@ impl<'a> Config<'a> {
             None => None,
         }
     }
+
+    /// What a task for a ref matching this config runs with.
+    pub fn resolved(&self) -> ResolvedConfig {
+        ResolvedConfig {
+            pattern: self.pattern.clone(),
+            method: self.method.to_string(),
+            task: self.make_task.as_ref().map(|task| String::from(task.task())),
+            playbook: self.ansible_task.as_ref().map(|task| task.playbook.clone()),
+            inventory: self.ansible_task.as_ref().map(|task| task.inventory.clone()),
+            environment: self.environment.clone(),
+            notifiers: self.notifiers.clone(),
+            lock: self.lock.clone(),
+        }
+    }
+}
+
+/// What a task ran with, out of the config for its ref, kept in its task
+/// record so it can be looked up without the repo.
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, PartialEq)]
+pub struct ResolvedConfig {
+    /// The branch or tag pattern that matched the ref.
+    pub pattern: String,
+    /// `makefile`, `ansible` or the name of a custom method.
+    pub method: String,
+    /// The make task, for make deploys.
+    pub task: Option<String>,
+    pub playbook: Option<String>,
+    pub inventory: Option<String>,
+    pub environment: Option<String>,
+    pub notifiers: Option<Vec<URL>>,
+    pub lock: Option<String>,
+}
+
+// Encoded with the same keys as a `[branch.<pattern>]` section, so encoding
+// with `toml` gives back a section that parses to the same config. The
+// pattern is the section's key, it isn't repeated.
+impl<'a> Encodable for Config<'a> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let (task, makefile, make_dir) = match self.make_task {
+            None => (None, None, None),
+            Some(ref make_task) => match *make_task.location() {
+                Location::Root => (Some(make_task.task()), None, None),
+                Location::File(ref file) => (Some(make_task.task()), file.to_str(), None),
+                Location::Directory(ref dir) => (Some(make_task.task()), None, dir.to_str()),
+            },
+        };
+        let ansible_task = self.ansible_task.as_ref();
+        let playbook = ansible_task.map(|task| &task.playbook[..]);
+        let inventory = ansible_task.map(|task| &task.inventory[..]);
+        let check = ansible_task.map_or(false, |task| task.check);
+        let vault_password = ansible_task.and_then(|task| task.vault_password.as_ref());
+        let vault_password_file = ansible_task.and_then(|task| task.vault_password_file.as_ref());
+        let make_args = self.make_task.as_ref().map_or(&[][..], |task| task.args());
+        let make_args = match make_args.is_empty() {
+            true => None,
+            false => Some(make_args),
+        };
+        let ansible_args = ansible_task.and_then(|task| match task.args.is_empty() {
+            true => None,
+            false => Some(&task.args),
+        });
+
+        let settings = match self.settings.is_empty() {
+            true => None,
+            false => Some(&self.settings),
+        };
+
+        let retry_delay = format!("{}s", self.retry_delay);
+        let timeout = self.timeout.map(|timeout| format!("{}s", timeout));
+        let semver = self.semver.as_ref().map(|semver| semver.to_string());
+        let workdir = self.workdir.as_ref().and_then(|workdir| workdir.to_str());
+        s.emit_struct("Config", 31, |s| {
+            try!(s.emit_struct_field("method", 0, |s| self.method.encode(s)));
+            try!(s.emit_struct_field("task", 1, |s| task.encode(s)));
+            try!(s.emit_struct_field("makefile", 2, |s| makefile.encode(s)));
+            try!(s.emit_struct_field("make_dir", 3, |s| make_dir.encode(s)));
+            try!(s.emit_struct_field("playbook", 4, |s| playbook.encode(s)));
+            try!(s.emit_struct_field("inventory", 5, |s| inventory.encode(s)));
+            try!(s.emit_struct_field("check", 6, |s| check.encode(s)));
+            try!(s.emit_struct_field("vault_password", 7, |s| vault_password.encode(s)));
+            try!(s.emit_struct_field("notifiers", 8, |s| self.notifiers.encode(s)));
+            try!(s.emit_struct_field("pagerduty_severity", 9, |s| self.pagerduty_severity.encode(s)));
+            try!(s.emit_struct_field("submodules", 10, |s| self.submodules.encode(s)));
+            try!(s.emit_struct_field("clean_checkout", 11, |s| self.clean_checkout.encode(s)));
+            try!(s.emit_struct_field("environment", 12, |s| self.environment.encode(s)));
+            try!(s.emit_struct_field("before_task", 13, |s| self.before_task.encode(s)));
+            try!(s.emit_struct_field("after_task", 14, |s| self.after_task.encode(s)));
+            try!(s.emit_struct_field("on_failure", 15, |s| self.on_failure.encode(s)));
+            try!(s.emit_struct_field("paths", 16, |s| self.paths.encode(s)));
+            try!(s.emit_struct_field("allowed_exit_codes", 17, |s| self.allowed_exit_codes.encode(s)));
+            try!(s.emit_struct_field("settings", 18, |s| settings.encode(s)));
+            try!(s.emit_struct_field("payload_template", 19, |s| self.payload_template.encode(s)));
+            try!(s.emit_struct_field("payload_format", 20, |s| self.payload_format.encode(s)));
+            try!(s.emit_struct_field("notify_log_lines", 21, |s| self.notify_log_lines.encode(s)));
+            try!(s.emit_struct_field("lock", 22, |s| self.lock.encode(s)));
+            try!(s.emit_struct_field("retries", 23, |s| self.retries.encode(s)));
+            try!(s.emit_struct_field("retry_delay", 24, |s| retry_delay.encode(s)));
+            try!(s.emit_struct_field("semver", 25, |s| semver.encode(s)));
+            try!(s.emit_struct_field("workdir", 26, |s| workdir.encode(s)));
+            try!(s.emit_struct_field("make_args", 27, |s| make_args.encode(s)));
+            try!(s.emit_struct_field("ansible_args", 28, |s| ansible_args.encode(s)));
+            try!(s.emit_struct_field("vault_password_file", 29, |s| vault_password_file.encode(s)));
+            s.emit_struct_field("timeout", 30, |s| timeout.encode(s))
+        })
+    }
 }
 
 // We want to sort most specific branches first, so the branches with the 1) least
-// amount of wildcards and 2) longest pattern.
+// amount of wildcards and 2) longest pattern. Regexes go between patterns
+// without wildcards and globs with them, the longest first.
 impl<'a> PartialOrd for Config<'a> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         let self_wildcards = self.pattern.matches('*').count();
@@ -66,6 +438,19 @@ impl<'a> PartialOrd for Config<'a> {
         if other.pattern == "*" {
             return Some(Ordering::Less)
         }
+        match (is_regex(&self.pattern), is_regex(&other.pattern)) {
+            (true, true) => {
+                return Some(match other_len.cmp(&self_len) {
+                    Ordering::Equal => self.pattern.cmp(&other.pattern),
+                    ordering => ordering,
+                })
+            }
+            (true, false) if other_wildcards == 0 => return Some(Ordering::Greater),
+            (true, false) => return Some(Ordering::Less),
+            (false, true) if self_wildcards == 0 => return Some(Ordering::Less),
+            (false, true) => return Some(Ordering::Greater),
+            (false, false) => {}
+        }
 
         match self_wildcards.cmp(&other_wildcards) {
             Ordering::Less => Some(Ordering::Less),
@@ -87,62 +472,186 @@ impl<'a> Ord for Config<'a> {
 
 pub type ConfigMap<'a> = BTreeMap<String, Config<'a>>;
 
+/// Where a repo's config is unless the server config says otherwise, see
+/// `ServerConfig::config_paths_for`.
+pub const CONFIG_FILE: &'static str = ".hookshot.conf";
+
+/// The first of `paths`, relative to `project_root`, that is a file.
+pub fn find(project_root: &Path, paths: &[String]) -> Option<PathBuf> {
+    paths.iter().map(|path| project_root.join(path)).find(|path| path.is_file())
+}
+
 // TODO: use https://crates.io/crates/url instead
 pub type URL = String;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Error {
+    /// None of the paths a config is looked for at exist.
+    NotFound(Vec<String>),
     FileLoad,
     FileRead,
     Parse,
+    UndefinedVariable(String),
     InvalidDefaultMethod,
     InvalidDefaultMakeTask,
+    UnknownDefaultMakeTask(String),
+    InvalidDefaultCheckTask,
+    InvalidDefaultMakefile,
+    InvalidDefaultCheck,
+    InvalidDefaultVaultPassword,
+    InvalidDefaultVaultPasswordFile,
+    InvalidDefaultMakeArgs,
+    InvalidDefaultAnsibleArgs,
+    InvalidDefaultHook,
+    InvalidDefaultPaths,
+    InvalidDefaultAllowedExitCodes,
     InvalidDefaultPlaybook,
     InvalidDefaultInventory,
     InvalidDefaultNotifier,
+    InvalidDefaultPagerDutySeverity,
+    InvalidDefaultSubmodules,
+    InvalidDefaultCleanCheckout,
+    InvalidDefaultEnvironment,
+    InvalidDefaultSettings,
+    InvalidDefaultPayloadTemplate,
+    InvalidDefaultPayloadFormat,
+    InvalidDefaultNotifyLogLines,
+    InvalidDefaultLock,
+    InvalidDefaultRetries,
+    InvalidDefaultRetryDelay,
+    InvalidDefaultTimeout,
     MissingConfiguration,
     InvalidConfigGroup,
     InvalidConfigEntry(String),
+    InvalidPattern(String, String),
     InvalidMethod(String),
     InvalidPlaybook(String),
     InvalidInventory(String),
     InvalidNotifier(String),
+    InvalidPagerDutySeverity(String),
+    InvalidSubmodules(String),
+    InvalidCleanCheckout(String),
+    InvalidEnvironment(String),
+    InvalidSettings(String),
+    InvalidPayloadTemplate(String),
+    InvalidPayloadFormat(String),
+    InvalidNotifyLogLines(String),
+    InvalidLock(String),
+    InvalidRetries(String),
+    InvalidRetryDelay(String),
+    InvalidTimeout(String),
+    InvalidSemver(String),
+    SemverOnBranch(String),
     MissingMethod(String),
     InvalidMakeTask(String),
+    UnknownMakeTask(String, String),
+    InvalidCheckTask(String),
+    InvalidMakefile(String),
+    InvalidWorkdir(String),
+    InvalidCheck(String),
+    InvalidVaultPassword(String),
+    InvalidVaultPasswordFile(String),
+    InvalidMakeArgs(String),
+    InvalidAnsibleArgs(String),
+    InvalidHook(String),
+    InvalidPaths(String),
+    InvalidAllowedExitCodes(String),
     MissingTask(String),
     InvalidAnsibleConfig,
     InvalidMakeTaskConfig,
-
+    AnsibleNotSupported,
 }
 impl StdError for Error {
     fn description(&self) -> &str {
         match *self {
+            Error::NotFound(_) => "could not find a hookshot configuration",
             Error::FileLoad => "could not open hookshot configuration",
             Error::FileRead => "could not read file contents",
             Error::Parse => "could not parse file as toml",
-            Error::InvalidDefaultMethod => "invalid type for `default.method`, valid values are 'ansible' and 'makefile'",
+            Error::UndefinedVariable(_) => "the config refers to a variable that isn't in the task environment",
+            Error::InvalidDefaultMethod => "invalid type for `default.method`, valid values are 'ansible', 'makefile' and methods registered with the server",
             Error::InvalidDefaultMakeTask => "`default.task` must be a valid, existing make task",
+            Error::UnknownDefaultMakeTask(_) => "`default.task` is not a task make knows how to build",
+            Error::InvalidDefaultCheckTask => "`default.check_task` must be a boolean",
+            Error::InvalidDefaultHook => "`default.before_task`, `default.after_task` and `default.on_failure` must be strings or tables with a `command` and an optional `allow_failure` boolean",
+            Error::InvalidDefaultPaths => "`default.paths` must be an array of glob patterns",
+            Error::InvalidDefaultAllowedExitCodes => "`default.allowed_exit_codes` must be an array of exit codes",
+            Error::InvalidDefaultCheck => "`default.check` must be a boolean",
+            Error::InvalidDefaultVaultPassword => "`default.vault_password` must be the name of an environment variable",
+            Error::InvalidDefaultVaultPasswordFile => "`default.vault_password_file` must point to an existing file, and can't be set along with `default.vault_password`",
+            Error::InvalidDefaultMakeArgs => "`default.make_args` must be an array of strings",
+            Error::InvalidDefaultAnsibleArgs => "`default.ansible_args` must be an array of strings",
+            Error::InvalidDefaultMakefile => "`default.makefile` must point to an existing file or `default.make_dir` to an existing directory, not both",
             Error::InvalidDefaultPlaybook => "`default.playbook` must point to an existing file",
             Error::InvalidDefaultInventory => "`default.inventory` must point to an existing file",
             Error::InvalidDefaultNotifier => "`default.notifiers` must be an array of urls",
+            Error::InvalidDefaultPagerDutySeverity => "`default.pagerduty_severity` must be one of 'critical', 'error', 'warning' or 'info'",
+            Error::InvalidDefaultSubmodules => "`default.submodules` must be a boolean",
+            Error::InvalidDefaultCleanCheckout => "`default.clean_checkout` must be a boolean",
+            Error::InvalidDefaultEnvironment => "`default.environment` must be a string",
+            Error::InvalidDefaultSettings => "`default.settings` must be a table of strings",
+            Error::InvalidDefaultPayloadTemplate => "`default.payload_template` must be a string that is valid for the `payload_format` once filled in",
+            Error::InvalidDefaultPayloadFormat => "`default.payload_format` must be 'json' or 'form'",
+            Error::InvalidDefaultNotifyLogLines => "`default.notify_log_lines` must be a number of lines, 0 or more",
+            Error::InvalidDefaultLock => "`default.lock` must be a non-empty string",
+            Error::InvalidDefaultRetries => "`default.retries` must be a number of retries, 0 or more",
+            Error::InvalidDefaultRetryDelay => "`default.retry_delay` must be a number of seconds or a duration like \"30s\", \"5m\" or \"1h\", up to a day",
+            Error::InvalidDefaultTimeout => "`default.timeout` must be a positive number of seconds or a duration like \"30s\", \"5m\" or \"1h\", up to a week",
             Error::MissingConfiguration => "must have at least one `branch` or `tag` entry",
             Error::InvalidConfigGroup => "`branch` or `tag` must be a table",
             Error::InvalidConfigEntry(_) => "every `branch.<pattern>` or `tag.<pattern>` entry must be a table",
-            Error::InvalidMethod(_) => "invalid branch `method`, valid values are 'ansible' and 'makefile'",
+            Error::InvalidPattern(..) => "`re:` patterns must be valid regular expressions",
+            Error::InvalidMethod(_) => "invalid branch `method`, valid values are 'ansible', 'makefile' and methods registered with the server",
             Error::InvalidPlaybook(_) => "branch `playbook` must point to an existing file",
             Error::InvalidInventory(_) => "branch `inventory` must point to an existing file",
             Error::InvalidNotifier(_) => "branch `notifiers` must be valid URL",
+            Error::InvalidPagerDutySeverity(_) => "branch `pagerduty_severity` must be one of 'critical', 'error', 'warning' or 'info'",
+            Error::InvalidSubmodules(_) => "branch `submodules` must be a boolean",
+            Error::InvalidCleanCheckout(_) => "branch `clean_checkout` must be a boolean",
+            Error::InvalidEnvironment(_) => "branch `environment` must be a string",
+            Error::InvalidSettings(_) => "branch `settings` must be a table of strings",
+            Error::InvalidPayloadTemplate(_) => "branch `payload_template` must be a string that is valid for the `payload_format` once filled in",
+            Error::InvalidPayloadFormat(_) => "branch `payload_format` must be 'json' or 'form'",
+            Error::InvalidNotifyLogLines(_) => "branch `notify_log_lines` must be a number of lines, 0 or more",
+            Error::InvalidLock(_) => "branch `lock` must be a non-empty string",
+            Error::InvalidRetries(_) => "branch `retries` must be a number of retries, 0 or more",
+            Error::InvalidSemver(_) => "tag `semver` must be a version range like \">=1.2, <2\"",
+            Error::SemverOnBranch(_) => "`semver` only works in tag sections",
+            Error::InvalidRetryDelay(_) => "branch `retry_delay` must be a number of seconds or a duration like \"30s\", \"5m\" or \"1h\", up to a day",
+            Error::InvalidTimeout(_) => "branch `timeout` must be a positive number of seconds or a duration like \"30s\", \"5m\" or \"1h\", up to a week",
             Error::MissingMethod(_) => "could not find `method` between default and branch config",
             Error::InvalidMakeTask(_) => "branch `task` must be valid, existing make task",
+            Error::UnknownMakeTask(..) => "branch `task` is not a task make knows how to build",
+            Error::InvalidCheckTask(_) => "branch `check_task` must be a boolean",
+            Error::InvalidHook(_) => "branch `before_task`, `after_task` and `on_failure` must be strings or tables with a `command` and an optional `allow_failure` boolean",
+            Error::InvalidPaths(_) => "branch `paths` must be an array of glob patterns",
+            Error::InvalidAllowedExitCodes(_) => "branch `allowed_exit_codes` must be an array of exit codes",
+            Error::InvalidCheck(_) => "branch `check` must be a boolean",
+            Error::InvalidVaultPassword(_) => "branch `vault_password` must be the name of an environment variable",
+            Error::InvalidVaultPasswordFile(_) => "branch `vault_password_file` must point to an existing file, and can't be set along with `vault_password`",
+            Error::InvalidMakeArgs(_) => "branch `make_args` must be an array of strings",
+            Error::InvalidAnsibleArgs(_) => "branch `ansible_args` must be an array of strings",
+            Error::InvalidMakefile(_) => "branch `makefile` must point to an existing file or `make_dir` to an existing directory, not both",
+            Error::InvalidWorkdir(_) => "branch `workdir` must point to an existing directory inside the project",
             Error::MissingTask(_) => "could not find make or ansible task between default and branch config",
             Error::InvalidAnsibleConfig => "could not find playbook + inventory between default and branch config",
             Error::InvalidMakeTaskConfig => "could not find valid make task between default and branch config",
+            Error::AnsibleNotSupported => "this hookshot was built without ansible support, `method` must be 'makefile'",
         }
     }
 }
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.description())
+        match *self {
+            // What make said is more useful than the description
+            Error::UnknownDefaultMakeTask(ref make_error) |
+            Error::UnknownMakeTask(_, ref make_error) |
+            Error::InvalidPattern(_, ref make_error) =>
+                write!(f, "{} ({})", self.description(), make_error),
+            Error::UndefinedVariable(ref name) => write!(f, "{} (`${{{}}}`)", self.description(), name),
+            Error::NotFound(ref paths) => write!(f, "{} (looked for {})", self.description(), paths.join(", ")),
+            _ => write!(f, "{}", self.description()),
+        }
     }
 }
 impl Error {
@@ -150,11 +659,37 @@ impl Error {
         match *self {
             Error::MissingMethod(ref s) |
             Error::InvalidConfigEntry(ref s) |
+            Error::InvalidPattern(ref s, _) |
             Error::InvalidMethod(ref s) |
             Error::InvalidPlaybook(ref s) |
             Error::InvalidInventory(ref s) |
             Error::InvalidNotifier(ref s) |
+            Error::InvalidPagerDutySeverity(ref s) |
+            Error::InvalidSubmodules(ref s) |
+            Error::InvalidCleanCheckout(ref s) |
+            Error::InvalidEnvironment(ref s) |
+            Error::InvalidSettings(ref s) |
+            Error::InvalidPayloadTemplate(ref s) |
+            Error::InvalidPayloadFormat(ref s) |
+            Error::InvalidNotifyLogLines(ref s) |
+            Error::InvalidLock(ref s) |
+            Error::InvalidRetries(ref s) |
+            Error::InvalidRetryDelay(ref s) |
+            Error::InvalidSemver(ref s) |
+            Error::SemverOnBranch(ref s) |
             Error::InvalidMakeTask(ref s) |
+            Error::UnknownMakeTask(ref s, _) |
+            Error::InvalidCheckTask(ref s) |
+            Error::InvalidMakefile(ref s) |
+            Error::InvalidWorkdir(ref s) |
+            Error::InvalidCheck(ref s) |
+            Error::InvalidVaultPassword(ref s) |
+            Error::InvalidVaultPasswordFile(ref s) |
+            Error::InvalidMakeArgs(ref s) |
+            Error::InvalidAnsibleArgs(ref s) |
+            Error::InvalidHook(ref s) |
+            Error::InvalidPaths(ref s) |
+            Error::InvalidAllowedExitCodes(ref s) |
             Error::MissingTask(ref s) => Some(s),
             _ => None,
         }
@@ -162,14 +697,158 @@ impl Error {
     }
 }
 
-#[derive(Debug)]
+/// Every error found in a configuration.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Errors(pub Vec<Error>);
+impl StdError for Errors {
+    fn description(&self) -> &str {
+        "invalid hookshot configuration"
+    }
+}
+impl fmt::Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let count = self.0.len();
+        try!(write!(f, "{} error{} in hookshot configuration:", count, if count == 1 { "" } else { "s" }));
+        for error in &self.0 {
+            try!(match error.related_branch() {
+                Some(branch) => write!(f, "\n  {}: {}", branch, error),
+                None => write!(f, "\n  {}", error),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Something `lint` found that loads but probably doesn't do what was meant,
+/// or an error that keeps the config from loading at all.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Warning {
+    Invalid(Error),
+    /// A wildcard pattern that never matches because every ref it matches
+    /// is taken by the second, more specific, pattern first.
+    ShadowedPattern(RefType, String, String),
+    /// A wildcard pattern that matches exactly the refs the second one
+    /// does, with different settings. Only the second one's settings are
+    /// ever used.
+    DuplicatePattern(RefType, String, String),
+    /// A section with `notifiers` but no task of its own or in `default`.
+    /// Notifications only go out for tasks.
+    NotifiersWithoutTask(RefType, String),
+    /// A `default` setting every section overrides.
+    UnusedDefault(String),
+    /// A tag section whose `semver` range overlaps the second one's. Tags in
+    /// both go to the second one, which sorts first by name.
+    OverlappingSemver(String, String),
+    /// A glob pattern with characters that are special in a regex, `.`
+    /// included. Only `*` is special in globs, they used to be regexes with
+    /// `*` meaning `.*`.
+    RegexInGlob(RefType, String),
+}
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Warning::Invalid(ref error) => match error.related_branch() {
+                Some(branch) => write!(f, "{}: {}", branch, error),
+                None => write!(f, "{}", error),
+            },
+            Warning::ShadowedPattern(reftype, ref pattern, ref by) => {
+                write!(f,
+                       "{}.\"{}\" never matches, every {} it matches is matched by \"{}\" first",
+                       reftype.to_string(),
+                       pattern,
+                       reftype.to_string(),
+                       by)
+            }
+            Warning::DuplicatePattern(reftype, ref pattern, ref by) => {
+                write!(f,
+                       "{}.\"{}\" never matches, \"{}\" matches the same refs first with different settings",
+                       reftype.to_string(),
+                       pattern,
+                       by)
+            }
+            Warning::NotifiersWithoutTask(reftype, ref pattern) => {
+                write!(f,
+                       "{}.\"{}\" has `notifiers` but no task, notifications only go out for tasks",
+                       reftype.to_string(),
+                       pattern)
+            }
+            Warning::UnusedDefault(ref key) => {
+                write!(f, "`default.{}` is never used, every section sets its own", key)
+            }
+            Warning::OverlappingSemver(ref pattern, ref by) => {
+                write!(f,
+                       "tag.\"{}\" has a `semver` range that overlaps tag.\"{}\", which gets the tags in both",
+                       pattern,
+                       by)
+            }
+            Warning::RegexInGlob(reftype, ref pattern) => {
+                write!(f,
+                       "{}.\"{}\" only matches refs with these characters in them, `*` is the only wildcard in \
+                        globs and `.` is literal; start the pattern with `re:` for a regex",
+                       reftype.to_string(),
+                       pattern)
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct RepoConfig<'a> {
     branch: Option<ConfigMap<'a>>,
     tag: Option<ConfigMap<'a>>,
     project_root: &'a Path,
 }
 
+// Encoded in the shape of a `.hookshot.conf`, with every setting written
+// out in each section instead of in `[default]`.
+impl<'a> Encodable for RepoConfig<'a> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_struct("RepoConfig", 2, |s| {
+            try!(s.emit_struct_field("branch", 0, |s| self.branch.encode(s)));
+            s.emit_struct_field("tag", 1, |s| self.tag.encode(s))
+        })
+    }
+}
+
 impl<'a> RepoConfig<'a> {
+    /// A config without any branches or tags, to add them to with
+    /// `with_branch` and `with_tag`.
+    pub fn new(project_root: &'a Path) -> RepoConfig<'a> {
+        RepoConfig {
+            branch: None,
+            tag: None,
+            project_root: project_root,
+        }
+    }
+
+    pub fn with_branch(mut self, config: Config<'a>) -> RepoConfig<'a> {
+        insert_config(&mut self.branch, config);
+        self
+    }
+
+    pub fn with_tag(mut self, config: Config<'a>) -> RepoConfig<'a> {
+        insert_config(&mut self.tag, config);
+        self
+    }
+
+    pub fn branches(&self) -> Option<&ConfigMap<'a>> {
+        self.branch.as_ref()
+    }
+
+    pub fn tags(&self) -> Option<&ConfigMap<'a>> {
+        self.tag.as_ref()
+    }
+
+    pub fn project_root(&self) -> &'a Path {
+        self.project_root
+    }
+
+    /// The config as a `.hookshot.conf` that parses back to the same
+    /// config.
+    pub fn to_toml(&self) -> String {
+        toml::encode_str(self)
+    }
+
     pub fn lookup_branch(&self, name: &str) -> Option<&Config<'a>> {
         self.lookup(RefType::branch, name)
     }
@@ -191,68 +870,203 @@ impl<'a> RepoConfig<'a> {
             }
         };
 
-        if let Some(config) = structure.get(name) {
-            return Some(config);
+        // Sections with a version range only match by version
+        match structure.get(name) {
+            Some(config) if config.semver.is_none() => return Some(config),
+            _ => {}
+        }
+        if let Some(version) = versions::tag_version(name) {
+            let by_version = structure.values().find(|config| match config.semver {
+                Some(ref semver) => semver.matches(&version),
+                None => false,
+            });
+            if by_version.is_some() {
+                return by_version;
+            }
         }
 
         let mut catch_all = None;
-        let mut wildcards = vec![];
+        let mut patterns = vec![];
         for (pattern, config) in structure.iter() {
+            if config.semver.is_some() {
+                continue;
+            }
             if pattern == "*" {
                 catch_all = Some(config);
                 continue;
             }
 
-            if pattern.contains('*') {
-                wildcards.push(config);
+            if is_regex(pattern) || pattern.contains('*') {
+                patterns.push(config);
                 continue;
             }
         }
 
-        wildcards.sort();
-
-        for config in &wildcards {
-            let pattern = {
-                let regex_string = config.pattern.replace("*", ".*?");
-                match Regex::new(&format!("^{}$", regex_string)) {
-                    Ok(pattern) => pattern,
-                    // TODO: if there's an error we should be able to report it.
-                    // This error checking should probably happen on
-                    // configuration load rather than at time of lookup.
-                    Err(_) => return None,
-                }
-            };
+        patterns.sort();
 
-            if pattern.is_match(&name) {
+        for config in &patterns {
+            if config.matches(name) {
                 return Some(config);
             }
         }
 
-        if let Some(config) = catch_all {
-            return Some(config);
-        }
+        catch_all
+    }
+
+    pub fn load(project_root: &'a Path) -> Result<RepoConfig<'a>, Errors> {
+        Self::load_with_methods(project_root, &[])
+    }
+
+    /// Load a configuration that can also use the custom deploy methods in
+    /// `methods`, see `task_factory`.
+    pub fn load_with_methods(project_root: &'a Path, methods: &[String]) -> Result<RepoConfig<'a>, Errors> {
+        Self::load_with_env(project_root, methods, None)
+    }
 
-        None
+    /// `load_with_methods`, with the `${VAR}` placeholders in the config
+    /// filled in from `vars`, see `expand_vars`. Without `vars` they are left
+    /// as they are.
+    pub fn load_with_env(project_root: &'a Path,
+                         methods: &[String],
+                         vars: Option<&Environment>)
+                         -> Result<RepoConfig<'a>, Errors> {
+        Self::load_from(project_root, &[String::from(CONFIG_FILE)], methods, vars)
     }
 
-    pub fn load(project_root: &'a Path) -> Result<RepoConfig<'a>, Error> {
-        let config_path = project_root.join(".hookshot.conf");
+    /// `load_with_env`, with the config read from the first of `paths`,
+    /// relative to the project root, that exists, see `find`. Paths in the
+    /// config are still relative to the project root.
+    pub fn load_from(project_root: &'a Path,
+                     paths: &[String],
+                     methods: &[String],
+                     vars: Option<&Environment>)
+                     -> Result<RepoConfig<'a>, Errors> {
+        let config_path = match find(project_root, paths) {
+            Some(path) => path,
+            None => return Err(Errors(vec![Error::NotFound(paths.to_vec())])),
+        };
         let mut file = match File::open(&config_path) {
             Ok(file) => file,
-            Err(_) => return Err(Error::FileLoad),
+            Err(_) => return Err(Errors(vec![Error::FileLoad])),
         };
         let mut contents = String::new();
         if file.read_to_string(&mut contents).is_err() {
-            return Err(Error::FileRead);
+            return Err(Errors(vec![Error::FileRead]));
+        }
+        Self::from_str_with_env(&contents, project_root, methods, vars)
+    }
+
+    /// Check a configuration like `from_str`, and also for settings that
+    /// load but probably don't do what was meant. Errors come first in the
+    /// warnings, and there is only a config if there are none.
+    pub fn lint(string: &str, project_root: &'a Path) -> (Option<RepoConfig<'a>>, Vec<Warning>) {
+        let mut warnings = vec![];
+        if let Some(root) = toml::Parser::new(string).parse() {
+            lint_sections(&root, &mut warnings);
+        }
+        match RepoConfig::from_str(string, project_root) {
+            Ok(config) => {
+                let mut shadowed = config.unreachable_patterns();
+                shadowed.extend(warnings);
+                (Some(config), shadowed)
+            }
+            Err(Errors(errors)) => {
+                let mut errors = errors.into_iter().map(Warning::Invalid).collect::<Vec<Warning>>();
+                errors.extend(warnings);
+                (None, errors)
+            }
+        }
+    }
+
+    /// Wildcard patterns that can't match anything because an earlier one,
+    /// in the order `lookup` tries them, matches everything they do. That
+    /// includes the `*` catch-all, which comes last. Regexes aren't
+    /// checked. Tag sections with overlapping `semver` ranges are reported
+    /// too, since only the one that sorts first gets the tags in both.
+    pub fn unreachable_patterns(&self) -> Vec<Warning> {
+        let mut warnings = vec![];
+        if let Some(ref tags) = self.tag {
+            let ranges = tags.values()
+                             .filter_map(|config| config.semver.as_ref().map(|semver| (&config.pattern, semver)))
+                             .collect::<Vec<_>>();
+            for (i, &(pattern, semver)) in ranges.iter().enumerate() {
+                if let Some(&(by, _)) = ranges[..i].iter().find(|&&(_, earlier)| versions::overlaps(earlier, semver)) {
+                    warnings.push(Warning::OverlappingSemver(pattern.clone(), by.clone()));
+                }
+            }
+        }
+        let groups = [(RefType::branch, &self.branch), (RefType::tag, &self.tag)];
+        for &(reftype, group) in groups.iter() {
+            let group = match *group {
+                Some(ref group) => group,
+                None => continue,
+            };
+            let mut wildcards = group.values()
+                                     .filter(|config| config.semver.is_none())
+                                     .filter(|config| !is_regex(&config.pattern))
+                                     .filter(|config| config.pattern != "*" && config.pattern.contains('*'))
+                                     .collect::<Vec<&Config>>();
+            wildcards.sort();
+            let unreachable = |config: &Config, by: &Config| {
+                // Patterns that cover each other match the same refs
+                let same_refs = glob_covers(config.pattern.as_bytes(), by.pattern.as_bytes());
+                match same_refs && toml::encode_str(config) != toml::encode_str(by) {
+                    true => Warning::DuplicatePattern(reftype, config.pattern.clone(), by.pattern.clone()),
+                    false => Warning::ShadowedPattern(reftype, config.pattern.clone(), by.pattern.clone()),
+                }
+            };
+            for (i, config) in wildcards.iter().enumerate() {
+                let pattern = config.pattern.as_bytes();
+                if let Some(by) = wildcards[..i].iter().find(|by| glob_covers(by.pattern.as_bytes(), pattern)) {
+                    warnings.push(unreachable(*config, *by));
+                }
+            }
+            if let Some(catch_all) = group.get("*").into_iter().find(|config| config.semver.is_none()) {
+                if let Some(by) = wildcards.iter().find(|by| glob_covers(by.pattern.as_bytes(), b"*")) {
+                    warnings.push(unreachable(catch_all, *by));
+                }
+            }
         }
-        Self::from_str(&contents, project_root)
+        warnings
+    }
+
+    /// Parse a configuration, checking every section instead of stopping at
+    /// the first problem so all of them can be fixed in one go.
+    pub fn from_str(string: &str, project_root: &'a Path) -> Result<RepoConfig<'a>, Errors> {
+        Self::from_str_with_methods(string, project_root, &[])
+    }
+
+    /// `from_str`, with the custom deploy methods in `methods` as valid
+    /// values for `method`.
+    pub fn from_str_with_methods(string: &str,
+                                 project_root: &'a Path,
+                                 methods: &[String])
+                                 -> Result<RepoConfig<'a>, Errors> {
+        Self::from_str_with_env(string, project_root, methods, None)
     }
 
-    pub fn from_str(string: &str, project_root: &'a Path) -> Result<RepoConfig<'a>, Error> {
-        let root = match toml::Parser::new(string).parse() {
+    /// `from_str_with_methods`, filling in `${VAR}` placeholders from `vars`
+    /// like `load_with_env`.
+    pub fn from_str_with_env(string: &str,
+                             project_root: &'a Path,
+                             methods: &[String],
+                             vars: Option<&Environment>)
+                             -> Result<RepoConfig<'a>, Errors> {
+        let mut root = match toml::Parser::new(string).parse() {
             Some(value) => value,
-            None => return Err(Error::Parse),
+            None => return Err(Errors(vec![Error::Parse])),
         };
+        if let Some(vars) = vars {
+            let mut undefined = vec![];
+            for value in root.values_mut() {
+                expand_vars(value, vars, &mut undefined);
+            }
+            if !undefined.is_empty() {
+                return Err(Errors(undefined.into_iter().map(Error::UndefinedVariable).collect()));
+            }
+        }
+
+        let mut errors = vec![];
 
         let empty_table = toml::Value::Table(Table::new());
         let default = match root.get("default") {
@@ -263,55 +1077,199 @@ impl<'a> RepoConfig<'a> {
         let default_method = match lookup_as_string(default, "method") {
             LookupResult::Missing => None,
             LookupResult::StringValue(v) => match v {
+                "ansible" if !cfg!(feature = "ansible") => invalid(&mut errors, Error::AnsibleNotSupported),
                 "ansible" => Some(DeployMethod::Ansible),
                 "makefile" | "make" => Some(DeployMethod::Makefile),
-                _ => return Err(Error::InvalidDefaultMethod),
+                v if methods.iter().any(|method| method == v) => Some(DeployMethod::Custom(String::from(v))),
+                _ => invalid(&mut errors, Error::InvalidDefaultMethod),
             },
-            _ => return Err(Error::InvalidDefaultMethod),
+            _ => invalid(&mut errors, Error::InvalidDefaultMethod),
+        };
+
+        let default_check_task = match lookup_as_bool(default, "check_task") {
+            LookupResult::Missing => true,
+            LookupResult::BoolValue(v) => v,
+            _ => invalid(&mut errors, Error::InvalidDefaultCheckTask).unwrap_or(true),
+        };
+
+        let default_make_location = match make_location(default, project_root) {
+            Ok(location) => location.unwrap_or(Location::Root),
+            Err(_) => invalid(&mut errors, Error::InvalidDefaultMakefile).unwrap_or(Location::Root),
         };
 
+        let default_make_args = lookup_as_args(default, "make_args")
+                                    .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidDefaultMakeArgs))
+                                    .unwrap_or(vec![]);
+        let default_ansible_args = lookup_as_args(default, "ansible_args")
+                                       .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidDefaultAnsibleArgs))
+                                       .unwrap_or(vec![]);
+
+        let default_task_name = match lookup_as_string(default, "task") {
+            LookupResult::StringValue(v) => Some(v),
+            _ => None,
+        };
         let default_task = match lookup_as_string(default, "task") {
             LookupResult::Missing => None,
-            LookupResult::StringValue(v) => match MakeTask::new(project_root, v) {
+            LookupResult::StringValue(v) => match check_make_task(project_root,
+                                                                  default_make_location.clone(),
+                                                                  None,
+                                                                  &default_make_args,
+                                                                  v,
+                                                                  default_check_task) {
                 Ok(v) => Some(v),
-                Err(_) => return Err(Error::InvalidDefaultMakeTask),
+                Err(make_error) => invalid(&mut errors, Error::UnknownDefaultMakeTask(make_error)),
             },
-            _ => return Err(Error::InvalidDefaultMakeTask),
+            _ => invalid(&mut errors, Error::InvalidDefaultMakeTask),
         };
 
         let default_playbook = match lookup_as_string(default, "playbook") {
             LookupResult::Missing => None,
             LookupResult::StringValue(v) => match VerifiedPath::file(Some(project_root), Path::new(v)) {
                 Ok(v) => Some(v),
-                Err(_) => return Err(Error::InvalidDefaultPlaybook),
+                Err(_) => invalid(&mut errors, Error::InvalidDefaultPlaybook),
             },
-            _ => return Err(Error::InvalidDefaultPlaybook),
+            _ => invalid(&mut errors, Error::InvalidDefaultPlaybook),
         };
 
         let default_inventory = match lookup_as_string(default, "inventory") {
             LookupResult::Missing => None,
             LookupResult::StringValue(v) => match VerifiedPath::file(Some(project_root), Path::new(v)) {
                 Ok(v) => Some(v),
-                Err(_) => return Err(Error::InvalidDefaultInventory),
+                Err(_) => invalid(&mut errors, Error::InvalidDefaultInventory),
             },
-            _ => return Err(Error::InvalidDefaultInventory),
+            _ => invalid(&mut errors, Error::InvalidDefaultInventory),
         };
 
         let default_notifiers = match lookup_as_array(default, "notifiers") {
             LookupResult::Missing => None,
             LookupResult::VectorValue(v) => Some(v),
-            _ => return Err(Error::InvalidDefaultNotifier),
+            _ => invalid(&mut errors, Error::InvalidDefaultNotifier),
+        };
+
+        let default_payload_format = match lookup_as_string(default, "payload_format") {
+            LookupResult::Missing => PayloadFormat::Json,
+            LookupResult::StringValue(v) => match PayloadFormat::from_str(v) {
+                Some(v) => v,
+                None => invalid(&mut errors, Error::InvalidDefaultPayloadFormat).unwrap_or(PayloadFormat::Json),
+            },
+            _ => invalid(&mut errors, Error::InvalidDefaultPayloadFormat).unwrap_or(PayloadFormat::Json),
+        };
+
+        let default_payload_template = match lookup_as_string(default, "payload_template") {
+            LookupResult::Missing => None,
+            LookupResult::StringValue(v) if valid_payload_template(v, default_payload_format) => Some(String::from(v)),
+            _ => invalid(&mut errors, Error::InvalidDefaultPayloadTemplate),
+        };
+
+        let default_notify_log_lines = lookup_as_count(default, "notify_log_lines")
+                                           .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidDefaultNotifyLogLines))
+                                           .unwrap_or(DEFAULT_NOTIFY_LOG_LINES);
+
+        let default_retries = lookup_as_count(default, "retries")
+                                  .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidDefaultRetries))
+                                  .unwrap_or(0);
+        let default_retry_delay = lookup_as_delay(default, "retry_delay", MAX_RETRY_DELAY)
+                                      .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidDefaultRetryDelay))
+                                      .unwrap_or(DEFAULT_RETRY_DELAY);
+        let default_timeout = lookup_as_timeout(default)
+                                  .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidDefaultTimeout));
+
+        let default_severity = match lookup_as_string(default, "pagerduty_severity") {
+            LookupResult::Missing => None,
+            LookupResult::StringValue(v) => match Severity::from_str(v) {
+                Some(v) => Some(v),
+                None => invalid(&mut errors, Error::InvalidDefaultPagerDutySeverity),
+            },
+            _ => invalid(&mut errors, Error::InvalidDefaultPagerDutySeverity),
+        };
+
+        let default_submodules = match lookup_as_bool(default, "submodules") {
+            LookupResult::Missing => false,
+            LookupResult::BoolValue(v) => v,
+            _ => invalid(&mut errors, Error::InvalidDefaultSubmodules).unwrap_or(false),
+        };
+
+        let default_clean_checkout = match lookup_as_bool(default, "clean_checkout") {
+            LookupResult::Missing => false,
+            LookupResult::BoolValue(v) => v,
+            _ => invalid(&mut errors, Error::InvalidDefaultCleanCheckout).unwrap_or(false),
+        };
+
+        let default_check = match lookup_as_bool(default, "check") {
+            LookupResult::Missing => false,
+            LookupResult::BoolValue(v) => v,
+            _ => invalid(&mut errors, Error::InvalidDefaultCheck).unwrap_or(false),
+        };
+
+        let default_vault_password = match lookup_as_string(default, "vault_password") {
+            LookupResult::Missing => None,
+            LookupResult::StringValue(v) if environment::valid_key(v) => Some(String::from(v)),
+            _ => invalid(&mut errors, Error::InvalidDefaultVaultPassword),
+        };
+
+        let default_vault_password_file = match lookup_as_string(default, "vault_password_file") {
+            LookupResult::Missing => None,
+            LookupResult::StringValue(_) if default.lookup("vault_password").is_some() =>
+                invalid(&mut errors, Error::InvalidDefaultVaultPasswordFile),
+            LookupResult::StringValue(v) => match VerifiedPath::file(Some(project_root), Path::new(v)) {
+                Ok(v) => Some(v),
+                Err(_) => invalid(&mut errors, Error::InvalidDefaultVaultPasswordFile),
+            },
+            _ => invalid(&mut errors, Error::InvalidDefaultVaultPasswordFile),
+        };
+
+        let default_paths = match lookup_as_array(default, "paths") {
+            LookupResult::Missing => None,
+            LookupResult::VectorValue(ref v) if PathFilter::new(v).is_ok() => Some(v.clone()),
+            _ => invalid(&mut errors, Error::InvalidDefaultPaths),
+        };
+
+        let default_allowed_exit_codes = match lookup_as_exit_codes(default, "allowed_exit_codes") {
+            LookupResult::Missing => vec![0],
+            LookupResult::ExitCodesValue(v) => v,
+            _ => invalid(&mut errors, Error::InvalidDefaultAllowedExitCodes).unwrap_or(vec![0]),
+        };
+
+        let default_before_task = lookup_hook(default, "before_task", None)
+                                      .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidDefaultHook));
+        let default_after_task = lookup_hook(default, "after_task", None)
+                                     .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidDefaultHook));
+        let default_on_failure = lookup_hook(default, "on_failure", None)
+                                     .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidDefaultHook));
+
+        let default_environment = match lookup_as_string(default, "environment") {
+            LookupResult::Missing => None,
+            LookupResult::StringValue(v) => Some(String::from(v)),
+            _ => invalid(&mut errors, Error::InvalidDefaultEnvironment),
+        };
+
+        let default_lock = match lookup_as_string(default, "lock") {
+            LookupResult::Missing => None,
+            LookupResult::StringValue(v) if !v.is_empty() => Some(String::from(v)),
+            _ => invalid(&mut errors, Error::InvalidDefaultLock),
         };
 
+        let default_settings = lookup_settings(default, &BTreeMap::new())
+                                   .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidDefaultSettings)
+                                                           .unwrap_or(BTreeMap::new()));
+
         let mut config_groups = BTreeMap::new();
 
+        // Sections without a `method` of their own all have the same
+        // problem when the default has none, or an invalid one that was
+        // reported already, so it's reported once
+        let mut missing_method_reported = default.lookup("method").is_some();
+
         let tag_type = "tag";
         let branch_type = "branch";
         for group_type in [tag_type, branch_type].iter() {
             let group = match root.get(group_type.to_owned()) {
                 None => continue,
                 Some(v) => match v.as_table() {
-                    None => return Err(Error::InvalidConfigGroup),
+                    None => {
+                        errors.push(Error::InvalidConfigGroup);
+                        continue;
+                    }
                     Some(v) => v,
                 },
             };
@@ -320,95 +1278,359 @@ impl<'a> RepoConfig<'a> {
 
             for (pattern, config) in group.iter() {
                 if config.as_table().is_none() {
-                    return Err(Error::InvalidConfigEntry(pattern.clone()));
+                    errors.push(Error::InvalidConfigEntry(pattern.clone()));
+                    continue;
                 }
+                // Keep checking the rest of the entry after an error, but
+                // don't add it to the config
+                let errors_before = errors.len();
+
+                let matcher = match Matcher::new(pattern) {
+                    Ok(matcher) => Some(matcher),
+                    Err(e) => invalid(&mut errors, Error::InvalidPattern(pattern.clone(), e)),
+                };
 
                 let method = match lookup_as_string(config, "method") {
                     LookupResult::Missing => match default_method {
-                        Some(method) => method,
-                        None => return Err(Error::MissingMethod(pattern.clone())),
+                        Some(ref method) => Some(method.clone()),
+                        // Nothing else about the section means much
+                        // without a method, so it isn't checked further
+                        None => {
+                            if !missing_method_reported {
+                                errors.push(Error::MissingMethod(pattern.clone()));
+                                missing_method_reported = true;
+                            }
+                            continue;
+                        }
                     },
                     LookupResult::StringValue(v) => match v {
-                        "ansible" => DeployMethod::Ansible,
-                        "makefile" | "make" => DeployMethod::Makefile,
-                        _ => return Err(Error::InvalidMethod(pattern.clone())),
+                        "ansible" if !cfg!(feature = "ansible") =>
+                            invalid(&mut errors, Error::AnsibleNotSupported),
+                        "ansible" => Some(DeployMethod::Ansible),
+                        "makefile" | "make" => Some(DeployMethod::Makefile),
+                        v if methods.iter().any(|method| method == v) =>
+                            Some(DeployMethod::Custom(String::from(v))),
+                        _ => invalid(&mut errors, Error::InvalidMethod(pattern.clone())),
                     },
-                    _ => return Err(Error::InvalidMethod(pattern.clone())),
+                    _ => invalid(&mut errors, Error::InvalidMethod(pattern.clone())),
+                };
+
+                // The task's paths are relative to its `workdir`, the
+                // default's too when the section has one and uses them
+                let workdir = match lookup_as_string(config, "workdir") {
+                    LookupResult::Missing => None,
+                    LookupResult::StringValue(v) if inside_project(Path::new(v)) =>
+                        match VerifiedPath::directory(Some(project_root), Path::new(v)) {
+                            Ok(v) => Some(v.path().to_path_buf()),
+                            Err(_) => invalid(&mut errors, Error::InvalidWorkdir(pattern.clone())),
+                        },
+                    _ => invalid(&mut errors, Error::InvalidWorkdir(pattern.clone())),
+                };
+                let task_root = match workdir {
+                    Some(ref workdir) => project_root.join(workdir),
+                    None => project_root.to_path_buf(),
                 };
+                let ansible_in_workdir = workdir.is_some() && method == Some(DeployMethod::Ansible);
 
                 let playbook = match lookup_as_string(config, "playbook") {
+                    LookupResult::Missing if ansible_in_workdir =>
+                        default_file_in(&task_root, &default_playbook)
+                            .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidPlaybook(pattern.clone()))),
                     LookupResult::Missing => default_playbook.clone(),
                     LookupResult::StringValue(v) =>
-                        match VerifiedPath::file(Some(project_root), Path::new(v)) {
+                        match VerifiedPath::file(Some(task_root.as_path()), Path::new(v)) {
                             Ok(v) => Some(v),
-                            Err(_) => return Err(Error::InvalidPlaybook(pattern.clone())),
+                            Err(_) => invalid(&mut errors, Error::InvalidPlaybook(pattern.clone())),
                         },
-                    _ => return Err(Error::InvalidPlaybook(pattern.clone())),
+                    _ => invalid(&mut errors, Error::InvalidPlaybook(pattern.clone())),
                 };
                 let inventory = match lookup_as_string(config, "inventory") {
+                    LookupResult::Missing if ansible_in_workdir =>
+                        default_file_in(&task_root, &default_inventory)
+                            .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidInventory(pattern.clone()))),
                     LookupResult::Missing => default_inventory.clone(),
                     LookupResult::StringValue(v) =>
-                        match VerifiedPath::file(Some(project_root), Path::new(v)) {
+                        match VerifiedPath::file(Some(task_root.as_path()), Path::new(v)) {
                             Ok(v) => Some(v),
-                            Err(_) => return Err(Error::InvalidInventory(pattern.clone())),
+                            Err(_) => invalid(&mut errors, Error::InvalidInventory(pattern.clone())),
                         },
-                    _ => return Err(Error::InvalidInventory(pattern.clone()))
+                    _ => invalid(&mut errors, Error::InvalidInventory(pattern.clone())),
                 };
 
                 let notifiers = match lookup_as_array(config, "notifiers") {
                     LookupResult::Missing => default_notifiers.clone(),
                     LookupResult::VectorValue(v) => Some(v),
-                    _ => return Err(Error::InvalidNotifier(pattern.clone())),
+                    _ => invalid(&mut errors, Error::InvalidNotifier(pattern.clone())),
                 };
 
-                let branch_make_task = match lookup_as_string(config, "task") {
-                    LookupResult::Missing => None,
-                    LookupResult::StringValue(v) => match MakeTask::new(project_root, v) {
-                        Ok(v) => Some(v),
-                        Err(_) => return Err(Error::InvalidMakeTask(pattern.clone())),
+                let payload_format = match lookup_as_string(config, "payload_format") {
+                    LookupResult::Missing => default_payload_format,
+                    LookupResult::StringValue(v) => match PayloadFormat::from_str(v) {
+                        Some(v) => v,
+                        None => invalid(&mut errors, Error::InvalidPayloadFormat(pattern.clone()))
+                                    .unwrap_or(PayloadFormat::Json),
                     },
-                    _ => return Err(Error::InvalidMakeTask(pattern.clone())),
+                    _ => invalid(&mut errors, Error::InvalidPayloadFormat(pattern.clone())).unwrap_or(PayloadFormat::Json),
                 };
 
-                let ansible_task = if method == DeployMethod::Ansible {
-                    match (playbook, inventory) {
-                        (Some(playbook), Some(inventory)) =>
-                            Some(AnsibleTask::new(playbook.to_string(),
-                                                  inventory.to_string(),
-                                                  &project_root)),
-                        (_, _) => return Err(Error::InvalidAnsibleConfig),
-                    }
-                } else {
-                    None
+                // Checked here rather than as it's read, a template from the
+                // default may not be valid for the format the section uses
+                let payload_template = match lookup_as_string(config, "payload_template") {
+                    LookupResult::Missing => default_payload_template.clone(),
+                    LookupResult::StringValue(v) => Some(String::from(v)),
+                    _ => invalid(&mut errors, Error::InvalidPayloadTemplate(pattern.clone())),
+                };
+                let payload_template = match payload_template {
+                    Some(ref v) if !valid_payload_template(v, payload_format) =>
+                        invalid(&mut errors, Error::InvalidPayloadTemplate(pattern.clone())),
+                    v => v,
                 };
 
-                let make_task = if method == DeployMethod::Makefile {
-                    match (branch_make_task, default_task.clone()) {
-                        (Some(task), _) => Some(task),
-                        (None, Some(task)) => Some(task),
-                        (None, None) => return Err(Error::InvalidMakeTaskConfig),
-                    }
-                } else {
-                    None
+                let notify_log_lines = lookup_as_count(config, "notify_log_lines")
+                                           .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidNotifyLogLines(pattern.clone())))
+                                           .unwrap_or(default_notify_log_lines);
+
+                let retries = lookup_as_count(config, "retries")
+                                  .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidRetries(pattern.clone())))
+                                  .unwrap_or(default_retries);
+                let retry_delay = lookup_as_delay(config, "retry_delay", MAX_RETRY_DELAY)
+                                      .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidRetryDelay(pattern.clone())))
+                                      .unwrap_or(default_retry_delay);
+                let timeout = lookup_as_timeout(config)
+                                  .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidTimeout(pattern.clone())))
+                                  .or(default_timeout);
+
+                let pagerduty_severity = match lookup_as_string(config, "pagerduty_severity") {
+                    LookupResult::Missing => default_severity,
+                    LookupResult::StringValue(v) => match Severity::from_str(v) {
+                        Some(v) => Some(v),
+                        None => invalid(&mut errors, Error::InvalidPagerDutySeverity(pattern.clone())),
+                    },
+                    _ => invalid(&mut errors, Error::InvalidPagerDutySeverity(pattern.clone())),
                 };
 
-                if make_task.is_none() && ansible_task.is_none() {
-                    return Err(Error::MissingTask(pattern.clone()));
-                }
+                let submodules = match lookup_as_bool(config, "submodules") {
+                    LookupResult::Missing => default_submodules,
+                    LookupResult::BoolValue(v) => v,
+                    _ => invalid(&mut errors, Error::InvalidSubmodules(pattern.clone())).unwrap_or(false),
+                };
 
-                let config = Config {
-                    pattern: pattern.clone(),
-                    ansible_task: ansible_task,
-                    make_task: make_task,
-                    method: method,
-                    notifiers: notifiers
+                let clean_checkout = match lookup_as_bool(config, "clean_checkout") {
+                    LookupResult::Missing => default_clean_checkout,
+                    LookupResult::BoolValue(v) => v,
+                    _ => invalid(&mut errors, Error::InvalidCleanCheckout(pattern.clone())).unwrap_or(false),
                 };
 
-                let mut map = config_groups.get_mut(group_type).unwrap();
-                map.insert(pattern.clone(), config);
-            }
+                let check = match lookup_as_bool(config, "check") {
+                    LookupResult::Missing => default_check,
+                    LookupResult::BoolValue(v) => v,
+                    _ => invalid(&mut errors, Error::InvalidCheck(pattern.clone())).unwrap_or(false),
+                };
 
-        }
+                // Setting one of `vault_password` and `vault_password_file`
+                // replaces both defaults
+                let vault_password = match lookup_as_string(config, "vault_password") {
+                    LookupResult::Missing if config.lookup("vault_password_file").is_some() => None,
+                    LookupResult::Missing => default_vault_password.clone(),
+                    LookupResult::StringValue(v) if environment::valid_key(v) => Some(String::from(v)),
+                    _ => invalid(&mut errors, Error::InvalidVaultPassword(pattern.clone())),
+                };
+                let vault_password_file = match lookup_as_string(config, "vault_password_file") {
+                    LookupResult::Missing if config.lookup("vault_password").is_some() => None,
+                    LookupResult::Missing if ansible_in_workdir =>
+                        default_file_in(&task_root, &default_vault_password_file)
+                            .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidVaultPasswordFile(pattern.clone()))),
+                    LookupResult::Missing => default_vault_password_file.clone(),
+                    LookupResult::StringValue(_) if config.lookup("vault_password").is_some() =>
+                        invalid(&mut errors, Error::InvalidVaultPasswordFile(pattern.clone())),
+                    LookupResult::StringValue(v) =>
+                        match VerifiedPath::file(Some(task_root.as_path()), Path::new(v)) {
+                            Ok(v) => Some(v),
+                            Err(_) => invalid(&mut errors, Error::InvalidVaultPasswordFile(pattern.clone())),
+                        },
+                    _ => invalid(&mut errors, Error::InvalidVaultPasswordFile(pattern.clone())),
+                };
+
+                let make_args = lookup_as_args(config, "make_args")
+                                    .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidMakeArgs(pattern.clone())))
+                                    .unwrap_or(default_make_args.clone());
+                let ansible_args = lookup_as_args(config, "ansible_args")
+                                       .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidAnsibleArgs(pattern.clone())))
+                                       .unwrap_or(default_ansible_args.clone());
+
+                let paths = match lookup_as_array(config, "paths") {
+                    LookupResult::Missing => default_paths.clone(),
+                    LookupResult::VectorValue(ref v) if PathFilter::new(v).is_ok() => Some(v.clone()),
+                    _ => invalid(&mut errors, Error::InvalidPaths(pattern.clone())),
+                };
+
+                let allowed_exit_codes = match lookup_as_exit_codes(config, "allowed_exit_codes") {
+                    LookupResult::Missing => default_allowed_exit_codes.clone(),
+                    LookupResult::ExitCodesValue(v) => v,
+                    _ => invalid(&mut errors, Error::InvalidAllowedExitCodes(pattern.clone()))
+                             .unwrap_or(vec![0]),
+                };
+
+                let before_task = lookup_hook(config, "before_task", default_before_task.clone())
+                                      .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidHook(pattern.clone())));
+                let after_task = lookup_hook(config, "after_task", default_after_task.clone())
+                                     .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidHook(pattern.clone())));
+                let on_failure = lookup_hook(config, "on_failure", default_on_failure.clone())
+                                     .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidHook(pattern.clone())));
+
+                let environment = match lookup_as_string(config, "environment") {
+                    LookupResult::Missing => default_environment.clone(),
+                    LookupResult::StringValue(v) => Some(String::from(v)),
+                    _ => invalid(&mut errors, Error::InvalidEnvironment(pattern.clone())),
+                };
+
+                let lock = match lookup_as_string(config, "lock") {
+                    LookupResult::Missing => default_lock.clone(),
+                    LookupResult::StringValue(v) if !v.is_empty() => Some(String::from(v)),
+                    _ => invalid(&mut errors, Error::InvalidLock(pattern.clone())),
+                };
+
+                let semver = match lookup_as_string(config, "semver") {
+                    LookupResult::Missing => None,
+                    _ if *group_type == branch_type => invalid(&mut errors, Error::SemverOnBranch(pattern.clone())),
+                    LookupResult::StringValue(v) => match VersionReq::parse(v) {
+                        Ok(semver) => Some(semver),
+                        Err(_) => invalid(&mut errors, Error::InvalidSemver(pattern.clone())),
+                    },
+                    _ => invalid(&mut errors, Error::InvalidSemver(pattern.clone())),
+                };
+
+                let settings = lookup_settings(config, &default_settings)
+                                   .unwrap_or_else(|_| invalid(&mut errors, Error::InvalidSettings(pattern.clone()))
+                                                           .unwrap_or(BTreeMap::new()));
+
+                let check_task = match lookup_as_bool(config, "check_task") {
+                    LookupResult::Missing => default_check_task,
+                    LookupResult::BoolValue(v) => v,
+                    _ => invalid(&mut errors, Error::InvalidCheckTask(pattern.clone())).unwrap_or(true),
+                };
+
+                let make_location = match make_location(config, &task_root) {
+                    Ok(Some(location)) => location,
+                    Ok(None) => default_make_location.clone(),
+                    Err(_) => invalid(&mut errors, Error::InvalidMakefile(pattern.clone()))
+                                  .unwrap_or(Location::Root),
+                };
+
+                let branch_make_task = match lookup_as_string(config, "task") {
+                    LookupResult::Missing => None,
+                    LookupResult::StringValue(v) => match check_make_task(project_root,
+                                                                          make_location.clone(),
+                                                                          workdir.clone(),
+                                                                          &make_args,
+                                                                          v,
+                                                                          check_task) {
+                        Ok(v) => Some(v),
+                        Err(make_error) =>
+                            invalid(&mut errors, Error::UnknownMakeTask(pattern.clone(), make_error)),
+                    },
+                    _ => invalid(&mut errors, Error::InvalidMakeTask(pattern.clone())),
+                };
+
+                // Finding the task needs a valid method, playbook,
+                // inventory and make task, so skip it if any of them were
+                // reported already
+                let method = match method {
+                    Some(method) if errors.len() == errors_before => method,
+                    _ => continue,
+                };
+
+                let ansible_task = if method == DeployMethod::Ansible {
+                    match (playbook, inventory) {
+                        (Some(playbook), Some(inventory)) => {
+                            let mut task = AnsibleTask::new(playbook.to_string(),
+                                                            inventory.to_string(),
+                                                            &project_root);
+                            task.check = check;
+                            task.vault_password = vault_password;
+                            task.vault_password_file = vault_password_file.map(|file| file.to_string());
+                            task.workdir = workdir.clone();
+                            task.args = ansible_args;
+                            Some(task)
+                        }
+                        (_, _) => invalid(&mut errors, Error::InvalidAnsibleConfig),
+                    }
+                } else {
+                    None
+                };
+
+                let make_task = if method == DeployMethod::Makefile {
+                    match (branch_make_task, default_task.clone()) {
+                        (Some(task), _) => Some(task),
+                        (None, Some(task)) if make_location == default_make_location && workdir.is_none() &&
+                                              make_args == default_make_args => Some(task),
+                        // The default task, from the branch's Makefile or
+                        // with its arguments
+                        (None, Some(_)) => match check_make_task(project_root,
+                                                                 make_location.clone(),
+                                                                 workdir.clone(),
+                                                                 &make_args,
+                                                                 default_task_name.unwrap(),
+                                                                 check_task) {
+                            Ok(task) => Some(task),
+                            Err(make_error) =>
+                                invalid(&mut errors, Error::UnknownMakeTask(pattern.clone(), make_error)),
+                        },
+                        (None, None) => invalid(&mut errors, Error::InvalidMakeTaskConfig),
+                    }
+                } else {
+                    None
+                };
+
+                if errors.len() > errors_before {
+                    continue;
+                }
+                let custom = match method {
+                    DeployMethod::Custom(_) => true,
+                    _ => false,
+                };
+                if make_task.is_none() && ansible_task.is_none() && !custom {
+                    errors.push(Error::MissingTask(pattern.clone()));
+                    continue;
+                }
+
+                let config = Config {
+                    pattern: pattern.clone(),
+                    matcher: matcher,
+                    semver: semver,
+                    ansible_task: ansible_task,
+                    make_task: make_task,
+                    method: method,
+                    notifiers: notifiers,
+                    payload_template: payload_template,
+                    payload_format: payload_format,
+                    notify_log_lines: notify_log_lines,
+                    retries: retries,
+                    retry_delay: retry_delay,
+                    timeout: timeout,
+                    pagerduty_severity: pagerduty_severity,
+                    submodules: submodules,
+                    clean_checkout: clean_checkout,
+                    environment: environment,
+                    lock: lock,
+                    paths: paths,
+                    settings: settings,
+                    workdir: workdir,
+                    allowed_exit_codes: allowed_exit_codes,
+                    before_task: before_task,
+                    after_task: after_task,
+                    on_failure: on_failure,
+                };
+
+                let mut map = config_groups.get_mut(group_type).unwrap();
+                map.insert(pattern.clone(), config);
+            }
+
+        }
+
+        if !errors.is_empty() {
+            return Err(Errors(errors));
+        }
 
         Ok(RepoConfig {
             tag: config_groups.remove(&tag_type),
@@ -418,11 +1640,185 @@ impl<'a> RepoConfig<'a> {
     }
 }
 
+/// Whether every ref the glob `pattern` matches is matched by `by` too.
+fn glob_covers(by: &[u8], pattern: &[u8]) -> bool {
+    if by.is_empty() {
+        return pattern.is_empty();
+    }
+    if by[0] == b'*' {
+        // The star matches nothing more, or takes the next byte (or star)
+        // of the pattern
+        return glob_covers(&by[1..], pattern) || (!pattern.is_empty() && glob_covers(by, &pattern[1..]));
+    }
+    !pattern.is_empty() && pattern[0] != b'*' && pattern[0] == by[0] && glob_covers(&by[1..], &pattern[1..])
+}
+
+/// The lint checks that need the sections as they were written, before
+/// defaults are filled in.
+fn lint_sections(root: &Table, warnings: &mut Vec<Warning>) {
+    let empty_table = toml::Value::Table(Table::new());
+    let default = root.get("default").unwrap_or(&empty_table);
+    let mut sections = vec![];
+    for &(reftype, key) in [(RefType::branch, "branch"), (RefType::tag, "tag")].iter() {
+        if let Some(group) = root.get(key).and_then(|group| group.as_table()) {
+            for (pattern, section) in group.iter().filter(|&(_, section)| section.as_table().is_some()) {
+                sections.push((reftype, pattern, section));
+            }
+        }
+    }
+
+    let has_task = |section: &toml::Value| {
+        ["task", "playbook"].iter().any(|key| section.lookup(key).is_some())
+    };
+    for &(reftype, pattern, section) in sections.iter() {
+        if section.lookup("notifiers").is_some() && !has_task(section) && !has_task(default) {
+            warnings.push(Warning::NotifiersWithoutTask(reftype, pattern.clone()));
+        }
+    }
+    for &(reftype, pattern, _) in sections.iter() {
+        if !is_regex(pattern) && pattern.contains(|c: char| ".[]()+?|^${}\\".contains(c)) {
+            warnings.push(Warning::RegexInGlob(reftype, pattern.clone()));
+        }
+    }
+
+    if sections.is_empty() {
+        return;
+    }
+    if let Some(default) = default.as_table() {
+        for key in default.keys() {
+            if sections.iter().all(|&(_, _, section)| section.lookup(key).is_some()) {
+                warnings.push(Warning::UnusedDefault(key.clone()));
+            }
+        }
+    }
+}
+
+fn insert_config<'a>(group: &mut Option<ConfigMap<'a>>, config: Config<'a>) {
+    if group.is_none() {
+        *group = Some(ConfigMap::new());
+    }
+    if let Some(ref mut group) = *group {
+        group.insert(config.pattern.clone(), config);
+    }
+}
+
+/// The `settings` table of a section on top of `defaults`. An error if it
+/// isn't a table or has anything but strings in it.
+fn lookup_settings(obj: &toml::Value, defaults: &BTreeMap<String, String>) -> Result<BTreeMap<String, String>, ()> {
+    let mut settings = defaults.clone();
+    let table = match obj.lookup("settings") {
+        None => return Ok(settings),
+        Some(value) => try!(value.as_table().ok_or(())),
+    };
+    for (key, value) in table {
+        settings.insert(key.clone(), String::from(try!(value.as_str().ok_or(()))));
+    }
+    Ok(settings)
+}
+
+/// A make task, run from `workdir` if there is one and with `args`, checked
+/// with make unless `check` is off. The error is what make had to say about it.
+/// Tasks with a `${VAR}` placeholder left in them aren't checked, make can
+/// only say whether they exist once the placeholder is filled in.
+fn check_make_task<'a>(project_root: &'a Path,
+                       location: Location,
+                       workdir: Option<PathBuf>,
+                       args: &[String],
+                       task: &str,
+                       check: bool)
+                       -> Result<MakeTask<'a>, String> {
+    let make_task = MakeTask::unchecked(project_root, location, task).with_args(args.to_vec());
+    let make_task = match workdir {
+        Some(workdir) => make_task.with_workdir(workdir),
+        None => make_task,
+    };
+    let unexpanded = task.contains("${") || args.iter().any(|arg| arg.contains("${"));
+    if !check || unexpanded {
+        return Ok(make_task);
+    }
+    make_task.check().map_err(|e| match e.subject() {
+        Some(ref subject) if !subject.is_empty() => subject.clone(),
+        _ => String::from(e.desc),
+    })
+}
+
+/// Whether `path` is relative and stays inside the directory it's relative
+/// to.
+fn inside_project(path: &Path) -> bool {
+    path.components().all(|component| match component {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    })
+}
+
+/// A playbook or inventory from the `default` section, for a section with a
+/// `workdir`. It has to exist in the workdir, since that's where the task
+/// runs.
+fn default_file_in(task_root: &Path, default: &Option<VerifiedPath>) -> Result<Option<VerifiedPath>, ()> {
+    match *default {
+        Some(ref path) => VerifiedPath::file(Some(task_root), path.path()).map(Some).map_err(|_| ()),
+        None => Ok(None),
+    }
+}
+
+/// Where the `makefile` or `make_dir` of a section points, `None` if it
+/// doesn't set either. An error if the path doesn't exist or both are set.
+fn make_location(obj: &toml::Value, project_root: &Path) -> Result<Option<Location>, ()> {
+    match (lookup_as_string(obj, "makefile"), lookup_as_string(obj, "make_dir")) {
+        (LookupResult::Missing, LookupResult::Missing) => Ok(None),
+        (LookupResult::StringValue(v), LookupResult::Missing) =>
+            match VerifiedPath::file(Some(project_root), Path::new(v)) {
+                Ok(v) => Ok(Some(Location::File(v.path().to_path_buf()))),
+                Err(_) => Err(()),
+            },
+        (LookupResult::Missing, LookupResult::StringValue(v)) =>
+            match VerifiedPath::directory(Some(project_root), Path::new(v)) {
+                Ok(v) => Ok(Some(Location::Directory(v.path().to_path_buf()))),
+                Err(_) => Err(()),
+            },
+        _ => Err(()),
+    }
+}
+
+/// A hook, `default` if the section doesn't have one. An error if it isn't
+/// a command or a table with a command.
+fn lookup_hook(obj: &toml::Value, key: &'static str, default: Option<Hook>) -> Result<Option<Hook>, ()> {
+    let value = match obj.lookup(key) {
+        None => return Ok(default),
+        Some(value) => value,
+    };
+    if let LookupResult::StringValue(v) = as_string(value) {
+        return Ok(Some(Hook::new(v)));
+    }
+    if value.as_table().is_none() {
+        return Err(());
+    }
+    match (lookup_as_string(value, "command"), lookup_as_bool(value, "allow_failure")) {
+        (LookupResult::StringValue(command), LookupResult::Missing) => Ok(Some(Hook::new(command))),
+        (LookupResult::StringValue(command), LookupResult::BoolValue(allow_failure)) => {
+            Ok(Some(Hook {
+                command: String::from(command),
+                allow_failure: allow_failure,
+            }))
+        }
+        _ => Err(()),
+    }
+}
+
+/// Record an error, returning `None` in place of the value that couldn't be
+/// read.
+fn invalid<T>(errors: &mut Vec<Error>, error: Error) -> Option<T> {
+    errors.push(error);
+    None
+}
+
 enum LookupResult<'a> {
     Missing,
     WrongType,
     StringValue(&'a str),
-    VectorValue(Vec<String>)
+    BoolValue(bool),
+    VectorValue(Vec<String>),
+    ExitCodesValue(Vec<i32>),
 }
 
 fn as_string<'a>(val: &'a toml::Value) -> LookupResult<'a> {
@@ -440,6 +1836,16 @@ fn lookup_as_string<'a>(obj: &'a toml::Value, key: &'static str) -> LookupResult
     }
 }
 
+fn lookup_as_bool<'a>(obj: &'a toml::Value, key: &'static str) -> LookupResult<'a> {
+    match obj.lookup(key) {
+        None => LookupResult::Missing,
+        Some(v) => match v.as_bool() {
+            None => LookupResult::WrongType,
+            Some(v) => LookupResult::BoolValue(v),
+        },
+    }
+}
+
 fn lookup_as_array<'a>(obj: &'a toml::Value, key: &'static str) -> LookupResult<'a> {
     match obj.lookup(key) {
         None => LookupResult::Missing,
@@ -461,23 +1867,189 @@ fn lookup_as_array<'a>(obj: &'a toml::Value, key: &'static str) -> LookupResult<
     }
 }
 
+/// Extra arguments for make or ansible-playbook, `None` if they're missing.
+/// An error if they aren't all strings.
+fn lookup_as_args(obj: &toml::Value, key: &'static str) -> Result<Option<Vec<String>>, ()> {
+    match obj.lookup(key) {
+        None => Ok(None),
+        Some(value) => match value.as_slice() {
+            Some(args) => args.iter().map(|arg| arg.as_str().map(String::from).ok_or(())).collect::<Result<Vec<_>, ()>>().map(Some),
+            None => Err(()),
+        },
+    }
+}
+
+/// A count of something, `None` if it's missing. An error if it isn't a
+/// whole number, 0 or more.
+fn lookup_as_count(obj: &toml::Value, key: &'static str) -> Result<Option<usize>, ()> {
+    match obj.lookup(key) {
+        None => Ok(None),
+        Some(value) => match value.as_integer() {
+            Some(count) if count >= 0 => Ok(Some(count as usize)),
+            _ => Err(()),
+        },
+    }
+}
+
+/// Replace every `${VAR}` in the strings of `value`, including the ones in
+/// arrays and tables, with `VAR` from `vars`. `$${` is a literal `${`.
+/// Names that aren't in `vars` are left in place and added to `undefined`.
+fn expand_vars(value: &mut toml::Value, vars: &Environment, undefined: &mut Vec<String>) {
+    match *value {
+        toml::Value::String(ref mut s) => *s = expand_string(s, vars, undefined),
+        toml::Value::Array(ref mut values) => {
+            for value in values.iter_mut() {
+                expand_vars(value, vars, undefined);
+            }
+        }
+        toml::Value::Table(ref mut table) => {
+            for value in table.values_mut() {
+                expand_vars(value, vars, undefined);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn expand_string(s: &str, vars: &Environment, undefined: &mut Vec<String>) -> String {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with("$${") {
+            expanded.push_str("${");
+            rest = &rest[3..];
+            continue;
+        }
+        let end = match rest.starts_with("${") {
+            true => rest.find('}'),
+            false => None,
+        };
+        match end {
+            Some(end) => {
+                let name = &rest[2..end];
+                match vars.get(name) {
+                    Some(value) => expanded.push_str(value),
+                    None => {
+                        if !undefined.iter().any(|undefined| undefined == name) {
+                            undefined.push(String::from(name));
+                        }
+                        expanded.push_str(&rest[..end + 1]);
+                    }
+                }
+                rest = &rest[end + 1..];
+            }
+            None => {
+                expanded.push('$');
+                rest = &rest[1..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Seconds, given as a number or as a string like `"30s"`, `"5m"` or
+/// `"1h"`, of at most `max` seconds.
+fn lookup_as_delay(obj: &toml::Value, key: &'static str, max: u64) -> Result<Option<u64>, ()> {
+    let delay = match obj.lookup(key) {
+        None => return Ok(None),
+        Some(&toml::Value::Integer(seconds)) if seconds >= 0 => seconds as u64,
+        Some(&toml::Value::String(ref delay)) => try!(parse_delay(delay).ok_or(())),
+        Some(_) => return Err(()),
+    };
+    match delay <= max {
+        true => Ok(Some(delay)),
+        false => Err(()),
+    }
+}
+
+/// `timeout`, which is like a delay that can't be 0.
+fn lookup_as_timeout(obj: &toml::Value) -> Result<Option<u64>, ()> {
+    match try!(lookup_as_delay(obj, "timeout", MAX_TIMEOUT)) {
+        Some(0) => Err(()),
+        timeout => Ok(timeout),
+    }
+}
+
+fn parse_delay(delay: &str) -> Option<u64> {
+    let delay = delay.trim();
+    let (number, unit) = match delay.find(|c: char| !c.is_digit(10)) {
+        Some(i) => (&delay[..i], &delay[i..]),
+        None => (delay, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return None,
+    };
+    number.parse::<u64>().ok().and_then(|n| n.checked_mul(multiplier))
+}
+
+/// An array of exit codes. Unlike other arrays an empty one is an error,
+/// nothing would ever succeed.
+fn lookup_as_exit_codes<'a>(obj: &'a toml::Value, key: &'static str) -> LookupResult<'a> {
+    let values = match obj.lookup(key) {
+        None => return LookupResult::Missing,
+        Some(val) => match val.as_slice() {
+            Some(values) if !values.is_empty() => values,
+            _ => return LookupResult::WrongType,
+        },
+    };
+    let mut codes = vec![];
+    for value in values {
+        match value.as_integer() {
+            Some(code) if code >= 0 && code <= 255 => codes.push(code as i32),
+            _ => return LookupResult::WrongType,
+        }
+    }
+    LookupResult::ExitCodesValue(codes)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::path::Path;
+    use hook::Hook;
+    use make_task::{Location, MakeTask};
+    use message::RefType;
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
     use std::error::Error as StdError;
 
     fn branch_config_pattern(pattern: &'static str) -> Config {
         Config {
             pattern: String::from(pattern),
+            matcher: Matcher::new(pattern).ok(),
+            semver: None,
             method: DeployMethod::Makefile,
             make_task: None,
             ansible_task: None,
             notifiers: None,
+            payload_template: None,
+            payload_format: PayloadFormat::Json,
+            notify_log_lines: DEFAULT_NOTIFY_LOG_LINES,
+            retries: 0,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            timeout: None,
+            pagerduty_severity: None,
+            submodules: false,
+            clean_checkout: false,
+            environment: None,
+            lock: None,
+            before_task: None,
+            after_task: None,
+            on_failure: None,
+            paths: None,
+            settings: BTreeMap::new(),
+            workdir: None,
+            allowed_exit_codes: vec![0],
         }
     }
 
     #[test]
+    #[cfg(feature = "ansible")]
     fn test_valid_configuration() {
         let project_root = Path::new("./src/test/repo_config");
         let config = RepoConfig::load(project_root).unwrap();
@@ -515,6 +2087,24 @@ mod tests {
 
     }
 
+    #[test]
+    #[cfg(feature = "ansible")]
+    fn test_load_from() {
+        let project_root = Path::new("./src/test/repo_config");
+        let paths = vec![String::from(".deploy/hookshot.toml"), String::from(CONFIG_FILE)];
+        assert_eq!(find(project_root, &paths), Some(project_root.join(CONFIG_FILE)));
+        let config = RepoConfig::load_from(project_root, &paths, &[], None).unwrap();
+        assert!(config.lookup_branch("production").is_some());
+
+        // Directories don't count
+        let paths = vec![String::from("ansible"), String::from(".deploy/hookshot.toml")];
+        assert_eq!(find(project_root, &paths), None);
+        let Errors(errors) = RepoConfig::load_from(project_root, &paths, &[], None).unwrap_err();
+        assert_eq!(errors, vec![Error::NotFound(paths.clone())]);
+        assert_eq!(errors[0].to_string(),
+                   "could not find a hookshot configuration (looked for ansible, .deploy/hookshot.toml)");
+    }
+
     #[test]
     fn test_branch_sorting() {
         let mut branches = vec![branch_config_pattern("branch-one"),
@@ -522,7 +2112,9 @@ mod tests {
                                 branch_config_pattern("*-one-*"),
                                 branch_config_pattern("branch-one-two"),
                                 branch_config_pattern("branch-*"),
-                                branch_config_pattern("branch*")];
+                                branch_config_pattern("branch*"),
+                                branch_config_pattern("re:^b"),
+                                branch_config_pattern("re:^branch-\\d+$")];
 
         branches.sort();
 
@@ -532,6 +2124,8 @@ mod tests {
 
         let expected_patterns = vec!["branch-one",
                                      "branch-one-two",
+                                     "re:^branch-\\d+$",
+                                     "re:^b",
                                      "branch-*",
                                      "branch*",
                                      "*-one-*",
@@ -610,4 +2204,1128 @@ mod tests {
         assert_eq!("*", config.lookup_tag("v901.4.5").unwrap().pattern);
     }
 
+    #[test]
+    fn test_submodules() {
+        let toml = r#"
+            [default]
+            method = "make"
+
+            [branch.production]
+            task = "build"
+            submodules = true
+
+            [branch.staging]
+            task = "build"
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert!(config.lookup_branch("production").unwrap().submodules);
+        assert!(!config.lookup_branch("staging").unwrap().submodules);
+    }
+
+    #[test]
+    fn test_clean_checkout() {
+        let toml = r#"
+            [default]
+            method = "make"
+
+            [branch.production]
+            task = "build"
+            clean_checkout = true
+
+            [branch.staging]
+            task = "build"
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert!(config.lookup_branch("production").unwrap().clean_checkout);
+        assert!(!config.lookup_branch("staging").unwrap().clean_checkout);
+
+        let toml = r#"
+            [branch.production]
+            method = "make"
+            task = "build"
+            clean_checkout = "yes please"
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0, vec![Error::InvalidCleanCheckout(String::from("production"))]);
+    }
+
+    #[test]
+    fn test_environment() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "build"
+            environment = "staging"
+
+            [branch.production]
+            environment = "production"
+
+            [branch.staging]
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert_eq!(config.lookup_branch("production").unwrap().environment,
+                   Some(String::from("production")));
+        assert_eq!(config.lookup_branch("staging").unwrap().environment,
+                   Some(String::from("staging")));
+
+        let toml = r#"
+            [branch.production]
+            method = "make"
+            task = "build"
+            environment = ["production"]
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0, vec![Error::InvalidEnvironment(String::from("production"))]);
+    }
+
+    #[test]
+    fn test_lock() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "build"
+            lock = "production-db"
+
+            [branch.production]
+
+            [branch.staging]
+            lock = "staging-db"
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert_eq!(config.lookup_branch("production").unwrap().lock,
+                   Some(String::from("production-db")));
+        assert_eq!(config.lookup_branch("staging").unwrap().lock,
+                   Some(String::from("staging-db")));
+
+        let toml = r#"
+            [branch.production]
+            method = "make"
+            task = "build"
+            lock = ""
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0, vec![Error::InvalidLock(String::from("production"))]);
+    }
+
+    #[test]
+    fn test_resolved() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "build"
+            notifiers = ["http://127.0.0.1:7231"]
+
+            [branch."release-*"]
+            task = "release"
+            environment = "production"
+            lock = "production-db"
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert_eq!(config.lookup_branch("release-1").unwrap().resolved(),
+                   ResolvedConfig {
+                       pattern: String::from("release-*"),
+                       method: String::from("makefile"),
+                       task: Some(String::from("release")),
+                       playbook: None,
+                       inventory: None,
+                       environment: Some(String::from("production")),
+                       notifiers: Some(vec![String::from("http://127.0.0.1:7231")]),
+                       lock: Some(String::from("production-db")),
+                   });
+    }
+
+    #[test]
+    fn test_check_task() {
+        let toml = r#"
+            [default]
+            method = "make"
+
+            [branch.production]
+            task = "no-such-task"
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0.len(), 1);
+        match err.0[0] {
+            Error::UnknownMakeTask(ref branch, ref make_error) => {
+                assert_eq!(branch, "production");
+                assert!(make_error.contains("No rule to make target"));
+            }
+            _ => panic!("expected UnknownMakeTask, got {:?}", err),
+        }
+        assert!(err.to_string().contains("No rule to make target"));
+
+        // Not checked until it runs
+        let toml = r#"
+            [default]
+            method = "make"
+            check_task = false
+
+            [branch.production]
+            task = "no-such-task"
+        "#;
+        assert!(RepoConfig::from_str(toml, &project_root).is_ok());
+    }
+
+    #[test]
+    fn test_makefile_location() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "echo"
+            make_dir = "make_task"
+
+            [branch.production]
+
+            [branch.staging]
+            makefile = "make_task/Makefile"
+            task = "env"
+
+            [branch.prototype]
+            make_dir = "repo_config"
+            task = "build"
+        "#;
+        let project_root = Path::new("./src/test");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert!(config.lookup_branch("production").unwrap().make_task().is_some());
+        assert!(config.lookup_branch("staging").unwrap().make_task().is_some());
+        assert!(config.lookup_branch("prototype").unwrap().make_task().is_some());
+
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "echo"
+            make_dir = "make_task"
+
+            [branch.production]
+            make_dir = "repo_config"
+
+            [branch.staging]
+            makefile = "make_task/Makefile"
+            make_dir = "make_task"
+
+            [branch.prototype]
+            makefile = "does/not/exist"
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0.len(), 3);
+        match err.0[0] {
+            // `echo` isn't in repo_config/Makefile
+            Error::UnknownMakeTask(ref branch, _) => assert_eq!(branch, "production"),
+            ref other => panic!("expected UnknownMakeTask, got {:?}", other),
+        }
+        assert_eq!(err.0[1], Error::InvalidMakefile(String::from("prototype")));
+        assert_eq!(err.0[2], Error::InvalidMakefile(String::from("staging")));
+    }
+
+    #[test]
+    fn test_workdir() {
+        let toml = r#"
+            [default]
+            method = "make"
+
+            [branch.production]
+            workdir = "make_task"
+            task = "echo"
+
+            [branch.staging]
+            task = "env"
+            makefile = "make_task/Makefile"
+        "#;
+        let project_root = Path::new("./src/test");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        let production = config.lookup_branch("production").unwrap();
+        assert_eq!(production.workdir, Some(PathBuf::from("make_task")));
+        assert_eq!(production.make_task().unwrap().workdir(), Some(Path::new("make_task")));
+        assert_eq!(config.lookup_branch("staging").unwrap().make_task().unwrap().workdir(), None);
+
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "echo"
+            check_task = false
+
+            [branch.production]
+            workdir = "../test/make_task"
+
+            [branch.staging]
+            workdir = "does-not-exist"
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0,
+                   vec![Error::InvalidWorkdir(String::from("production")),
+                        Error::InvalidWorkdir(String::from("staging"))]);
+    }
+
+    #[test]
+    fn test_make_args() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "env"
+            makefile = "make_task/Makefile"
+            make_args = ["ENV=production", "-j4"]
+
+            [branch.production]
+
+            [branch.staging]
+            make_args = ["ENV=staging"]
+
+            [branch.review]
+            make_args = []
+        "#;
+        let project_root = Path::new("./src/test");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        let args = |branch: &str| {
+            config.lookup_branch(branch).unwrap().make_task().unwrap().args().to_vec()
+        };
+        assert_eq!(args("production"), vec!["ENV=production", "-j4"]);
+        assert_eq!(args("staging"), vec!["ENV=staging"]);
+        assert!(args("review").is_empty());
+
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "echo"
+            makefile = "make_task/Makefile"
+            make_args = "ENV=production"
+
+            [branch.production]
+            make_args = [1, 2]
+            ansible_args = ["--limit", 3]
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0,
+                   vec![Error::InvalidDefaultMakeArgs,
+                        Error::InvalidMakeArgs(String::from("production")),
+                        Error::InvalidAnsibleArgs(String::from("production"))]);
+    }
+
+    #[test]
+    #[cfg(feature = "ansible")]
+    fn test_check() {
+        let toml = r#"
+            [default]
+            method = "ansible"
+            playbook = "ansible/deploy.yml"
+            inventory = "ansible/inventory/production"
+
+            [branch."plan-*"]
+            check = true
+
+            [branch.production]
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert!(config.lookup_branch("plan-production").unwrap().ansible_task().unwrap().check);
+        assert!(!config.lookup_branch("production").unwrap().ansible_task().unwrap().check);
+
+        let toml = r#"
+            [branch.production]
+            method = "make"
+            task = "build"
+            check = "please"
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0, vec![Error::InvalidCheck(String::from("production"))]);
+    }
+
+    #[test]
+    #[cfg(feature = "ansible")]
+    fn test_vault_password() {
+        let toml = r#"
+            [default]
+            method = "ansible"
+            playbook = "ansible/deploy.yml"
+            inventory = "ansible/inventory/production"
+            vault_password = "vault_password"
+
+            [branch.production]
+
+            [branch.staging]
+            vault_password = "staging-vault"
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0, vec![Error::InvalidVaultPassword(String::from("staging"))]);
+
+        let toml = toml.replace("staging-vault", "staging_vault");
+        let config = RepoConfig::from_str(&toml, &project_root).unwrap();
+        assert_eq!(config.lookup_branch("production").unwrap().ansible_task().unwrap().vault_password,
+                   Some(String::from("vault_password")));
+        assert_eq!(config.lookup_branch("staging").unwrap().ansible_task().unwrap().vault_password,
+                   Some(String::from("staging_vault")));
+    }
+
+    #[test]
+    #[cfg(feature = "ansible")]
+    fn test_vault_password_file() {
+        let toml = r#"
+            [default]
+            method = "ansible"
+            playbook = "ansible/deploy.yml"
+            inventory = "ansible/inventory/production"
+            vault_password_file = "ansible/vault-password"
+
+            [branch.production]
+
+            [branch.staging]
+            vault_password = "staging_vault"
+
+            [branch.review]
+            vault_password_file = "ansible/missing"
+
+            [branch.qa]
+            vault_password = "qa_vault"
+            vault_password_file = "ansible/vault-password"
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0,
+                   vec![Error::InvalidVaultPasswordFile(String::from("review")),
+                        Error::InvalidVaultPasswordFile(String::from("qa"))]);
+
+        let toml = toml.replace("ansible/missing", "ansible/vault-password").replace("vault_password = \"qa_vault\"", "");
+        let config = RepoConfig::from_str(&toml, &project_root).unwrap();
+        let production = config.lookup_branch("production").unwrap().ansible_task().unwrap();
+        assert_eq!(production.vault_password_file, Some(String::from("ansible/vault-password")));
+        assert_eq!(production.vault_password, None);
+        let staging = config.lookup_branch("staging").unwrap().ansible_task().unwrap();
+        assert_eq!(staging.vault_password_file, None);
+        assert_eq!(staging.vault_password, Some(String::from("staging_vault")));
+    }
+
+    #[test]
+    fn test_hooks() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "build"
+            on_failure = "./notify-ops"
+
+            [branch.production]
+            before_task = "touch maintenance"
+            after_task = "rm maintenance"
+
+            [branch.staging]
+            on_failure = "true"
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        let production = config.lookup_branch("production").unwrap();
+        assert_eq!(production.before_task, Some(Hook::new("touch maintenance")));
+        assert_eq!(production.after_task, Some(Hook::new("rm maintenance")));
+        assert_eq!(production.on_failure, Some(Hook::new("./notify-ops")));
+        let staging = config.lookup_branch("staging").unwrap();
+        assert_eq!(staging.before_task, None);
+        assert_eq!(staging.on_failure, Some(Hook::new("true")));
+
+        let toml = r#"
+            [branch.production]
+            method = "make"
+            task = "build"
+
+            [branch.production.after_task]
+            command = "./warm-caches"
+            allow_failure = true
+        "#;
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert_eq!(config.lookup_branch("production").unwrap().after_task,
+                   Some(Hook {
+                       command: String::from("./warm-caches"),
+                       allow_failure: true,
+                   }));
+
+        let toml = r#"
+            [branch.production]
+            method = "make"
+            task = "build"
+            after_task = ["rm", "maintenance"]
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0, vec![Error::InvalidHook(String::from("production"))]);
+    }
+
+    #[test]
+    fn test_allowed_exit_codes() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "build"
+
+            [branch.production]
+            allowed_exit_codes = [0, 2]
+
+            [branch.staging]
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert_eq!(config.lookup_branch("production").unwrap().allowed_exit_codes, vec![0, 2]);
+        assert_eq!(config.lookup_branch("staging").unwrap().allowed_exit_codes, vec![0]);
+
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "build"
+            allowed_exit_codes = []
+
+            [branch.production]
+            allowed_exit_codes = ["2"]
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0,
+                   vec![Error::InvalidDefaultAllowedExitCodes,
+                        Error::InvalidAllowedExitCodes(String::from("production"))]);
+    }
+
+    #[test]
+    fn test_paths() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "build"
+            paths = ["!docs/**"]
+
+            [branch.production]
+            paths = ["services/api/**", "!**/*.md"]
+
+            [branch.staging]
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert_eq!(config.lookup_branch("production").unwrap().paths,
+                   Some(vec![String::from("services/api/**"), String::from("!**/*.md")]));
+        assert_eq!(config.lookup_branch("staging").unwrap().paths,
+                   Some(vec![String::from("!docs/**")]));
+
+        let toml = r#"
+            [branch.production]
+            method = "make"
+            task = "build"
+            paths = ["!"]
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0, vec![Error::InvalidPaths(String::from("production"))]);
+    }
+
+    #[test]
+    fn test_pagerduty_severity() {
+        let toml = r#"
+            [default]
+            method = "make"
+            pagerduty_severity = "warning"
+
+            [branch.production]
+            task = "build"
+            pagerduty_severity = "critical"
+
+            [branch.staging]
+            task = "build"
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert_eq!(Some(Severity::Critical),
+                   config.lookup_branch("production").unwrap().pagerduty_severity);
+        assert_eq!(Some(Severity::Warning),
+                   config.lookup_branch("staging").unwrap().pagerduty_severity);
+
+        let toml = r#"
+            [branch.production]
+            method = "make"
+            task = "build"
+            pagerduty_severity = "apocalyptic"
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0, vec![Error::InvalidPagerDutySeverity(String::from("production"))]);
+    }
+
+    #[test]
+    #[cfg(not(feature = "ansible"))]
+    fn test_ansible_not_supported() {
+        let toml = r#"
+            [branch.production]
+            method = "ansible"
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0, vec![Error::AnsibleNotSupported]);
+    }
+
+    #[test]
+    fn test_all_errors_reported() {
+        let toml = r#"
+            [default]
+            method = "make"
+            submodules = "sure"
+
+            [branch.production]
+            task = "build"
+            pagerduty_severity = "apocalyptic"
+            clean_checkout = 1
+
+            [branch.staging]
+            method = "carrier-pigeon"
+
+            [branch.prototype]
+            task = "build"
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0,
+                   vec![Error::InvalidDefaultSubmodules,
+                        Error::InvalidPagerDutySeverity(String::from("production")),
+                        Error::InvalidCleanCheckout(String::from("production")),
+                        Error::InvalidMethod(String::from("staging"))]);
+        let message = err.to_string();
+        assert!(message.starts_with("4 errors in hookshot configuration:\n"));
+        assert!(message.contains("\n  staging: invalid branch `method`"));
+
+        // A missing method is reported once, not for every section
+        let toml = r#"
+            [branch.production]
+            task = "build"
+            pagerduty_severity = "apocalyptic"
+
+            [branch.staging]
+            task = "build"
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0, vec![Error::MissingMethod(String::from("production"))]);
+        let toml = r#"
+            [default]
+            method = "carrier-pigeon"
+
+            [branch.production]
+            task = "build"
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0, vec![Error::InvalidDefaultMethod]);
+    }
+
+    #[test]
+    fn test_error_messages() {
+        assert_eq!(Error::MissingConfiguration.to_string(),
+                   "must have at least one `branch` or `tag` entry");
+        assert_eq!(Error::MissingMethod(String::from("production")).to_string(),
+                   "could not find `method` between default and branch config");
+    }
+
+    #[test]
+    fn test_custom_method() {
+        let toml = r#"
+            [default.settings]
+            namespace = "web"
+            manifest = "k8s/staging.yaml"
+
+            [branch.master]
+            method = "kubectl"
+
+            [branch.master.settings]
+            manifest = "k8s/production.yaml"
+
+            [branch.staging]
+            method = "kubectl"
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let methods = vec![String::from("kubectl")];
+        let config = RepoConfig::from_str_with_methods(toml, &project_root, &methods).unwrap();
+
+        let master = config.lookup(RefType::branch, "master").unwrap();
+        assert_eq!(master.method, DeployMethod::Custom(String::from("kubectl")));
+        assert!(master.make_task().is_none());
+        assert_eq!(master.settings.get("manifest").unwrap(), "k8s/production.yaml");
+        assert_eq!(master.settings.get("namespace").unwrap(), "web");
+        let staging = config.lookup(RefType::branch, "staging").unwrap();
+        assert_eq!(staging.settings.get("manifest").unwrap(), "k8s/staging.yaml");
+
+        // Without the method registered it's just an unknown method
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0,
+                   vec![Error::InvalidMethod(String::from("master")),
+                        Error::InvalidMethod(String::from("staging"))]);
+
+        let toml = r#"
+            [branch.master]
+            method = "kubectl"
+
+            [branch.master.settings]
+            replicas = 3
+        "#;
+        let err = RepoConfig::from_str_with_methods(toml, &project_root, &methods).err().unwrap();
+        assert_eq!(err.0, vec![Error::InvalidSettings(String::from("master"))]);
+    }
+
+    #[test]
+    fn test_payload_template() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+            notifiers = ["https://hooks.slack.com/services/T0/B0/x"]
+            payload_template = '{"text": "{repo} {refstring}: {status}", "attempt": {attempt}}'
+
+            [branch.master]
+
+            [branch.staging]
+            payload_format = "form"
+            payload_template = "text={repo}+{refstring}+{status}"
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        let master = config.lookup_branch("master").unwrap();
+        assert_eq!(master.payload_format, PayloadFormat::Json);
+        assert_eq!(master.payload_template.as_ref().unwrap(),
+                   r#"{"text": "{repo} {refstring}: {status}", "attempt": {attempt}}"#);
+        let staging = config.lookup_branch("staging").unwrap();
+        assert_eq!(staging.payload_format, PayloadFormat::Form);
+        assert_eq!(staging.notify_log_lines, DEFAULT_NOTIFY_LOG_LINES);
+        assert_eq!(config, RepoConfig::from_str(&config.to_toml(), &project_root).unwrap());
+
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+            payload_format = "xml"
+
+            [branch.master]
+            payload_template = '{"text": "{repo}'
+
+            [branch.staging]
+            payload_template = '{"id": {task_id}, "attempt": {attempt}}'
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0,
+                   vec![Error::InvalidDefaultPayloadFormat,
+                        Error::InvalidPayloadTemplate(String::from("master")),
+                        Error::InvalidPayloadTemplate(String::from("staging"))]);
+    }
+
+    #[test]
+    fn test_notify_log_lines() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+            notify_log_lines = 50
+
+            [branch.master]
+
+            [branch.quiet]
+            notify_log_lines = 0
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert_eq!(config.lookup_branch("master").unwrap().notify_log_lines, 50);
+        assert_eq!(config.lookup_branch("quiet").unwrap().notify_log_lines, 0);
+
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+
+            [branch.master]
+            notify_log_lines = -1
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0, vec![Error::InvalidNotifyLogLines(String::from("master"))]);
+    }
+
+    #[test]
+    fn test_retries() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+            retries = 2
+
+            [branch.master]
+
+            [branch.staging]
+            retries = 0
+            retry_delay = "5m"
+
+            [branch.dev]
+            retry_delay = 10
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        let master = config.lookup_branch("master").unwrap();
+        assert_eq!((master.retries, master.retry_delay), (2, DEFAULT_RETRY_DELAY));
+        let staging = config.lookup_branch("staging").unwrap();
+        assert_eq!((staging.retries, staging.retry_delay), (0, 300));
+        let dev = config.lookup_branch("dev").unwrap();
+        assert_eq!((dev.retries, dev.retry_delay), (2, 10));
+
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+            retry_delay = "soon"
+
+            [branch.master]
+            retries = -1
+
+            [branch.staging]
+            retry_delay = "1d"
+
+            [branch.dev]
+            retry_delay = "25h"
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0,
+                   vec![Error::InvalidDefaultRetryDelay,
+                        Error::InvalidRetryDelay(String::from("dev")),
+                        Error::InvalidRetries(String::from("master")),
+                        Error::InvalidRetryDelay(String::from("staging"))]);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+            timeout = "30m"
+
+            [branch.master]
+
+            [branch.staging]
+            timeout = 90
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert_eq!(config.lookup_branch("master").unwrap().timeout, Some(1800));
+        assert_eq!(config.lookup_branch("staging").unwrap().timeout, Some(90));
+
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+
+            [branch.master]
+
+            [branch.staging]
+            timeout = 0
+
+            [branch.dev]
+            timeout = "8d"
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0,
+                   vec![Error::InvalidTimeout(String::from("dev")),
+                        Error::InvalidTimeout(String::from("staging"))]);
+
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+
+            [branch.master]
+        "#;
+        assert_eq!(RepoConfig::from_str(toml, &project_root).unwrap().lookup_branch("master").unwrap().timeout,
+                   None);
+    }
+
+    #[test]
+    fn test_semver() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+
+            [tag.legacy]
+            semver = ">=1, <2"
+
+            [tag.current]
+            semver = "^2"
+
+            [tag."v*"]
+
+            [tag."*"]
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert_eq!(config.lookup_tag("v1.4.2").unwrap().pattern, "legacy");
+        assert_eq!(config.lookup_tag("1.0.0").unwrap().pattern, "legacy");
+        assert_eq!(config.lookup_tag("v2.0.1").unwrap().pattern, "current");
+        assert_eq!(config.lookup_tag("v3.0.0").unwrap().pattern, "v*");
+        assert_eq!(config.lookup_tag("nightly").unwrap().pattern, "*");
+        // The section names are only labels
+        assert_eq!(config.lookup_tag("legacy").unwrap().pattern, "*");
+        assert!(config.unreachable_patterns().is_empty());
+        // Release candidates of 2.0.0 aren't 1.x
+        assert_eq!(config.lookup_tag("v2.0.0-rc.1").unwrap().pattern, "v*");
+
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+
+            [tag.legacy]
+            semver = ">=1, <2"
+
+            [tag.backports]
+            semver = "~1.9"
+        "#;
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert_eq!(config.lookup_tag("v1.9.3").unwrap().pattern, "backports");
+        assert_eq!(config.unreachable_patterns(),
+                   vec![Warning::OverlappingSemver(String::from("legacy"), String::from("backports"))]);
+
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+
+            [branch.master]
+            semver = "^1"
+
+            [tag.legacy]
+            semver = "one-ish"
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0,
+                   vec![Error::InvalidSemver(String::from("legacy")),
+                        Error::SemverOnBranch(String::from("master"))]);
+    }
+
+    #[test]
+    fn test_regex_patterns() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+
+            [branch."re:^feature/JIRA-\\d+$"]
+
+            [branch."feature/*"]
+
+            [branch."release.*"]
+
+            [branch."*"]
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert_eq!(config.lookup_branch("feature/JIRA-123").unwrap().pattern, "re:^feature/JIRA-\\d+$");
+        assert_eq!(config.lookup_branch("feature/JIRA-123-wip").unwrap().pattern, "feature/*");
+        // Globs only treat `*` specially
+        assert_eq!(config.lookup_branch("release-1").unwrap().pattern, "*");
+        assert_eq!(config.lookup_branch("release.1").unwrap().pattern, "release.*");
+        assert!(config.unreachable_patterns().is_empty());
+
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+
+            [branch."re:^(feature"]
+        "#;
+        let err = RepoConfig::from_str(toml, &project_root).err().unwrap();
+        assert_eq!(err.0.len(), 1);
+        match err.0[0] {
+            Error::InvalidPattern(ref pattern, _) => assert_eq!(pattern, "re:^(feature"),
+            ref error => panic!("expected an invalid pattern, got {:?}", error),
+        }
+    }
+
+    #[test]
+    fn test_env_vars() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+            notifiers = ["http://${notify_host}/hooks/${git_repo_name}"]
+
+            [branch.master]
+            environment = "${stage}"
+
+            [branch.master.settings]
+            replicas = "${replicas}"
+            literal = "$${stage}"
+            price = "$5"
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let mut vars = Environment::new();
+        vars.insert(String::from("notify_host"), String::from("chat.example.org"));
+        vars.insert(String::from("git_repo_name"), String::from("hookshot"));
+        vars.insert(String::from("stage"), String::from("production"));
+        vars.insert(String::from("replicas"), String::from("3"));
+        let config = RepoConfig::from_str_with_env(toml, &project_root, &[], Some(&vars)).unwrap();
+        let master = config.lookup_branch("master").unwrap();
+        assert_eq!(master.notifiers,
+                   Some(vec![String::from("http://chat.example.org/hooks/hookshot")]));
+        assert_eq!(master.environment, Some(String::from("production")));
+        assert_eq!(master.settings["replicas"], "3");
+        assert_eq!(master.settings["literal"], "${stage}");
+        assert_eq!(master.settings["price"], "$5");
+
+        // Without variables the placeholders stay
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        assert_eq!(config.lookup_branch("master").unwrap().environment,
+                   Some(String::from("${stage}")));
+
+        vars.remove("stage");
+        vars.remove("replicas");
+        let err = RepoConfig::from_str_with_env(toml, &project_root, &[], Some(&vars)).err().unwrap();
+        assert_eq!(err.0,
+                   vec![Error::UndefinedVariable(String::from("stage")),
+                        Error::UndefinedVariable(String::from("replicas"))]);
+        assert_eq!(format!("{}", err.0[0]),
+                   "the config refers to a variable that isn't in the task environment (`${stage}`)");
+
+        // Like `check-config` sees it, make isn't asked about a task it
+        // can only know once the placeholder is filled in
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy-${stage}"
+        "#;
+        let (config, warnings) = RepoConfig::lint(toml, &project_root);
+        assert!(config.is_some());
+        assert!(!warnings.iter().any(|warning| match *warning {
+            Warning::Invalid(_) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn test_parse_delay() {
+        assert_eq!(super::parse_delay("30s"), Some(30));
+        assert_eq!(super::parse_delay("5m"), Some(300));
+        assert_eq!(super::parse_delay("1h"), Some(3600));
+        assert_eq!(super::parse_delay("45"), Some(45));
+        assert_eq!(super::parse_delay("m"), None);
+        assert_eq!(super::parse_delay("1.5h"), None);
+        assert_eq!(super::parse_delay("18446744073709551615h"), None);
+    }
+
+    #[test]
+    fn test_to_toml() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "deploy"
+            notifiers = ["http://example.org"]
+
+            [branch.master]
+            before_task = "make clean"
+            allowed_exit_codes = [0, 2]
+
+            [branch.master.on_failure]
+            command = "make page"
+            allow_failure = true
+
+            [tag."v*"]
+            task = "build"
+            makefile = "Makefile"
+            submodules = true
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let config = RepoConfig::from_str(toml, &project_root).unwrap();
+        let encoded = config.to_toml();
+        assert_eq!(RepoConfig::from_str(&encoded, &project_root).unwrap(), config);
+    }
+
+    #[test]
+    fn test_builder() {
+        let project_root = Path::new("./src/test/repo_config");
+        let task = MakeTask::unchecked(project_root, Location::Root, "deploy");
+        let mut master = Config::make("master", task.clone());
+        master.submodules = true;
+        let config = RepoConfig::new(project_root)
+                         .with_branch(master)
+                         .with_tag(Config::make("v*", task));
+
+        assert!(config.lookup_branch("master").unwrap().submodules);
+        assert_eq!(config.lookup_tag("v1.0.0").unwrap().pattern, "v*");
+        assert!(config.lookup_branch("staging").is_none());
+        assert_eq!(RepoConfig::from_str(&config.to_toml(), &project_root).unwrap(), config);
+    }
+
+    #[test]
+    fn test_lint() {
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "build"
+            submodules = true
+
+            [branch."feature-*"]
+            submodules = false
+
+            [branch."feature-*-*"]
+            submodules = false
+
+            [branch.production]
+            task = "deploy"
+            submodules = false
+        "#;
+        let project_root = Path::new("./src/test/repo_config");
+        let (config, warnings) = RepoConfig::lint(toml, &project_root);
+        assert!(config.is_some());
+        assert_eq!(warnings,
+                   vec![Warning::ShadowedPattern(RefType::branch,
+                                                 String::from("feature-*-*"),
+                                                 String::from("feature-*")),
+                        Warning::UnusedDefault(String::from("submodules"))]);
+        assert_eq!(warnings[0].to_string(),
+                   "branch.\"feature-*-*\" never matches, every branch it matches is matched by \"feature-*\" first");
+
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "build"
+
+            [tag."v*"]
+            task = "release"
+
+            [tag."v**"]
+            task = "prerelease"
+
+            [tag."**"]
+
+            [tag."*"]
+            task = "other"
+        "#;
+        let (config, warnings) = RepoConfig::lint(toml, &project_root);
+        assert!(config.is_some());
+        assert_eq!(warnings,
+                   vec![Warning::DuplicatePattern(RefType::tag, String::from("v**"), String::from("v*")),
+                        Warning::DuplicatePattern(RefType::tag, String::from("*"), String::from("**"))]);
+
+        let toml = r#"
+            [default]
+            method = "make"
+
+            [branch.production]
+            notifiers = ["http://example.org"]
+        "#;
+        let (config, warnings) = RepoConfig::lint(toml, &project_root);
+        assert!(config.is_none());
+        assert_eq!(warnings,
+                   vec![Warning::Invalid(Error::InvalidMakeTaskConfig),
+                        Warning::NotifiersWithoutTask(RefType::branch, String::from("production"))]);
+
+        let toml = r#"
+            [default]
+            method = "make"
+            task = "build"
+
+            [branch."release-[0-9]*"]
+
+            [branch."re:^hotfix-[0-9]+$"]
+
+            [tag."v1.*"]
+        "#;
+        let (config, warnings) = RepoConfig::lint(toml, &project_root);
+        assert!(config.is_some());
+        assert_eq!(warnings,
+                   vec![Warning::RegexInGlob(RefType::branch, String::from("release-[0-9]*")),
+                        Warning::RegexInGlob(RefType::tag, String::from("v1.*"))]);
+    }
 }
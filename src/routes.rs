@@ -0,0 +1,247 @@
+//! The server's route table.
+//!
+//! `Routes` wraps a `Router`, remembering every route it registers so
+//! requests that don't match anything can get a useful answer: a JSON `404`
+//! listing the known routes, or a JSON `405` with an `Allow` header if the
+//! path exists but not for that method. `OPTIONS` requests for a known path
+//! are answered with the methods it allows.
+//!
+//! Groups of routes turned off in `[endpoints]` are never registered, so to
+//! clients they don't exist any more than a mistyped path does. The group
+//! of every route is kept, for the headers of `[headers]` groups.
+//!
+//! Errors use the same envelope everywhere:
+//!
+//! ```json
+//! {"error": {"status": 405, "message": "method not allowed", "allowed_methods": ["GET"]}}
+//! ```
+
+use hyper::method::Method;
+use iron::headers::{Allow, Connection};
+use iron::mime::Mime;
+use iron::modifiers::Header;
+use iron::status;
+use iron::{AfterMiddleware, Chain, Handler, IronError, IronResult, Request, Response};
+use router::{NoRoute, Router};
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+
+pub struct Routes {
+    router: Router,
+    table: Vec<(Method, &'static str)>,
+    groups: Vec<(Method, &'static str, Option<&'static str>)>,
+    enabled: bool,
+    group: Option<&'static str>,
+}
+
+impl Routes {
+    pub fn new() -> Routes {
+        Routes {
+            router: Router::new(),
+            table: vec![],
+            groups: vec![],
+            enabled: true,
+            group: None,
+        }
+    }
+
+    /// Until the next call, only register routes if `enabled`, outside of
+    /// any group. Routes registered while disabled are dropped.
+    pub fn enable(&mut self, enabled: bool) -> &mut Routes {
+        self.enabled = enabled;
+        self.group = None;
+        self
+    }
+
+    /// Until the next call, register routes as part of `group`, and only if
+    /// `enabled`.
+    pub fn group(&mut self, group: &'static str, enabled: bool) -> &mut Routes {
+        self.enabled = enabled;
+        self.group = Some(group);
+        self
+    }
+
+    /// The group of every route registered so far.
+    pub fn groups(&self) -> RouteGroups {
+        RouteGroups { table: self.groups.clone() }
+    }
+
+    pub fn get<H: Handler>(&mut self, glob: &'static str, handler: H) -> &mut Routes {
+        self.route(Method::Get, glob, handler)
+    }
+
+    pub fn post<H: Handler>(&mut self, glob: &'static str, handler: H) -> &mut Routes {
+        self.route(Method::Post, glob, handler)
+    }
+
+    pub fn route<H: Handler>(&mut self, method: Method, glob: &'static str, handler: H) -> &mut Routes {
+        if !self.enabled {
+            return self;
+        }
+        self.router.route(method.clone(), glob, handler);
+        self.table.push((method.clone(), glob));
+        self.groups.push((method, glob, self.group));
+        self
+    }
+
+    /// The handler to serve: the router followed by the fallback for
+    /// requests it couldn't route.
+    pub fn into_handler(self) -> Chain {
+        let mut chain = Chain::new(self.router);
+        chain.link_after(Fallback { table: self.table });
+        chain
+    }
+}
+
+/// Which group each route is in, see `Routes::group`.
+#[derive(Debug, Clone, Default)]
+pub struct RouteGroups {
+    table: Vec<(Method, &'static str, Option<&'static str>)>,
+}
+
+impl RouteGroups {
+    /// The group of the route for a request. Requests without a route for
+    /// their method, which get a `405`, are in the group of a route for
+    /// their path. `None` for routes outside of the groups and paths
+    /// without a route.
+    pub fn find(&self, method: &Method, path: &[&str]) -> Option<&'static str> {
+        let route = self.table
+                        .iter()
+                        .find(|&&(ref route_method, glob, _)| route_method == method && matches(glob, path))
+                        .or_else(|| self.table.iter().find(|&&(_, glob, _)| matches(glob, path)));
+        route.and_then(|&(_, _, group)| group)
+    }
+}
+
+/// Whether a route glob like `/tasks/:uuid` matches a request path.
+fn matches(glob: &str, path: &[&str]) -> bool {
+    let segments = glob.split('/').filter(|s| !s.is_empty()).collect::<Vec<_>>();
+    segments.len() == path.len() &&
+    segments.iter().zip(path.iter()).all(|(segment, part)| {
+        segment.starts_with(':') || segment == part
+    })
+}
+
+/// Methods with a route for the path.
+fn allowed_methods(table: &[(Method, &'static str)], path: &[&str]) -> Vec<Method> {
+    let mut methods: Vec<Method> = vec![];
+    for &(ref method, glob) in table {
+        if matches(glob, path) && !methods.contains(method) {
+            methods.push(method.clone());
+        }
+    }
+    methods
+}
+
+/// A response in the error envelope. `extra` fields are added next to the
+/// status and message.
+pub fn error_response(status: status::Status,
+                      message: &str,
+                      extra: BTreeMap<String, Json>)
+                      -> Response {
+    let mut error = extra;
+    error.insert(String::from("status"), Json::U64(status.to_u16() as u64));
+    error.insert(String::from("message"), message.to_json());
+    let mut body = BTreeMap::new();
+    body.insert(String::from("error"), Json::Object(error));
+
+    let content_type = "application/json".parse::<Mime>().unwrap();
+    Response::with((Header(Connection::close()),
+                    content_type,
+                    status,
+                    Json::Object(body).to_string()))
+}
+
+struct Fallback {
+    table: Vec<(Method, &'static str)>,
+}
+
+impl AfterMiddleware for Fallback {
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        if !err.error.is::<NoRoute>() {
+            return Err(err);
+        }
+
+        let path = req.url.path.iter().map(|s| &s[..]).filter(|s| !s.is_empty()).collect::<Vec<_>>();
+        let mut allowed = allowed_methods(&self.table, &path);
+
+        if allowed.is_empty() {
+            let routes = self.table
+                             .iter()
+                             .map(|&(ref method, glob)| format!("{} {}", method, glob).to_json())
+                             .collect::<Vec<_>>();
+            let mut extra = BTreeMap::new();
+            extra.insert(String::from("routes"), Json::Array(routes));
+            return Ok(error_response(status::NotFound, "not found", extra));
+        }
+
+        allowed.push(Method::Options);
+        if req.method == Method::Options {
+            return Ok(Response::with((Header(Connection::close()),
+                                      Header(Allow(allowed)),
+                                      status::Ok)));
+        }
+
+        let mut extra = BTreeMap::new();
+        extra.insert(String::from("allowed_methods"),
+                     Json::Array(allowed.iter().map(|m| m.to_string().to_json()).collect()));
+        let mut response = error_response(status::MethodNotAllowed, "method not allowed", extra);
+        response.headers.set(Allow(allowed));
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{matches, allowed_methods, Routes};
+    use iron::{status, IronResult, Request, Response};
+    use hyper::method::Method;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("/health", &["health"]));
+        assert!(matches("/tasks/:uuid", &["tasks", "abc"]));
+        assert!(matches("/tasks/:uuid/status", &["tasks", "abc", "status"]));
+        assert!(!matches("/tasks/:uuid", &["tasks"]));
+        assert!(!matches("/tasks/:uuid", &["tasks", "abc", "status"]));
+        assert!(!matches("/history/:owner/:repo/:ref", &["tasks", "a", "b", "c"]));
+    }
+
+    #[test]
+    fn test_allowed_methods() {
+        let table = vec![(Method::Get, "/tasks/:uuid/status"),
+                         (Method::Post, "/tasks/status"),
+                         (Method::Post, "/tasks"),
+                         (Method::Get, "/tasks/:uuid")];
+        assert_eq!(allowed_methods(&table, &["tasks", "status"]),
+                   vec![Method::Post, Method::Get]);
+        assert_eq!(allowed_methods(&table, &["tasks"]), vec![Method::Post]);
+        assert!(allowed_methods(&table, &["nope"]).is_empty());
+    }
+
+    #[test]
+    fn test_groups() {
+        fn handler(_: &mut Request) -> IronResult<Response> {
+            Ok(Response::with(status::Ok))
+        }
+        let mut routes = Routes::new();
+        routes.get("/health", handler);
+        routes.group("history", true);
+        routes.get("/tasks/:uuid", handler);
+        routes.post("/tasks/status", handler);
+        routes.group("admin", false);
+        routes.get("/locks", handler);
+        routes.enable(true);
+        routes.post("/tasks", handler);
+
+        let groups = routes.groups();
+        assert_eq!(groups.find(&Method::Get, &["tasks", "abc"]), Some("history"));
+        assert_eq!(groups.find(&Method::Post, &["tasks", "status"]), Some("history"));
+        // A 405 for a path in the group
+        assert_eq!(groups.find(&Method::Delete, &["tasks", "abc"]), Some("history"));
+        assert_eq!(groups.find(&Method::Post, &["tasks"]), None);
+        assert_eq!(groups.find(&Method::Get, &["health"]), None);
+        // Turned off, so not a route
+        assert_eq!(groups.find(&Method::Get, &["locks"]), None);
+    }
+}
@@ -0,0 +1,224 @@
+//! Deploys that run at fixed times.
+//!
+//! Every `[[schedule]]` entry in the server config names a ref and a cron
+//! expression. The scheduler wakes up at the start of every minute, reads the
+//! entries from the running config (so a reload changes the schedule right
+//! away) and hands every entry that is due to a callback, which queues the
+//! deploy like any other task. Times are in UTC.
+
+use chrono::{DateTime, Datelike, Timelike, UTC};
+use reload::SharedConfig;
+use rustc_serialize::{Encodable, Encoder};
+use std::thread::{self, JoinHandle};
+
+/// A parsed five field cron expression: minute, hour, day of month, month
+/// and day of week. Fields can be `*`, a number, a range like `1-5`, a list
+/// like `1,15` and any of those with a step like `*/15`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    expression: String,
+    minutes: Vec<u32>,
+    hours: Vec<u32>,
+    days_of_month: Vec<u32>,
+    months: Vec<u32>,
+    /// 0 is Sunday, 7 is accepted as Sunday too.
+    days_of_week: Vec<u32>,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+fn parse_number(s: &str, min: u32, max: u32) -> Option<u32> {
+    match s.parse::<u32>() {
+        Ok(n) if n >= min && n <= max => Some(n),
+        _ => None,
+    }
+}
+
+/// Every value a field matches, in order.
+fn parse_field(field: &str, min: u32, max: u32) -> Option<Vec<u32>> {
+    let mut values = vec![];
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            None => (part, None),
+            Some(slash) => match part[slash + 1..].parse::<u32>() {
+                Ok(step) if step > 0 => (&part[..slash], Some(step)),
+                _ => return None,
+            },
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else {
+            match range.find('-') {
+                Some(dash) => {
+                    match (parse_number(&range[..dash], min, max),
+                           parse_number(&range[dash + 1..], min, max)) {
+                        (Some(start), Some(end)) if start <= end => (start, end),
+                        _ => return None,
+                    }
+                }
+                // `5/15` means every 15 starting at 5
+                None => match (parse_number(range, min, max), step) {
+                    (Some(start), Some(_)) => (start, max),
+                    (Some(start), None) => (start, start),
+                    _ => return None,
+                },
+            }
+        };
+        let step = step.unwrap_or(1);
+        let mut value = start;
+        while value <= end {
+            values.push(value);
+            value += step;
+        }
+    }
+    values.sort();
+    values.dedup();
+    Some(values)
+}
+
+impl Cron {
+    pub fn parse(expression: &str) -> Option<Cron> {
+        let fields = expression.split_whitespace().collect::<Vec<&str>>();
+        if fields.len() != 5 {
+            return None;
+        }
+        let minutes = parse_field(fields[0], 0, 59);
+        let hours = parse_field(fields[1], 0, 23);
+        let days_of_month = parse_field(fields[2], 1, 31);
+        let months = parse_field(fields[3], 1, 12);
+        let days_of_week = parse_field(fields[4], 0, 7).map(|days| {
+            let mut days = days.into_iter().map(|day| day % 7).collect::<Vec<u32>>();
+            days.sort();
+            days.dedup();
+            days
+        });
+        match (minutes, hours, days_of_month, months, days_of_week) {
+            (Some(minutes), Some(hours), Some(days_of_month), Some(months), Some(days_of_week)) => {
+                Some(Cron {
+                    expression: String::from(expression),
+                    minutes: minutes,
+                    hours: hours,
+                    days_of_month: days_of_month,
+                    months: months,
+                    days_of_week: days_of_week,
+                    any_day_of_month: fields[2] == "*",
+                    any_day_of_week: fields[4] == "*",
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// The expression as it was written in the config.
+    pub fn expression(&self) -> &str {
+        &self.expression
+    }
+
+    /// Whether the expression matches the minute `time` falls in. Like cron,
+    /// when both the day of month and the day of week are restricted either
+    /// one matching is enough.
+    pub fn matches(&self, time: &DateTime<UTC>) -> bool {
+        let day_of_month = self.days_of_month.contains(&time.day());
+        let day_of_week = self.days_of_week.contains(&time.weekday().num_days_from_sunday());
+        let day = match (self.any_day_of_month, self.any_day_of_week) {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        };
+        day && self.minutes.contains(&time.minute()) && self.hours.contains(&time.hour()) &&
+        self.months.contains(&time.month())
+    }
+}
+
+/// One `[[schedule]]` entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleEntry {
+    pub owner: String,
+    pub name: String,
+    pub refstring: String,
+    pub cron: Cron,
+}
+
+// Encoded with the keys of a `[[schedule]]` entry.
+impl Encodable for ScheduleEntry {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_struct("ScheduleEntry", 3, |s| {
+            try!(s.emit_struct_field("repo", 0, |s| s.emit_str(&format!("{}/{}", self.owner, self.name))));
+            try!(s.emit_struct_field("refstring", 1, |s| self.refstring.encode(s)));
+            s.emit_struct_field("cron", 2, |s| s.emit_str(self.cron.expression()))
+        })
+    }
+}
+
+/// Call `on_due` with every entry that is due, once a minute, on a new
+/// thread.
+pub fn start<F>(config: SharedConfig, on_due: F) -> JoinHandle<()>
+    where F: 'static + Fn(&ScheduleEntry) + Send
+{
+    thread::spawn(move || {
+        let mut last_minute = None;
+        loop {
+            let now = UTC::now();
+            let minute = now.timestamp() / 60;
+            // Waking up a little early must not run the same minute twice
+            if last_minute != Some(minute) {
+                last_minute = Some(minute);
+                let entries = config.read().unwrap().schedule.clone();
+                for entry in entries.iter().filter(|entry| entry.cron.matches(&now)) {
+                    on_due(entry);
+                }
+            }
+            thread::sleep_ms((60 - now.second()) * 1000);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cron;
+    use chrono::{TimeZone, UTC};
+
+    #[test]
+    fn test_parse() {
+        let cron = Cron::parse("0 4 * * *").unwrap();
+        assert_eq!(cron.expression(), "0 4 * * *");
+        assert_eq!(cron.minutes, vec![0]);
+        assert_eq!(cron.hours, vec![4]);
+        assert_eq!(cron.days_of_month.len(), 31);
+
+        let cron = Cron::parse("*/15 9-17 1,15 */6 1-5").unwrap();
+        assert_eq!(cron.minutes, vec![0, 15, 30, 45]);
+        assert_eq!(cron.hours, vec![9, 10, 11, 12, 13, 14, 15, 16, 17]);
+        assert_eq!(cron.days_of_month, vec![1, 15]);
+        assert_eq!(cron.months, vec![1, 7]);
+        assert_eq!(cron.days_of_week, vec![1, 2, 3, 4, 5]);
+
+        assert_eq!(Cron::parse("5/20 * * * 7").unwrap().minutes, vec![5, 25, 45]);
+        assert_eq!(Cron::parse("0 0 * * 7").unwrap().days_of_week, vec![0]);
+
+        assert!(Cron::parse("0 4 * *").is_none());
+        assert!(Cron::parse("60 4 * * *").is_none());
+        assert!(Cron::parse("0 4 0 * *").is_none());
+        assert!(Cron::parse("0 5-4 * * *").is_none());
+        assert!(Cron::parse("*/0 * * * *").is_none());
+        assert!(Cron::parse("@daily").is_none());
+    }
+
+    #[test]
+    fn test_matches() {
+        let nightly = Cron::parse("0 4 * * *").unwrap();
+        assert!(nightly.matches(&UTC.ymd(2016, 1, 4).and_hms(4, 0, 30)));
+        assert!(!nightly.matches(&UTC.ymd(2016, 1, 4).and_hms(4, 1, 0)));
+        assert!(!nightly.matches(&UTC.ymd(2016, 1, 4).and_hms(16, 0, 0)));
+
+        // 2016-01-04 is a Monday
+        let weekdays = Cron::parse("30 9 * * 1-5").unwrap();
+        assert!(weekdays.matches(&UTC.ymd(2016, 1, 4).and_hms(9, 30, 0)));
+        assert!(!weekdays.matches(&UTC.ymd(2016, 1, 3).and_hms(9, 30, 0)));
+
+        // Either day field matching is enough when both are restricted
+        let either = Cron::parse("0 0 1 * 0").unwrap();
+        assert!(either.matches(&UTC.ymd(2016, 2, 1).and_hms(0, 0, 0)));
+        assert!(either.matches(&UTC.ymd(2016, 1, 3).and_hms(0, 0, 0)));
+        assert!(!either.matches(&UTC.ymd(2016, 1, 4).and_hms(0, 0, 0)));
+    }
+}
@@ -0,0 +1,2293 @@
+//! The hookshot server: the webhook receiver, the task endpoints and
+//! everything running behind them.
+//!
+//! `Server` is what `hookshot` runs. Other programs can embed it to receive
+//! webhooks and run tasks themselves, and add routes of their own next to
+//! the built-in ones.
+
+use ansi;
+use auth::ReadAuth;
+use chaos;
+use compare;
+use cors::CorsHeaders;
+use deploy_task::{DeployTask, Shared};
+use extra_headers::ExtraHeaders;
+use git::{self, GitRepo, CloneProtocol, Transfer};
+use headers::{XHubSignature, XHubSignature256, XSignature, XCorrelationId, XGitHubDelivery, XHookshotTimestamp,
+              Prefer, RetryAfter};
+use health;
+use history::{TaskHistory, TaskRecord, TaskStatus, SharedHistory, Trigger};
+use history;
+use iron::headers::{Connection, Location};
+use janitor::Janitor;
+use maintenance::{Maintenance, MaintenanceChange, MaintenanceMode, SharedMaintenance};
+use logging;
+use iron::mime::Mime;
+use iron::modifiers::Header;
+use iron::status;
+use iron::{Handler, Iron, Request, Response};
+use message::{RefType, SimpleMessage, GitHubMessage, WarmRequest, skip_directive, valid_correlation_id};
+use metrics::{Metrics, SharedMetrics};
+use payloads::{self, Payload, ParseResult};
+use percent;
+use rustc_serialize::json;
+use router::Router;
+use reload::{self, SharedConfig};
+use replay::{self, ReplayGuard, SharedReplayGuard};
+use repo_config::MAX_RETRY_DELAY;
+use routes::{self, Routes};
+use schedule;
+use process::{self, OrphanAction, ProcessGroups};
+use quarantine::{self, Quarantine, QuarantineChange, SharedQuarantine};
+use queue_graph::QueueGraph;
+use queue_store::{self, QueuedTask};
+use server_config::{AcceptedFormat, ServerConfig, Environment};
+use signature::Signature;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use task_factory::{TaskFactory, TaskRegistry};
+use task_manager::{self, Locks, Observer, QueueEvent, QueueKey, TaskManager};
+use template;
+use uuid::Uuid;
+use workspace::CheckoutPath;
+
+const ENV_INSECURE_KEY: &'static str = "HOOKSHOT_INSECURE";
+const BATCH_STATUS_LIMIT: usize = 100;
+const DEFAULT_WAIT_TIMEOUT: u64 = 300;
+const MAX_WAIT_TIMEOUT: u64 = 3600;
+
+/// How to respond once a task has been scheduled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResponseMode {
+    /// `202 Accepted` with a link to the task log. The default.
+    Async,
+    /// `303 See Other` to the task status.
+    Redirect,
+    /// Wait up to this many seconds for the task to finish and respond with
+    /// its status, or `303 See Other` to the status if it is still going.
+    Wait(u64),
+}
+
+struct TaskStatusPrinter {
+    task_id: Uuid,
+    correlation_id: Option<String>,
+}
+impl TaskStatusPrinter {
+    fn new(task_id: Uuid) -> TaskStatusPrinter {
+        TaskStatusPrinter { task_id: task_id, correlation_id: None }
+    }
+    fn for_task(task: &DeployTask) -> TaskStatusPrinter {
+        TaskStatusPrinter { task_id: task.id, correlation_id: task.correlation_id.clone() }
+    }
+    fn print<T: AsRef<str> + Display>(&self, msg: T) {
+        match self.correlation_id {
+            Some(ref correlation_id) => info!(format!("{} {}", self.task_id, correlation_id), "{}", msg),
+            None => info!(self.task_id, "{}", msg),
+        }
+    }
+}
+
+/// Whether hookshot runs without checking signatures, because
+/// `HOOKSHOT_INSECURE` is set to `true`, `t` or `1`.
+pub fn skip_signature_check() -> bool {
+    match env::var(ENV_INSECURE_KEY) {
+        Ok(ref value) if value == "true" || value == "t" || value == "1" => true,
+        _ => false,
+    }
+}
+
+/// The body of a `202 Accepted` with `accepted_format = "json"`.
+#[derive(RustcEncodable)]
+struct Accepted<'a> {
+    id: &'a str,
+    location: &'a str,
+    status: &'a str,
+    queue_position: usize,
+    /// The project of a monorepo the task deploys.
+    project: Option<&'a str>,
+}
+
+#[derive(RustcEncodable)]
+struct ReloadReport {
+    /// Settings that changed but only take effect after a restart.
+    restart_required: Vec<&'static str>,
+}
+
+fn log_reload(restart_required: &[&'static str]) {
+    info!("reload", "reloaded config");
+    if !restart_required.is_empty() {
+        warn!("reload",
+              "restart hookshot for changes to {} to take effect",
+              restart_required.join(", "));
+    }
+}
+
+fn json_response(status: status::Status, body: String) -> Response {
+    let content_type = "application/json".parse::<Mime>().unwrap();
+    Response::with((Header(Connection::close()), content_type, status, body))
+}
+
+/// Whether `repo` looks like `owner/name`.
+fn valid_repo(repo: &str) -> bool {
+    let parts = repo.split('/').collect::<Vec<&str>>();
+    parts.len() == 2 && !parts[0].is_empty() && !parts[1].is_empty()
+}
+
+/// The `:owner`, `:repo` and `:ref` of a route, percent decoded. Anything
+/// that doesn't decode is taken as it is.
+fn ref_params(req: &Request) -> (String, String, String) {
+    let params = req.extensions.get::<Router>().unwrap();
+    let param = |name: &str| {
+        let raw = params.find(name).unwrap_or("");
+        percent::decode(raw).unwrap_or(String::from(raw))
+    };
+    (param("owner"), param("repo"), param("ref"))
+}
+
+/// Find the value of a query string parameter. Values aren't percent decoded.
+fn query_param(req: &Request, name: &str) -> Option<String> {
+    let query = match req.url.query {
+        Some(ref query) => query,
+        None => return None,
+    };
+    query.split('&')
+         .filter_map(|pair| {
+             let mut parts = pair.splitn(2, '=');
+             match (parts.next(), parts.next()) {
+                 (Some(key), value) if key == name => Some(String::from(value.unwrap_or(""))),
+                 _ => None,
+             }
+         })
+         .next()
+}
+
+/// Parse the number of seconds to wait for a task, capped at the maximum.
+fn parse_wait(value: &str) -> Option<u64> {
+    match value.trim().parse::<u64>() {
+        Ok(seconds) if seconds <= MAX_WAIT_TIMEOUT => Some(seconds),
+        _ => None,
+    }
+}
+
+/// Parse a yes/no query parameter: `1` and `true` are yes, `0` and `false`
+/// no. A parameter without a value, like `?redirect`, is yes too.
+fn parse_flag(value: &str) -> Option<bool> {
+    match value.trim() {
+        "" | "1" | "true" => Some(true),
+        "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Work out how the client wants the trigger response. Clients can ask to
+/// wait for the task with a `wait=<seconds>` query parameter or a
+/// `Prefer: wait=<seconds>` header, or for a redirect to the status with a
+/// `redirect` query parameter.
+fn response_mode(req: &Request) -> Result<ResponseMode, Response> {
+    let invalid_wait = Response::with((Header(Connection::close()),
+                                       status::BadRequest,
+                                       format!("wait must be a number of seconds no greater than {}",
+                                               MAX_WAIT_TIMEOUT)));
+    if let Some(wait) = query_param(req, "wait") {
+        return match parse_wait(&wait) {
+            Some(seconds) => Ok(ResponseMode::Wait(seconds)),
+            None => Err(invalid_wait),
+        };
+    }
+    if let Some(redirect) = query_param(req, "redirect") {
+        match parse_flag(&redirect) {
+            Some(true) => return Ok(ResponseMode::Redirect),
+            Some(false) => (),
+            None => {
+                return Err(Response::with((Header(Connection::close()),
+                                           status::BadRequest,
+                                           "redirect must be 1, true, 0 or false")))
+            }
+        }
+    }
+    if let Some(prefer) = req.headers.get::<Prefer>() {
+        for preference in prefer.split(|c| c == ',' || c == ';').map(|p| p.trim()) {
+            if preference.starts_with("wait=") {
+                return match parse_wait(&preference[5..]) {
+                    Some(seconds) => Ok(ResponseMode::Wait(seconds)),
+                    None => Err(invalid_wait),
+                };
+            }
+        }
+    }
+    Ok(ResponseMode::Async)
+}
+
+/// Read the log for the task in the `uuid` route parameter, which can also be
+/// a correlation id, trying each log file extension in turn. Returns the task
+/// id along with the log.
+fn read_log(req: &Request,
+            config: &ServerConfig,
+            history: &SharedHistory,
+            extensions: &[&str])
+            -> Option<(String, String)> {
+    let uuid = match req.extensions.get::<Router>().unwrap().find("uuid") {
+        Some(query) => query.to_owned(),
+        None => return None,
+    };
+
+    // Tasks can be looked up by correlation id too
+    let uuid = match history.lock().unwrap().resolve(&uuid) {
+        Some(record) => record.id.clone(),
+        None => uuid,
+    };
+
+    for extension in extensions {
+        let logfile_path = config.log_root.path().join(format!("{}.{}", uuid.to_string(), extension));
+
+        let mut file = match File::open(&logfile_path) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+
+        let mut content = String::new();
+        if let Err(_) = file.read_to_string(&mut content) {
+            continue;
+        };
+        return Some((uuid, content));
+    }
+    None
+}
+
+/// What a webhook body says to deploy.
+struct ParsedMessage {
+    repo: GitRepo,
+    /// `simple` or `github`.
+    format: &'static str,
+    correlation_id: Option<String>,
+    changed_files: Option<Vec<String>>,
+    commit_message: Option<String>,
+}
+
+/// Parse a webhook body as a simple message or, failing that, as a GitHub
+/// message.
+// TODO: we can be smarter about this. If we see the XHubSignature header we
+// should try to parse as a github message, otherwise go simple message.
+fn parse_message(payload: &str, checkout_root: &Path) -> Option<ParsedMessage> {
+    match SimpleMessage::from_str(payload) {
+        Ok(message) => {
+            let correlation_id = message.correlation_id.clone();
+            let changed_files = message.changed_files.clone();
+            let commit_message = message.commit_message.clone();
+            Some(ParsedMessage {
+                repo: GitRepo::from(message, checkout_root),
+                format: "simple",
+                correlation_id: correlation_id,
+                changed_files: changed_files,
+                commit_message: commit_message,
+            })
+        }
+        Err(_) => match GitHubMessage::from_str(payload) {
+            Ok(message) => {
+                let changed_files = message.changed_files();
+                let commit_message = message.head_commit_message();
+                Some(ParsedMessage {
+                    repo: GitRepo::from(message, checkout_root),
+                    format: "github",
+                    correlation_id: None,
+                    changed_files: changed_files,
+                    commit_message: commit_message,
+                })
+            }
+            Err(_) => None,
+        },
+    }
+}
+
+/// Use the clone settings from the server config for a repository, if
+/// there are any.
+fn apply_repo_settings(repo: &mut GitRepo, config: &ServerConfig) {
+    if let Some(settings) = config.repo_settings(&repo.owner, &repo.name) {
+        repo.clone_protocol = settings.clone_protocol;
+        repo.token = settings.token.clone();
+        repo.submodules = settings.submodules;
+        repo.transfer = settings.transfer.clone();
+    }
+}
+
+/// The tasks to queue for `task`: one for each of the repo's `projects`,
+/// or just `task` for repos without them.
+fn project_tasks(task: DeployTask, config: &ServerConfig) -> Vec<DeployTask> {
+    let projects = config.projects_for(&task.repo.owner, &task.repo.name);
+    match projects.is_empty() {
+        true => vec![task],
+        false => {
+            projects.into_iter()
+                    .map(|project| task.for_project(project, config.checkout_root.path()))
+                    .collect()
+        }
+    }
+}
+
+/// Read the request body, verifying it against the signature header unless
+/// hookshot is running in insecure mode, and turning away replayed requests
+/// if `replay_window` is set. If the request should be rejected the response
+/// to send back is returned as the error.
+fn read_signed_body(req: &mut Request,
+                    config: &ServerConfig,
+                    replay: &SharedReplayGuard,
+                    task_status: &TaskStatusPrinter)
+                    -> Result<String, Response> {
+    let mut signature = None;
+    let mut from_github = false;
+    let timestamp = req.headers.get::<XHookshotTimestamp>().map(|h| h.to_string());
+    let delivery = req.headers.get::<XGitHubDelivery>().map(|h| h.to_string());
+    if !skip_signature_check() {
+        task_status.print("looking up signature");
+
+        // Get the signature from the header. We support `X-Hub-Signature-256`,
+        // `X-Hub-Signature` and `X-Signature` but they all represent the same
+        // type underneath, a string. GitHub sends both of its headers, the
+        // sha256 one wins. It might eventually be better to put this
+        // functionality on the Signature type itself.
+        signature = {
+            let github_header = match (req.headers.get::<XHubSignature256>(),
+                                       req.headers.get::<XHubSignature>()) {
+                (Some(h), _) => Some(h.to_string()),
+                (None, Some(h)) => Some(h.to_string()),
+                (None, None) => None,
+            };
+            from_github = github_header.is_some();
+            let possible_headers = (req.headers.get::<XSignature>().map(|h| h.to_string()), github_header);
+
+            let signature_string = match possible_headers {
+                (Some(h), None) => h,
+                (None, Some(h)) => h,
+                (None, None) => {
+                    task_status.print("missing signature");
+                    return Err(Response::with((Header(Connection::close()),
+                                               status::Unauthorized,
+                                               "missing signature")));
+                }
+                (Some(_), Some(_)) => {
+                    task_status.print("too many signatures");
+                    return Err(Response::with((Header(Connection::close()),
+                                               status::Unauthorized,
+                                               "too many signatures")));
+                }
+            };
+
+            match Signature::from_str(&signature_string) {
+                Some(signature) => Some(signature),
+                None => {
+                    task_status.print("could not parse signature");
+                    return Err(Response::with((Header(Connection::close()),
+                                               status::Unauthorized,
+                                               "could not parse signature")));
+                }
+            }
+        };
+    }
+
+    task_status.print("loading body into string");
+    let mut payload = String::new();
+    if req.body.read_to_string(&mut payload).is_err() {
+        task_status.print("could not read body into string");
+        return Err(Response::with((Header(Connection::close()), status::InternalServerError)));
+    }
+
+    if !skip_signature_check() {
+        // Bail out if the signature doesn't match what we're expecting. A
+        // timestamp is signed along with the body.
+        task_status.print("signature found, verifying");
+        let signature = signature.unwrap();
+        let signed = match timestamp {
+            Some(ref timestamp) => replay::signed_data(timestamp, &payload),
+            None => payload.clone(),
+        };
+        if signature.verify(&signed, &config.secret) == false {
+            task_status.print("signature mismatch");
+            return Err(Response::with((Header(Connection::close()),
+                                       status::Unauthorized,
+                                       "signature doesn't match")));
+        }
+
+        if let Some(window) = config.replay_window {
+            let now = history::now();
+            // GitHub doesn't send timestamps, its deliveries only get
+            // checked for duplicates
+            let fresh = timestamp.as_ref()
+                                 .and_then(|timestamp| timestamp.parse::<i64>().ok())
+                                 .map_or(false, |timestamp| replay::is_fresh(timestamp, now, window));
+            if !from_github && !fresh {
+                task_status.print("missing or stale timestamp");
+                return Err(Response::with((Header(Connection::close()),
+                                           status::Unauthorized,
+                                           "missing or stale X-Hookshot-Timestamp")));
+            }
+            let id = match (from_github, delivery) {
+                (true, Some(delivery)) => delivery,
+                _ => signature.to_string(),
+            };
+            if !replay.lock().unwrap().check(&id, now, window) {
+                task_status.print("request already seen, rejecting replay");
+                return Err(Response::with((Header(Connection::close()),
+                                           status::Conflict,
+                                           "request already seen")));
+            }
+        }
+    }
+
+    Ok(payload)
+}
+
+/// A task `queue_task` recorded and queued, for the response about it.
+struct Scheduled {
+    task_id: Uuid,
+    /// The correlation id if the task has one, otherwise the task id.
+    public_id: String,
+    key: QueueKey,
+    project: Option<String>,
+    vars: Vec<(&'static str, String)>,
+}
+
+/// Record a task in the history, create its logfile and add it to the queue
+/// for its branch. Returns the response to send back to the client, see
+/// `ResponseMode`.
+fn schedule(task: DeployTask,
+            manager: &Arc<Mutex<TaskManager<DeployTask>>>,
+            history: &SharedHistory,
+            config: &ServerConfig,
+            task_status: &TaskStatusPrinter,
+            mode: ResponseMode)
+            -> Response {
+    let scheduled = match queue_task(task, manager, history, config, task_status) {
+        Ok(scheduled) => scheduled,
+        Err(response) => return response,
+    };
+    let task_id = scheduled.task_id;
+    let (location, status_location) = task_locations(config, &scheduled.public_id);
+    let see_other = || {
+        Response::with((Header(Connection::close()),
+                        Header(Location(status_location.clone())),
+                        status::SeeOther,
+                        format!("Location: {}", status_location)))
+    };
+
+    match mode {
+        ResponseMode::Async => {
+            let queue_position = queue_position(manager, &scheduled.key, &task_id.to_string());
+            let body = accepted_body(config, &scheduled, &location, &status_location, queue_position);
+            accepted(config, body, Some(location))
+        }
+        ResponseMode::Redirect => see_other(),
+        ResponseMode::Wait(timeout) => {
+            task_status.print(format!("waiting up to {} seconds for task to finish", timeout));
+            match history::wait_for(history, &task_id.to_string(), timeout) {
+                Some(ref record) if record.status.is_terminal() =>
+                    json_response(status::Ok, json::encode(record).unwrap()),
+                _ => see_other(),
+            }
+        }
+    }
+}
+
+/// Schedule the tasks for one request, which are several for the projects
+/// of a monorepo, see `DeployTask::for_project`. A single task is scheduled
+/// like any other. Several get a single `202 Accepted` whatever `mode` asks
+/// for, with the body for each task on a line of its own, or in a JSON
+/// array with `accepted_format = "json"`. Maintenance turns them all away
+/// before any is queued. If one of them can't be queued after all, the
+/// response has its status and the bodies of the tasks before it, which
+/// stay queued.
+fn schedule_all(mut tasks: Vec<DeployTask>,
+                manager: &Arc<Mutex<TaskManager<DeployTask>>>,
+                history: &SharedHistory,
+                config: &ServerConfig,
+                task_status: &TaskStatusPrinter,
+                mode: ResponseMode)
+                -> Response {
+    if tasks.len() == 1 {
+        let task = tasks.pop().unwrap();
+        return match task.id == task_status.task_id {
+            true => schedule(task, manager, history, config, task_status, mode),
+            false => {
+                let project_status = TaskStatusPrinter::for_task(&task);
+                schedule(task, manager, history, config, &project_status, mode)
+            }
+        };
+    }
+
+    // The projects are all of one repo, so they are all in maintenance or
+    // none is
+    if let Some(response) = tasks.first().and_then(|task| rejected_for_maintenance(task, config, task_status)) {
+        return response;
+    }
+
+    let ids = tasks.iter().map(|task| task.id.to_string()).collect::<Vec<String>>();
+    task_status.print(format!("deploying {} projects as {}", tasks.len(), ids.join(", ")));
+    let count = tasks.len();
+    let mut bodies = vec![];
+    let mut failed = None;
+    for task in tasks {
+        let project_status = TaskStatusPrinter::for_task(&task);
+        let scheduled = match queue_task(task, manager, history, config, &project_status) {
+            Ok(scheduled) => scheduled,
+            Err(response) => {
+                failed = Some(response);
+                break;
+            }
+        };
+        let (location, status_location) = task_locations(config, &scheduled.public_id);
+        let queue_position = queue_position(manager, &scheduled.key, &scheduled.task_id.to_string());
+        bodies.push(accepted_body(config, &scheduled, &location, &status_location, queue_position));
+    }
+    let body = match config.accepted_format {
+        AcceptedFormat::Text => bodies.join("\n"),
+        AcceptedFormat::Json => format!("[{}]", bodies.join(",")),
+    };
+    match failed {
+        None => accepted(config, body, None),
+        Some(response) if bodies.is_empty() => response,
+        Some(failure) => {
+            task_status.print(format!("queued {} of {} projects", bodies.len(), count));
+            let mut response = accepted(config, body, None);
+            response.status = failure.status;
+            response
+        }
+    }
+}
+
+/// The response turning `task` away if its repo is in maintenance and
+/// `maintenance_mode` is `reject`.
+fn rejected_for_maintenance(task: &DeployTask,
+                            config: &ServerConfig,
+                            task_status: &TaskStatusPrinter)
+                            -> Option<Response> {
+    if config.maintenance_mode != MaintenanceMode::Reject ||
+       !task.maintenance.lock().unwrap().is_active(&task.repo.owner, &task.repo.name) {
+        return None;
+    }
+    task_status.print("in maintenance, rejecting task");
+    Some(Response::with((Header(Connection::close()),
+                         Header(RetryAfter(config.maintenance_retry_after)),
+                         status::ServiceUnavailable,
+                         "in maintenance, try again later")))
+}
+
+/// Everything `schedule` does short of responding: turn the task away
+/// during maintenance, record it, create its logfile and queue it.
+#[allow(unused_must_use)]
+fn queue_task(task: DeployTask,
+              manager: &Arc<Mutex<TaskManager<DeployTask>>>,
+              history: &SharedHistory,
+              config: &ServerConfig,
+              task_status: &TaskStatusPrinter)
+              -> Result<Scheduled, Response> {
+    let task_id = task.id;
+    let project = task.project.as_ref().map(|project| project.name.clone());
+
+    // During maintenance tasks are either turned away or left to wait in
+    // the queue, see `DeployTask::run`
+    if let Some(response) = rejected_for_maintenance(&task, config, task_status) {
+        return Err(response);
+    }
+    if task.maintenance.lock().unwrap().is_active(&task.repo.owner, &task.repo.name) {
+        task_status.print("in maintenance, task will wait until it is lifted");
+    }
+
+    // Try to create the log file upfront to make sure we can report
+    // back. If we aren't able to create it we shouldn't accept the task
+    // because we will be unable to report task status.
+    let logfile_path = config.log_root.path().join(format!("{}.log", task_id.to_string()));
+    let mut logfile = match File::create(&logfile_path) {
+        Ok(file) => file,
+        Err(e) => {
+            task_status.print(format!("could not open logfile for writing: {}", e));
+            return Err(Response::with((Header(Connection::close()), status::InternalServerError)));
+        }
+    };
+
+    let public_id = task.correlation_id.clone().unwrap_or(task_id.to_string());
+    let inserted = history.lock().unwrap().insert_unique(TaskRecord {
+        id: task_id.to_string(),
+        owner: task.repo.owner.clone(),
+        repo: task.repo.name.clone(),
+        refstring: task.repo.refstring.clone(),
+        reftype: task.repo.reftype,
+        sha: task.repo.sha.clone(),
+        remote_path: task.repo.remote_path.clone(),
+        local_path: task.repo.local_path.to_string_lossy().into_owned(),
+        status: TaskStatus::Queued,
+        is_rollback: task.is_rollback,
+        project: project.clone(),
+        trigger: Some(task.trigger.clone()),
+        correlation_id: task.correlation_id.clone(),
+        queued_at: history::now(),
+        started_at: None,
+        finished_at: None,
+        wait_seconds: None,
+        run_seconds: None,
+        checkout_bytes: None,
+        tmp_bytes: None,
+        steps: None,
+        hosts: None,
+        config: None,
+        error: None,
+        quarantine: None,
+    });
+    if !inserted {
+        // Another request with the same correlation id got in first
+        task_status.print("duplicate correlation id");
+        drop(logfile);
+        fs::remove_file(&logfile_path);
+        return Err(Response::with((Header(Connection::close()),
+                                   status::Conflict,
+                                   "correlation id already in use")));
+    }
+
+    // What the acceptance response can mention, since the task itself is
+    // handed to the queue
+    let vars = vec![("owner", task.repo.owner.clone()),
+                    ("repo", task.repo.name.clone()),
+                    ("refstring", task.repo.refstring.clone()),
+                    ("sha", task.repo.sha.clone()),
+                    ("project", project.clone().unwrap_or(String::new()))];
+
+    task_status.print("attempting to schedule");
+    let key = task.queue_key(config.queue_strategy);
+    let store = if config.persist_queue {
+        Some(config.log_root.path().to_path_buf())
+    } else {
+        None
+    };
+    match enqueue(manager, key.clone(), task, store) {
+        Ok(_) => task_status.print("scheduled"),
+        Err(_) => {
+            task_status.print("could not add task to queue");
+            history.lock().unwrap().set_status(&task_id.to_string(), TaskStatus::Cancelled);
+            return Err(Response::with((Header(Connection::close()), status::ServiceUnavailable)));
+        }
+    }
+    task_status.print("request complete");
+
+    logfile.write_all(b"task pending");
+
+    Ok(Scheduled {
+        task_id: task_id,
+        public_id: public_id,
+        key: key,
+        project: project,
+        vars: vars,
+    })
+}
+
+/// The URLs of a task and its status.
+fn task_locations(config: &ServerConfig, public_id: &str) -> (String, String) {
+    // TODO: probably shouldn't hardcode http://, someone might want to run
+    // this behind HTTPS someday.
+    let location = format!("http://{}/tasks/{}", config.authority(), public_id);
+    let status_location = format!("{}/status", location);
+    (location, status_location)
+}
+
+/// Where a task is in its queue: 1 when it runs next, 0 once a worker has
+/// picked it up.
+fn queue_position(manager: &Arc<Mutex<TaskManager<DeployTask>>>, key: &QueueKey, task_id: &str) -> usize {
+    manager.lock()
+           .unwrap()
+           .snapshot()
+           .get(key)
+           .and_then(|state| state.waiting.iter().position(|id| id == task_id))
+           .map(|position| position + 1)
+           .unwrap_or(0)
+}
+
+/// The body of the `202 Accepted` for a queued task, as `accepted_format`
+/// and `accepted_template` ask for.
+fn accepted_body(config: &ServerConfig,
+                 scheduled: &Scheduled,
+                 location: &str,
+                 status_location: &str,
+                 queue_position: usize)
+                 -> String {
+    let task_id = &scheduled.public_id[..];
+    match (&config.accepted_template, config.accepted_format) {
+        (&Some(ref accepted_template), format) => {
+            let queue_position = queue_position.to_string();
+            let mut vars = vec![("task_id", task_id),
+                                ("task_url", location),
+                                ("status_url", status_location),
+                                ("queue_position", &queue_position[..])];
+            vars.extend(scheduled.vars.iter().map(|&(name, ref value)| (name, &value[..])));
+            let escape: fn(&str) -> String = match format {
+                AcceptedFormat::Text => template::verbatim,
+                AcceptedFormat::Json => template::json_escape,
+            };
+            template::expand(accepted_template, &vars, escape)
+        }
+        (&None, AcceptedFormat::Text) => format!("Location: {}", location),
+        (&None, AcceptedFormat::Json) => {
+            json::encode(&Accepted {
+                id: task_id,
+                location: location,
+                status: status_location,
+                queue_position: queue_position,
+                project: scheduled.project.as_ref().map(|project| &project[..]),
+            })
+                .unwrap()
+        }
+    }
+}
+
+/// A `202 Accepted` with `body`, pointing at `location` for a single task.
+fn accepted(config: &ServerConfig, body: String, location: Option<String>) -> Response {
+    let content_type = match config.accepted_format {
+        AcceptedFormat::Text => "text/plain; charset=utf-8",
+        AcceptedFormat::Json => "application/json",
+    };
+    let mut response = Response::with((Header(Connection::close()),
+                                       content_type.parse::<Mime>().unwrap(),
+                                       status::Accepted,
+                                       body));
+    if let Some(location) = location {
+        response.headers.set(Location(location));
+    }
+    response
+}
+
+/// Add a task to its queue, saving it under `store` first if that's set.
+/// A task that failed and has retries left comes back with `retry_in` set,
+/// and is queued again as its next attempt once the delay is over.
+fn enqueue(manager: &Arc<Mutex<TaskManager<DeployTask>>>,
+           key: QueueKey,
+           task: DeployTask,
+           store: Option<PathBuf>)
+           -> Result<(), task_manager::Error> {
+    let task_id = task.id.to_string();
+
+    // Saved before it's queued so it's already on disk if a worker picks it
+    // up and removes it right away
+    if let Some(ref log_root) = store {
+        if let Err(e) = queue_store::save(log_root, &QueuedTask::from_task(&task)) {
+            warn!(&task_id, "could not save queued task: {}", e);
+        }
+    }
+
+    let finished = match TaskManager::enqueue(manager, key.clone(), task) {
+        Ok(finished) => finished,
+        Err(e) => {
+            if let Some(ref log_root) = store {
+                queue_store::remove(log_root, &task_id);
+            }
+            return Err(e);
+        }
+    };
+
+    let manager = manager.clone();
+    thread::spawn(move || {
+        let mut task = match finished.recv() {
+            Ok((task, _)) => task,
+            Err(_) => return,
+        };
+        let delay = match task.next_attempt() {
+            Some(delay) => delay,
+            None => return,
+        };
+        info!(&task_id, "queueing attempt {} in {} seconds", task.attempt, delay);
+        enqueue_after(&manager, key, task, store, delay);
+    });
+    Ok(())
+}
+
+/// Wait `delay` seconds, then `enqueue` a task that is going to be tried
+/// again. It's saved under `store` with when it's due first, so a restart
+/// in the meantime waits out the rest, see `restore_queue`.
+fn enqueue_after(manager: &Arc<Mutex<TaskManager<DeployTask>>>,
+                 key: QueueKey,
+                 task: DeployTask,
+                 store: Option<PathBuf>,
+                 delay: u64) {
+    let task_id = task.id.to_string();
+    if let Some(ref log_root) = store {
+        let queued = QueuedTask { retry_at: Some(history::now() + delay as i64), ..QueuedTask::from_task(&task) };
+        if let Err(e) = queue_store::save(log_root, &queued) {
+            warn!(&task_id, "could not save queued task: {}", e);
+        }
+    }
+    // Repo configs can't ask for more, this keeps the milliseconds in a u32
+    thread::sleep_ms((cmp::min(delay, MAX_RETRY_DELAY) * 1000) as u32);
+    let history = task.history.clone();
+    if let Err(e) = enqueue(manager, key, task, store) {
+        error!(&task_id, "could not queue the next attempt: {}", e);
+        history.lock().unwrap().set_status(&task_id, TaskStatus::Failed);
+    }
+}
+
+/// `GET /metrics`, the task counters in the Prometheus text format.
+#[cfg(feature = "metrics")]
+fn metrics_route(routes: &mut Routes, metrics: &SharedMetrics) {
+    let shared_metrics = metrics.clone();
+    routes.get("/metrics", move |_: &mut Request| {
+        let body = shared_metrics.lock().unwrap().render();
+        Ok(Response::with((Header(Connection::close()), status::Ok, body)))
+    });
+}
+// Metrics are still collected without the `metrics` feature, they just
+// aren't exposed
+#[cfg(not(feature = "metrics"))]
+fn metrics_route(_: &mut Routes, _: &SharedMetrics) {}
+
+/// Deal with the commands a previous hookshot process left running, as
+/// `orphan_processes` says, before any task starts. See `process`.
+fn recover_orphans(config: &ServerConfig, processes: &ProcessGroups, maintenance: &SharedMaintenance) {
+    let dir = process::marker_dir(config.log_root.path());
+    let orphans = process::orphans(&dir);
+    if orphans.is_empty() {
+        return;
+    }
+    for orphan in &orphans {
+        let task = match orphan.task {
+            Some(ref task) => format!(" of task {}", task),
+            None => String::new(),
+        };
+        warn!("server",
+              "{}{} (process group {}) is still running from a previous hookshot process",
+              orphan.program,
+              task,
+              orphan.pgid);
+    }
+    match config.orphan_processes {
+        OrphanAction::Terminate => {
+            info!("server", "stopping {} orphaned process groups", orphans.len());
+            process::terminate_orphans(&dir, &orphans, process::TERMINATE_GRACE_MS);
+        }
+        OrphanAction::Adopt => {
+            // Nothing starts while hookshot is in maintenance, which is lifted
+            // again once they're gone unless hookshot started out in it or
+            // someone changed it since
+            info!("server", "holding off tasks until {} orphaned process groups exit", orphans.len());
+            maintenance.lock().unwrap().hold_for_orphans();
+            let adopted = processes.adopt(&orphans);
+            let maintenance = maintenance.clone();
+            thread::spawn(move || {
+                adopted.join().ok();
+                match maintenance.lock().unwrap().release_orphans() {
+                    true => info!("server", "orphaned process groups have exited, tasks can start"),
+                    false => info!("server", "orphaned process groups have exited, leaving maintenance as it is"),
+                }
+            });
+        }
+        OrphanAction::Ignore => {}
+    }
+}
+
+/// Queue the tasks saved in `log_root/queue/` again, oldest first. Tasks
+/// that aren't queued in the history anymore are forgotten.
+#[allow(unused_must_use)]
+fn restore_queue(config: &ServerConfig, manager: &Arc<Mutex<TaskManager<DeployTask>>>, shared: &Shared) {
+    let history = &shared.history;
+    let log_root = config.log_root.path();
+    let mut queued = vec![];
+    for task in queue_store::load(log_root) {
+        let mut history = history.lock().unwrap();
+        let queued_at = history.get(&task.id).map(|record| record.queued_at);
+        match (Uuid::parse_str(&task.id), queued_at) {
+            (Ok(id), Some(queued_at)) => {
+                if history.claim(&task.id) {
+                    queued.push((queued_at, id, task));
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        queue_store::remove(log_root, &task.id);
+    }
+    queued.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (_, id, task) in queued {
+        let mut repo = task.git_repo();
+        apply_repo_settings(&mut repo, config);
+        // Not saved with the task, see `queue_store`
+        let env = match task.trigger {
+            Trigger::Warm => Environment::new(),
+            _ => match config.environment_for(&repo.owner, &repo.name, &repo.refstring) {
+                Ok(env) => env,
+                Err(_) => {
+                    warn!(&task.id, "error loading environment for {}, definition flawed",
+                          repo.fully_qualified_branch());
+                    Environment::new()
+                }
+            },
+        };
+        let project = match task.project {
+            Some(ref name) => match config.project(&repo.owner, &repo.name, name) {
+                Some(project) => Some(project),
+                None => {
+                    warn!(&task.id, "project {} is no longer in the config, not queueing again", name);
+                    let mut history = history.lock().unwrap();
+                    history.set_status(&task.id, TaskStatus::Cancelled);
+                    history.release(&task.id);
+                    queue_store::remove(log_root, &task.id);
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let mut deploy_task = DeployTask::new(repo, id, task.trigger, config, shared);
+        deploy_task.env = env;
+        deploy_task.is_rollback = task.is_rollback;
+        deploy_task.attempt = task.attempt;
+        deploy_task.correlation_id = task.correlation_id;
+        deploy_task.changed_files = task.changed_files;
+        deploy_task.project = project;
+        let key = deploy_task.queue_key(config.queue_strategy);
+        let store = Some(log_root.to_path_buf());
+        match task.retry_at.map_or(0, |at| at - history::now()) {
+            wait if wait > 0 => {
+                info!(&task.id, "queueing attempt {} again after restart, in {} seconds", task.attempt, wait);
+                let manager = manager.clone();
+                thread::spawn(move || enqueue_after(&manager, key, deploy_task, store, wait as u64));
+            }
+            _ => {
+                info!(&task.id, "queueing again after restart");
+                enqueue(manager, key, deploy_task, store);
+            }
+        }
+    }
+}
+
+/// The webhook receiver and task machinery, for running hookshot inside
+/// another program. Routes added with `router` are served next to the
+/// built-in ones.
+///
+/// ```no_run
+/// # extern crate hookshot;
+/// # extern crate iron;
+/// use hookshot::server::Server;
+/// use hookshot::server_config::ServerConfig;
+/// use iron::{status, Request, Response};
+/// use std::path::Path;
+///
+/// # fn main() {
+/// let config = ServerConfig::from_file(Path::new("hookshot.toml")).unwrap();
+/// let mut server = Server::new(config);
+/// server.router().get("/version", |_: &mut Request| Ok(Response::with((status::Ok, "1.0.0"))));
+/// server.run();
+/// # }
+/// ```
+pub struct Server {
+    config: SharedConfig,
+    /// Where the config was loaded from, for reloading it.
+    config_path: Option<PathBuf>,
+    manager: Arc<Mutex<TaskManager<DeployTask>>>,
+    history: SharedHistory,
+    metrics: SharedMetrics,
+    processes: ProcessGroups,
+    maintenance: SharedMaintenance,
+    quarantine: SharedQuarantine,
+    locks: Locks,
+    replay: SharedReplayGuard,
+    methods: TaskRegistry,
+    routes: Routes,
+}
+
+impl Server {
+    /// A server for `config`, loading the task history, metrics and
+    /// quarantine from its `log_root`.
+    /// Nothing runs until `run`.
+    pub fn new(config: ServerConfig) -> Server {
+        let mut history = TaskHistory::load(config.log_root.path());
+        history.set_sync(config.log_sync);
+        let metrics = Metrics::load(config.log_root.path(), &history);
+        let manager = TaskManager::new(config.queue_limit);
+        Server {
+            locks: manager.locks(),
+            manager: Arc::new(Mutex::new(manager)),
+            history: Arc::new(Mutex::new(history)),
+            metrics: Arc::new(Mutex::new(metrics)),
+            processes: ProcessGroups::new().with_markers(process::marker_dir(config.log_root.path())),
+            maintenance: Arc::new(Mutex::new(Maintenance::from_config(&config))),
+            quarantine: Arc::new(Mutex::new(Quarantine::load(config.log_root.path(), history::now()))),
+            replay: Arc::new(Mutex::new(ReplayGuard::new())),
+            config: Arc::new(RwLock::new(config)),
+            config_path: None,
+            methods: TaskRegistry::new(),
+            routes: Routes::new(),
+        }
+    }
+
+    /// Reload the config from `path` on `SIGHUP` and `POST /admin/reload`.
+    /// Without it neither reloads anything.
+    pub fn with_config_path(mut self, path: PathBuf) -> Server {
+        self.config_path = Some(path);
+        self
+    }
+
+    /// The routes to serve besides the built-in ones.
+    pub fn router(&mut self) -> &mut Routes {
+        &mut self.routes
+    }
+
+    /// The config, as it is after any reloads.
+    pub fn config(&self) -> SharedConfig {
+        self.config.clone()
+    }
+
+    /// The task history, for routes that report on tasks.
+    pub fn history(&self) -> SharedHistory {
+        self.history.clone()
+    }
+
+    pub fn metrics(&self) -> SharedMetrics {
+        self.metrics.clone()
+    }
+
+    /// Hear about every deploy task that is queued, starts, finishes or is
+    /// dropped, see `TaskManager::on_event`. Task ids are the task uuids.
+    pub fn on_queue_event(&self, observer: Observer) {
+        self.manager.lock().unwrap().on_event(observer);
+    }
+
+    /// Let repo configs use `name` as their deploy `method`, running what
+    /// `factory` builds for the ref. See `task_factory`.
+    pub fn register_method<F: TaskFactory + 'static>(&mut self, name: &str, factory: F) {
+        self.methods.register(name, factory);
+    }
+
+    /// Start the janitor, the schedule and the signal handlers, and serve
+    /// every address in `config.listen`. Only returns if the listeners stop.
+    //
+    // TODO: Note that we always send Connection: close. This is a workaround
+    // for a bug in hyper: https://github.com/hyperium/hyper/issues/658 (link
+    // is to the one I filed for my specific issue which links to the ticket
+    // it's a dupe of). Once this is fixed we can remove the
+    // Connection::close() modifiers.
+    //
+    // In the meantime we should probably implement that Connection::close()
+    // thing as Iron middleware, but I don't wanna look up how to do that right
+    // now.
+    #[allow(unused_must_use)]
+    pub fn run(self) {
+        let Server { config: global_config,
+                     config_path,
+                     manager: global_manager,
+                     history: global_history,
+                     metrics: global_metrics,
+                     processes: global_processes,
+                     maintenance: global_maintenance,
+                     quarantine: global_quarantine,
+                     locks: global_locks,
+                     replay: global_replay,
+                     methods: global_methods,
+                     mut routes } = self;
+        let config = global_config.read().unwrap().clone();
+        logging::init(config.log_format, config.log_level);
+
+        recover_orphans(&config, &global_processes, &global_maintenance);
+
+        // The state every task gets a handle to
+        let global_shared = Shared {
+            history: global_history.clone(),
+            metrics: global_metrics.clone(),
+            processes: global_processes.clone(),
+            maintenance: global_maintenance.clone(),
+            quarantine: global_quarantine.clone(),
+            locks: global_locks.clone(),
+            methods: global_methods.clone(),
+        };
+
+        // Tasks are saved to disk until a worker starts them, see
+        // `queue_store`. The ones a previous process left queued are queued
+        // again before the janitor can mark them as failed.
+        {
+            let log_root = config.log_root.path().to_path_buf();
+            global_manager.lock().unwrap().on_event(Box::new(move |event| {
+                match event {
+                    QueueEvent::Started { task: Some(ref id), .. } |
+                    QueueEvent::Dropped { task: Some(ref id), .. } => queue_store::remove(&log_root, id),
+                    _ => {}
+                }
+            }));
+        }
+        if config.persist_queue {
+            restore_queue(&config, &global_manager, &global_shared);
+        }
+
+        // Retention is configured in days and the checkout quota in megabytes
+        let janitor = Arc::new(Janitor {
+            history: global_history.clone(),
+            interval: config.janitor_interval,
+            retention: config.history_retention.map(|days| (days * 24 * 60 * 60) as i64),
+            checkout_root: config.checkout_root.path().to_path_buf(),
+            checkout_retention: config.checkout_retention.map(|days| (days * 24 * 60 * 60) as i64),
+            checkout_quota: config.checkout_quota.map(|megabytes| megabytes * 1024 * 1024),
+            changes: global_locks.changes(),
+        });
+        Janitor::start(janitor.clone());
+
+        // Reload the config file on SIGHUP
+        if let Some(config_path) = config_path.clone() {
+            let shared_config = global_config.clone();
+            let shared_manager = global_manager.clone();
+            reload::watch_hangup(move || {
+                match reload::reload(&config_path, &shared_config, &shared_manager) {
+                    Ok(restart_required) => log_reload(&restart_required),
+                    Err(e) => error!("reload", "could not reload {}: {}", config_path.display(), e),
+                }
+            });
+        }
+
+        // On SIGTERM or SIGINT stop whatever tasks are running along with
+        // everything they started, let the workers finish up and exit
+        {
+            let shared_manager = global_manager.clone();
+            let shared_processes = global_processes.clone();
+            process::watch_shutdown(move || {
+                info!("server", "shutting down, stopping running tasks");
+                shared_processes.stop(process::TERMINATE_GRACE_MS);
+                shared_manager.lock().unwrap().shutdown();
+                ::std::process::exit(0);
+            });
+        }
+
+        // Queue the deploys from `[[schedule]]` when they are due. Where to clone
+        // from comes from the last task for the ref, so a ref needs to have
+        // been deployed once before it can be scheduled. The tip of the ref is
+        // deployed, not the sha of that task.
+        {
+            let shared_config = global_config.clone();
+            let shared_manager = global_manager.clone();
+            let shared_history = global_history.clone();
+            let shared = global_shared.clone();
+            schedule::start(global_config.clone(), move |entry| {
+                let config = shared_config.read().unwrap().clone();
+                let task_id = Uuid::new_v4();
+                let task_status = TaskStatusPrinter::new(task_id);
+                task_status.print(format!("scheduled deploy of {}/{} {} ({})",
+                                          entry.owner,
+                                          entry.name,
+                                          entry.refstring,
+                                          entry.cron.expression()));
+
+                let previous = shared_history.lock()
+                                             .unwrap()
+                                             .for_ref(&entry.owner, &entry.name, &entry.refstring)
+                                             .last()
+                                             .map(|record| (*record).clone());
+                let previous = match previous {
+                    Some(previous) => previous,
+                    None => return task_status.print("no previous task for ref, not deploying"),
+                };
+
+                let sha = match previous.reftype {
+                    RefType::branch => format!("origin/{}", previous.refstring),
+                    RefType::tag => previous.refstring.clone(),
+                };
+                let mut repo = GitRepo {
+                    owner: previous.owner,
+                    name: previous.repo,
+                    refstring: previous.refstring,
+                    reftype: previous.reftype,
+                    sha: sha,
+                    remote_path: previous.remote_path,
+                    local_path: PathBuf::from(previous.local_path),
+                    clone_protocol: CloneProtocol::Ssh,
+                    token: None,
+                    submodules: false,
+                    transfer: Transfer::default(),
+                };
+                apply_repo_settings(&mut repo, &config);
+
+                let environment = match config.environment_for(&repo.owner,
+                                                                     &repo.name,
+                                                                     &repo.refstring) {
+                    Ok(environment) => environment,
+                    Err(_) => {
+                        task_status.print(format!("warning: error loading environment for {}, definition flawed",
+                                                  repo.fully_qualified_branch()));
+                        Environment::new()
+                    }
+                };
+
+                let mut task = DeployTask::new(repo, task_id, Trigger::Schedule, &config, &shared);
+                task.env = environment;
+                schedule_all(project_tasks(task, &config),
+                             &shared_manager,
+                             &shared_history,
+                             &config,
+                             &task_status,
+                             ResponseMode::Async);
+            });
+        }
+
+        // Groups of routes can be turned off in `[endpoints]`, the webhook
+        // receiver and the healthcheck are always there
+        let endpoints = config.endpoints;
+        if !endpoints.disabled().is_empty() {
+            info!("server", "endpoints turned off: {}", endpoints.disabled().join(", "));
+        }
+        chaos::announce();
+
+        // Create a healthcheck endpoint. Responds with a report of every check,
+        // with a 503 if any of them failed.
+        let shared_config = global_config.clone();
+        let shared_manager = global_manager.clone();
+        routes.get("/health", move |_: &mut Request| {
+            let config = shared_config.read().unwrap().clone();
+            let running = !shared_manager.lock().unwrap().is_stopped();
+            let report = health::check(config.checkout_root.path(),
+                                       config.log_root.path(),
+                                       &config.required_tools,
+                                       running);
+            let status = match report.healthy {
+                true => status::Ok,
+                false => status::ServiceUnavailable,
+            };
+            Ok(json_response(status, json::encode(&report).unwrap()))
+        });
+
+        routes.group("history", endpoints.history);
+
+        // Show the status of a specific task by UUID. If there is no log file by
+        // that name or if the log file can't be read for any reason return a 404.
+        let shared_config = global_config.clone();
+        let shared_history = global_history.clone();
+        routes.get("/tasks/:uuid", move |req: &mut Request| {
+            let config = shared_config.read().unwrap().clone();
+            match read_log(req, &config, &shared_history, &["log"]) {
+                Some((_, content)) => Ok(Response::with((Header(Connection::close()), status::Ok, content))),
+                None => Ok(Response::with((Header(Connection::close()), status::NotFound, "Not Found"))),
+            }
+        });
+
+        routes.group("dashboard", endpoints.dashboard);
+
+        // The same log rendered as HTML, with colors and collapsible sections.
+        // Uses the raw log if there is one since the main log might have had its
+        // colors stripped.
+        let shared_config = global_config.clone();
+        let shared_history = global_history.clone();
+        routes.get("/tasks/:uuid/html", move |req: &mut Request| {
+            let config = shared_config.read().unwrap().clone();
+            match read_log(req, &config, &shared_history, &["raw.log", "log"]) {
+                Some((uuid, content)) => {
+                    let content_type = "text/html; charset=utf-8".parse::<Mime>().unwrap();
+                    Ok(Response::with((Header(Connection::close()),
+                                       content_type,
+                                       status::Ok,
+                                       ansi::to_html(&format!("hookshot task {}", uuid), &content))))
+                }
+                None => Ok(Response::with((Header(Connection::close()), status::NotFound, "Not Found"))),
+            }
+        });
+
+        routes.group("history", endpoints.history);
+
+        // Structured status for a task, including how long it waited in the queue
+        // and how long it ran.
+        let shared_history = global_history.clone();
+        routes.get("/tasks/:uuid/status", move |req: &mut Request| {
+            let uuid = req.extensions.get::<Router>().unwrap().find("uuid").unwrap_or("").to_owned();
+            let history = shared_history.lock().unwrap();
+            match history.resolve(&uuid) {
+                Some(record) => Ok(json_response(status::Ok, json::encode(record).unwrap())),
+                None => Ok(Response::with((Header(Connection::close()), status::NotFound, "Not Found"))),
+            }
+        });
+
+        // Block until a task is finished, or until `timeout` seconds (default 300)
+        // pass, and respond with its status. Responds with 200 if the task
+        // finished and 202 if it is still queued or running.
+        let shared_history = global_history.clone();
+        routes.get("/tasks/:uuid/wait", move |req: &mut Request| {
+            let uuid = req.extensions.get::<Router>().unwrap().find("uuid").unwrap_or("").to_owned();
+            let timeout = match query_param(req, "timeout") {
+                None => DEFAULT_WAIT_TIMEOUT,
+                Some(timeout) => match parse_wait(&timeout) {
+                    Some(timeout) => timeout,
+                    None => return Ok(Response::with((Header(Connection::close()),
+                                                   status::BadRequest,
+                                                   format!("timeout must be a number of seconds \
+                                                            no greater than {}",
+                                                           MAX_WAIT_TIMEOUT)))),
+                },
+            };
+            match history::wait_for(&shared_history, &uuid, timeout) {
+                Some(record) => {
+                    let status = match record.status.is_terminal() {
+                        true => status::Ok,
+                        false => status::Accepted,
+                    };
+                    Ok(json_response(status, json::encode(&record).unwrap()))
+                }
+                None => Ok(Response::with((Header(Connection::close()), status::NotFound, "Not Found"))),
+            }
+        });
+
+        // The webhook payload a task was created from, as it was received.
+        let shared_config = global_config.clone();
+        let shared_history = global_history.clone();
+        routes.get("/tasks/:uuid/payload", move |req: &mut Request| {
+            let config = shared_config.read().unwrap().clone();
+            let uuid = req.extensions.get::<Router>().unwrap().find("uuid").unwrap_or("").to_owned();
+            let uuid = match shared_history.lock().unwrap().resolve(&uuid) {
+                Some(record) => record.id.clone(),
+                None => uuid,
+            };
+            match payloads::read(config.log_root.path(), &uuid) {
+                Some(payload) => Ok(json_response(status::Ok, payload)),
+                None => Ok(Response::with((Header(Connection::close()), status::NotFound, "Not Found"))),
+            }
+        });
+
+        // Look up many tasks at once. Takes a JSON array of task or correlation ids
+        // and responds with an object mapping each id to its task record, or null
+        // if there is no such task.
+        let shared_history = global_history.clone();
+        routes.post("/tasks/status", move |req: &mut Request| {
+            let mut body = String::new();
+            if req.body.read_to_string(&mut body).is_err() {
+                return Ok(Response::with((Header(Connection::close()), status::BadRequest)));
+            }
+            let ids = match json::decode::<Vec<String>>(&body) {
+                Ok(ids) => ids,
+                Err(_) => return Ok(Response::with((Header(Connection::close()),
+                                                    status::BadRequest,
+                                                    "body must be a JSON array of task ids"))),
+            };
+            if ids.len() > BATCH_STATUS_LIMIT {
+                return Ok(Response::with((Header(Connection::close()),
+                                          status::BadRequest,
+                                          format!("at most {} task ids can be looked up at once",
+                                                  BATCH_STATUS_LIMIT))));
+            }
+            let statuses = shared_history.lock().unwrap().resolve_all(&ids);
+            Ok(json_response(status::Ok, json::encode(&statuses).unwrap()))
+        });
+
+        routes.group("admin", endpoints.admin);
+
+        // Every named lock from the repo configs that a task holds or is waiting
+        // for, with the ids of those tasks.
+        let shared_locks = global_locks.clone();
+        routes.get("/locks", move |_: &mut Request| {
+            Ok(json_response(status::Ok, json::encode(&shared_locks.snapshot()).unwrap()))
+        });
+
+        // The queues with their running and waiting tasks, and the locks with
+        // their holders and waiters, as JSON or with `?format=dot` for graphviz.
+        let shared_manager = global_manager.clone();
+        let shared_locks = global_locks.clone();
+        routes.get("/admin/queues/graph", move |req: &mut Request| {
+            let queues = shared_manager.lock().unwrap().snapshot();
+            let graph = QueueGraph::new(queues, shared_locks.snapshot());
+            match query_param(req, "format").as_ref().map(|format| &format[..]) {
+                None | Some("json") => Ok(json_response(status::Ok, json::encode(&graph).unwrap())),
+                Some("dot") => {
+                    let content_type = "text/vnd.graphviz; charset=utf-8".parse::<Mime>().unwrap();
+                    Ok(Response::with((Header(Connection::close()), content_type, status::Ok, graph.to_dot())))
+                }
+                Some(_) => Ok(Response::with((Header(Connection::close()),
+                                              status::BadRequest,
+                                              "`format` must be \"json\" or \"dot\""))),
+            }
+        });
+
+        routes.group("history", endpoints.history);
+
+        // Every task recorded for a ref, oldest first. `?trigger=` narrows it
+        // down to tasks started a certain way, e.g. `schedule` or `rollback-of`,
+        // and `?project=` to the tasks of one project of a monorepo.
+        let shared_history = global_history.clone();
+        routes.get("/history/:owner/:repo/:ref", move |req: &mut Request| {
+            let (owner, repo, refstring) = ref_params(req);
+            let trigger = query_param(req, "trigger");
+            let project = query_param(req, "project");
+            let history = shared_history.lock().unwrap();
+            let records = history.for_ref(&owner, &repo, &refstring)
+                                 .into_iter()
+                                 .filter(|record| project.is_none() || record.project == project)
+                                 .filter(|record| match (&trigger, &record.trigger) {
+                                     (&None, _) => true,
+                                     (&Some(ref filter), &Some(ref trigger)) => trigger.matches(filter),
+                                     (&Some(_), &None) => false,
+                                 })
+                                 .collect::<Vec<&TaskRecord>>();
+            Ok(json_response(status::Ok, json::encode(&records).unwrap()))
+        });
+
+        // The repo config the last successful deploy of a ref ran with, so it
+        // can be checked without cloning the repo. `?project=` picks the project
+        // of a monorepo.
+        let shared_history = global_history.clone();
+        routes.get("/repos/:owner/:repo/:ref/config", move |req: &mut Request| {
+            let (owner, repo, refstring) = ref_params(req);
+            let history = shared_history.lock().unwrap();
+            let project = query_param(req, "project");
+            match history.last_successful(&owner, &repo, &refstring, project.as_ref().map(|p| &p[..]))
+                         .and_then(|record| record.config.as_ref()) {
+                Some(config) => Ok(json_response(status::Ok, json::encode(config).unwrap())),
+                None => Ok(Response::with((Header(Connection::close()), status::NotFound, "Not Found"))),
+            }
+        });
+
+        // What changed between two deploys of a ref, `?from=` and `?to=` being
+        // task or correlation ids, see `compare`.
+        let shared_history = global_history.clone();
+        routes.get("/repos/:owner/:repo/:ref/deploys/compare", move |req: &mut Request| {
+            let (owner, repo, refstring) = ref_params(req);
+            let (from, to) = match (query_param(req, "from"), query_param(req, "to")) {
+                (Some(from), Some(to)) => (from, to),
+                _ => {
+                    return Ok(Response::with((Header(Connection::close()),
+                                              status::BadRequest,
+                                              "`from` and `to` are both required")))
+                }
+            };
+            let (from, to) = {
+                let history = shared_history.lock().unwrap();
+                match (history.resolve(&from), history.resolve(&to)) {
+                    (Some(from), Some(to)) => (from.clone(), to.clone()),
+                    _ => return Ok(Response::with((Header(Connection::close()), status::NotFound, "Not Found"))),
+                }
+            };
+            let of_ref = |record: &TaskRecord| {
+                git::names_match(&record.owner, &owner) && git::names_match(&record.repo, &repo) &&
+                record.refstring == refstring
+            };
+            if !of_ref(&from) || !of_ref(&to) || from.project != to.project {
+                return Ok(Response::with((Header(Connection::close()),
+                                          status::BadRequest,
+                                          "`from` and `to` must be deploys of this ref")));
+            }
+            // Git runs without the history locked
+            let comparison = compare::compare(&from, &to, Path::new(&to.local_path));
+            Ok(json_response(status::Ok, json::encode(&comparison).unwrap()))
+        });
+
+        routes.group("metrics", endpoints.metrics);
+
+        metrics_route(&mut routes, &global_metrics);
+
+        routes.group("admin", endpoints.admin);
+
+        // Clean up checkouts right away instead of waiting for the janitor. The
+        // request must be signed like any other, the body can be empty. Responds
+        // with a report of what was removed.
+        let shared_config = global_config.clone();
+        let shared_replay = global_replay.clone();
+        routes.post("/admin/cleanup", move |req: &mut Request| {
+            let config = shared_config.read().unwrap().clone();
+            let task_status = TaskStatusPrinter::new(Uuid::new_v4());
+            task_status.print("cleanup requested");
+            if let Err(response) = read_signed_body(req, &config, &shared_replay, &task_status) {
+                return Ok(response);
+            }
+            let report = janitor.clean_checkouts();
+            task_status.print(format!("removed {} checkouts", report.removed.len()));
+            Ok(json_response(status::Ok, json::encode(&report).unwrap()))
+        });
+
+        // Turn maintenance on or off, globally or for one repo. The request must
+        // be signed, the body is like `{"enabled": true, "repo": "owner/name"}`
+        // without `repo` for global maintenance. Responds with what is in
+        // maintenance now.
+        let shared_config = global_config.clone();
+        let shared_maintenance = global_maintenance.clone();
+        let shared_replay = global_replay.clone();
+        routes.post("/admin/maintenance", move |req: &mut Request| {
+            let config = shared_config.read().unwrap().clone();
+            let task_status = TaskStatusPrinter::new(Uuid::new_v4());
+            task_status.print("maintenance change requested");
+            let body = match read_signed_body(req, &config, &shared_replay, &task_status) {
+                Ok(body) => body,
+                Err(response) => return Ok(response),
+            };
+            let usage = || {
+                Response::with((Header(Connection::close()),
+                                status::BadRequest,
+                                "body must be like {\"enabled\": true, \"repo\": \"owner/name\"}"))
+            };
+            let change = match json::decode::<MaintenanceChange>(&body) {
+                Ok(change) => change,
+                Err(_) => return Ok(usage()),
+            };
+            if let Some(ref repo) = change.repo {
+                if !valid_repo(repo) {
+                    return Ok(usage());
+                }
+            }
+            let mut maintenance = shared_maintenance.lock().unwrap();
+            maintenance.set(change.repo.as_ref().map(|repo| &repo[..]), change.enabled);
+            task_status.print(format!("maintenance {} for {}",
+                                      if change.enabled { "on" } else { "off" },
+                                      change.repo.unwrap_or(String::from("every repo"))));
+            Ok(json_response(status::Ok, json::encode(&*maintenance).unwrap()))
+        });
+
+        // Cancel a task that is queued or running. The request must be signed,
+        // the body can be empty. The commands the task is running are stopped
+        // like on shutdown, and the task ends as cancelled. Responds with the
+        // task's status, a 409 if it already finished.
+        let shared_config = global_config.clone();
+        let shared_history = global_history.clone();
+        let shared_processes = global_processes.clone();
+        let shared_locks = global_locks.clone();
+        let shared_replay = global_replay.clone();
+        routes.post("/admin/tasks/:uuid/cancel", move |req: &mut Request| {
+            let config = shared_config.read().unwrap().clone();
+            let uuid = req.extensions.get::<Router>().unwrap().find("uuid").unwrap_or("").to_owned();
+            let task_status = TaskStatusPrinter::new(Uuid::new_v4());
+            task_status.print(format!("cancelling task {} requested", uuid));
+            if let Err(response) = read_signed_body(req, &config, &shared_replay, &task_status) {
+                return Ok(response);
+            }
+            let record = match shared_history.lock().unwrap().resolve(&uuid) {
+                Some(record) => record.clone(),
+                None => return Ok(Response::with((Header(Connection::close()), status::NotFound, "Not Found"))),
+            };
+            if record.status.is_terminal() {
+                return Ok(json_response(status::Conflict, json::encode(&record).unwrap()));
+            }
+            // Stopping the commands can take the grace period, the task
+            // finds out it was cancelled on its own. One waiting for a lock
+            // is woken up to check.
+            let processes = shared_processes.clone();
+            let changes = shared_locks.changes();
+            let task_id = record.id.clone();
+            thread::spawn(move || {
+                processes.cancel(&task_id, process::TERMINATE_GRACE_MS);
+                changes.notify();
+            });
+            task_status.print(format!("cancelling task {}", record.id));
+            Ok(json_response(status::Accepted, json::encode(&record).unwrap()))
+        });
+
+        // The targets in quarantine, see `quarantine`.
+        let shared_quarantine = global_quarantine.clone();
+        routes.get("/admin/quarantine", move |_: &mut Request| {
+            let mut quarantine = shared_quarantine.lock().unwrap();
+            quarantine.expire(history::now());
+            Ok(json_response(status::Ok, json::encode(&*quarantine).unwrap()))
+        });
+
+        // Put a repo or one of its refs in quarantine, or lift it. The request
+        // must be signed, the body is like `{"enabled": true, "repo":
+        // "owner/name", "refstring": "staging", "seconds": 86400, "reason":
+        // "..."}` without `refstring` for every ref of the repo. Responds with
+        // what is in quarantine now.
+        let shared_config = global_config.clone();
+        let shared_quarantine = global_quarantine.clone();
+        let shared_replay = global_replay.clone();
+        routes.post("/admin/quarantine", move |req: &mut Request| {
+            let config = shared_config.read().unwrap().clone();
+            let task_status = TaskStatusPrinter::new(Uuid::new_v4());
+            task_status.print("quarantine change requested");
+            let body = match read_signed_body(req, &config, &shared_replay, &task_status) {
+                Ok(body) => body,
+                Err(response) => return Ok(response),
+            };
+            let usage = || {
+                Response::with((Header(Connection::close()),
+                                status::BadRequest,
+                                format!("body must be like {{\"enabled\": true, \"repo\": \"owner/name\", \
+                                         \"refstring\": \"staging\", \"seconds\": 86400, \"reason\": \
+                                         \"...\"}}, with seconds no greater than {}",
+                                        quarantine::MAX_SECONDS)))
+            };
+            let change = match json::decode::<QuarantineChange>(&body) {
+                Ok(change) => change,
+                Err(_) => return Ok(usage()),
+            };
+            if !valid_repo(&change.repo) {
+                return Ok(usage());
+            }
+            let target = match change.refstring {
+                Some(ref refstring) => format!("{} {}", change.repo, refstring),
+                None => change.repo.clone(),
+            };
+            let refstring = change.refstring.as_ref().map(|refstring| &refstring[..]);
+            let now = history::now();
+            let mut quarantine = shared_quarantine.lock().unwrap();
+            quarantine.expire(now);
+            match (change.enabled, change.seconds, change.reason.as_ref()) {
+                (true, Some(seconds), Some(reason)) if seconds > 0 && seconds <= quarantine::MAX_SECONDS &&
+                                                       !reason.trim().is_empty() => {
+                    quarantine.add(&change.repo, refstring, reason.trim(), now + seconds);
+                    task_status.print(format!("{} in quarantine for {} seconds: {}", target, seconds, reason.trim()));
+                }
+                (true, _, _) => return Ok(usage()),
+                (false, _, _) => {
+                    match quarantine.remove(&change.repo, refstring) {
+                        true => task_status.print(format!("{} out of quarantine", target)),
+                        false => task_status.print(format!("{} wasn't in quarantine", target)),
+                    }
+                }
+            }
+            if let Err(e) = quarantine.save(config.log_root.path()) {
+                warn!("quarantine", "could not save the quarantine: {}", e);
+            }
+            Ok(json_response(status::Ok, json::encode(&*quarantine).unwrap()))
+        });
+
+        // Reload the config file, like a SIGHUP. The request must be signed with
+        // the secret from before the reload. Only there if the config came from a
+        // file.
+        if let Some(config_path) = config_path {
+            let shared_config = global_config.clone();
+            let shared_manager = global_manager.clone();
+            let shared_replay = global_replay.clone();
+            routes.post("/admin/reload", move |req: &mut Request| {
+                let config = shared_config.read().unwrap().clone();
+                let task_status = TaskStatusPrinter::new(Uuid::new_v4());
+                task_status.print("reload requested");
+                if let Err(response) = read_signed_body(req, &config, &shared_replay, &task_status) {
+                    return Ok(response);
+                }
+                match reload::reload(&config_path, &shared_config, &shared_manager) {
+                    Ok(restart_required) => {
+                        log_reload(&restart_required);
+                        let report = ReloadReport { restart_required: restart_required };
+                        Ok(json_response(status::Ok, json::encode(&report).unwrap()))
+                    }
+                    Err(e) => {
+                        task_status.print(format!("could not reload: {}", e));
+                        Ok(routes::error_response(status::UnprocessableEntity,
+                                                  &format!("could not reload config: {}", e),
+                                                  BTreeMap::new()))
+                    }
+                }
+            });
+        }
+
+        routes.enable(true);
+
+        // Create Webhook receiver endpoint
+        let shared_manager = global_manager.clone();
+        let shared_history = global_history.clone();
+        let shared = global_shared.clone();
+        let shared_config = global_config.clone();
+
+        let shared_replay = global_replay.clone();
+        routes.post("/tasks", move |req: &mut Request| {
+            let config = shared_config.read().unwrap().clone();
+            let checkout_root = config.checkout_root.path().to_path_buf();
+            let task_id = Uuid::new_v4();
+            let mut task_status = TaskStatusPrinter::new(task_id);
+
+            task_status.print("request received, processing");
+
+            let header_correlation_id = req.headers.get::<XCorrelationId>().map(|h| h.to_string());
+            let headers = req.headers
+                             .iter()
+                             .map(|header| (String::from(header.name()), header.value_string()))
+                             .collect::<BTreeMap<String, String>>();
+            let mode = match response_mode(req) {
+                Ok(mode) => mode,
+                Err(response) => return Ok(response),
+            };
+
+            let payload = match read_signed_body(req, &config, &shared_replay, &task_status) {
+                Ok(payload) => payload,
+                Err(response) => return Ok(response),
+            };
+
+            task_status.print("attempting to parse message from payload");
+            let ParsedMessage {
+                repo: mut repo,
+                format: message_format,
+                correlation_id: message_correlation_id,
+                changed_files,
+                commit_message,
+            } = match parse_message(&payload, &checkout_root) {
+                Some(message) => message,
+                None => {
+                    task_status.print("could not parse message");
+                    return Ok(Response::with((Header(Connection::close()),
+                                              status::BadRequest,
+                                              "could not parse message")));
+                }
+            };
+            apply_repo_settings(&mut repo, &config);
+
+            if let Some(directive) = commit_message.as_ref()
+                                                   .and_then(|m| skip_directive(m, &config.skip_directives)) {
+                task_status.print(format!("skipping, commit message contains '{}'", directive));
+                return Ok(Response::with((Header(Connection::close()),
+                                          status::Ok,
+                                          format!("skipped: commit message contains '{}'", directive))));
+            }
+
+            let correlation_id = header_correlation_id.or(message_correlation_id);
+            if let Some(ref correlation_id) = correlation_id {
+                if !valid_correlation_id(correlation_id) {
+                    task_status.print("invalid correlation id");
+                    return Ok(Response::with((Header(Connection::close()),
+                                              status::BadRequest,
+                                              "invalid correlation id")));
+                }
+                if shared_history.lock().unwrap().resolve(correlation_id).is_some() {
+                    task_status.print("duplicate correlation id");
+                    return Ok(Response::with((Header(Connection::close()),
+                                              status::Conflict,
+                                              "correlation id already in use")));
+                }
+                task_status.correlation_id = Some(correlation_id.clone());
+            }
+
+            let environment = match config.environment_for(&repo.owner,
+                                                                 &repo.name,
+                                                                 &repo.refstring) {
+                Ok(environment) => environment,
+                Err(_) => {
+                    task_status.print(format!("warning: error loading environment for {}, definition flawed",
+                                              repo.fully_qualified_branch()));
+                    Environment::new()
+                }
+            };
+
+            let parsed = ParseResult {
+                format: String::from(message_format),
+                owner: repo.owner.clone(),
+                repo: repo.name.clone(),
+                refstring: repo.refstring.clone(),
+                reftype: repo.reftype,
+                sha: repo.sha.clone(),
+                remote_path: repo.remote_path.clone(),
+                correlation_id: correlation_id.clone(),
+                changed_files: changed_files.clone(),
+            };
+            let received_at = history::now();
+            let trigger = match message_format {
+                "github" => Trigger::GitHubWebhook,
+                _ => Trigger::SimpleMessage,
+            };
+
+            let mut task = DeployTask::new(repo, task_id, trigger, &config, &shared);
+            task.env = environment;
+            task.correlation_id = correlation_id;
+            task.changed_files = changed_files;
+
+            // The projects of a monorepo get correlation ids of their own
+            let tasks = project_tasks(task, &config);
+            for task in tasks.iter().filter(|task| task.project.is_some()) {
+                let correlation_id = match task.correlation_id {
+                    Some(ref correlation_id) => correlation_id,
+                    None => continue,
+                };
+                if !valid_correlation_id(correlation_id) {
+                    task_status.print("invalid correlation id");
+                    return Ok(Response::with((Header(Connection::close()),
+                                              status::BadRequest,
+                                              format!("invalid correlation id for a project: {}", correlation_id))));
+                }
+                if shared_history.lock().unwrap().resolve(correlation_id).is_some() {
+                    task_status.print("duplicate correlation id");
+                    return Ok(Response::with((Header(Connection::close()),
+                                              status::Conflict,
+                                              "correlation id already in use")));
+                }
+            }
+            let task_ids = tasks.iter().map(|task| task.id.to_string()).collect::<Vec<String>>();
+            let response = schedule_all(tasks, &shared_manager, &shared_history, &config, &task_status, mode);
+
+            // Only deliveries that became tasks are worth keeping
+            for task_id in task_ids {
+                if shared_history.lock().unwrap().get(&task_id).is_none() {
+                    continue;
+                }
+                let payload = Payload {
+                    task_id: task_id,
+                    received_at: received_at,
+                    headers: headers.clone(),
+                    body: payload.clone(),
+                    parsed: parsed.clone(),
+                };
+                if let Err(e) = payloads::save(config.log_root.path(), &payload) {
+                    task_status.print(format!("could not archive payload: {}", e));
+                }
+            }
+            Ok(response)
+        });
+
+        routes.group("manual_trigger", endpoints.manual_trigger);
+
+        // Queue a new task from the archived payload of an earlier one, like
+        // redelivering the webhook. The request must be signed, the body is
+        // ignored.
+        let shared_manager = global_manager.clone();
+        let shared_history = global_history.clone();
+        let shared = global_shared.clone();
+        let shared_config = global_config.clone();
+
+        let shared_replay = global_replay.clone();
+        routes.post("/tasks/:uuid/replay", move |req: &mut Request| {
+            let config = shared_config.read().unwrap().clone();
+            let checkout_root = config.checkout_root.path().to_path_buf();
+            let task_id = Uuid::new_v4();
+            let task_status = TaskStatusPrinter::new(task_id);
+            let mode = match response_mode(req) {
+                Ok(mode) => mode,
+                Err(response) => return Ok(response),
+            };
+
+            task_status.print("replay request received, processing");
+
+            if let Err(response) = read_signed_body(req, &config, &shared_replay, &task_status) {
+                return Ok(response);
+            }
+
+            let uuid = req.extensions.get::<Router>().unwrap().find("uuid").unwrap_or("").to_owned();
+            let (uuid, replayed_project) = match shared_history.lock().unwrap().resolve(&uuid) {
+                Some(record) => (record.id.clone(), record.project.clone()),
+                None => (uuid, None),
+            };
+            let archived = match payloads::read(config.log_root.path(), &uuid)
+                                     .and_then(|archived| json::decode::<Payload>(&archived).ok()) {
+                Some(archived) => archived,
+                None => {
+                    task_status.print("no archived payload to replay");
+                    return Ok(Response::with((Header(Connection::close()),
+                                              status::NotFound,
+                                              "no archived payload for task")));
+                }
+            };
+            task_status.print(format!("replaying payload of task {}", uuid));
+
+            // The original task keeps its correlation id
+            let ParsedMessage { repo: mut repo, format: message_format, changed_files, .. } =
+                match parse_message(&archived.body, &checkout_root) {
+                    Some(message) => message,
+                    None => {
+                        task_status.print("could not parse archived payload");
+                        return Ok(Response::with((Header(Connection::close()),
+                                                  status::UnprocessableEntity,
+                                                  "could not parse archived payload")));
+                    }
+                };
+            apply_repo_settings(&mut repo, &config);
+
+            let environment = match config.environment_for(&repo.owner,
+                                                                 &repo.name,
+                                                                 &repo.refstring) {
+                Ok(environment) => environment,
+                Err(_) => {
+                    task_status.print(format!("warning: error loading environment for {}, definition flawed",
+                                              repo.fully_qualified_branch()));
+                    Environment::new()
+                }
+            };
+
+            let parsed = ParseResult {
+                format: String::from(message_format),
+                owner: repo.owner.clone(),
+                repo: repo.name.clone(),
+                refstring: repo.refstring.clone(),
+                reftype: repo.reftype,
+                sha: repo.sha.clone(),
+                remote_path: repo.remote_path.clone(),
+                correlation_id: None,
+                changed_files: changed_files.clone(),
+            };
+
+            let mut task = DeployTask::new(repo, task_id, Trigger::ReplayOf(uuid.clone()), &config, &shared);
+            task.env = environment;
+            task.changed_files = changed_files;
+
+            // Replaying the task of one project of a monorepo deploys that
+            // project again, replaying any other deploys all of them
+            let tasks = match replayed_project {
+                Some(name) => match config.project(&task.repo.owner, &task.repo.name, &name) {
+                    Some(project) => vec![task.for_project(project, &checkout_root)],
+                    None => {
+                        task_status.print(format!("project {} is no longer in the config", name));
+                        return Ok(Response::with((Header(Connection::close()),
+                                                  status::UnprocessableEntity,
+                                                  format!("project {} is no longer in the config", name))));
+                    }
+                },
+                None => project_tasks(task, &config),
+            };
+            let task_ids = tasks.iter().map(|task| task.id.to_string()).collect::<Vec<String>>();
+            let response = schedule_all(tasks, &shared_manager, &shared_history, &config, &task_status, mode);
+
+            // Archived again under the new tasks so they can be replayed in turn
+            for task_id in task_ids {
+                if shared_history.lock().unwrap().get(&task_id).is_none() {
+                    continue;
+                }
+                let payload = Payload {
+                    task_id: task_id,
+                    parsed: parsed.clone(),
+                    ..archived.clone()
+                };
+                if let Err(e) = payloads::save(config.log_root.path(), &payload) {
+                    task_status.print(format!("could not archive payload: {}", e));
+                }
+            }
+            Ok(response)
+        });
+
+        // Clone or fetch a ref without running anything, so the first deploy of a
+        // big repo doesn't spend its time on the clone. Goes through the ref's
+        // queue like any task so it never touches a checkout a task is using.
+        let shared_manager = global_manager.clone();
+        let shared_history = global_history.clone();
+        let shared = global_shared.clone();
+        let shared_config = global_config.clone();
+        let shared_replay = global_replay.clone();
+        routes.post("/repos/:owner/:repo/warm", move |req: &mut Request| {
+            let config = shared_config.read().unwrap().clone();
+            let task_id = Uuid::new_v4();
+            let task_status = TaskStatusPrinter::new(task_id);
+            let mode = match response_mode(req) {
+                Ok(mode) => mode,
+                Err(response) => return Ok(response),
+            };
+
+            task_status.print("warm request received, processing");
+
+            let body = match read_signed_body(req, &config, &shared_replay, &task_status) {
+                Ok(body) => body,
+                Err(response) => return Ok(response),
+            };
+            let warm = match WarmRequest::from_str(&body) {
+                Ok(warm) => warm,
+                Err(e) => {
+                    task_status.print(format!("invalid warm request: {}", e));
+                    return Ok(Response::with((Header(Connection::close()), status::BadRequest, e)));
+                }
+            };
+
+            let (owner, repo_name) = {
+                let params = req.extensions.get::<Router>().unwrap();
+                (params.find("owner").unwrap_or("").to_owned(),
+                 params.find("repo").unwrap_or("").to_owned())
+            };
+
+            let remote_path = match warm.remote {
+                Some(remote) => remote,
+                None => {
+                    let latest = shared_history.lock()
+                                               .unwrap()
+                                               .latest_for_repo(&owner, &repo_name)
+                                               .map(|record| record.remote_path.clone());
+                    match latest {
+                        Some(remote) => remote,
+                        None => {
+                            task_status.print("no remote given or known for repo");
+                            return Ok(Response::with((Header(Connection::close()),
+                                                      status::BadRequest,
+                                                      "no `remote` given and no previous task for repo")));
+                        }
+                    }
+                }
+            };
+
+            let sha = match warm.reftype {
+                RefType::branch => format!("origin/{}", warm.refstring),
+                RefType::tag => warm.refstring.clone(),
+            };
+            let mut repo = GitRepo {
+                local_path: CheckoutPath::new(config.checkout_root.path(), &owner, &repo_name, &warm.refstring)
+                                .into_path_buf(),
+                owner: owner,
+                name: repo_name,
+                refstring: warm.refstring,
+                reftype: warm.reftype,
+                sha: sha,
+                remote_path: remote_path,
+                clone_protocol: CloneProtocol::Ssh,
+                token: None,
+                submodules: false,
+                transfer: Transfer::default(),
+            };
+            apply_repo_settings(&mut repo, &config);
+
+            let task = DeployTask::new(repo, task_id, Trigger::Warm, &config, &shared);
+
+            Ok(schedule_all(project_tasks(task, &config),
+                            &shared_manager,
+                            &shared_history,
+                            &config,
+                            &task_status,
+                            mode))
+        });
+
+        // Redeploy the sha of the last successful task for a ref. The request
+        // must be signed the same way as a webhook message, the body is ignored.
+        let shared_manager = global_manager.clone();
+        let shared_history = global_history.clone();
+        let shared = global_shared.clone();
+        let shared_config = global_config.clone();
+
+        let shared_replay = global_replay.clone();
+        routes.post("/rollback/:owner/:repo/:ref", move |req: &mut Request| {
+            let config = shared_config.read().unwrap().clone();
+            let task_id = Uuid::new_v4();
+            let task_status = TaskStatusPrinter::new(task_id);
+            let mode = match response_mode(req) {
+                Ok(mode) => mode,
+                Err(response) => return Ok(response),
+            };
+
+            task_status.print("rollback request received, processing");
+
+            if let Err(response) = read_signed_body(req, &config, &shared_replay, &task_status) {
+                return Ok(response);
+            }
+
+            let (owner, repo_name, refstring) = ref_params(req);
+
+            // The projects of a monorepo are rolled back one at a time
+            let project = match (query_param(req, "project"), config.projects_for(&owner, &repo_name).is_empty()) {
+                (None, true) => None,
+                (None, false) => {
+                    task_status.print("no project given for repo with projects");
+                    return Ok(Response::with((Header(Connection::close()),
+                                              status::BadRequest,
+                                              "repo has projects, pick one with ?project=")));
+                }
+                (Some(name), _) => match config.project(&owner, &repo_name, &name) {
+                    Some(project) => Some(project),
+                    None => {
+                        task_status.print(format!("unknown project {}", name));
+                        return Ok(Response::with((Header(Connection::close()),
+                                                  status::NotFound,
+                                                  format!("no project {} for repo", name))));
+                    }
+                },
+            };
+
+            let previous = {
+                let history = shared_history.lock().unwrap();
+                let project_name = project.as_ref().map(|project| &project.name[..]);
+                match history.last_successful(&owner, &repo_name, &refstring, project_name) {
+                    Some(record) => record.clone(),
+                    None => {
+                        task_status.print("no successful task to roll back to");
+                        return Ok(Response::with((Header(Connection::close()),
+                                                  status::NotFound,
+                                                  "no successful deploy found for ref")));
+                    }
+                }
+            };
+            task_status.print(format!("rolling back to {} from task {}", previous.sha, previous.id));
+
+            let mut repo = GitRepo {
+                owner: previous.owner,
+                name: previous.repo,
+                refstring: previous.refstring,
+                reftype: previous.reftype,
+                sha: previous.sha,
+                remote_path: previous.remote_path,
+                local_path: PathBuf::from(previous.local_path),
+                clone_protocol: CloneProtocol::Ssh,
+                token: None,
+                submodules: false,
+                transfer: Transfer::default(),
+            };
+            apply_repo_settings(&mut repo, &config);
+
+            let environment = match config.environment_for(&repo.owner,
+                                                                 &repo.name,
+                                                                 &repo.refstring) {
+                Ok(environment) => environment,
+                Err(_) => {
+                    task_status.print(format!("warning: error loading environment for {}, definition flawed",
+                                              repo.fully_qualified_branch()));
+                    Environment::new()
+                }
+            };
+
+            let mut task = DeployTask::new(repo, task_id, Trigger::RollbackOf(previous.id), &config, &shared);
+            task.env = environment;
+            task.is_rollback = true;
+            task.project = project;
+
+            Ok(schedule(task, &shared_manager, &shared_history, &config, &task_status, mode))
+        });
+
+        // Every listener shares the one handler. Dropping a listener waits for
+        // it to stop, which is never, so this blocks until the process exits.
+        let groups = routes.groups();
+        let mut chain = routes.into_handler();
+        chain.link_before(ReadAuth::new(global_config.clone()));
+        chain.link_after(CorsHeaders::new(global_config.clone(), groups.clone()));
+        chain.link_after(ExtraHeaders::new(global_config.clone(), groups));
+        let handler = Arc::new(chain);
+        let mut listeners = vec![];
+        for addr in config.listen.iter() {
+            let handler = handler.clone();
+            match Iron::new(move |req: &mut Request| handler.handle(req)).http(addr) {
+                Ok(listener) => {
+                    info!("server", "listening on {}", addr);
+                    listeners.push(listener);
+                }
+                Err(e) => {
+                    error!("server", "could not listen on {}: {}", addr, e);
+                    ::std::process::exit(1);
+                }
+            }
+        }
+        drop(listeners);
+        global_manager.lock().unwrap().shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ResponseMode, TaskStatusPrinter, parse_flag, project_tasks, schedule_all};
+    use deploy_task::{DeployTask, Shared};
+    use git::GitRepo;
+    use history::{TaskHistory, Trigger};
+    use iron::status;
+    use maintenance::Maintenance;
+    use message::{RefType, SimpleMessage};
+    use metrics::Metrics;
+    use process::ProcessGroups;
+    use quarantine::Quarantine;
+    use queue_store::QueuedTask;
+    use server_config::ServerConfig;
+    use std::sync::{Arc, Mutex};
+    use task_factory::TaskRegistry;
+    use task_manager::{Locks, TaskManager};
+    use tempdir::TempDir;
+    use uuid::Uuid;
+
+    fn monorepo_config(root: &TempDir) -> ServerConfig {
+        let toml = format!(r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "{root}"
+            log_root = "{root}"
+            maintenance_mode = "reject"
+
+            [repo.brianloveswords.platform.projects.web]
+            dir = "services/web"
+
+            [repo.brianloveswords.platform.projects.api]
+            dir = "./services/api"
+        "#,
+                           root = root.path().display());
+        ServerConfig::from(&toml).unwrap()
+    }
+
+    fn push(config: &ServerConfig, changed_files: Option<Vec<String>>) -> DeployTask {
+        let message = SimpleMessage::new("brianloveswords",
+                                         "platform",
+                                         "master",
+                                         RefType::branch,
+                                         "git@github.com:brianloveswords/platform.git",
+                                         "HEAD");
+        let shared = Shared {
+            history: Arc::new(Mutex::new(TaskHistory::new(config.log_root.path()))),
+            metrics: Arc::new(Mutex::new(Metrics::new())),
+            processes: ProcessGroups::new(),
+            maintenance: Arc::new(Mutex::new(Maintenance::default())),
+            quarantine: Arc::new(Mutex::new(Quarantine::default())),
+            locks: Locks::new(),
+            methods: TaskRegistry::new(),
+        };
+        let mut task = DeployTask::new(GitRepo::from(message, config.checkout_root.path()),
+                                       Uuid::new_v4(),
+                                       Trigger::SimpleMessage,
+                                       config,
+                                       &shared);
+        task.correlation_id = Some(String::from("build-42"));
+        task.changed_files = changed_files;
+        task
+    }
+
+    #[test]
+    fn test_project_tasks() {
+        let root = TempDir::new("hookshot-server-test").unwrap();
+        let config = monorepo_config(&root);
+        let changed_files = vec![String::from("services/api/main.rs"), String::from("README.md")];
+        let task = push(&config, Some(changed_files));
+        let id = task.id;
+        let tasks = project_tasks(task, &config);
+
+        // One task per project, in name order, each with an id and a
+        // checkout of its own
+        assert_eq!(tasks.iter().map(|task| task.project.as_ref().unwrap().name.clone()).collect::<Vec<String>>(),
+                   vec!["api", "web"]);
+        assert!(tasks.iter().all(|task| task.id != id));
+        assert!(tasks[0].id != tasks[1].id);
+        assert!(tasks[0].repo.local_path != tasks[1].repo.local_path);
+        assert!(tasks[0].repo.local_path.to_string_lossy().ends_with("~api"));
+        assert_eq!(tasks[0].correlation_id, Some(String::from("build-42.api")));
+        assert_eq!(tasks[1].correlation_id, Some(String::from("build-42.web")));
+
+        // Only the files in a project's directory are its changed files
+        assert_eq!(tasks[0].changed_files, Some(vec![String::from("services/api/main.rs")]));
+        assert_eq!(tasks[1].changed_files, Some(vec![]));
+
+        // Not knowing what changed stays that way
+        let tasks = project_tasks(push(&config, Some(vec![])), &config);
+        assert!(tasks.iter().all(|task| task.changed_files.is_none()));
+
+        // Repos without projects keep their task
+        let mut task = push(&config, None);
+        task.repo.name = String::from("hookshot");
+        let id = task.id;
+        let tasks = project_tasks(task, &config);
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].id, id);
+        assert!(tasks[0].project.is_none());
+    }
+
+    #[test]
+    fn test_parse_flag() {
+        assert_eq!(parse_flag(""), Some(true));
+        assert_eq!(parse_flag("1"), Some(true));
+        assert_eq!(parse_flag("true"), Some(true));
+        assert_eq!(parse_flag("0"), Some(false));
+        assert_eq!(parse_flag("false"), Some(false));
+        assert_eq!(parse_flag("yes please"), None);
+    }
+
+    #[test]
+    fn test_next_attempt() {
+        let root = TempDir::new("hookshot-server-test").unwrap();
+        let config = monorepo_config(&root);
+        let mut task = push(&config, None);
+        assert_eq!(task.next_attempt(), None);
+        assert_eq!(task.attempt, 1);
+
+        task.retry_in = Some(30);
+        assert_eq!(task.next_attempt(), Some(30));
+        assert_eq!(task.attempt, 2);
+        assert_eq!(task.retry_in, None);
+        task.retry_in = Some(60);
+        assert_eq!(task.next_attempt(), Some(60));
+        assert_eq!(task.attempt, 3);
+
+        // Saved with the attempt it is, so a restart doesn't start over
+        assert_eq!(QueuedTask::from_task(&task).attempt, 3);
+    }
+
+    #[test]
+    fn test_schedule_all_in_maintenance() {
+        let root = TempDir::new("hookshot-server-test").unwrap();
+        let config = monorepo_config(&root);
+        let task = push(&config, None);
+        task.maintenance.lock().unwrap().set(Some("brianloveswords/platform"), true);
+        let history = task.history.clone();
+        let task_status = TaskStatusPrinter::new(task.id);
+        let tasks = project_tasks(task, &config);
+        let ids = tasks.iter().map(|task| task.id.to_string()).collect::<Vec<String>>();
+        let manager = Arc::new(Mutex::new(TaskManager::new(None)));
+
+        // None of the projects is queued, not even the ones before the
+        // first that is turned away
+        let response = schedule_all(tasks, &manager, &history, &config, &task_status, ResponseMode::Async);
+        assert_eq!(response.status, Some(status::ServiceUnavailable));
+        assert!(ids.iter().all(|id| history.lock().unwrap().get(id).is_none()));
+        assert!(manager.lock().unwrap().snapshot().is_empty());
+    }
+}
@@ -4,24 +4,562 @@ use std::env;
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
-use std::u16;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Component, Path};
+use std::{u16, u32};
+use artifact::{self, Artifact};
+use git::{self, CloneProtocol, GitRepo, Transfer};
+use http::{self, Timeouts};
+use logging;
+use maintenance::MaintenanceMode;
+use message::RefType;
+use process::OrphanAction;
+use repo_config;
+use rustc_serialize::{Encodable, Encoder};
+use schedule::{Cron, ScheduleEntry};
+use storage::SyncPolicy;
+use task_manager::QueueStrategy;
+use template;
 use toml::{self, Value, Table};
 use verified_path::VerifiedPath;
+use workspace::{Quota, QuotaAction};
 
-#[derive(Debug, Clone)]
+/// Per-repository settings from the `[repo.<owner>.<name>]` tables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepoSettings {
+    pub clone_protocol: CloneProtocol,
+    pub token: Option<String>,
+    pub submodules: bool,
+    /// Limit on the size of the checkout after a task, configured in
+    /// megabytes.
+    pub quota: Option<Quota>,
+    /// Whether the repo starts out in maintenance.
+    pub maintenance: bool,
+    /// Repos, as `owner/name`, whose most recent task for a ref has to
+    /// succeed before a task for the same ref of this repo starts.
+    pub depends_on: Vec<String>,
+    /// Clone depth, partial clones and bandwidth limit for the repo's git
+    /// network operations.
+    pub transfer: Transfer,
+    /// Release assets to deploy tags from instead of checking them out,
+    /// see `artifact`.
+    pub artifacts: Vec<Artifact>,
+    /// Where in the checkout the repo's config is, tried before
+    /// `config.config_paths`.
+    pub config_path: Option<String>,
+    /// Services in the repo that deploy on their own, ordered by name. See
+    /// `Project`.
+    pub projects: Vec<Project>,
+}
+
+/// A service in a monorepo, from a `[repo.<owner>.<name>.projects.<name>]`
+/// table. Every task for the repo becomes a task for each of its projects,
+/// with a queue and a checkout of its own, which runs from `dir` and reads
+/// its config from there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Project {
+    pub name: String,
+    /// The project's directory, relative to the root of the checkout.
+    pub dir: String,
+}
+
+impl Project {
+    /// Whether `name` can name a project: letters, digits, `_` and `-`, so
+    /// it fits into queue keys, checkout names and correlation ids.
+    pub fn valid_name(name: &str) -> bool {
+        !name.is_empty() &&
+        name.chars().all(|c| {
+            (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9') || c == '_' || c == '-'
+        })
+    }
+}
+
+impl Encodable for Project {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_struct("Project", 1, |s| s.emit_struct_field("dir", 0, |s| self.dir.encode(s)))
+    }
+}
+
+impl RepoSettings {
+    /// The settings of a repo without a `[repo.<owner>.<name>]` table.
+    pub fn new() -> RepoSettings {
+        RepoSettings {
+            clone_protocol: CloneProtocol::Ssh,
+            token: None,
+            submodules: false,
+            quota: None,
+            maintenance: false,
+            depends_on: vec![],
+            transfer: Transfer::default(),
+            artifacts: vec![],
+            config_path: None,
+            projects: vec![],
+        }
+    }
+}
+
+// Encoded with the keys of a `[repo.<owner>.<name>]` table.
+impl Encodable for RepoSettings {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let clone_protocol = match self.clone_protocol {
+            CloneProtocol::Ssh => "ssh",
+            CloneProtocol::Https => "https",
+        };
+        let quota = self.quota.as_ref().map(|quota| quota.bytes / 1024 / 1024);
+        let quota_action = self.quota.as_ref().map(|quota| quota.action.as_str());
+        let hours = self.transfer
+                        .bandwidth_limit_hours
+                        .map(|(start, end)| format!("{}-{}", start, end));
+        let artifacts = match self.artifacts.is_empty() {
+            true => None,
+            false => Some(self.artifacts
+                              .iter()
+                              .map(|artifact| (&artifact.pattern, artifact))
+                              .collect::<BTreeMap<&String, &Artifact>>()),
+        };
+        let projects = match self.projects.is_empty() {
+            true => None,
+            false => Some(self.projects
+                              .iter()
+                              .map(|project| (&project.name, project))
+                              .collect::<BTreeMap<&String, &Project>>()),
+        };
+        s.emit_struct("RepoSettings", 14, |s| {
+            try!(s.emit_struct_field("clone_protocol", 0, |s| s.emit_str(clone_protocol)));
+            try!(s.emit_struct_field("token", 1, |s| self.token.encode(s)));
+            try!(s.emit_struct_field("submodules", 2, |s| self.submodules.encode(s)));
+            try!(s.emit_struct_field("quota", 3, |s| quota.encode(s)));
+            try!(s.emit_struct_field("quota_action", 4, |s| quota_action.encode(s)));
+            try!(s.emit_struct_field("maintenance", 5, |s| self.maintenance.encode(s)));
+            try!(s.emit_struct_field("depends_on", 6, |s| self.depends_on.encode(s)));
+            try!(s.emit_struct_field("depth", 7, |s| self.transfer.depth.encode(s)));
+            try!(s.emit_struct_field("partial_clone", 8, |s| self.transfer.partial_clone.encode(s)));
+            try!(s.emit_struct_field("bandwidth_limit", 9, |s| self.transfer.bandwidth_limit.encode(s)));
+            try!(s.emit_struct_field("bandwidth_limit_hours", 10, |s| hours.encode(s)));
+            try!(s.emit_struct_field("artifacts", 11, |s| artifacts.encode(s)));
+            try!(s.emit_struct_field("config_path", 12, |s| self.config_path.encode(s)));
+            s.emit_struct_field("projects", 13, |s| projects.encode(s))
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ServerConfig {
     pub secret: String,
     pub hostname: String,
     pub checkout_root: VerifiedPath,
     pub log_root: VerifiedPath,
     pub queue_limit: Option<u64>,
+    /// Which tasks share a queue, see `task_manager::QueueKey::for_repo`.
+    pub queue_strategy: QueueStrategy,
+    /// Save queued tasks under `log_root/queue/` and queue them again on
+    /// startup, see `queue_store`.
+    pub persist_queue: bool,
+    /// Port in the links hookshot hands out, which is also the port of the
+    /// default listener.
     pub port: u16,
+    /// Addresses to listen on, IPv4 and IPv6.
+    pub listen: Vec<SocketAddr>,
     pub environments: Table,
+    /// Variables from the `[environment.<name>]` sections, for repo configs to
+    /// share between branches with `environment = "<name>"`.
+    pub named_environments: BTreeMap<String, Environment>,
+    pub pagerduty_routing_key: Option<String>,
+    pub repos: BTreeMap<String, RepoSettings>,
+    pub janitor_interval: u64,
+    pub history_retention: Option<u64>,
+    pub checkout_retention: Option<u64>,
+    pub checkout_quota: Option<u64>,
+    pub strip_ansi_logs: bool,
+    pub raw_logs: bool,
+    /// Whether task logs list hookshot's own environment and the one a task
+    /// runs with. Redaction applies either way, these keep the environment
+    /// out of the logs entirely.
+    pub log_system_environment: bool,
+    pub log_hookshot_environment: bool,
+    /// When task logs and records are flushed to disk, see `storage`.
+    pub log_sync: SyncPolicy,
+    /// Where in a checkout to look for the repo config, in order, for repos
+    /// without a `config_path` of their own.
+    pub config_paths: Vec<String>,
+    /// What happens on startup to commands a previous hookshot process
+    /// left running, see `process`.
+    pub orphan_processes: OrphanAction,
+    pub log_format: logging::Format,
+    pub log_level: logging::Level,
+    pub allow_env_override: Vec<String>,
+    pub required_tools: Vec<String>,
+    /// Strings that skip the deploy when the head commit message contains
+    /// one of them.
+    pub skip_directives: Vec<String>,
+    /// Deploys to queue at fixed times, from the `[[schedule]]` entries.
+    pub schedule: Vec<ScheduleEntry>,
+    /// Whether hookshot starts out in maintenance, see `maintenance`.
+    pub maintenance: bool,
+    pub maintenance_mode: MaintenanceMode,
+    /// Seconds clients are told to wait when turned away during
+    /// maintenance.
+    pub maintenance_retry_after: u64,
+    /// Seconds a signed request stays valid for, see `replay`. `None`
+    /// turns replay protection off.
+    pub replay_window: Option<u64>,
+    /// Timeouts for notifier messages and other requests hookshot makes.
+    pub http_timeouts: Timeouts,
+    /// Token `GET` requests have to send, see `auth`. `None` leaves them
+    /// open.
+    pub read_token: Option<String>,
+    /// Names of variables to mask in task logs on top of
+    /// `redact::DEFAULT_PATTERNS`.
+    pub redact: Vec<String>,
+    /// Body of the `202 Accepted` a queued task gets, which
+    /// `accepted_template` replaces when it's set.
+    pub accepted_format: AcceptedFormat,
+    pub accepted_template: Option<String>,
+    /// Groups of routes to serve, from the `[endpoints]` section.
+    pub endpoints: Endpoints,
+    /// Extra headers for responses, from the `[headers]` section.
+    pub headers: ResponseHeaders,
+    /// CORS for browsers calling the API from other origins, `None` without
+    /// a `[cors]` section.
+    pub cors: Option<Cors>,
+}
+
+/// Which groups of routes the server has. Each one is on unless turned off,
+/// the webhook receiver (`POST /tasks`) and `/health` always are.
+#[derive(RustcEncodable, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Endpoints {
+    /// `/tasks/:uuid/html`.
+    pub dashboard: bool,
+    /// Task logs, records and payloads, `/history` and the configs of past
+    /// deploys.
+    pub history: bool,
+    /// `/locks` and everything under `/admin`.
+    pub admin: bool,
+    /// Deploys that aren't pushes: replays, rollbacks and warming a
+    /// checkout.
+    pub manual_trigger: bool,
+    /// `/metrics`, in builds with the `metrics` feature.
+    pub metrics: bool,
+}
+
+impl Endpoints {
+    /// The `[endpoints]` section, `None` if it isn't a table of booleans
+    /// for the groups above.
+    fn from_toml(value: &Value) -> Option<Endpoints> {
+        let mut endpoints = Endpoints::default();
+        let table = match value.as_table() {
+            Some(table) => table,
+            None => return None,
+        };
+        for (key, value) in table {
+            let enabled = match value.as_bool() {
+                Some(enabled) => enabled,
+                None => return None,
+            };
+            match &key[..] {
+                "dashboard" => endpoints.dashboard = enabled,
+                "history" => endpoints.history = enabled,
+                "admin" => endpoints.admin = enabled,
+                "manual_trigger" => endpoints.manual_trigger = enabled,
+                "metrics" => endpoints.metrics = enabled,
+                _ => return None,
+            }
+        }
+        Some(endpoints)
+    }
+
+    /// The names of the groups that are off.
+    pub fn disabled(&self) -> Vec<&'static str> {
+        [("dashboard", self.dashboard),
+         ("history", self.history),
+         ("admin", self.admin),
+         ("manual_trigger", self.manual_trigger),
+         ("metrics", self.metrics)]
+            .iter()
+            .filter(|&&(_, enabled)| !enabled)
+            .map(|&(name, _)| name)
+            .collect()
+    }
+}
+
+impl Default for Endpoints {
+    fn default() -> Endpoints {
+        Endpoints {
+            dashboard: true,
+            history: true,
+            admin: true,
+            manual_trigger: true,
+            metrics: true,
+        }
+    }
+}
+
+/// Groups of routes, from `[endpoints]`, that can have headers of their own.
+pub const HEADER_GROUPS: &'static [&'static str] = &["dashboard", "history", "admin", "manual_trigger", "metrics"];
+
+/// Headers hookshot has to set itself, since they're about the connection
+/// and the body.
+const RESERVED_HEADERS: &'static [&'static str] = &["connection", "content-length", "transfer-encoding"];
+
+/// Whether `name` is a valid header name, which is also what HTTP methods
+/// look like.
+fn valid_header_name(name: &str) -> bool {
+    !name.is_empty() &&
+    name.chars().all(|c| {
+        (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9') ||
+        "!#$%&'*+-.^_`|~".contains(c)
+    })
+}
+
+/// Whether `name: value` can go in a response: the name is a valid header
+/// name that isn't reserved and the value doesn't have line breaks or other
+/// control characters in it.
+fn valid_header(name: &str, value: &str) -> bool {
+    valid_header_name(name) && !RESERVED_HEADERS.contains(&&name.to_lowercase()[..]) &&
+    value.chars().all(|c| c == '\t' || !c.is_control())
+}
+
+/// Headers to add to responses, like `Strict-Transport-Security` or
+/// `Cache-Control`, for when the proxy in front of hookshot can't add them.
+/// Strings in `[headers]` are for every response, tables named after a
+/// group of routes are for that group's, see `extra_headers`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResponseHeaders {
+    pub global: BTreeMap<String, String>,
+    pub groups: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl ResponseHeaders {
+    /// The `[headers]` section, `None` if it has anything but valid headers
+    /// and tables of them for the groups in `HEADER_GROUPS`.
+    fn from_toml(value: &Value) -> Option<ResponseHeaders> {
+        let table = match value.as_table() {
+            Some(table) => table,
+            None => return None,
+        };
+        let mut headers = ResponseHeaders::default();
+        for (key, value) in table {
+            match *value {
+                Value::String(ref v) if valid_header(key, v) => {
+                    headers.global.insert(key.clone(), v.clone());
+                }
+                Value::Table(ref group) if HEADER_GROUPS.contains(&&key[..]) => {
+                    let mut group_headers = BTreeMap::new();
+                    for (name, value) in group {
+                        match value.as_str() {
+                            Some(v) if valid_header(name, v) => group_headers.insert(name.clone(), String::from(v)),
+                            _ => return None,
+                        };
+                    }
+                    headers.groups.insert(key.clone(), group_headers);
+                }
+                _ => return None,
+            }
+        }
+        Some(headers)
+    }
+
+    /// The headers for a response from a route in `group`, `None` for
+    /// routes outside of the groups. A group's own header replaces the one
+    /// for every response of the same name, whatever its case.
+    pub fn for_group(&self, group: Option<&str>) -> Vec<(&str, &str)> {
+        let own = group.and_then(|group| self.groups.get(group));
+        let overridden = |name: &str| {
+            own.map_or(false, |own| own.keys().any(|other| other.to_lowercase() == name.to_lowercase()))
+        };
+        let mut headers = self.global
+                              .iter()
+                              .filter(|&(name, _)| !overridden(&name[..]))
+                              .map(|(name, value)| (&name[..], &value[..]))
+                              .collect::<Vec<_>>();
+        if let Some(own) = own {
+            headers.extend(own.iter().map(|(name, value)| (&name[..], &value[..])));
+        }
+        headers
+    }
+}
+
+// Encoded in the shape of the `[headers]` section, the headers for every
+// response next to the tables of the groups.
+impl Encodable for ResponseHeaders {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_map(self.global.len() + self.groups.len(), |s| {
+            let mut i = 0;
+            for (name, value) in self.global.iter() {
+                try!(s.emit_map_elt_key(i, |s| name.encode(s)));
+                try!(s.emit_map_elt_val(i, |s| value.encode(s)));
+                i += 1;
+            }
+            for (group, headers) in self.groups.iter() {
+                try!(s.emit_map_elt_key(i, |s| group.encode(s)));
+                try!(s.emit_map_elt_val(i, |s| headers.encode(s)));
+                i += 1;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Whether `origin` can be in `allowed_origins`: `*`, or a scheme and host
+/// like `https://dashboard.example.org:8443` without a path.
+fn valid_origin(origin: &str) -> bool {
+    let host = if origin.starts_with("https://") {
+        &origin[8..]
+    } else if origin.starts_with("http://") {
+        &origin[7..]
+    } else {
+        return origin == "*";
+    };
+    !host.is_empty() && !host.contains('/') && host.chars().all(|c| !c.is_whitespace() && !c.is_control())
+}
+
+/// CORS for the routes of some groups, from the `[cors]` section, see
+/// `cors`.
+#[derive(RustcEncodable, Debug, Clone, PartialEq, Eq)]
+pub struct Cors {
+    /// Origins browsers can call the routes from, `*` for any.
+    pub allowed_origins: Vec<String>,
+    /// Methods and request headers preflight requests are told they can
+    /// use.
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// Seconds browsers can cache what a preflight request said.
+    pub max_age: u64,
+    /// The groups of routes, from `HEADER_GROUPS`, that allow CORS.
+    pub groups: Vec<String>,
+}
+
+impl Cors {
+    /// The `[cors]` section, `None` if it's invalid or has no
+    /// `allowed_origins`.
+    fn from_toml(value: &Value) -> Option<Cors> {
+        let table = match value.as_table() {
+            Some(table) => table,
+            None => return None,
+        };
+        let keys = ["allowed_origins", "allowed_methods", "allowed_headers", "max_age", "groups"];
+        if table.keys().any(|key| !keys.contains(&&key[..])) {
+            return None;
+        }
+        let allowed_origins = match lookup_as_string_array(value, "allowed_origins") {
+            LookupResult::StringArrayValue(ref v) if !v.is_empty() && v.iter().all(|origin| valid_origin(origin)) =>
+                v.clone(),
+            _ => return None,
+        };
+        let allowed_methods = match lookup_as_string_array(value, "allowed_methods") {
+            LookupResult::Missing => vec![String::from("GET"), String::from("POST")],
+            LookupResult::StringArrayValue(ref v) if v.iter().all(|method| valid_header_name(method)) =>
+                v.iter().map(|method| method.to_uppercase()).collect(),
+            _ => return None,
+        };
+        let allowed_headers = match lookup_as_string_array(value, "allowed_headers") {
+            LookupResult::Missing => vec![String::from("Authorization"), String::from("Content-Type")],
+            LookupResult::StringArrayValue(ref v) if v.iter().all(|header| valid_header_name(header)) => v.clone(),
+            _ => return None,
+        };
+        let max_age = match lookup_as_integer(value, "max_age") {
+            LookupResult::Missing => DEFAULT_CORS_MAX_AGE,
+            LookupResult::IntegerValue(v) if v >= 0 => v as u64,
+            _ => return None,
+        };
+        let groups = match lookup_as_string_array(value, "groups") {
+            LookupResult::Missing => vec![String::from("history")],
+            LookupResult::StringArrayValue(ref v) if v.iter().all(|group| HEADER_GROUPS.contains(&&group[..])) =>
+                v.clone(),
+            _ => return None,
+        };
+        Some(Cors {
+            allowed_origins: allowed_origins,
+            allowed_methods: allowed_methods,
+            allowed_headers: allowed_headers,
+            max_age: max_age,
+            groups: groups,
+        })
+    }
+}
+
+/// How the body of a `202 Accepted` is written, and so how the values filled
+/// into `accepted_template` are escaped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptedFormat {
+    /// `Location: <task url>`, the default.
+    Text,
+    /// A JSON object with the task id, its urls and its place in the queue.
+    Json,
+}
+
+impl AcceptedFormat {
+    pub fn from_str(s: &str) -> Option<AcceptedFormat> {
+        match s {
+            "text" => Some(AcceptedFormat::Text),
+            "json" => Some(AcceptedFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            AcceptedFormat::Text => "text",
+            AcceptedFormat::Json => "json",
+        }
+    }
+}
+
+/// What can be filled into `accepted_template`.
+pub const ACCEPTED_PLACEHOLDERS: &'static [&'static str] = &["task_id",
+                                                             "task_url",
+                                                             "status_url",
+                                                             "queue_position",
+                                                             "owner",
+                                                             "repo",
+                                                             "refstring",
+                                                             "sha",
+                                                             "project"];
+
+/// Whether a JSON `accepted_template` renders to valid JSON whatever it's
+/// filled in with. Only `queue_position` is a number, and can go outside of
+/// quotes.
+fn valid_accepted_template(template: &str, format: AcceptedFormat) -> bool {
+    match format {
+        AcceptedFormat::Text => true,
+        AcceptedFormat::Json => template::renders_json(template, ACCEPTED_PLACEHOLDERS, &["queue_position"]),
+    }
 }
 
 pub type Environment = BTreeMap<String, String>;
 
+const DEFAULT_PORT: u16 = 1469;
+const DEFAULT_JANITOR_INTERVAL: u64 = 300;
+const DEFAULT_MAINTENANCE_RETRY_AFTER: u64 = 300;
+const DEFAULT_CORS_MAX_AGE: u64 = 600;
+
+/// Programs `/health` checks for unless `required_tools` says otherwise.
+fn default_required_tools() -> Vec<String> {
+    let mut tools = vec![String::from("git"), String::from("make")];
+    if cfg!(feature = "ansible") {
+        tools.push(String::from("ansible-playbook"));
+    }
+    tools
+}
+
+fn default_skip_directives() -> Vec<String> {
+    vec![String::from("[skip deploy]"), String::from("[hookshot skip]")]
+}
+
+fn default_config_paths() -> Vec<String> {
+    vec![String::from(repo_config::CONFIG_FILE)]
+}
+
+/// Whether a path is relative and stays inside the directory it's relative
+/// to, like `.deploy/hookshot.toml`.
+fn is_checkout_path(path: &str) -> bool {
+    !path.is_empty() &&
+    Path::new(path).components().all(|component| match component {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    })
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Error {
     ParseError,
@@ -30,7 +568,9 @@ pub enum Error {
     InvalidSecret,
     MissingPort,
     InvalidPort,
+    InvalidListen,
     InvalidQueueLimit,
+    InvalidQueueStrategy,
     MissingCheckoutRoot,
     InvalidCheckoutRoot,
     MissingLogRoot,
@@ -38,6 +578,58 @@ pub enum Error {
     MissingHostname,
     InvalidHostname,
     InvalidEnvironmentTable,
+    InvalidPagerDutyRoutingKey,
+    InvalidJanitorInterval,
+    InvalidHistoryRetention,
+    InvalidCheckoutRetention,
+    InvalidCheckoutQuota,
+    InvalidStripAnsiLogs,
+    InvalidRawLogs,
+    InvalidLogSystemEnvironment,
+    InvalidLogHookshotEnvironment,
+    InvalidLogSync,
+    InvalidConfigPaths,
+    InvalidOrphanProcesses,
+    InvalidPersistQueue,
+    InvalidLogFormat,
+    InvalidLogLevel,
+    InvalidAllowEnvOverride,
+    InvalidRequiredTools,
+    InvalidSkipDirectives,
+    InvalidMaintenance,
+    InvalidMaintenanceMode,
+    InvalidMaintenanceRetryAfter,
+    InvalidReplayWindow,
+    InvalidHttpReadTimeout,
+    InvalidHttpDeadline,
+    InvalidReadToken,
+    InvalidRedact,
+    InvalidAcceptedFormat,
+    InvalidAcceptedTemplate,
+    InvalidEndpoints,
+    InvalidHeaders,
+    InvalidCors,
+    InvalidNamedEnvironment,
+    InvalidRepoTable,
+    InvalidCloneProtocol,
+    InvalidToken,
+    InvalidSubmodules,
+    InvalidRepoQuota,
+    InvalidRepoQuotaAction,
+    InvalidRepoMaintenance,
+    InvalidRepoDependsOn,
+    InvalidRepoDepth,
+    InvalidRepoPartialClone,
+    InvalidRepoBandwidthLimit,
+    InvalidRepoBandwidthLimitHours,
+    InvalidRepoArtifacts,
+    InvalidRepoConfigPath,
+    InvalidRepoProjects,
+    DependencyCycle,
+    InvalidScheduleTable,
+    InvalidScheduleRepo,
+    InvalidScheduleRef,
+    InvalidScheduleCron,
     FileOpenError,
     FileReadError,
 }
@@ -59,12 +651,66 @@ impl StdError for Error {
             Error::InvalidHostname => "'config.hostname' must be a string",
             Error::MissingPort => "missing 'config.port'",
             Error::InvalidPort => "'config.port' must be 16 integer",
+            Error::InvalidListen => "'config.listen' must be an array of addresses like \"0.0.0.0:1469\" or \"[::]:1469\"",
             Error::MissingCheckoutRoot => "missing 'config.checkout_root'",
             Error::InvalidCheckoutRoot => "'config.checkout_root' must be a directory",
             Error::InvalidQueueLimit => "'config.queue' must be a positive integer",
+            Error::InvalidQueueStrategy => "'config.queue_strategy' must be \"ref\" or \"repo\"",
             Error::MissingLogRoot => "missing 'config.log_root'",
             Error::InvalidLogRoot => "'config.log_root' must be a directory",
             Error::InvalidEnvironmentTable => "'env' table is invalid, check configuration",
+            Error::InvalidPagerDutyRoutingKey => "'config.pagerduty_routing_key' must be a string",
+            Error::InvalidJanitorInterval => "'config.janitor_interval' must be a positive integer",
+            Error::InvalidHistoryRetention => "'config.history_retention' must be a positive integer",
+            Error::InvalidCheckoutRetention => "'config.checkout_retention' must be a positive integer",
+            Error::InvalidCheckoutQuota => "'config.checkout_quota' must be a positive integer",
+            Error::InvalidStripAnsiLogs => "'config.strip_ansi_logs' must be a boolean",
+            Error::InvalidRawLogs => "'config.raw_logs' must be a boolean",
+            Error::InvalidLogSystemEnvironment => "'config.log_system_environment' must be a boolean",
+            Error::InvalidLogHookshotEnvironment => "'config.log_hookshot_environment' must be a boolean",
+            Error::InvalidLogSync => "'config.log_sync' must be \"never\", \"close\" or \"always\"",
+            Error::InvalidConfigPaths => "'config.config_paths' must be a non-empty array of paths inside the checkout",
+            Error::InvalidOrphanProcesses => "'config.orphan_processes' must be \"terminate\", \"adopt\" or \"ignore\"",
+            Error::InvalidPersistQueue => "'config.persist_queue' must be a boolean",
+            Error::InvalidLogFormat => "'config.log_format' must be \"text\" or \"json\"",
+            Error::InvalidAllowEnvOverride => "'config.allow_env_override' must be an array of strings",
+            Error::InvalidRequiredTools => "'config.required_tools' must be an array of strings",
+            Error::InvalidSkipDirectives => "'config.skip_directives' must be an array of strings",
+            Error::InvalidMaintenance => "'config.maintenance' must be a boolean",
+            Error::InvalidMaintenanceMode => "'config.maintenance_mode' must be \"queue\" or \"reject\"",
+            Error::InvalidMaintenanceRetryAfter => "'config.maintenance_retry_after' must be a positive integer",
+            Error::InvalidReplayWindow => "'config.replay_window' must be a positive integer",
+            Error::InvalidHttpReadTimeout => "'config.http_read_timeout' must be a positive integer",
+            Error::InvalidHttpDeadline => "'config.http_deadline' must be a positive integer",
+            Error::InvalidReadToken => "'config.read_token' must be a non-empty string",
+            Error::InvalidRedact => "'config.redact' must be an array of strings",
+            Error::InvalidAcceptedFormat => "'config.accepted_format' must be \"text\" or \"json\"",
+            Error::InvalidAcceptedTemplate => "'config.accepted_template' must be a string, and render to valid JSON with `accepted_format = \"json\"`",
+            Error::InvalidEndpoints => "'endpoints' must be a table of booleans for \"dashboard\", \"history\", \"admin\", \"manual_trigger\" and \"metrics\"",
+            Error::InvalidHeaders => "'headers' must be a table of header names to strings, and of \"dashboard\", \"history\", \"admin\", \"manual_trigger\" or \"metrics\" to tables of them; 'Connection', 'Content-Length' and 'Transfer-Encoding' can't be set",
+            Error::InvalidCors => "'cors' must have a non-empty 'allowed_origins' array of origins like \"https://dashboard.example.org\" or \"*\", and optionally 'allowed_methods' and 'allowed_headers' arrays, a 'max_age' in seconds and a 'groups' array of \"dashboard\", \"history\", \"admin\", \"manual_trigger\" or \"metrics\"",
+            Error::InvalidNamedEnvironment => "every 'environment.<name>' section must be a table of strings",
+            Error::InvalidLogLevel => "'config.log_level' must be one of \"debug\", \"info\", \"warn\" or \"error\"",
+            Error::InvalidRepoTable => "'repo' table is invalid, entries must be [repo.<owner>.<name>] tables",
+            Error::InvalidCloneProtocol => "'repo.<owner>.<name>.clone_protocol' must be \"ssh\" or \"https\"",
+            Error::InvalidToken => "'repo.<owner>.<name>.token' must be a string",
+            Error::InvalidSubmodules => "'repo.<owner>.<name>.submodules' must be a boolean",
+            Error::InvalidRepoQuota => "'repo.<owner>.<name>.quota' must be a positive integer",
+            Error::InvalidRepoQuotaAction => "'repo.<owner>.<name>.quota_action' must be \"fail\" or \"clean\"",
+            Error::InvalidRepoMaintenance => "'repo.<owner>.<name>.maintenance' must be a boolean",
+            Error::InvalidRepoDependsOn => "'repo.<owner>.<name>.depends_on' must be an array of repos like \"owner/name\"",
+            Error::InvalidRepoDepth => "'repo.<owner>.<name>.depth' must be a non-negative integer",
+            Error::InvalidRepoPartialClone => "'repo.<owner>.<name>.partial_clone' must be a boolean",
+            Error::InvalidRepoBandwidthLimit => "'repo.<owner>.<name>.bandwidth_limit' must be a positive integer",
+            Error::InvalidRepoBandwidthLimitHours => "'repo.<owner>.<name>.bandwidth_limit_hours' must be a range of hours like \"9-17\"",
+            Error::InvalidRepoArtifacts => "'repo.<owner>.<name>.artifacts' must be a table of tag patterns to tables with an `asset` and optionally a `checksum_asset`, `public_key`, `signature_asset` and `api_url`",
+            Error::InvalidRepoConfigPath => "'repo.<owner>.<name>.config_path' must be a path inside the checkout",
+            Error::InvalidRepoProjects => "'repo.<owner>.<name>.projects' must be a table of project names (letters, digits, `_` and `-`) to tables with a `dir` inside the checkout",
+            Error::DependencyCycle => "'repo.<owner>.<name>.depends_on' must not form a cycle",
+            Error::InvalidScheduleTable => "'schedule' must be an array of tables",
+            Error::InvalidScheduleRepo => "'schedule.repo' must be a string like \"owner/name\"",
+            Error::InvalidScheduleRef => "'schedule.refstring' must be a string",
+            Error::InvalidScheduleCron => "'schedule.cron' must be a five field cron expression",
             Error::FileOpenError => "could not open config file",
             Error::FileReadError => "could not read config file into string",
         }
@@ -87,7 +733,7 @@ fn get_xdg_data_home() -> Option<String> {
     })
 }
 
-fn get_default_log_dir() -> Option<String> {
+pub fn get_default_log_dir() -> Option<String> {
     let xdg_data_home = match get_xdg_data_home() {
         None => return None,
         Some(dir) => dir,
@@ -95,7 +741,7 @@ fn get_default_log_dir() -> Option<String> {
     Some(format!("{}/hookshot/logs", xdg_data_home))
 }
 
-fn get_default_checkout_dir() -> Option<String> {
+pub fn get_default_checkout_dir() -> Option<String> {
     let xdg_data_home = match get_xdg_data_home() {
         None => return None,
         Some(dir) => dir,
@@ -116,8 +762,60 @@ impl ServerConfig {
         Self::from(&contents)
     }
 
+    /// A config with everything but the secret, hostname and roots left at
+    /// the defaults, for building a config without a config file.
+    pub fn new(secret: &str,
+               hostname: &str,
+               checkout_root: VerifiedPath,
+               log_root: VerifiedPath)
+               -> ServerConfig {
+        ServerConfig {
+            secret: String::from(secret),
+            hostname: String::from(hostname),
+            checkout_root: checkout_root,
+            log_root: log_root,
+            queue_limit: None,
+            queue_strategy: QueueStrategy::Ref,
+            port: DEFAULT_PORT,
+            listen: default_listen(DEFAULT_PORT),
+            environments: Table::new(),
+            named_environments: BTreeMap::new(),
+            pagerduty_routing_key: None,
+            repos: BTreeMap::new(),
+            janitor_interval: DEFAULT_JANITOR_INTERVAL,
+            history_retention: None,
+            checkout_retention: None,
+            checkout_quota: None,
+            strip_ansi_logs: true,
+            raw_logs: false,
+            log_system_environment: true,
+            log_hookshot_environment: true,
+            log_sync: SyncPolicy::Never,
+            config_paths: default_config_paths(),
+            orphan_processes: OrphanAction::Terminate,
+            persist_queue: true,
+            log_format: logging::Format::Text,
+            log_level: logging::Level::Info,
+            allow_env_override: vec![],
+            required_tools: default_required_tools(),
+            skip_directives: default_skip_directives(),
+            schedule: vec![],
+            maintenance: false,
+            maintenance_mode: MaintenanceMode::Queue,
+            maintenance_retry_after: DEFAULT_MAINTENANCE_RETRY_AFTER,
+            replay_window: None,
+            http_timeouts: Timeouts::default(),
+            read_token: None,
+            redact: vec![],
+            accepted_format: AcceptedFormat::Text,
+            accepted_template: None,
+            endpoints: Endpoints::default(),
+            headers: ResponseHeaders::default(),
+            cors: None,
+        }
+    }
+
     pub fn from(string: &str) -> Result<ServerConfig, Error> {
-        let default_port = 1469;
         let default_checkout_dir = get_default_checkout_dir();
         let default_log_dir = get_default_log_dir();
 
@@ -135,12 +833,29 @@ impl ServerConfig {
             _ => return Err(Error::InvalidSecret),
 
         };
+        let listen = match lookup_as_string_array(config, "listen") {
+            LookupResult::Missing => None,
+            LookupResult::StringArrayValue(ref addresses) if !addresses.is_empty() => {
+                let mut listen = vec![];
+                for address in addresses {
+                    match address.parse::<SocketAddr>() {
+                        Ok(address) => listen.push(address),
+                        Err(_) => return Err(Error::InvalidListen),
+                    }
+                }
+                Some(listen)
+            }
+            _ => return Err(Error::InvalidListen),
+        };
+        // Without a port, links use the port of the first listener
         let u16_max = u16::max_value() as i64;
-        let port = match config.lookup("port") {
-            None => default_port,
-            Some(&Value::Integer(port)) if port < u16_max => port as u16,
+        let port = match (config.lookup("port"), &listen) {
+            (None, &Some(ref listen)) => listen[0].port(),
+            (None, &None) => DEFAULT_PORT,
+            (Some(&Value::Integer(port)), _) if port < u16_max => port as u16,
             _ => return Err(Error::InvalidPort),
         };
+        let listen = listen.unwrap_or(default_listen(port));
 
         let checkout_root = match lookup_as_string(config, "checkout_root") {
             LookupResult::Missing => {
@@ -189,6 +904,172 @@ impl ServerConfig {
             LookupResult::IntegerValue(v) if v > 0 => Some(v as u64),
             _ => return Err(Error::InvalidQueueLimit),
         };
+        let queue_strategy = match lookup_as_string(config, "queue_strategy") {
+            LookupResult::Missing => QueueStrategy::Ref,
+            LookupResult::StringValue(v) => match QueueStrategy::from_str(v) {
+                Some(strategy) => strategy,
+                None => return Err(Error::InvalidQueueStrategy),
+            },
+            _ => return Err(Error::InvalidQueueStrategy),
+        };
+        let janitor_interval = match lookup_as_integer(config, "janitor_interval") {
+            LookupResult::Missing => DEFAULT_JANITOR_INTERVAL,
+            LookupResult::IntegerValue(v) if v > 0 => v as u64,
+            _ => return Err(Error::InvalidJanitorInterval),
+        };
+        let history_retention = match lookup_as_integer(config, "history_retention") {
+            LookupResult::Missing => None,
+            LookupResult::IntegerValue(v) if v > 0 => Some(v as u64),
+            _ => return Err(Error::InvalidHistoryRetention),
+        };
+        let checkout_retention = match lookup_as_integer(config, "checkout_retention") {
+            LookupResult::Missing => None,
+            LookupResult::IntegerValue(v) if v > 0 => Some(v as u64),
+            _ => return Err(Error::InvalidCheckoutRetention),
+        };
+        let checkout_quota = match lookup_as_integer(config, "checkout_quota") {
+            LookupResult::Missing => None,
+            LookupResult::IntegerValue(v) if v > 0 => Some(v as u64),
+            _ => return Err(Error::InvalidCheckoutQuota),
+        };
+        let strip_ansi_logs = match config.lookup("strip_ansi_logs") {
+            None => true,
+            Some(&Value::Boolean(v)) => v,
+            _ => return Err(Error::InvalidStripAnsiLogs),
+        };
+        let raw_logs = match config.lookup("raw_logs") {
+            None => false,
+            Some(&Value::Boolean(v)) => v,
+            _ => return Err(Error::InvalidRawLogs),
+        };
+        let log_system_environment = match config.lookup("log_system_environment") {
+            None => true,
+            Some(&Value::Boolean(v)) => v,
+            _ => return Err(Error::InvalidLogSystemEnvironment),
+        };
+        let log_hookshot_environment = match config.lookup("log_hookshot_environment") {
+            None => true,
+            Some(&Value::Boolean(v)) => v,
+            _ => return Err(Error::InvalidLogHookshotEnvironment),
+        };
+        let log_sync = match lookup_as_string(config, "log_sync") {
+            LookupResult::Missing => SyncPolicy::Never,
+            LookupResult::StringValue(v) => match SyncPolicy::from_str(v) {
+                Some(sync) => sync,
+                None => return Err(Error::InvalidLogSync),
+            },
+            _ => return Err(Error::InvalidLogSync),
+        };
+        let config_paths = match lookup_as_string_array(config, "config_paths") {
+            LookupResult::Missing => default_config_paths(),
+            LookupResult::StringArrayValue(ref v) if !v.is_empty() && v.iter().all(|p| is_checkout_path(p)) => v.clone(),
+            _ => return Err(Error::InvalidConfigPaths),
+        };
+        let orphan_processes = match lookup_as_string(config, "orphan_processes") {
+            LookupResult::Missing => OrphanAction::Terminate,
+            LookupResult::StringValue(v) => match OrphanAction::from_str(v) {
+                Some(action) => action,
+                None => return Err(Error::InvalidOrphanProcesses),
+            },
+            _ => return Err(Error::InvalidOrphanProcesses),
+        };
+        let persist_queue = match config.lookup("persist_queue") {
+            None => true,
+            Some(&Value::Boolean(v)) => v,
+            _ => return Err(Error::InvalidPersistQueue),
+        };
+        let log_format = match lookup_as_string(config, "log_format") {
+            LookupResult::Missing => logging::Format::Text,
+            LookupResult::StringValue(v) => match logging::Format::from_str(v) {
+                Some(format) => format,
+                None => return Err(Error::InvalidLogFormat),
+            },
+            _ => return Err(Error::InvalidLogFormat),
+        };
+        let log_level = match lookup_as_string(config, "log_level") {
+            LookupResult::Missing => logging::Level::Info,
+            LookupResult::StringValue(v) => match logging::Level::from_str(v) {
+                Some(level) => level,
+                None => return Err(Error::InvalidLogLevel),
+            },
+            _ => return Err(Error::InvalidLogLevel),
+        };
+        let allow_env_override = match lookup_as_string_array(config, "allow_env_override") {
+            LookupResult::Missing => vec![],
+            LookupResult::StringArrayValue(v) => v,
+            _ => return Err(Error::InvalidAllowEnvOverride),
+        };
+        let redact = match lookup_as_string_array(config, "redact") {
+            LookupResult::Missing => vec![],
+            LookupResult::StringArrayValue(v) => v,
+            _ => return Err(Error::InvalidRedact),
+        };
+        let required_tools = match lookup_as_string_array(config, "required_tools") {
+            LookupResult::Missing => default_required_tools(),
+            LookupResult::StringArrayValue(v) => v,
+            _ => return Err(Error::InvalidRequiredTools),
+        };
+        let skip_directives = match lookup_as_string_array(config, "skip_directives") {
+            LookupResult::Missing => default_skip_directives(),
+            LookupResult::StringArrayValue(v) => v,
+            _ => return Err(Error::InvalidSkipDirectives),
+        };
+        let maintenance = match config.lookup("maintenance") {
+            None => false,
+            Some(&Value::Boolean(v)) => v,
+            _ => return Err(Error::InvalidMaintenance),
+        };
+        let maintenance_mode = match lookup_as_string(config, "maintenance_mode") {
+            LookupResult::Missing => MaintenanceMode::Queue,
+            LookupResult::StringValue(v) => match MaintenanceMode::from_str(v) {
+                Some(mode) => mode,
+                None => return Err(Error::InvalidMaintenanceMode),
+            },
+            _ => return Err(Error::InvalidMaintenanceMode),
+        };
+        let maintenance_retry_after = match lookup_as_integer(config, "maintenance_retry_after") {
+            LookupResult::Missing => DEFAULT_MAINTENANCE_RETRY_AFTER,
+            LookupResult::IntegerValue(v) if v > 0 => v as u64,
+            _ => return Err(Error::InvalidMaintenanceRetryAfter),
+        };
+        let replay_window = match lookup_as_integer(config, "replay_window") {
+            LookupResult::Missing => None,
+            LookupResult::IntegerValue(v) if v > 0 => Some(v as u64),
+            _ => return Err(Error::InvalidReplayWindow),
+        };
+        let http_read_timeout = match lookup_as_integer(config, "http_read_timeout") {
+            LookupResult::Missing => http::DEFAULT_READ_TIMEOUT,
+            LookupResult::IntegerValue(v) if v > 0 => v as u64,
+            _ => return Err(Error::InvalidHttpReadTimeout),
+        };
+        let http_deadline = match lookup_as_integer(config, "http_deadline") {
+            LookupResult::Missing => http::DEFAULT_DEADLINE,
+            LookupResult::IntegerValue(v) if v > 0 => v as u64,
+            _ => return Err(Error::InvalidHttpDeadline),
+        };
+        let accepted_format = match lookup_as_string(config, "accepted_format") {
+            LookupResult::Missing => AcceptedFormat::Text,
+            LookupResult::StringValue(v) => match AcceptedFormat::from_str(v) {
+                Some(format) => format,
+                None => return Err(Error::InvalidAcceptedFormat),
+            },
+            _ => return Err(Error::InvalidAcceptedFormat),
+        };
+        let accepted_template = match lookup_as_string(config, "accepted_template") {
+            LookupResult::Missing => None,
+            LookupResult::StringValue(v) if valid_accepted_template(v, accepted_format) => Some(String::from(v)),
+            _ => return Err(Error::InvalidAcceptedTemplate),
+        };
+        let read_token = match lookup_as_string(config, "read_token") {
+            LookupResult::Missing => None,
+            LookupResult::StringValue(v) if !v.is_empty() => Some(String::from(v)),
+            _ => return Err(Error::InvalidReadToken),
+        };
+        let pagerduty_routing_key = match lookup_as_string(config, "pagerduty_routing_key") {
+            LookupResult::Missing => None,
+            LookupResult::StringValue(v) => Some(String::from(v)),
+            _ => return Err(Error::InvalidPagerDutyRoutingKey),
+        };
         let environments = match root.get("env") {
             None => Table::new(),
             Some(value) => match value.as_table() {
@@ -197,17 +1078,358 @@ impl ServerConfig {
             },
         };
 
+        let mut named_environments = BTreeMap::new();
+        if let Some(value) = root.get("environment") {
+            let sections = match value.as_table() {
+                None => return Err(Error::InvalidNamedEnvironment),
+                Some(table) => table,
+            };
+            for (name, section) in sections {
+                let section = match section.as_table() {
+                    None => return Err(Error::InvalidNamedEnvironment),
+                    Some(table) => table,
+                };
+                let mut environment = Environment::new();
+                for (k, v) in section {
+                    match v.as_str() {
+                        Some(v) => environment.insert(k.clone(), String::from(v)),
+                        None => return Err(Error::InvalidNamedEnvironment),
+                    };
+                }
+                named_environments.insert(name.clone(), environment);
+            }
+        }
+
+        let mut repos = BTreeMap::new();
+        if let Some(value) = root.get("repo") {
+            let owners = match value.as_table() {
+                None => return Err(Error::InvalidRepoTable),
+                Some(table) => table,
+            };
+            for (owner, repo_table) in owners {
+                let repo_table = match repo_table.as_table() {
+                    None => return Err(Error::InvalidRepoTable),
+                    Some(table) => table,
+                };
+                for (name, settings) in repo_table {
+                    if settings.as_table().is_none() {
+                        return Err(Error::InvalidRepoTable);
+                    }
+                    let clone_protocol = match lookup_as_string(settings, "clone_protocol") {
+                        LookupResult::Missing => CloneProtocol::Ssh,
+                        LookupResult::StringValue("ssh") => CloneProtocol::Ssh,
+                        LookupResult::StringValue("https") => CloneProtocol::Https,
+                        _ => return Err(Error::InvalidCloneProtocol),
+                    };
+                    let token = match lookup_as_string(settings, "token") {
+                        LookupResult::Missing => None,
+                        LookupResult::StringValue(v) => Some(String::from(v)),
+                        _ => return Err(Error::InvalidToken),
+                    };
+                    let submodules = match settings.lookup("submodules") {
+                        None => false,
+                        Some(&Value::Boolean(v)) => v,
+                        _ => return Err(Error::InvalidSubmodules),
+                    };
+                    let quota_action = match lookup_as_string(settings, "quota_action") {
+                        LookupResult::Missing => QuotaAction::Fail,
+                        LookupResult::StringValue(v) => match QuotaAction::from_str(v) {
+                            Some(action) => action,
+                            None => return Err(Error::InvalidRepoQuotaAction),
+                        },
+                        _ => return Err(Error::InvalidRepoQuotaAction),
+                    };
+                    let quota = match lookup_as_integer(settings, "quota") {
+                        LookupResult::Missing => None,
+                        LookupResult::IntegerValue(v) if v > 0 => Some(Quota {
+                            bytes: (v as u64) * 1024 * 1024,
+                            action: quota_action,
+                        }),
+                        _ => return Err(Error::InvalidRepoQuota),
+                    };
+                    let maintenance = match settings.lookup("maintenance") {
+                        None => false,
+                        Some(&Value::Boolean(v)) => v,
+                        _ => return Err(Error::InvalidRepoMaintenance),
+                    };
+                    let depends_on = match lookup_as_string_array(settings, "depends_on") {
+                        LookupResult::Missing => vec![],
+                        LookupResult::StringArrayValue(repos) => repos,
+                        _ => return Err(Error::InvalidRepoDependsOn),
+                    };
+                    for repo in &depends_on {
+                        let parts = repo.split('/').collect::<Vec<&str>>();
+                        if parts.len() != 2 || parts[0].is_empty() || parts[1].is_empty() {
+                            return Err(Error::InvalidRepoDependsOn);
+                        }
+                    }
+                    let depth = match lookup_as_integer(settings, "depth") {
+                        LookupResult::Missing => None,
+                        LookupResult::IntegerValue(v) if v >= 0 && v <= u32::MAX as i64 => Some(v as u32),
+                        _ => return Err(Error::InvalidRepoDepth),
+                    };
+                    let partial_clone = match settings.lookup("partial_clone") {
+                        None => false,
+                        Some(&Value::Boolean(v)) => v,
+                        _ => return Err(Error::InvalidRepoPartialClone),
+                    };
+                    let bandwidth_limit = match lookup_as_integer(settings, "bandwidth_limit") {
+                        LookupResult::Missing => None,
+                        LookupResult::IntegerValue(v) if v > 0 && v <= u32::MAX as i64 => Some(v as u32),
+                        _ => return Err(Error::InvalidRepoBandwidthLimit),
+                    };
+                    let bandwidth_limit_hours = match lookup_as_string(settings, "bandwidth_limit_hours") {
+                        LookupResult::Missing => None,
+                        LookupResult::StringValue(v) => match Transfer::parse_hours(v) {
+                            Some(hours) => Some(hours),
+                            None => return Err(Error::InvalidRepoBandwidthLimitHours),
+                        },
+                        _ => return Err(Error::InvalidRepoBandwidthLimitHours),
+                    };
+                    let artifacts = try!(lookup_artifacts(settings).ok_or(Error::InvalidRepoArtifacts));
+                    let config_path = match lookup_as_string(settings, "config_path") {
+                        LookupResult::Missing => None,
+                        LookupResult::StringValue(v) if is_checkout_path(v) => Some(String::from(v)),
+                        _ => return Err(Error::InvalidRepoConfigPath),
+                    };
+                    let projects = try!(lookup_projects(settings).ok_or(Error::InvalidRepoProjects));
+                    repos.insert(format!("{}/{}", owner, name),
+                                 RepoSettings {
+                                     clone_protocol: clone_protocol,
+                                     token: token,
+                                     submodules: submodules,
+                                     quota: quota,
+                                     maintenance: maintenance,
+                                     depends_on: depends_on,
+                                     transfer: Transfer {
+                                         depth: depth,
+                                         partial_clone: partial_clone,
+                                         bandwidth_limit: bandwidth_limit,
+                                         bandwidth_limit_hours: bandwidth_limit_hours,
+                                     },
+                                     artifacts: artifacts,
+                                     config_path: config_path,
+                                     projects: projects,
+                                 });
+                }
+            }
+        }
+        if has_dependency_cycle(&repos) {
+            return Err(Error::DependencyCycle);
+        }
+
+        let endpoints = match root.get("endpoints") {
+            None => Endpoints::default(),
+            Some(value) => match Endpoints::from_toml(value) {
+                Some(endpoints) => endpoints,
+                None => return Err(Error::InvalidEndpoints),
+            },
+        };
+
+        let headers = match root.get("headers") {
+            None => ResponseHeaders::default(),
+            Some(value) => match ResponseHeaders::from_toml(value) {
+                Some(headers) => headers,
+                None => return Err(Error::InvalidHeaders),
+            },
+        };
+
+        let cors = match root.get("cors") {
+            None => None,
+            Some(value) => match Cors::from_toml(value) {
+                Some(cors) => Some(cors),
+                None => return Err(Error::InvalidCors),
+            },
+        };
+
+        let mut schedule = vec![];
+        if let Some(value) = root.get("schedule") {
+            let entries = match value.as_slice() {
+                None => return Err(Error::InvalidScheduleTable),
+                Some(entries) => entries,
+            };
+            for entry in entries {
+                if entry.as_table().is_none() {
+                    return Err(Error::InvalidScheduleTable);
+                }
+                let (owner, name) = match lookup_as_string(entry, "repo") {
+                    LookupResult::StringValue(v) => {
+                        let parts = v.split('/').collect::<Vec<&str>>();
+                        if parts.len() != 2 || parts[0].is_empty() || parts[1].is_empty() {
+                            return Err(Error::InvalidScheduleRepo);
+                        }
+                        (String::from(parts[0]), String::from(parts[1]))
+                    }
+                    _ => return Err(Error::InvalidScheduleRepo),
+                };
+                let refstring = match lookup_as_string(entry, "refstring") {
+                    LookupResult::StringValue(v) => String::from(v),
+                    _ => return Err(Error::InvalidScheduleRef),
+                };
+                let cron = match lookup_as_string(entry, "cron") {
+                    LookupResult::StringValue(v) => match Cron::parse(v) {
+                        Some(cron) => cron,
+                        None => return Err(Error::InvalidScheduleCron),
+                    },
+                    _ => return Err(Error::InvalidScheduleCron),
+                };
+                schedule.push(ScheduleEntry {
+                    owner: owner,
+                    name: name,
+                    refstring: refstring,
+                    cron: cron,
+                });
+            }
+        }
+
         Ok(ServerConfig {
             port: port,
+            listen: listen,
             queue_limit: queue_limit,
+            queue_strategy: queue_strategy,
             checkout_root: checkout_root,
             log_root: log_root,
             secret: secret,
             environments: environments,
+            named_environments: named_environments,
             hostname: hostname,
+            pagerduty_routing_key: pagerduty_routing_key,
+            repos: repos,
+            janitor_interval: janitor_interval,
+            history_retention: history_retention,
+            checkout_retention: checkout_retention,
+            checkout_quota: checkout_quota,
+            strip_ansi_logs: strip_ansi_logs,
+            raw_logs: raw_logs,
+            log_system_environment: log_system_environment,
+            log_hookshot_environment: log_hookshot_environment,
+            log_sync: log_sync,
+            config_paths: config_paths,
+            orphan_processes: orphan_processes,
+            persist_queue: persist_queue,
+            log_format: log_format,
+            log_level: log_level,
+            allow_env_override: allow_env_override,
+            required_tools: required_tools,
+            skip_directives: skip_directives,
+            schedule: schedule,
+            maintenance: maintenance,
+            maintenance_mode: maintenance_mode,
+            maintenance_retry_after: maintenance_retry_after,
+            replay_window: replay_window,
+            http_timeouts: Timeouts::from_secs(http_read_timeout, http_deadline),
+            read_token: read_token,
+            accepted_format: accepted_format,
+            accepted_template: accepted_template,
+            redact: redact,
+            endpoints: endpoints,
+            headers: headers,
+            cors: cors,
+        })
+    }
+
+    /// The config as a config file that parses back to the same config,
+    /// secret and tokens included.
+    pub fn to_toml(&self) -> String {
+        toml::encode_str(self)
+    }
+
+    fn encode_config_section<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let listen = self.listen.iter().map(|address| address.to_string()).collect::<Vec<String>>();
+        s.emit_struct("config", 36, |s| {
+            try!(s.emit_struct_field("secret", 0, |s| self.secret.encode(s)));
+            try!(s.emit_struct_field("hostname", 1, |s| self.hostname.encode(s)));
+            try!(s.emit_struct_field("port", 2, |s| self.port.encode(s)));
+            try!(s.emit_struct_field("checkout_root", 3, |s| s.emit_str(&self.checkout_root.to_string())));
+            try!(s.emit_struct_field("log_root", 4, |s| s.emit_str(&self.log_root.to_string())));
+            try!(s.emit_struct_field("queue_limit", 5, |s| self.queue_limit.encode(s)));
+            try!(s.emit_struct_field("janitor_interval", 6, |s| self.janitor_interval.encode(s)));
+            try!(s.emit_struct_field("history_retention", 7, |s| self.history_retention.encode(s)));
+            try!(s.emit_struct_field("checkout_retention", 8, |s| self.checkout_retention.encode(s)));
+            try!(s.emit_struct_field("checkout_quota", 9, |s| self.checkout_quota.encode(s)));
+            try!(s.emit_struct_field("strip_ansi_logs", 10, |s| self.strip_ansi_logs.encode(s)));
+            try!(s.emit_struct_field("raw_logs", 11, |s| self.raw_logs.encode(s)));
+            try!(s.emit_struct_field("log_format", 12, |s| s.emit_str(self.log_format.as_str())));
+            try!(s.emit_struct_field("log_level", 13, |s| s.emit_str(self.log_level.as_str())));
+            try!(s.emit_struct_field("allow_env_override", 14, |s| self.allow_env_override.encode(s)));
+            try!(s.emit_struct_field("required_tools", 15, |s| self.required_tools.encode(s)));
+            try!(s.emit_struct_field("skip_directives", 16, |s| self.skip_directives.encode(s)));
+            try!(s.emit_struct_field("maintenance", 17, |s| self.maintenance.encode(s)));
+            try!(s.emit_struct_field("maintenance_mode", 18, |s| s.emit_str(self.maintenance_mode.as_str())));
+            try!(s.emit_struct_field("maintenance_retry_after", 19, |s| self.maintenance_retry_after.encode(s)));
+            try!(s.emit_struct_field("pagerduty_routing_key", 20, |s| self.pagerduty_routing_key.encode(s)));
+            try!(s.emit_struct_field("listen", 21, |s| listen.encode(s)));
+            try!(s.emit_struct_field("replay_window", 22, |s| self.replay_window.encode(s)));
+            try!(s.emit_struct_field("http_read_timeout", 23, |s| self.http_timeouts.read.as_secs().encode(s)));
+            try!(s.emit_struct_field("http_deadline", 24, |s| self.http_timeouts.deadline.as_secs().encode(s)));
+            try!(s.emit_struct_field("read_token", 25, |s| self.read_token.encode(s)));
+            try!(s.emit_struct_field("redact", 26, |s| self.redact.encode(s)));
+            try!(s.emit_struct_field("queue_strategy", 27, |s| s.emit_str(self.queue_strategy.as_str())));
+            try!(s.emit_struct_field("persist_queue", 28, |s| self.persist_queue.encode(s)));
+            try!(s.emit_struct_field("accepted_format", 29, |s| s.emit_str(self.accepted_format.as_str())));
+            try!(s.emit_struct_field("accepted_template", 30, |s| self.accepted_template.encode(s)));
+            try!(s.emit_struct_field("log_system_environment", 31, |s| self.log_system_environment.encode(s)));
+            try!(s.emit_struct_field("log_hookshot_environment", 32, |s| self.log_hookshot_environment.encode(s)));
+            try!(s.emit_struct_field("log_sync", 33, |s| s.emit_str(self.log_sync.as_str())));
+            try!(s.emit_struct_field("config_paths", 34, |s| self.config_paths.encode(s)));
+            s.emit_struct_field("orphan_processes", 35, |s| s.emit_str(self.orphan_processes.as_str()))
+        })
+    }
+
+    /// Where hookshot can be reached, like `127.0.0.1:1469`, with IPv6
+    /// hostnames in brackets.
+    pub fn authority(&self) -> String {
+        match self.hostname.contains(':') && !self.hostname.starts_with('[') {
+            true => format!("[{}]:{}", self.hostname, self.port),
+            false => format!("{}:{}", self.hostname, self.port),
+        }
+    }
+
+    /// The `repo.*` settings for a repo, whatever the case of its name in
+    /// the config.
+    pub fn repo_settings(&self, owner: &str, name: &str) -> Option<&RepoSettings> {
+        let repo = format!("{}/{}", owner, name);
+        self.repos.get(&repo).or_else(|| {
+            self.repos.iter().find(|&(key, _)| git::names_match(key, &repo)).map(|(_, settings)| settings)
         })
     }
 
+    /// Where to look for a repo's config in its checkout, in order: its
+    /// `config_path`, if it has one, and then `config_paths`.
+    pub fn config_paths_for(&self, owner: &str, name: &str) -> Vec<String> {
+        let mut paths = self.repo_settings(owner, name)
+                            .and_then(|settings| settings.config_path.clone())
+                            .into_iter()
+                            .collect::<Vec<String>>();
+        for path in &self.config_paths {
+            if !paths.contains(path) {
+                paths.push(path.clone());
+            }
+        }
+        paths
+    }
+
+    /// The projects of a repo, empty for repos that deploy as a whole.
+    pub fn projects_for(&self, owner: &str, name: &str) -> Vec<Project> {
+        self.repo_settings(owner, name).map(|settings| settings.projects.clone()).unwrap_or(vec![])
+    }
+
+    /// A project of a repo, by name.
+    pub fn project(&self, owner: &str, name: &str, project: &str) -> Option<Project> {
+        self.projects_for(owner, name).into_iter().find(|p| p.name == project)
+    }
+
+    /// The release asset to deploy instead of a checkout, for tags of repos
+    /// with `artifacts`.
+    pub fn artifact_for(&self, repo: &GitRepo) -> Option<Artifact> {
+        if repo.reftype != RefType::tag {
+            return None;
+        }
+        self.repo_settings(&repo.owner, &repo.name)
+            .and_then(|settings| artifact::for_tag(&settings.artifacts, &repo.refstring))
+            .cloned()
+    }
+
     pub fn environment_for<'a>(&self,
                                owner: &'a str,
                                repo: &'a str,
@@ -215,7 +1437,7 @@ impl ServerConfig {
                                -> Result<Environment, Error> {
         let mut result = BTreeMap::new();
 
-        let owner_table = match self.environments.get(owner) {
+        let owner_table = match lookup_name(&self.environments, owner) {
             None => return Ok(result),
             Some(value) => match value.as_table() {
                 None => return Err(Error::InvalidEnvironmentTable),
@@ -223,7 +1445,7 @@ impl ServerConfig {
             },
         };
 
-        let repo_table = match owner_table.get(repo) {
+        let repo_table = match lookup_name(owner_table, repo) {
             None => return Ok(result),
             Some(value) => match value.as_table() {
                 None => return Err(Error::InvalidEnvironmentTable),
@@ -250,12 +1472,91 @@ impl ServerConfig {
     }
 }
 
+// Encoded in the shape of the config file, so encoding with `toml` gives a
+// file `from` parses back to the same config.
+impl Encodable for ServerConfig {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        // Repos are kept as `owner/name`, the file has a table per owner
+        let mut repos = BTreeMap::new();
+        for (repo, settings) in self.repos.iter() {
+            let mut parts = repo.splitn(2, '/');
+            let owner = parts.next().unwrap_or("");
+            let name = parts.next().unwrap_or("");
+            repos.entry(owner).or_insert_with(BTreeMap::new).insert(name, settings);
+        }
+        s.emit_struct("ServerConfig", 8, |s| {
+            try!(s.emit_struct_field("config", 0, |s| self.encode_config_section(s)));
+            try!(s.emit_struct_field("env", 1, |s| self.environments.encode(s)));
+            try!(s.emit_struct_field("environment", 2, |s| self.named_environments.encode(s)));
+            try!(s.emit_struct_field("repo", 3, |s| repos.encode(s)));
+            try!(s.emit_struct_field("schedule", 4, |s| self.schedule.encode(s)));
+            try!(s.emit_struct_field("endpoints", 5, |s| self.endpoints.encode(s)));
+            try!(s.emit_struct_field("headers", 6, |s| self.headers.encode(s)));
+            s.emit_struct_field("cors", 7, |s| self.cors.encode(s))
+        })
+    }
+}
 
 enum LookupResult<'a> {
     Missing,
     WrongType,
     StringValue(&'a str),
     IntegerValue(i64),
+    StringArrayValue(Vec<String>),
+}
+
+/// Whether following `depends_on` from some repo leads back to it, which
+/// would leave the tasks involved waiting on each other forever.
+fn has_dependency_cycle(repos: &BTreeMap<String, RepoSettings>) -> bool {
+    fn visits(repos: &BTreeMap<String, RepoSettings>, start: &str, repo: &str, seen: &mut Vec<String>) -> bool {
+        let settings = match repos.get(repo) {
+            Some(settings) => settings,
+            None => return false,
+        };
+        for dependency in &settings.depends_on {
+            if dependency == start {
+                return true;
+            }
+            if seen.contains(dependency) {
+                continue;
+            }
+            seen.push(dependency.clone());
+            if visits(repos, start, dependency, seen) {
+                return true;
+            }
+        }
+        false
+    }
+    repos.keys().any(|repo| visits(repos, repo, repo, &mut vec![]))
+}
+
+/// Listen on every IPv4 interface.
+fn default_listen(port: u16) -> Vec<SocketAddr> {
+    vec![SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port))]
+}
+
+/// The entry of `table` for an owner or repo name, an exact match or else
+/// one that only differs in case.
+fn lookup_name<'a>(table: &'a Table, name: &str) -> Option<&'a Value> {
+    table.get(name).or_else(|| table.iter().find(|&(key, _)| git::names_match(key, name)).map(|(_, value)| value))
+}
+
+fn lookup_as_string_array<'a>(obj: &'a toml::Value, key: &'static str) -> LookupResult<'a> {
+    let values = match obj.lookup(key) {
+        None => return LookupResult::Missing,
+        Some(v) => match v.as_slice() {
+            None => return LookupResult::WrongType,
+            Some(values) => values,
+        },
+    };
+    let mut strings = vec![];
+    for value in values {
+        match value.as_str() {
+            Some(s) => strings.push(String::from(s)),
+            None => return LookupResult::WrongType,
+        }
+    }
+    LookupResult::StringArrayValue(strings)
 }
 
 fn lookup_as_integer<'a>(obj: &'a toml::Value, key: &'static str) -> LookupResult<'a> {
@@ -269,6 +1570,81 @@ fn lookup_as_integer<'a>(obj: &'a toml::Value, key: &'static str) -> LookupResul
         }
     }
 }
+/// The `projects` of a `[repo.<owner>.<name>]` table, `None` if they are
+/// invalid.
+fn lookup_projects(settings: &toml::Value) -> Option<Vec<Project>> {
+    let table = match settings.lookup("projects") {
+        None => return Some(vec![]),
+        Some(table) => match table.as_table() {
+            Some(table) => table,
+            None => return None,
+        },
+    };
+    let mut projects = vec![];
+    for (name, entry) in table {
+        if !Project::valid_name(name) {
+            return None;
+        }
+        let dir = match lookup_as_string(entry, "dir") {
+            LookupResult::StringValue(v) if is_checkout_path(v) => String::from(v),
+            _ => return None,
+        };
+        projects.push(Project {
+            name: name.clone(),
+            dir: dir,
+        });
+    }
+    Some(projects)
+}
+
+/// The `artifacts` table of a repo, `None` if it's malformed.
+fn lookup_artifacts(settings: &toml::Value) -> Option<Vec<Artifact>> {
+    let table = match settings.lookup("artifacts") {
+        None => return Some(vec![]),
+        Some(table) => match table.as_table() {
+            Some(table) => table,
+            None => return None,
+        },
+    };
+    let mut artifacts = vec![];
+    for (pattern, entry) in table {
+        let asset = match lookup_as_string(entry, "asset") {
+            LookupResult::StringValue(v) if !v.is_empty() => String::from(v),
+            _ => return None,
+        };
+        let checksum_asset = match lookup_as_string(entry, "checksum_asset") {
+            LookupResult::Missing => format!("{}.sha256", asset),
+            LookupResult::StringValue(v) if !v.is_empty() => String::from(v),
+            _ => return None,
+        };
+        let public_key = match lookup_as_string(entry, "public_key") {
+            LookupResult::Missing => None,
+            LookupResult::StringValue(v) if !v.is_empty() => Some(String::from(v)),
+            _ => return None,
+        };
+        // A signature is only checked with a key to check it with
+        let signature_asset = match (lookup_as_string(entry, "signature_asset"), &public_key) {
+            (LookupResult::Missing, _) => format!("{}.sig", asset),
+            (LookupResult::StringValue(v), &Some(_)) if !v.is_empty() => String::from(v),
+            _ => return None,
+        };
+        let api_url = match lookup_as_string(entry, "api_url") {
+            LookupResult::Missing => String::from(artifact::DEFAULT_API_URL),
+            LookupResult::StringValue(v) if v.starts_with("http://") || v.starts_with("https://") => String::from(v),
+            _ => return None,
+        };
+        artifacts.push(Artifact {
+            pattern: pattern.clone(),
+            asset: asset,
+            checksum_asset: checksum_asset,
+            public_key: public_key,
+            signature_asset: signature_asset,
+            api_url: api_url,
+        });
+    }
+    Some(artifacts)
+}
+
 fn lookup_as_string<'a>(obj: &'a toml::Value, key: &'static str) -> LookupResult<'a> {
     match obj.lookup(key) {
         None => LookupResult::Missing,
@@ -284,9 +1660,21 @@ fn lookup_as_string<'a>(obj: &'a toml::Value, key: &'static str) -> LookupResult
 #[cfg(test)]
 mod tests {
     use super::*;
+    use artifact::{self, Artifact};
+    use git::{CloneProtocol, GitRepo, Transfer};
+    use http::Timeouts;
+    use logging;
     use std::path::Path;
     use std::env;
     use std::fs;
+    use std::net::SocketAddr;
+    use maintenance::MaintenanceMode;
+    use message::RefType;
+    use process::OrphanAction;
+    use storage::SyncPolicy;
+    use task_manager::QueueStrategy;
+    use workspace::{Quota, QuotaAction};
+    use verified_path::VerifiedPath;
 
     macro_rules! expect_error {
         ( $i:ident, $error:path ) => {{
@@ -444,24 +1832,1174 @@ mod tests {
         "#;
         let config = ServerConfig::from(&toml).unwrap();
         assert_eq!(config.port, 1469);
+        assert_eq!(config.listen, vec!["0.0.0.0:1469".parse::<SocketAddr>().unwrap()]);
     }
 
     #[test]
-    fn test_config_queue_limit() {
+    fn test_config_listen() {
         let toml = r#"
             [config]
             secret = "it's a secret to everyone"
-            hostname = "127.0.0.1"
+            hostname = "::1"
             checkout_root = "/tmp"
             log_root = "/tmp"
-            queue_limit = 10
+            listen = ["0.0.0.0:5712", "[::]:5712"]
         "#;
         let config = ServerConfig::from(&toml).unwrap();
-        assert_eq!(config.queue_limit, Some(10));
+        assert_eq!(config.listen,
+                   vec!["0.0.0.0:5712".parse::<SocketAddr>().unwrap(),
+                        "[::]:5712".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(config.port, 5712);
+        assert_eq!(config.authority(), "[::1]:5712");
+        assert_eq!(ServerConfig::from(&config.to_toml()).unwrap(), config);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            listen = ["[::]"]
+        "#;
+        expect_error!(toml, Error::InvalidListen);
+    }
+
+    #[test]
+    fn test_config_queue_limit() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            queue_limit = 10
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.queue_limit, Some(10));
+    }
+
+    #[test]
+    fn test_config_default_queue_limit() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.queue_limit, None);
+    }
+
+    #[test]
+    fn test_config_invalid_queue_limit() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            queue_limit = "not an integer"
+        "#;
+        expect_error!(toml, Error::InvalidQueueLimit);
+    }
+
+    #[test]
+    fn test_config_queue_strategy() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+        "#;
+        assert_eq!(ServerConfig::from(&toml).unwrap().queue_strategy, QueueStrategy::Ref);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            queue_strategy = "repo"
+        "#;
+        assert_eq!(ServerConfig::from(&toml).unwrap().queue_strategy, QueueStrategy::Repo);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            queue_strategy = "branch"
+        "#;
+        expect_error!(toml, Error::InvalidQueueStrategy);
+    }
+
+    #[test]
+    fn test_config_janitor() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.janitor_interval, 300);
+        assert_eq!(config.history_retention, None);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            history_retention = -4
+        "#;
+        expect_error!(toml, Error::InvalidHistoryRetention);
+    }
+
+    #[test]
+    fn test_config_allow_env_override() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            allow_env_override = ["git_ref"]
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.allow_env_override, vec![String::from("git_ref")]);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            allow_env_override = [1, 2]
+        "#;
+        expect_error!(toml, Error::InvalidAllowEnvOverride);
+    }
+
+    #[test]
+    fn test_config_skip_directives() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.skip_directives, vec!["[skip deploy]", "[hookshot skip]"]);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            skip_directives = []
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert!(config.skip_directives.is_empty());
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            skip_directives = "[ci skip]"
+        "#;
+        expect_error!(toml, Error::InvalidSkipDirectives);
+    }
+
+    #[test]
+    fn test_config_required_tools() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        match cfg!(feature = "ansible") {
+            true => assert_eq!(config.required_tools, vec!["git", "make", "ansible-playbook"]),
+            false => assert_eq!(config.required_tools, vec!["git", "make"]),
+        }
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            required_tools = ["git", "make"]
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.required_tools, vec!["git", "make"]);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            required_tools = "git"
+        "#;
+        expect_error!(toml, Error::InvalidRequiredTools);
+    }
+
+    #[test]
+    fn test_config_logging() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.log_format, logging::Format::Text);
+        assert_eq!(config.log_level, logging::Level::Info);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            log_format = "json"
+            log_level = "debug"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.log_format, logging::Format::Json);
+        assert_eq!(config.log_level, logging::Level::Debug);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            log_format = "xml"
+        "#;
+        expect_error!(toml, Error::InvalidLogFormat);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            log_level = "loud"
+        "#;
+        expect_error!(toml, Error::InvalidLogLevel);
+    }
+
+    #[test]
+    fn test_config_ansi_logs() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert!(config.strip_ansi_logs);
+        assert!(!config.raw_logs);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            strip_ansi_logs = false
+            raw_logs = true
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert!(!config.strip_ansi_logs);
+        assert!(config.raw_logs);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            raw_logs = "yes"
+        "#;
+        expect_error!(toml, Error::InvalidRawLogs);
+    }
+
+    #[test]
+    fn test_config_log_environment() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert!(config.log_system_environment);
+        assert!(config.log_hookshot_environment);
+        assert_eq!(config.log_sync, SyncPolicy::Never);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            log_system_environment = false
+            log_hookshot_environment = false
+            log_sync = "close"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert!(!config.log_system_environment);
+        assert!(!config.log_hookshot_environment);
+        assert_eq!(config.log_sync, SyncPolicy::Close);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            log_hookshot_environment = "no"
+        "#;
+        expect_error!(toml, Error::InvalidLogHookshotEnvironment);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            log_sync = "sometimes"
+        "#;
+        expect_error!(toml, Error::InvalidLogSync);
+    }
+
+    #[test]
+    fn test_config_paths() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.config_paths_for("brianloveswords", "hookshot"),
+                   vec![String::from(".hookshot.conf")]);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            config_paths = [".deploy/hookshot.toml", ".hookshot.conf"]
+
+            [repo.brianloveswords.monorepo]
+            config_path = "services/api/hookshot.toml"
+
+            [repo.brianloveswords.other]
+            config_path = ".hookshot.conf"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.config_paths_for("brianloveswords", "hookshot"),
+                   vec![String::from(".deploy/hookshot.toml"), String::from(".hookshot.conf")]);
+        assert_eq!(config.config_paths_for("brianloveswords", "monorepo"),
+                   vec![String::from("services/api/hookshot.toml"),
+                        String::from(".deploy/hookshot.toml"),
+                        String::from(".hookshot.conf")]);
+        assert_eq!(config.config_paths_for("brianloveswords", "other"),
+                   vec![String::from(".hookshot.conf"), String::from(".deploy/hookshot.toml")]);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            config_paths = []
+        "#;
+        expect_error!(toml, Error::InvalidConfigPaths);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            config_paths = ["/etc/hookshot.conf"]
+        "#;
+        expect_error!(toml, Error::InvalidConfigPaths);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.hookshot]
+            config_path = "../elsewhere/hookshot.conf"
+        "#;
+        expect_error!(toml, Error::InvalidRepoConfigPath);
+    }
+
+    #[test]
+    fn test_config_endpoints() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.endpoints, Endpoints::default());
+        assert!(config.endpoints.disabled().is_empty());
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [endpoints]
+            dashboard = false
+            manual_trigger = false
+            metrics = false
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert!(config.endpoints.history && config.endpoints.admin);
+        assert_eq!(config.endpoints.disabled(), vec!["dashboard", "manual_trigger", "metrics"]);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [endpoints]
+            webhooks = false
+        "#;
+        expect_error!(toml, Error::InvalidEndpoints);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [endpoints]
+            admin = "no"
+        "#;
+        expect_error!(toml, Error::InvalidEndpoints);
+    }
+
+    #[test]
+    fn test_config_headers() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [headers]
+            Strict-Transport-Security = "max-age=31536000; includeSubDomains"
+            Cache-Control = "no-cache"
+
+            [headers.history]
+            cache-control = "private, max-age=60"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.headers.for_group(None),
+                   vec![("Cache-Control", "no-cache"),
+                        ("Strict-Transport-Security", "max-age=31536000; includeSubDomains")]);
+        assert_eq!(config.headers.for_group(Some("history")),
+                   vec![("Strict-Transport-Security", "max-age=31536000; includeSubDomains"),
+                        ("cache-control", "private, max-age=60")]);
+        assert_eq!(config.headers.for_group(Some("admin")), config.headers.for_group(None));
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [headers.webhooks]
+            Cache-Control = "no-store"
+        "#;
+        expect_error!(toml, Error::InvalidHeaders);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [headers]
+            Content-Length = "0"
+        "#;
+        expect_error!(toml, Error::InvalidHeaders);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [headers]
+            X-Frame-Options = "DENY\r\nSet-Cookie: a=b"
+        "#;
+        expect_error!(toml, Error::InvalidHeaders);
+    }
+
+    #[test]
+    fn test_config_cors() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+        "#;
+        assert_eq!(ServerConfig::from(&toml).unwrap().cors, None);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [cors]
+            allowed_origins = ["https://dashboard.example.org", "http://localhost:3000"]
+            allowed_methods = ["get"]
+        "#;
+        let cors = ServerConfig::from(&toml).unwrap().cors.unwrap();
+        assert_eq!(cors.allowed_origins, vec!["https://dashboard.example.org", "http://localhost:3000"]);
+        assert_eq!(cors.allowed_methods, vec!["GET"]);
+        assert_eq!(cors.allowed_headers, vec!["Authorization", "Content-Type"]);
+        assert_eq!(cors.max_age, 600);
+        assert_eq!(cors.groups, vec!["history"]);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [cors]
+            allowed_origins = ["https://dashboard.example.org/app"]
+        "#;
+        expect_error!(toml, Error::InvalidCors);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [cors]
+            allowed_origins = ["*"]
+            groups = ["webhooks"]
+        "#;
+        expect_error!(toml, Error::InvalidCors);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [cors]
+            allowed_methods = ["GET"]
+        "#;
+        expect_error!(toml, Error::InvalidCors);
+    }
+
+    #[test]
+    fn test_config_persist_queue() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+        "#;
+        assert!(ServerConfig::from(&toml).unwrap().persist_queue);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            persist_queue = false
+        "#;
+        assert!(!ServerConfig::from(&toml).unwrap().persist_queue);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            persist_queue = "no"
+        "#;
+        expect_error!(toml, Error::InvalidPersistQueue);
+    }
+
+    #[test]
+    fn test_config_orphan_processes() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+        "#;
+        assert_eq!(ServerConfig::from(&toml).unwrap().orphan_processes, OrphanAction::Terminate);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            orphan_processes = "adopt"
+        "#;
+        assert_eq!(ServerConfig::from(&toml).unwrap().orphan_processes, OrphanAction::Adopt);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            orphan_processes = "kill"
+        "#;
+        expect_error!(toml, Error::InvalidOrphanProcesses);
+    }
+
+    #[test]
+    fn test_config_accepted() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.accepted_format, AcceptedFormat::Text);
+        assert_eq!(config.accepted_template, None);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            accepted_format = "json"
+            accepted_template = '{"text": "deploying {refstring}: {task_url}", "position": {queue_position}}'
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.accepted_format, AcceptedFormat::Json);
+        assert!(config.accepted_template.is_some());
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            accepted_format = "html"
+        "#;
+        expect_error!(toml, Error::InvalidAcceptedFormat);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            accepted_format = "json"
+            accepted_template = '{"text": "{task_url}"'
+        "#;
+        expect_error!(toml, Error::InvalidAcceptedTemplate);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            accepted_format = "json"
+            accepted_template = '{"id": {task_id}}'
+        "#;
+        expect_error!(toml, Error::InvalidAcceptedTemplate);
+    }
+
+    #[test]
+    fn test_config_checkout_cleanup() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            checkout_retention = 14
+            checkout_quota = 2048
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.checkout_retention, Some(14));
+        assert_eq!(config.checkout_quota, Some(2048));
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            checkout_retention = 0
+        "#;
+        expect_error!(toml, Error::InvalidCheckoutRetention);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            checkout_quota = "2gb"
+        "#;
+        expect_error!(toml, Error::InvalidCheckoutQuota);
+    }
+
+    #[test]
+    fn test_config_pagerduty_routing_key() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            pagerduty_routing_key = "abc123"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.pagerduty_routing_key, Some(String::from("abc123")));
+    }
+
+    #[test]
+    fn test_config_invalid_pagerduty_routing_key() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            pagerduty_routing_key = 12
+        "#;
+        expect_error!(toml, Error::InvalidPagerDutyRoutingKey);
+    }
+
+    #[test]
+    fn test_repo_settings() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.private-thing]
+            clone_protocol = "https"
+            token = "abc123"
+            submodules = true
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        let settings = config.repo_settings("brianloveswords", "private-thing").unwrap();
+        assert_eq!(settings.clone_protocol, CloneProtocol::Https);
+        assert!(settings.submodules);
+        assert_eq!(settings.token, Some(String::from("abc123")));
+        assert_eq!(settings.quota, None);
+        assert!(config.repo_settings("brianloveswords", "hookshot").is_none());
+    }
+
+    #[test]
+    fn test_repo_quota() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.hookshot]
+            quota = 100
+
+            [repo.brianloveswords.big-thing]
+            quota = 2048
+            quota_action = "clean"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.repo_settings("brianloveswords", "hookshot").unwrap().quota,
+                   Some(Quota {
+                       bytes: 100 * 1024 * 1024,
+                       action: QuotaAction::Fail,
+                   }));
+        assert_eq!(config.repo_settings("brianloveswords", "big-thing").unwrap().quota,
+                   Some(Quota {
+                       bytes: 2048 * 1024 * 1024,
+                       action: QuotaAction::Clean,
+                   }));
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.hookshot]
+            quota = 100
+            quota_action = "panic"
+        "#;
+        expect_error!(toml, Error::InvalidRepoQuotaAction);
+    }
+
+    #[test]
+    fn test_repo_transfer() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.big-thing]
+            depth = 0
+            partial_clone = true
+            bandwidth_limit = 500
+            bandwidth_limit_hours = "9-17"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.repo_settings("brianloveswords", "big-thing").unwrap().transfer,
+                   Transfer {
+                       depth: Some(0),
+                       partial_clone: true,
+                       bandwidth_limit: Some(500),
+                       bandwidth_limit_hours: Some((9, 17)),
+                   });
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.big-thing]
+            bandwidth_limit = 0
+        "#;
+        expect_error!(toml, Error::InvalidRepoBandwidthLimit);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.big-thing]
+            bandwidth_limit = 500
+            bandwidth_limit_hours = "business hours"
+        "#;
+        expect_error!(toml, Error::InvalidRepoBandwidthLimitHours);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.big-thing]
+            depth = -1
+        "#;
+        expect_error!(toml, Error::InvalidRepoDepth);
+    }
+
+    #[test]
+    fn test_repo_artifacts() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.hookshot.artifacts."v*"]
+            asset = "hookshot-{refstring}.tar.gz"
+
+            [repo.brianloveswords.hookshot.artifacts."nightly"]
+            asset = "hookshot.zip"
+            checksum_asset = "SHA256SUMS"
+            public_key = "/etc/hookshot/release.pem"
+            signature_asset = "SHA256SUMS.sig"
+            api_url = "https://github.example.org/api/v3"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        let mut repo = GitRepo {
+            owner: String::from("brianloveswords"),
+            name: String::from("hookshot"),
+            refstring: String::from("v1.2.0"),
+            reftype: RefType::tag,
+            sha: String::from("HEAD"),
+            remote_path: String::from("brianloveswords/hookshot"),
+            local_path: Path::new("/tmp").to_path_buf(),
+            clone_protocol: CloneProtocol::Ssh,
+            token: None,
+            submodules: false,
+            transfer: Transfer::default(),
+        };
+        assert_eq!(config.artifact_for(&repo).unwrap(),
+                   Artifact {
+                       pattern: String::from("v*"),
+                       asset: String::from("hookshot-{refstring}.tar.gz"),
+                       checksum_asset: String::from("hookshot-{refstring}.tar.gz.sha256"),
+                       public_key: None,
+                       signature_asset: String::from("hookshot-{refstring}.tar.gz.sig"),
+                       api_url: String::from(artifact::DEFAULT_API_URL),
+                   });
+        repo.refstring = String::from("nightly");
+        let nightly = config.artifact_for(&repo).unwrap();
+        assert_eq!(nightly.checksum_asset, "SHA256SUMS");
+        assert_eq!(nightly.public_key, Some(String::from("/etc/hookshot/release.pem")));
+        assert_eq!(nightly.signature_asset, "SHA256SUMS.sig");
+        repo.refstring = String::from("other");
+        assert!(config.artifact_for(&repo).is_none());
+        repo.refstring = String::from("v1.2.0");
+        repo.reftype = RefType::branch;
+        assert!(config.artifact_for(&repo).is_none());
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.hookshot.artifacts."v*"]
+            checksum_asset = "SHA256SUMS"
+        "#;
+        expect_error!(toml, Error::InvalidRepoArtifacts);
+
+        // A signature asset is no use without a key to check it
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.hookshot.artifacts."v*"]
+            asset = "hookshot-{refstring}.tar.gz"
+            signature_asset = "hookshot-{refstring}.tar.gz.asc"
+        "#;
+        expect_error!(toml, Error::InvalidRepoArtifacts);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.hookshot.artifacts."v*"]
+            asset = "hookshot.tar.gz"
+            api_url = "api.github.com"
+        "#;
+        expect_error!(toml, Error::InvalidRepoArtifacts);
+    }
+
+    #[test]
+    fn test_repo_projects() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.platform.projects.web]
+            dir = "services/web"
+
+            [repo.brianloveswords.platform.projects.api]
+            dir = "services/api"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        let projects = config.projects_for("brianloveswords", "platform");
+        assert_eq!(projects.iter().map(|p| &p.name[..]).collect::<Vec<&str>>(), vec!["api", "web"]);
+        assert_eq!(config.project("brianloveswords", "platform", "web").unwrap().dir, "services/web");
+        assert!(config.project("brianloveswords", "platform", "docs").is_none());
+        assert!(config.projects_for("brianloveswords", "hookshot").is_empty());
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.platform.projects."api/v2"]
+            dir = "services/api"
+        "#;
+        expect_error!(toml, Error::InvalidRepoProjects);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.platform.projects.api]
+            dir = "../api"
+        "#;
+        expect_error!(toml, Error::InvalidRepoProjects);
     }
 
     #[test]
-    fn test_config_default_queue_limit() {
+    fn test_replay_window() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            replay_window = 300
+        "#;
+        assert_eq!(ServerConfig::from(&toml).unwrap().replay_window, Some(300));
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            replay_window = 0
+        "#;
+        expect_error!(toml, Error::InvalidReplayWindow);
+    }
+
+    #[test]
+    fn test_http_timeouts() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+        "#;
+        assert_eq!(ServerConfig::from(&toml).unwrap().http_timeouts, Timeouts::from_secs(10, 30));
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            http_read_timeout = 5
+            http_deadline = 15
+        "#;
+        assert_eq!(ServerConfig::from(&toml).unwrap().http_timeouts, Timeouts::from_secs(5, 15));
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            http_deadline = 0
+        "#;
+        expect_error!(toml, Error::InvalidHttpDeadline);
+    }
+
+    #[test]
+    fn test_read_token() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            read_token = "r34d"
+        "#;
+        assert_eq!(ServerConfig::from(&toml).unwrap().read_token, Some(String::from("r34d")));
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            read_token = ""
+        "#;
+        expect_error!(toml, Error::InvalidReadToken);
+    }
+
+    #[test]
+    fn test_redact() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            redact = ["SENTRY_DSN", "*_KEY"]
+        "#;
+        assert_eq!(ServerConfig::from(&toml).unwrap().redact,
+                   vec![String::from("SENTRY_DSN"), String::from("*_KEY")]);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            redact = "SENTRY_DSN"
+        "#;
+        expect_error!(toml, Error::InvalidRedact);
+    }
+
+    #[test]
+    fn test_maintenance() {
         let toml = r#"
             [config]
             secret = "it's a secret to everyone"
@@ -470,20 +3008,159 @@ mod tests {
             log_root = "/tmp"
         "#;
         let config = ServerConfig::from(&toml).unwrap();
-        assert_eq!(config.queue_limit, None);
+        assert!(!config.maintenance);
+        assert_eq!(config.maintenance_mode, MaintenanceMode::Queue);
+        assert_eq!(config.maintenance_retry_after, 300);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            maintenance = true
+            maintenance_mode = "reject"
+            maintenance_retry_after = 60
+
+            [repo.brianloveswords.hookshot]
+            maintenance = true
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert!(config.maintenance);
+        assert_eq!(config.maintenance_mode, MaintenanceMode::Reject);
+        assert_eq!(config.maintenance_retry_after, 60);
+        assert!(config.repo_settings("brianloveswords", "hookshot").unwrap().maintenance);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            maintenance_mode = "drop"
+        "#;
+        expect_error!(toml, Error::InvalidMaintenanceMode);
     }
 
     #[test]
-    fn test_config_invalid_queue_limit() {
+    fn test_depends_on() {
         let toml = r#"
             [config]
             secret = "it's a secret to everyone"
             hostname = "127.0.0.1"
             checkout_root = "/tmp"
             log_root = "/tmp"
-            queue_limit = "not an integer"
+
+            [repo.brianloveswords.app]
+            depends_on = ["brianloveswords/lib", "brianloveswords/api"]
+
+            [repo.brianloveswords.api]
+            depends_on = ["brianloveswords/lib"]
         "#;
-        expect_error!(toml, Error::InvalidQueueLimit);
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.repo_settings("brianloveswords", "app").unwrap().depends_on,
+                   vec![String::from("brianloveswords/lib"), String::from("brianloveswords/api")]);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.app]
+            depends_on = ["lib"]
+        "#;
+        expect_error!(toml, Error::InvalidRepoDependsOn);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.app]
+            depends_on = ["brianloveswords/api"]
+
+            [repo.brianloveswords.api]
+            depends_on = ["brianloveswords/lib"]
+
+            [repo.brianloveswords.lib]
+            depends_on = ["brianloveswords/app"]
+        "#;
+        expect_error!(toml, Error::DependencyCycle);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [[schedule]]
+            repo = "brianloveswords/hookshot"
+            refstring = "main"
+            cron = "0 4 * * *"
+
+            [[schedule]]
+            repo = "brianloveswords/website"
+            refstring = "staging"
+            cron = "*/30 9-17 * * 1-5"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.schedule.len(), 2);
+        assert_eq!(config.schedule[0].owner, "brianloveswords");
+        assert_eq!(config.schedule[0].name, "hookshot");
+        assert_eq!(config.schedule[0].refstring, "main");
+        assert_eq!(config.schedule[0].cron.expression(), "0 4 * * *");
+        assert_eq!(config.schedule[1].name, "website");
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [[schedule]]
+            repo = "hookshot"
+            refstring = "main"
+            cron = "0 4 * * *"
+        "#;
+        expect_error!(toml, Error::InvalidScheduleRepo);
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [[schedule]]
+            repo = "brianloveswords/hookshot"
+            refstring = "main"
+            cron = "every night"
+        "#;
+        expect_error!(toml, Error::InvalidScheduleCron);
+    }
+
+    #[test]
+    fn test_invalid_clone_protocol() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            hostname = "127.0.0.1"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+
+            [repo.brianloveswords.private-thing]
+            clone_protocol = "carrier pigeon"
+        "#;
+        expect_error!(toml, Error::InvalidCloneProtocol);
     }
 
     #[test]
@@ -520,4 +3197,149 @@ mod tests {
         assert_eq!(env2.get("branch").unwrap(), "overrides");
     }
 
+    #[test]
+    fn test_mixed_case_names() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            hostname = "127.0.0.1"
+
+            [env.BrianLovesWords.HookShot.master]
+            username = "brianloveswords"
+
+            [repo.BrianLovesWords.HookShot]
+            token = "t0ken"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+
+        let env = config.environment_for("brianloveswords", "hookshot", "master").unwrap();
+        assert_eq!(env.get("username").unwrap(), "brianloveswords");
+        // Branches are case sensitive
+        assert!(config.environment_for("brianloveswords", "hookshot", "Master").unwrap().is_empty());
+
+        let settings = config.repo_settings("BRIANLOVESWORDS", "hookshot").unwrap();
+        assert_eq!(settings.token, Some(String::from("t0ken")));
+    }
+
+    #[test]
+    fn test_named_environments() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            hostname = "127.0.0.1"
+
+            [environment.production]
+            api_url = "https://api.example.org"
+            api_key = "s3cret"
+
+            [environment.staging]
+            api_url = "https://staging.example.org"
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        assert_eq!(config.named_environments.len(), 2);
+        assert_eq!(config.named_environments["production"]["api_key"], "s3cret");
+        assert_eq!(config.named_environments["staging"]["api_url"],
+                   "https://staging.example.org");
+
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            hostname = "127.0.0.1"
+
+            [environment.production]
+            replicas = 3
+        "#;
+        expect_error!(toml, Error::InvalidNamedEnvironment);
+    }
+
+    #[test]
+    fn test_to_toml() {
+        let toml = r#"
+            [config]
+            secret = "it's a secret to everyone"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            hostname = "127.0.0.1"
+            port = 5712
+            history_retention = 30
+            log_format = "json"
+            maintenance_mode = "reject"
+            replay_window = 300
+            queue_strategy = "repo"
+            accepted_template = "Deploying {refstring}, see {task_url}"
+            log_sync = "always"
+            config_paths = [".deploy/hookshot.toml", ".hookshot.conf"]
+            orphan_processes = "adopt"
+
+            [env.brianloveswords.hookshot.master]
+            username = "brianloveswords"
+
+            [environment.production]
+            api_url = "https://api.example.org"
+
+            [repo.brianloveswords.hookshot]
+            clone_protocol = "https"
+            token = "t0ken"
+            quota = 512
+            quota_action = "clean"
+            depends_on = ["brianloveswords/lib"]
+            depth = 20
+            partial_clone = true
+            bandwidth_limit = 1024
+            bandwidth_limit_hours = "9-17"
+            config_path = "deploy/hookshot.toml"
+
+            [repo.brianloveswords.hookshot.artifacts."v*"]
+            asset = "hookshot.tar.gz"
+
+            [repo.brianloveswords.hookshot.projects.api]
+            dir = "services/api"
+
+            [[schedule]]
+            repo = "brianloveswords/hookshot"
+            refstring = "master"
+            cron = "0 4 * * *"
+
+            [endpoints]
+            admin = false
+
+            [headers]
+            Strict-Transport-Security = "max-age=31536000"
+
+            [headers.history]
+            Cache-Control = "no-store"
+
+            [cors]
+            allowed_origins = ["https://dashboard.example.org"]
+            max_age = 60
+        "#;
+        let config = ServerConfig::from(&toml).unwrap();
+        let encoded = config.to_toml();
+        assert_eq!(ServerConfig::from(&encoded).unwrap(), config);
+    }
+
+    #[test]
+    fn test_new() {
+        let root = VerifiedPath::directory(None, Path::new("/tmp")).unwrap();
+        let mut config = ServerConfig::new("it's a secret to everyone", "127.0.0.1", root.clone(), root);
+        config.repos.insert(String::from("brianloveswords/hookshot"), RepoSettings::new());
+
+        let parsed = ServerConfig::from(&r#"
+            [config]
+            secret = "it's a secret to everyone"
+            checkout_root = "/tmp"
+            log_root = "/tmp"
+            hostname = "127.0.0.1"
+
+            [repo.brianloveswords.hookshot]
+        "#).unwrap();
+        assert_eq!(config, parsed);
+        assert_eq!(ServerConfig::from(&config.to_toml()).unwrap(), config);
+    }
 }
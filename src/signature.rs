@@ -97,10 +97,22 @@ impl Signature {
         }
     }
 
+    /// Whether this is the signature of `data` with `key`. The comparison
+    /// takes as long however much of the signature is right, so it can't be
+    /// guessed one digit at a time.
     pub fn verify(&self, data: &str, key: &str) -> bool {
-        *self == Self::create(self.alg, data, key)
+        let expected = Self::create(self.alg, data, key);
+        constant_time_eq(self.hex.as_bytes(), expected.hex.as_bytes())
     }
 }
+
+/// Compare two byte strings in time that only depends on their length.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
 impl fmt::Display for Signature {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}={}", self.alg.to_string(), self.hex)
@@ -120,4 +132,17 @@ mod tests {
         assert_eq!(sig1, sig2);
         assert!(sig1.verify("data", "key"));
     }
+
+    #[test]
+    fn test_verify() {
+        // generated with `echo -n "data" | openssl dgst -sha256 -hmac "key"`
+        let sig = Signature::from_str("sha256=5031fe3d989c6d1537a013fa6e739da23463fdaec3b70137d828e36ace221bd0")
+                      .unwrap();
+        assert!(sig.verify("data", "key"));
+        assert!(!sig.verify("date", "key"));
+        assert!(!sig.verify("data", "kez"));
+
+        let truncated = Signature::from_str("sha256=5031fe3d989c6d1537a013fa6e739da2").unwrap();
+        assert!(!truncated.verify("data", "key"));
+    }
 }
@@ -0,0 +1,329 @@
+//! Writing task logs and records to roots that may be on network storage.
+//!
+//! Deploy hosts often keep `log_root` and `checkout_root` on NFS or CIFS
+//! mounts. There a write can fail with `EIO` while the file server is
+//! briefly unreachable, and file handles go stale (`ESTALE`) when the export
+//! is remounted. Writes to task logs, task records and the saved queue are
+//! retried a few times on those errors, `log_sync` decides when task logs
+//! and records are flushed to the server, and `/health` reports roots on
+//! network filesystems that don't respond as degraded.
+
+use libc;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+use std::time::Instant;
+use tempdir::TempDir;
+
+/// When writes to task logs and records are flushed to disk, or to the
+/// file server for network filesystems.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Whenever the OS gets to it, the default.
+    Never,
+    /// When a task log is closed, and after every record is written.
+    Close,
+    /// After every write.
+    Always,
+}
+
+impl SyncPolicy {
+    pub fn from_str(s: &str) -> Option<SyncPolicy> {
+        match s {
+            "never" => Some(SyncPolicy::Never),
+            "close" => Some(SyncPolicy::Close),
+            "always" => Some(SyncPolicy::Always),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            SyncPolicy::Never => "never",
+            SyncPolicy::Close => "close",
+            SyncPolicy::Always => "always",
+        }
+    }
+}
+
+/// How many times an operation failing with `EIO` or `ESTALE` is tried
+/// again, and how long to wait before the first retry. The wait doubles
+/// every time.
+const RETRIES: u32 = 3;
+const RETRY_DELAY_MS: u32 = 100;
+
+/// How long writing, flushing and reading back a small file may take before
+/// a root on a network filesystem counts as degraded.
+const SLOW_PROBE_MS: u64 = 2000;
+/// How long `/health` waits for the probe of a root that doesn't respond at
+/// all, like a hard NFS mount whose server is gone.
+const PROBE_TIMEOUT_MS: u64 = 5000;
+const PROBE_POLL_MS: u32 = 20;
+
+/// Filesystem types, as `/proc/mounts` lists them, that are network
+/// storage.
+const NETWORK_FILESYSTEMS: &'static [&'static str] = &["nfs", "nfs4", "cifs", "smb3", "smbfs", "ceph",
+                                                       "glusterfs", "9p", "afs", "fuse.sshfs"];
+
+fn retryable(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(code) => code == libc::EIO || code == libc::ESTALE,
+        None => false,
+    }
+}
+
+/// Run `op`, and again after a short wait while it fails with an error that
+/// network filesystems give when their server is briefly unavailable. The
+/// whole operation is repeated, so it should be fine to do twice.
+pub fn retry<T, F: FnMut() -> io::Result<T>>(mut op: F) -> io::Result<T> {
+    let mut delay = RETRY_DELAY_MS;
+    let mut retries = 0;
+    loop {
+        match op() {
+            Err(ref e) if retryable(e) && retries < RETRIES => {
+                retries += 1;
+                thread::sleep_ms(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Add `buf` to the end of `file`, flushing it if `sync` is set, with
+/// retries on `EIO` and `ESTALE`. Only the part that wasn't written yet is
+/// written again, and before every retry the file is opened again with
+/// `reopen`, since a handle that went stale stays stale.
+pub fn append<F: FnMut() -> io::Result<File>>(file: &mut File,
+                                              buf: &[u8],
+                                              sync: bool,
+                                              mut reopen: F)
+                                              -> io::Result<()> {
+    let mut written = 0;
+    let mut delay = RETRY_DELAY_MS;
+    let mut retries = 0;
+    loop {
+        let result = if written < buf.len() {
+            match file.write(&buf[written..]) {
+                Ok(0) => Err(io::Error::new(io::ErrorKind::WriteZero, "could not write the whole buffer")),
+                Ok(n) => {
+                    written += n;
+                    continue;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            }
+        } else if sync {
+            file.sync_data()
+        } else {
+            Ok(())
+        };
+        match result {
+            Err(ref e) if retryable(e) && retries < RETRIES => {
+                retries += 1;
+                thread::sleep_ms(delay);
+                delay *= 2;
+                if let Ok(reopened) = reopen() {
+                    *file = reopened;
+                }
+            }
+            result => return result,
+        }
+    }
+}
+
+/// An error, with what it usually means when it comes from a network
+/// filesystem.
+pub fn describe(e: &io::Error) -> String {
+    match e.raw_os_error() {
+        Some(code) if code == libc::ESTALE => {
+            format!("{} (the file was removed or the filesystem remounted on the file server)", e)
+        }
+        Some(code) if code == libc::EIO => {
+            format!("{} (on a network filesystem this usually means the file server is unreachable)", e)
+        }
+        _ => format!("{}", e),
+    }
+}
+
+/// Write `contents` to `path`, replacing what was there, with retries on
+/// `EIO` and `ESTALE`.
+pub fn write_file(path: &Path, contents: &[u8], sync: SyncPolicy) -> io::Result<()> {
+    retry(|| {
+        let mut file = try!(File::create(path));
+        try!(file.write_all(contents));
+        match sync {
+            SyncPolicy::Never => Ok(()),
+            _ => file.sync_all(),
+        }
+    })
+}
+
+/// The type of the network filesystem `path` is on, like `nfs4`. `None` for
+/// local filesystems, and where there's no `/proc/mounts` to tell.
+pub fn network_filesystem(path: &Path) -> Option<String> {
+    let mut mounts = String::new();
+    if File::open("/proc/mounts").and_then(|mut file| file.read_to_string(&mut mounts)).is_err() {
+        return None;
+    }
+    let path = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+    match mount_type(&mounts, &path) {
+        Some(fstype) => match NETWORK_FILESYSTEMS.contains(&&fstype[..]) {
+            true => Some(fstype),
+            false => None,
+        },
+        None => None,
+    }
+}
+
+/// The filesystem type of the mount in `mounts`, in the format of
+/// `/proc/mounts`, that `path` is on: the longest mount point it is under,
+/// the one mounted last if there are several.
+fn mount_type(mounts: &str, path: &Path) -> Option<String> {
+    let mut found: Option<(usize, &str)> = None;
+    for line in mounts.lines() {
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        if fields.len() < 3 {
+            continue;
+        }
+        // Spaces in mount points are escaped
+        let mount_point = PathBuf::from(fields[1].replace("\\040", " "));
+        let depth = mount_point.components().count();
+        if path.starts_with(&mount_point) && found.map_or(true, |(longest, _)| depth >= longest) {
+            found = Some((depth, fields[2]));
+        }
+    }
+    found.map(|(_, fstype)| String::from(fstype))
+}
+
+/// Write, flush and read back a small file in `dir`, failing if that errors
+/// or takes so long that tasks writing there would crawl. A mount that
+/// hangs fails the probe after `PROBE_TIMEOUT_MS`, and leaves behind a
+/// thread stuck on it.
+pub fn probe(dir: &Path) -> Result<(), String> {
+    let (tx, rx) = mpsc::channel();
+    let probed = dir.to_path_buf();
+    thread::spawn(move || {
+        let _ = tx.send(probe_now(&probed));
+    });
+    let started = Instant::now();
+    loop {
+        match rx.try_recv() {
+            Ok(result) => return result,
+            Err(TryRecvError::Disconnected) => return Err(format!("{}: the probe panicked", dir.display())),
+            Err(TryRecvError::Empty) if elapsed_ms(started) >= PROBE_TIMEOUT_MS => {
+                return Err(format!("{}: writing and reading back a small file didn't finish within {}ms",
+                                   dir.display(),
+                                   PROBE_TIMEOUT_MS))
+            }
+            Err(TryRecvError::Empty) => thread::sleep_ms(PROBE_POLL_MS),
+        }
+    }
+}
+
+fn elapsed_ms(started: Instant) -> u64 {
+    let elapsed = started.elapsed();
+    elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64
+}
+
+fn probe_now(dir: &Path) -> Result<(), String> {
+    let started = Instant::now();
+    let result = TempDir::new_in(dir, "hookshot-probe").and_then(|tmp| {
+        let path = tmp.path().join("probe");
+        let mut file = try!(File::create(&path));
+        try!(file.write_all(b"hookshot"));
+        try!(file.sync_all());
+        let mut contents = String::new();
+        try!(try!(File::open(&path)).read_to_string(&mut contents));
+        match &contents[..] {
+            "hookshot" => Ok(()),
+            _ => Err(io::Error::new(io::ErrorKind::Other, "read back something else than was written")),
+        }
+    });
+    if let Err(e) = result {
+        return Err(format!("{}: {}", dir.display(), describe(&e)));
+    }
+    let elapsed_ms = elapsed_ms(started);
+    match elapsed_ms > SLOW_PROBE_MS {
+        true => Err(format!("{}: writing and reading back a small file took {}ms", dir.display(), elapsed_ms)),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SyncPolicy, append, mount_type, probe, retry, write_file};
+    use libc;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read};
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_retry() {
+        let mut calls = 0;
+        let result = retry(|| {
+            calls += 1;
+            match calls {
+                1 => Err(io::Error::from_raw_os_error(libc::ESTALE)),
+                _ => Ok(calls),
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+
+        let mut calls = 0;
+        let result: io::Result<()> = retry(|| {
+            calls += 1;
+            Err(io::Error::from_raw_os_error(libc::EACCES))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_append() {
+        let dir = TempDir::new("hookshot-storage-test").unwrap();
+        let path = dir.path().join("task.log");
+        let mut file = File::create(&path).unwrap();
+        let mut reopened = 0;
+        append(&mut file, b"first\n", false, || {
+            reopened += 1;
+            OpenOptions::new().append(true).open(&path)
+        }).unwrap();
+        append(&mut file, b"second\n", true, || {
+            reopened += 1;
+            OpenOptions::new().append(true).open(&path)
+        }).unwrap();
+        assert_eq!(reopened, 0);
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "first\nsecond\n");
+    }
+
+    #[test]
+    fn test_mount_type() {
+        let mounts = "/dev/sda1 / ext4 rw,relatime 0 0\n\
+                      fileserver:/exports/logs /srv/hookshot\\040logs nfs4 rw,vers=4.2 0 0\n\
+                      tmpfs /srv/hookshot\\040logs/tmp tmpfs rw 0 0\n";
+        assert_eq!(mount_type(mounts, Path::new("/srv/hookshot logs/abc.log")), Some(String::from("nfs4")));
+        assert_eq!(mount_type(mounts, Path::new("/srv/hookshot logs/tmp/x")), Some(String::from("tmpfs")));
+        assert_eq!(mount_type(mounts, Path::new("/srv/hookshot")), Some(String::from("ext4")));
+    }
+
+    #[test]
+    fn test_write_file_and_probe() {
+        let dir = TempDir::new("hookshot-storage-test").unwrap();
+        let path = dir.path().join("record.json");
+        write_file(&path, b"{}", SyncPolicy::Close).unwrap();
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "{}");
+
+        assert!(probe(dir.path()).is_ok());
+        assert!(probe(&dir.path().join("missing")).is_err());
+        assert_eq!(SyncPolicy::from_str("close"), Some(SyncPolicy::Close));
+        assert_eq!(SyncPolicy::from_str("sometimes"), None);
+    }
+}
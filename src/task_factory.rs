@@ -0,0 +1,159 @@
+//! Deploy methods beyond `ansible` and `makefile`.
+//!
+//! A program embedding the server can register a `TaskFactory` under a
+//! method name. Repo configs can then use that name as their `method`, and
+//! when a push for a matching ref comes in the factory builds the step that
+//! runs once the checkout is ready. Everything around the step is unchanged:
+//! the checkout, the hooks, the task log, notifiers and history.
+//!
+//! ```no_run
+//! # extern crate hookshot;
+//! # use hookshot::error::CommandError;
+//! # use hookshot::git::GitRepo;
+//! # use hookshot::process::{CommandLine, ProcessGroups};
+//! # use hookshot::repo_config::Config;
+//! # use hookshot::server::Server;
+//! # use hookshot::server_config::{Environment, ServerConfig};
+//! # use hookshot::task_factory::{MethodTask, TaskFactory};
+//! # use std::path::{Path, PathBuf};
+//! # use std::process::Output;
+//! #[derive(Debug)]
+//! struct Kubectl {
+//!     manifest: PathBuf,
+//! }
+//!
+//! impl MethodTask for Kubectl {
+//!     fn run(&self, env: &Environment, groups: &ProcessGroups) -> Result<Output, CommandError> {
+//!         let mut cmd = CommandLine::new("kubectl");
+//!         cmd.arg("apply").arg("-f").arg(&self.manifest);
+//!         for (k, v) in env {
+//!             cmd.env(k.to_uppercase(), v);
+//!         }
+//!         groups.output(&cmd).map_err(|e| CommandError {
+//!             desc: "failed to execute `kubectl`, see detail",
+//!             output: None,
+//!             detail: Some(format!("{}", e)),
+//!         })
+//!     }
+//! }
+//!
+//! struct KubectlFactory;
+//!
+//! impl TaskFactory for KubectlFactory {
+//!     fn build(&self, _: &GitRepo, config: &Config, project_root: &Path)
+//!              -> Result<Box<MethodTask>, String> {
+//!         match config.settings.get("manifest") {
+//!             Some(manifest) => Ok(Box::new(Kubectl { manifest: project_root.join(manifest) })),
+//!             None => Err(String::from("kubectl deploys need a `manifest` setting")),
+//!         }
+//!     }
+//! }
+//!
+//! # fn main() {
+//! # let config = ServerConfig::from_file(Path::new("hookshot.toml")).unwrap();
+//! let mut server = Server::new(config);
+//! server.register_method("kubectl", KubectlFactory);
+//! server.run();
+//! # }
+//! ```
+
+use error::CommandError;
+use git::GitRepo;
+use process::ProcessGroups;
+use repo_config::Config;
+use server_config::Environment;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::path::Path;
+use std::process::Output;
+use std::sync::Arc;
+
+/// What a deploy method runs for a ref, in the checkout.
+pub trait MethodTask: Debug {
+    /// Run the step with the task's environment. Commands should run in one
+    /// of `groups` so they can be stopped along with the task.
+    fn run(&self, env: &Environment, groups: &ProcessGroups) -> Result<Output, CommandError>;
+}
+
+/// Builds the step for a custom deploy method.
+pub trait TaskFactory: Send + Sync {
+    /// The step for `repo` at the ref matching `config`, from a checkout at
+    /// `project_root`. An error fails the task with it.
+    fn build(&self, repo: &GitRepo, config: &Config, project_root: &Path) -> Result<Box<MethodTask>, String>;
+}
+
+/// Custom deploy methods by name.
+#[derive(Clone, Default)]
+pub struct TaskRegistry {
+    factories: BTreeMap<String, Arc<TaskFactory>>,
+}
+
+impl TaskRegistry {
+    pub fn new() -> TaskRegistry {
+        TaskRegistry { factories: BTreeMap::new() }
+    }
+
+    /// Use `factory` for refs whose `method` is `name`, replacing any factory
+    /// registered for it already. `ansible` and `makefile` can't be replaced.
+    pub fn register<F: TaskFactory + 'static>(&mut self, name: &str, factory: F) {
+        self.factories.insert(String::from(name), Arc::new(factory));
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<TaskFactory>> {
+        self.factories.get(name).cloned()
+    }
+
+    /// The names of the registered methods.
+    pub fn methods(&self) -> Vec<String> {
+        self.factories.keys().cloned().collect()
+    }
+}
+
+impl ::std::fmt::Debug for TaskRegistry {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "TaskRegistry {:?}", self.methods())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MethodTask, TaskFactory, TaskRegistry};
+    use error::CommandError;
+    use git::GitRepo;
+    use process::ProcessGroups;
+    use repo_config::Config;
+    use server_config::Environment;
+    use std::path::Path;
+    use std::process::Output;
+
+    #[derive(Debug)]
+    struct Nothing;
+
+    impl MethodTask for Nothing {
+        fn run(&self, _: &Environment, _: &ProcessGroups) -> Result<Output, CommandError> {
+            Err(CommandError {
+                desc: "nothing to run",
+                output: None,
+                detail: None,
+            })
+        }
+    }
+
+    struct NothingFactory;
+
+    impl TaskFactory for NothingFactory {
+        fn build(&self, _: &GitRepo, _: &Config, _: &Path) -> Result<Box<MethodTask>, String> {
+            Ok(Box::new(Nothing))
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = TaskRegistry::new();
+        assert!(registry.get("kubectl").is_none());
+        registry.register("kubectl", NothingFactory);
+        registry.register("capistrano", NothingFactory);
+        assert!(registry.get("kubectl").is_some());
+        assert_eq!(registry.methods(), vec!["capistrano", "kubectl"]);
+    }
+}
@@ -16,14 +16,16 @@
 //! ## Waiting for tasks to finish
 //!
 //! ```
-//! use hookshot::task_manager::{TaskManager, Runnable};
+//! use hookshot::history::TaskStatus;
+//! use hookshot::task_manager::{TaskManager, TaskOutcome, Runnable};
 //! use std::thread;
 //!
 //! struct Task { msg: &'static str, delay: u32 };
 //! impl Runnable for Task {
-//!     fn run(&mut self) {
+//!     fn run(&mut self) -> TaskOutcome {
 //!         thread::sleep_ms(self.delay);
 //!         println!("{}", self.msg);
+//!         TaskOutcome::new(TaskStatus::Success)
 //!     }
 //! }
 //! // Set a limit of 100 items per queue
@@ -53,8 +55,11 @@
 //!
 //! ## Getting results of a task
 //!
+//! The task comes back with the `TaskOutcome` its `run` returned.
+//!
 //! ```
-//! use hookshot::task_manager::{TaskManager, Runnable};
+//! use hookshot::history::TaskStatus;
+//! use hookshot::task_manager::{TaskManager, TaskOutcome, Runnable};
 //!
 //! # fn do_some_hard_work() { }
 //! struct LongRunningTask {
@@ -66,9 +71,10 @@
 //!     }
 //! }
 //! impl Runnable for LongRunningTask {
-//!     fn run(&mut self) {
+//!     fn run(&mut self) -> TaskOutcome {
 //!         do_some_hard_work();
 //!         self.result = Some(42);
+//!         TaskOutcome::new(TaskStatus::Success)
 //!     }
 //! }
 //! // Allow queues to grow without bound
@@ -78,13 +84,15 @@
 //! let key = task_manager.ensure_queue(String::from("q"));
 //!
 //! let task_rx = task_manager.add_task(&key, LongRunningTask::new()).unwrap();
-//! let task = task_rx.recv().unwrap();
+//! let (task, outcome) = task_rx.recv().unwrap();
 //! assert_eq!(task.result, Some(42));
+//! assert_eq!(outcome.status, TaskStatus::Success);
 //! ```
 //!
 //! ## Graceful shutdowns
 //! ```
-//! # use hookshot::task_manager::{TaskManager, Runnable};
+//! # use hookshot::history::TaskStatus;
+//! # use hookshot::task_manager::{TaskManager, TaskOutcome, Runnable};
 //! # use std::thread;
 //! # use std::sync::{Arc, Mutex};
 //! # use std::sync::mpsc::channel;
@@ -97,7 +105,10 @@
 //! #
 //! # struct ImportantTask;
 //! # impl Runnable for ImportantTask {
-//! #     fn run(&mut self) { println!("task added") }
+//! #     fn run(&mut self) -> TaskOutcome {
+//! #         println!("task added");
+//! #         TaskOutcome::new(TaskStatus::Success)
+//! #     }
 //! # }
 //! # impl ImportantTask { fn new() -> ImportantTask { ImportantTask } }
 //! let (shutdown_tx, shutdown_rx) = channel();
@@ -123,46 +134,160 @@
 //! // This blocks until a call to `shutdown()` is completed.
 //! shutdown_rx.recv().unwrap();
 //! println!("task manager done");
+//! ```
+//!
+//! ## Watching the queues
+//!
+//! Observers registered with `on_event` hear about every task that is
+//! queued, starts, finishes or is dropped from a full queue.
+//!
+//! ```
+//! # use hookshot::history::TaskStatus;
+//! # use hookshot::task_manager::{TaskManager, TaskOutcome, Runnable, QueueEvent};
+//! # struct Task;
+//! # impl Runnable for Task {
+//! #     fn run(&mut self) -> TaskOutcome {
+//! #         TaskOutcome::new(TaskStatus::Success)
+//! #     }
+//! # }
+//! let mut task_manager = TaskManager::new(None);
+//! task_manager.on_event(Box::new(|event| {
+//!     if let QueueEvent::Finished { ref queue, ref outcome, .. } = event {
+//!         println!("{} finished a task: {:?}", queue.as_str(), outcome.status);
+//!     }
+//! }));
+//! let queue = task_manager.ensure_queue(String::from("q"));
+//! task_manager.add_task(&queue, Task).unwrap().recv().unwrap();
+//! ```
 
+use git::{self, GitRepo};
+use history::TaskStatus;
 use std::collections::BTreeMap;
 use std::collections::VecDeque;
 use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Sender, Receiver};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread::JoinHandle;
 use std::thread;
+use std::time::{Duration, Instant};
+use unicode_normalization::UnicodeNormalization;
 
 /// Types that are able to be added to a [TaskManager](./index.html) queue.
 pub trait Runnable {
-    fn run(&mut self);
+    fn run(&mut self) -> TaskOutcome;
     fn cancel(&self) { }
+    /// Ids of tasks, in any queue, that have to finish before this one can
+    /// start. The worker asks before it runs the task, and again every time
+    /// a task finishes, holding up the rest of the queue in the meantime. A
+    /// shutdown ends the wait, the task runs and should notice.
+    fn waits_for(&self) -> Vec<String> {
+        vec![]
+    }
+    /// What to call the task in queue events.
+    fn id(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Something that happened in a queue. `task` is the task's
+/// [`id`](trait.Runnable.html#method.id).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueueEvent {
+    /// A task was added to the queue.
+    Enqueued { queue: QueueKey, task: Option<String> },
+    /// A worker picked the task up and is running it.
+    Started { queue: QueueKey, task: Option<String> },
+    /// The task ran, and this is how it went. Tasks that panic never finish.
+    Finished { queue: QueueKey, task: Option<String>, outcome: TaskOutcome },
+    /// The queue was full, so the task was dropped without running to make
+    /// room for a newer one. With a limit of 0 a new task is dropped right
+    /// away, and is never `Enqueued`.
+    Dropped { queue: QueueKey, task: Option<String> },
+}
+
+pub type Observer = Box<Fn(QueueEvent) + Send + Sync>;
+type Observers = Arc<RwLock<Vec<Observer>>>;
+
+fn emit(observers: &Observers, event: QueueEvent) {
+    for observer in observers.read().unwrap().iter() {
+        observer(event.clone());
+    }
+}
+
+/// What came of running a task. It is sent back along with the task once
+/// the task is done.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskOutcome {
+    pub status: TaskStatus,
+    /// How long the task ran. The manager fills this in for tasks that
+    /// leave it at zero.
+    pub duration: Duration,
+    /// What went wrong, for tasks that failed.
+    pub error: Option<String>,
+}
+
+impl TaskOutcome {
+    pub fn new(status: TaskStatus) -> TaskOutcome {
+        TaskOutcome {
+            status: status,
+            duration: Duration::new(0, 0),
+            error: None,
+        }
+    }
+
+    pub fn failed(error: String) -> TaskOutcome {
+        TaskOutcome {
+            status: TaskStatus::Failed,
+            duration: Duration::new(0, 0),
+            error: Some(error),
+        }
+    }
+
+    pub fn with_duration(mut self, duration: Duration) -> TaskOutcome {
+        self.duration = duration;
+        self
+    }
 }
 
+/// What a finished task sends back: the task and how it went.
+pub type Finished<T> = (T, TaskOutcome);
+
 struct Queue<T>
     where T: Runnable + Send
 {
-    queue: VecDeque<(T, Sender<T>)>,
+    queue: VecDeque<(T, Sender<Finished<T>>)>,
     limit: Option<u64>,
+    /// The id of the task the worker is running.
+    running: Option<String>,
 }
 impl<T> Queue<T> where T: Runnable + Send {
     fn new(limit: Option<u64>) -> Queue<T> {
-        Queue { queue: VecDeque::new(), limit: limit }
+        Queue { queue: VecDeque::new(), limit: limit, running: None }
     }
-    fn push_task(&mut self, task: (T, Sender<T>)) {
+    /// Add a task, returning the tasks that were dropped to stay within the
+    /// limit, oldest first. With a limit of 0 there is no room for the task
+    /// itself, and it is handed back as the error.
+    fn push_task(&mut self, task: (T, Sender<Finished<T>>)) -> Result<Vec<T>, T> {
+        let mut dropped = vec![];
         if let Some(limit) = self.limit {
             if limit < 1 {
-                return;
+                return Err(task.0);
             }
-            if self.queue.len() + 1 > limit as usize {
-                if let Some((cancelled_task, _)) = self.pop_task() {
-                    cancelled_task.cancel();
+            while self.queue.len() + 1 > limit as usize {
+                match self.pop_task() {
+                    Some((cancelled_task, _)) => {
+                        cancelled_task.cancel();
+                        dropped.push(cancelled_task);
+                    }
+                    None => break,
                 }
-                return self.push_task(task);
             }
         }
         self.queue.push_back(task);
+        Ok(dropped)
     }
-    fn pop_task(&mut self) -> Option<(T, Sender<T>)> {
+    fn pop_task(&mut self) -> Option<(T, Sender<Finished<T>>)> {
         self.queue.pop_front()
     }
 }
@@ -186,6 +311,231 @@ pub struct QueueKey {
     k: String,
 }
 
+impl QueueKey {
+    /// The queue for a task deploying `repo`: `owner/repo/ref`, or
+    /// `owner/repo` when everything for a repo shares a queue. Owner and
+    /// repo names are compared ignoring case, like GitHub does, and all of
+    /// it in Unicode NFC, so the same ref sent two ways lands in the same
+    /// queue. The ref type is left out because a branch and a tag with the
+    /// same name share a checkout.
+    pub fn for_repo(repo: &GitRepo, strategy: QueueStrategy) -> QueueKey {
+        let owner = git::normalize_name(&repo.owner);
+        let name = git::normalize_name(&repo.name);
+        let k = match strategy {
+            QueueStrategy::Ref => format!("{}/{}/{}", owner, name, repo.refstring.nfc().collect::<String>()),
+            QueueStrategy::Repo => format!("{}/{}", owner, name),
+        };
+        QueueKey { k: k }
+    }
+
+    /// The queue for a task deploying one project of a monorepo: the
+    /// repo's queue with `~project` on the end, so the projects deploy
+    /// independently of each other. Refs can't have a `~` in them.
+    pub fn for_project(repo: &GitRepo, project: &str, strategy: QueueStrategy) -> QueueKey {
+        let key = QueueKey::for_repo(repo, strategy);
+        QueueKey { k: format!("{}~{}", key.k, project) }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.k
+    }
+}
+
+impl From<String> for QueueKey {
+    fn from(k: String) -> QueueKey {
+        QueueKey { k: k }
+    }
+}
+
+/// Which tasks share a queue, and so run one after the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueStrategy {
+    /// A queue for each ref of each repo.
+    Ref,
+    /// A queue for each repo, so deploys of different refs never overlap.
+    /// The queue limit is for all of its refs together.
+    Repo,
+}
+
+impl QueueStrategy {
+    pub fn from_str(s: &str) -> Option<QueueStrategy> {
+        match s {
+            "ref" => Some(QueueStrategy::Ref),
+            "repo" => Some(QueueStrategy::Repo),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            QueueStrategy::Ref => "ref",
+            QueueStrategy::Repo => "repo",
+        }
+    }
+}
+
+/// Who holds a named lock and which tasks are waiting for it, in the order
+/// they asked.
+#[derive(Debug, Clone, PartialEq, Eq, RustcEncodable)]
+pub struct LockState {
+    pub holder: Option<String>,
+    pub waiting: Vec<String>,
+}
+
+/// The task a queue's worker is running and the ones waiting behind it,
+/// oldest first. Tasks without an id are left out.
+#[derive(Debug, Clone, PartialEq, Eq, RustcEncodable)]
+pub struct QueueState {
+    pub running: Option<String>,
+    pub waiting: Vec<String>,
+}
+
+/// A count of the things tasks in one queue wait for from another, like a
+/// lock being released or a task finishing, with a way to wait for the next
+/// one instead of checking over and over. Cloning gives another handle to
+/// the same count.
+#[derive(Clone, Default)]
+pub struct Changes {
+    count: Arc<Mutex<u64>>,
+    changed: Arc<Condvar>,
+}
+
+impl Changes {
+    pub fn new() -> Changes {
+        Changes::default()
+    }
+
+    /// How many changes there have been so far, for `wait`.
+    pub fn current(&self) -> u64 {
+        *self.count.lock().unwrap()
+    }
+
+    /// Count a change and wake everything waiting for one.
+    pub fn notify(&self) {
+        *self.count.lock().unwrap() += 1;
+        self.changed.notify_all();
+    }
+
+    /// Wait until there has been a change since `current` said `seen`, or
+    /// until `deadline`. Returns false if the deadline came first.
+    pub fn wait(&self, seen: u64, deadline: Option<Instant>) -> bool {
+        let mut count = self.count.lock().unwrap();
+        while *count == seen {
+            count = match deadline {
+                None => self.changed.wait(count).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.changed.wait_timeout(count, deadline.duration_since(now)).unwrap().0
+                }
+            };
+        }
+        true
+    }
+}
+
+/// Why a task didn't get a lock it waited for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockError {
+    Cancelled,
+    TimedOut,
+}
+
+/// Named locks shared by every queue of a manager, so tasks in different
+/// queues that touch the same thing, like a database, never run at the same
+/// time. Tasks get a lock in the order they first asked for it.
+#[derive(Clone, Default)]
+pub struct Locks {
+    table: Arc<Mutex<BTreeMap<String, LockState>>>,
+    /// Counts every release, for tasks waiting in `acquire`.
+    changes: Changes,
+}
+
+impl Locks {
+    pub fn new() -> Locks {
+        Locks::default()
+    }
+
+    /// What tasks waiting in `acquire` are woken up by. Notifying it makes
+    /// them check whether they were cancelled.
+    pub fn changes(&self) -> Changes {
+        self.changes.clone()
+    }
+
+    /// Wait until `task` has `lock`, behind any task that asked for it
+    /// earlier. `cancelled` is asked every time a lock is released or
+    /// `changes` is notified otherwise, and when it says so the task gives
+    /// up its place in line, like it does at `deadline`.
+    pub fn acquire<F>(&self, lock: &str, task: &str, deadline: Option<Instant>, cancelled: F) -> Result<(), LockError>
+        where F: Fn() -> bool
+    {
+        loop {
+            let seen = self.changes.current();
+            if self.try_acquire(lock, task) {
+                return Ok(());
+            }
+            if cancelled() {
+                self.release(lock, task);
+                return Err(LockError::Cancelled);
+            }
+            if !self.changes.wait(seen, deadline) {
+                self.release(lock, task);
+                return Err(LockError::TimedOut);
+            }
+        }
+    }
+
+    /// Take `lock` for `task` if it's free and no task asked for it earlier.
+    /// Otherwise `task` is put in line, and should try again later with the
+    /// same id.
+    pub fn try_acquire(&self, lock: &str, task: &str) -> bool {
+        let mut table = self.table.lock().unwrap();
+        let state = table.entry(String::from(lock)).or_insert(LockState {
+            holder: None,
+            waiting: vec![],
+        });
+        if state.holder.as_ref().map(|holder| &holder[..]) == Some(task) {
+            return true;
+        }
+        let next = state.waiting.first().map_or(true, |first| first == task);
+        if state.holder.is_none() && next {
+            state.waiting.retain(|waiting| waiting != task);
+            state.holder = Some(String::from(task));
+            return true;
+        }
+        if !state.waiting.iter().any(|waiting| waiting == task) {
+            state.waiting.push(String::from(task));
+        }
+        false
+    }
+
+    /// Let go of `lock`, or of `task`'s place in line for it.
+    pub fn release(&self, lock: &str, task: &str) {
+        let mut table = self.table.lock().unwrap();
+        let unused = match table.get_mut(lock) {
+            Some(state) => {
+                if state.holder.as_ref().map(|holder| &holder[..]) == Some(task) {
+                    state.holder = None;
+                }
+                state.waiting.retain(|waiting| waiting != task);
+                state.holder.is_none() && state.waiting.is_empty()
+            }
+            None => false,
+        };
+        if unused {
+            table.remove(lock);
+        }
+        self.changes.notify();
+    }
+
+    /// Every lock that is held or waited for.
+    pub fn snapshot(&self) -> BTreeMap<String, LockState> {
+        self.table.lock().unwrap().clone()
+    }
+}
+
 type QueueMap<T> = BTreeMap<QueueKey, Arc<Mutex<Queue<T>>>>;
 type ThreadMap = BTreeMap<QueueKey, (JoinHandle<()>, Sender<()>)>;
 
@@ -196,7 +546,11 @@ pub struct TaskManager<T>
     threads: ThreadMap,
     shutdown_lock: Option<Sender<()>>,
     stopped: bool,
+    /// `stopped`, for workers with a task waiting on others.
+    stopping: Arc<AtomicBool>,
     limit: Option<u64>,
+    observers: Observers,
+    locks: Locks,
 }
 
 impl<'a, T> TaskManager<T> where T: 'static + Runnable + Send {
@@ -207,7 +561,10 @@ impl<'a, T> TaskManager<T> where T: 'static + Runnable + Send {
             threads: ThreadMap::new(),
             shutdown_lock: None,
             stopped: false,
+            stopping: Arc::new(AtomicBool::new(false)),
             limit: limit,
+            observers: Arc::new(RwLock::new(vec![])),
+            locks: Locks::new(),
         }
     }
 
@@ -219,12 +576,53 @@ impl<'a, T> TaskManager<T> where T: 'static + Runnable + Send {
             threads: ThreadMap::new(),
             shutdown_lock: Some(lock),
             stopped: false,
+            stopping: Arc::new(AtomicBool::new(false)),
             limit: limit,
+            observers: Arc::new(RwLock::new(vec![])),
+            locks: Locks::new(),
         }
     }
 
+    /// Call `observer` with every event from now on, in every queue.
+    ///
+    /// Observers are called on the thread where the event happens: `Enqueued`
+    /// and `Dropped` from `add_task`, while the manager is usually locked,
+    /// and `Started` and `Finished` from the queue's worker. They shouldn't
+    /// use the manager themselves, and should be quick, since the task waits
+    /// on them.
+    pub fn on_event(&mut self, observer: Observer) {
+        self.observers.write().unwrap().push(observer);
+    }
+
+    /// What every queue is running and has waiting.
+    pub fn snapshot(&self) -> BTreeMap<QueueKey, QueueState> {
+        self.queues
+            .iter()
+            .map(|(key, queue)| {
+                let queue = queue.lock().unwrap();
+                (key.clone(),
+                 QueueState {
+                     running: queue.running.clone(),
+                     waiting: queue.queue.iter().filter_map(|&(ref task, _)| task.id()).collect(),
+                 })
+            })
+            .collect()
+    }
+
+    /// The named locks tasks in any of the queues can take, see `Locks`.
+    pub fn locks(&self) -> Locks {
+        self.locks.clone()
+    }
+
+    /// Counts every task that finishes and every lock that is released.
+    /// Notifying it makes tasks that wait for others, see
+    /// `Runnable::waits_for`, check again.
+    pub fn changes(&self) -> Changes {
+        self.locks.changes()
+    }
+
     /// Add a task to a queue. When the task is complete it will be sent back
-    /// over the returned `Receiver`.
+    /// over the returned `Receiver`, along with its outcome.
     ///
     /// # Failures
     ///
@@ -236,12 +634,13 @@ impl<'a, T> TaskManager<T> where T: 'static + Runnable + Send {
     /// will happen if an [`add_task()`](#method.add_task) call happens after a
     /// [`shutdown()`](#method.shutdown) but before a
     /// [`restart()`](#method.restart).
-    pub fn add_task(&mut self, queue_key: &QueueKey, task: T) -> Result<Receiver<T>, Error> {
+    pub fn add_task(&mut self, queue_key: &QueueKey, task: T) -> Result<Receiver<Finished<T>>, Error> {
         if self.stopped {
             return Err(Error::Shutdown);
         }
         let (task_tx, task_rx) = channel();
-        {
+        let task_id = task.id();
+        let pushed = {
             let mut locked_queue = match self.queues.get_mut(queue_key) {
                 // Safe unwrap: With the current implementation it's impossible
                 // for a lock to get poisoned. There is exactly one other spot
@@ -254,7 +653,27 @@ impl<'a, T> TaskManager<T> where T: 'static + Runnable + Send {
                 Some(queue_mutex) => queue_mutex.lock().unwrap(),
                 None => return Err(Error::QueueMissing),
             };
-            locked_queue.push_task((task, task_tx));
+            locked_queue.push_task((task, task_tx))
+        };
+        let dropped = match pushed {
+            Ok(dropped) => {
+                emit(&self.observers,
+                     QueueEvent::Enqueued {
+                         queue: queue_key.clone(),
+                         task: task_id,
+                     });
+                dropped
+            }
+            // A task there was no room for was never in the queue, so
+            // observers only hear that it was dropped
+            Err(task) => vec![task],
+        };
+        for task in dropped {
+            emit(&self.observers,
+                 QueueEvent::Dropped {
+                     queue: queue_key.clone(),
+                     task: task.id(),
+                 });
         }
 
         // Safe unwrap: If the queue exists, a corresponding thread in the
@@ -281,10 +700,14 @@ impl<'a, T> TaskManager<T> where T: 'static + Runnable + Send {
     /// # use std::thread;
     /// # use std::sync::mpsc::channel;
     /// # use std::sync::{Arc, Mutex};
-    /// # use hookshot::task_manager::{TaskManager, Runnable, Error};
+    /// # use hookshot::history::TaskStatus;
+    /// # use hookshot::task_manager::{TaskManager, TaskOutcome, Runnable, Error};
     /// # struct ImportantTask;
     /// # impl Runnable for ImportantTask {
-    /// #     fn run(&mut self) { println!("task added") }
+    /// #     fn run(&mut self) -> TaskOutcome {
+    /// #         println!("task added");
+    /// #         TaskOutcome::new(TaskStatus::Success)
+    /// #     }
     /// # }
     /// # let important_task1 = ImportantTask;
     /// # let important_task2 = ImportantTask;
@@ -327,6 +750,10 @@ impl<'a, T> TaskManager<T> where T: 'static + Runnable + Send {
     /// ```
     pub fn shutdown(&mut self) {
         self.stopped = true;
+        self.stopping.store(true, Ordering::SeqCst);
+        // Tasks waiting for a lock or another task find out they won't get
+        // it
+        self.locks.changes().notify();
         for key in self.queues.keys() {
             // Remove thread join handle from threadmap, letting worker_tx drop
             // out of scope so the worker thread quits instead of picking a new
@@ -344,6 +771,21 @@ impl<'a, T> TaskManager<T> where T: 'static + Runnable + Send {
         }
     }
 
+    /// Whether the manager has been shut down and not restarted, i.e. new tasks
+    /// will be rejected.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Change the limit of every queue, including ones that already exist.
+    /// Queues that are over the new limit shrink as tasks are added to them.
+    pub fn set_limit(&mut self, limit: Option<u64>) {
+        self.limit = limit;
+        for queue in self.queues.values() {
+            queue.lock().unwrap().limit = limit;
+        }
+    }
+
     /// Restart all queue workers and remove `stopped` flag.
     pub fn restart(&mut self) {
         let keys: Vec<_> = self.queues.keys().cloned().collect();
@@ -351,12 +793,13 @@ impl<'a, T> TaskManager<T> where T: 'static + Runnable + Send {
             self.start_worker(key);
         }
         self.stopped = false;
+        self.stopping.store(false, Ordering::SeqCst);
     }
 
     /// Create a queue only if one doesn't already exist with that key. Returns
     /// the QueueKey for that queue.
-    pub fn ensure_queue(&mut self, queue_key: String) -> QueueKey {
-        let key = QueueKey { k: queue_key };
+    pub fn ensure_queue<K: Into<QueueKey>>(&mut self, queue_key: K) -> QueueKey {
+        let key = queue_key.into();
         if self.queues.contains_key(&key) {
             return key;
         }
@@ -378,7 +821,54 @@ impl<'a, T> TaskManager<T> where T: 'static + Runnable + Send {
         }
     }
 
-    #[allow(unused_must_use)]
+    /// Add a task to a queue of a shared manager, creating the queue if it
+    /// doesn't exist yet. This does what locking `manager` for
+    /// [`ensure_queue()`](#method.ensure_queue) and
+    /// [`add_task()`](#method.add_task) does, but a new queue's worker
+    /// thread is started while `manager` isn't locked, so tasks coming in
+    /// for other queues at the same time don't wait for it. Tasks for a
+    /// queue that exists only lock `manager` once. Starting a thread takes
+    /// well under a millisecond, so this only shows when many new queues
+    /// are made at once, like a burst of pushes to new branches; it isn't
+    /// benchmarked.
+    pub fn enqueue<K: Into<QueueKey>>(manager: &Mutex<TaskManager<T>>,
+                                      queue_key: K,
+                                      task: T)
+                                      -> Result<Receiver<Finished<T>>, Error> {
+        let key = queue_key.into();
+        let (limit, observers, changes, stopping) = {
+            let mut locked_manager = manager.lock().unwrap();
+            if locked_manager.stopped || locked_manager.queues.contains_key(&key) {
+                return locked_manager.add_task(&key, task);
+            }
+            (locked_manager.limit,
+             locked_manager.observers.clone(),
+             locked_manager.changes(),
+             locked_manager.stopping.clone())
+        };
+
+        let queue = Arc::new(Mutex::new(Queue::<T>::new(limit)));
+        let worker = spawn_worker(key.clone(), queue.clone(), observers, changes, stopping);
+
+        let mut locked_manager = manager.lock().unwrap();
+        locked_manager.adopt_queue(key.clone(), queue, worker);
+        locked_manager.add_task(&key, task)
+    }
+
+    /// Take a queue made by `enqueue`. If another thread made the same
+    /// queue in the meantime, or the manager was shut down, the queue and
+    /// its worker are dropped, and the worker quits as soon as it notices
+    /// its channel is closed.
+    fn adopt_queue(&mut self, key: QueueKey, queue: Arc<Mutex<Queue<T>>>, worker: (JoinHandle<()>, Sender<()>)) {
+        if self.stopped || self.queues.contains_key(&key) {
+            return;
+        }
+        // The limit may have changed while the queue was being made
+        queue.lock().unwrap().limit = self.limit;
+        self.queues.insert(key.clone(), queue);
+        self.threads.insert(key, worker);
+    }
+
     fn start_worker(&mut self, key: QueueKey) {
         if self.stopped {
             return;
@@ -388,39 +878,91 @@ impl<'a, T> TaskManager<T> where T: 'static + Runnable + Send {
         }
 
         let queue = self.find(&key).unwrap().clone();
-        let (worker_tx, worker_rx) = channel();
-        let worker = thread::spawn(move || {
-            loop {
-                if worker_rx.recv().is_err() {
-                    // This will only happen if the manager gets
-                    // deallocated, which will typically happen if the main
-                    // thread is in the process of shutting down.
-                    break;
-                }
+        let worker = spawn_worker(key.clone(), queue, self.observers.clone(), self.changes(), self.stopping.clone());
+        self.threads.insert(key, worker);
+    }
+}
 
-                // Safe unwrap: Impossible for lock to get poisoned, see
-                // comment in `add_task()`.
-                let possible_task = queue.lock().unwrap().pop_task();
-
-                if let Some((mut task, task_tx)) = possible_task {
-                    // Protect the worker thread from any panics that would
-                    // be caused by `task.run()`.
-                    thread::spawn(move || {
-                        task.run();
-                        task_tx.send(task);
-                    }).join();
-                }
+/// Start the worker thread of a queue. It runs a task from the queue every
+/// time it's sent a `()`, and quits once the sending end is dropped. Every
+/// task that finishes is counted in `changes`.
+#[allow(unused_must_use)]
+fn spawn_worker<T>(worker_key: QueueKey,
+                   queue: Arc<Mutex<Queue<T>>>,
+                   observers: Observers,
+                   changes: Changes,
+                   stopping: Arc<AtomicBool>)
+                   -> (JoinHandle<()>, Sender<()>)
+    where T: 'static + Runnable + Send
+{
+    let (worker_tx, worker_rx) = channel();
+    let worker = thread::spawn(move || {
+        loop {
+            if worker_rx.recv().is_err() {
+                // This will only happen if the manager gets
+                // deallocated, which will typically happen if the main
+                // thread is in the process of shutting down.
+                break;
             }
-        });
-        self.threads.insert(key, (worker, worker_tx));
-    }
+
+            // Safe unwrap: Impossible for lock to get poisoned, see
+            // comment in `add_task()`.
+            let possible_task = queue.lock().unwrap().pop_task();
+
+            if let Some((mut task, task_tx)) = possible_task {
+                queue.lock().unwrap().running = task.id();
+                let observers = observers.clone();
+                let key = worker_key.clone();
+                let waits = (changes.clone(), stopping.clone());
+                // Protect the worker thread from any panics that would
+                // be caused by `task.run()`, or by an observer.
+                thread::spawn(move || {
+                    let (changes, stopping) = waits;
+                    loop {
+                        let seen = changes.current();
+                        if stopping.load(Ordering::SeqCst) || task.waits_for().is_empty() {
+                            break;
+                        }
+                        changes.wait(seen, None);
+                    }
+                    emit(&observers,
+                         QueueEvent::Started {
+                             queue: key.clone(),
+                             task: task.id(),
+                         });
+                    let started = Instant::now();
+                    let mut outcome = task.run();
+                    // Tasks that don't time themselves ran as long as `run`
+                    if outcome.duration == Duration::new(0, 0) {
+                        outcome.duration = started.elapsed();
+                    }
+                    emit(&observers,
+                         QueueEvent::Finished {
+                             queue: key,
+                             task: task.id(),
+                             outcome: outcome.clone(),
+                         });
+                    task_tx.send((task, outcome));
+                }).join();
+                queue.lock().unwrap().running = None;
+                // Tasks waiting on this one check again
+                changes.notify();
+            }
+        }
+    });
+    (worker, worker_tx)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use git::{CloneProtocol, GitRepo, Transfer};
+    use history::TaskStatus;
+    use message::RefType;
+    use std::path::PathBuf;
     use std::thread;
     use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
     use uuid::Uuid;
 
     struct Task {
@@ -429,10 +971,37 @@ mod tests {
     }
 
     impl Runnable for Task {
-        fn run(&mut self) {
+        fn run(&mut self) -> TaskOutcome {
             let mut s = self.s.lock().unwrap();
             s.push_str(self.m);
             thread::sleep_ms(50);
+            match self.m {
+                "!" => TaskOutcome::failed(String::from("exclaimed")),
+                _ => TaskOutcome::new(TaskStatus::Success),
+            }
+        }
+        fn id(&self) -> Option<String> {
+            Some(String::from(self.m))
+        }
+    }
+
+    /// Adds `m` to `s` like `Task`, once `s` has `after` in it.
+    struct Dependent {
+        s: Arc<Mutex<String>>,
+        m: &'static str,
+        after: &'static str,
+    }
+
+    impl Runnable for Dependent {
+        fn run(&mut self) -> TaskOutcome {
+            self.s.lock().unwrap().push_str(self.m);
+            TaskOutcome::new(TaskStatus::Success)
+        }
+        fn waits_for(&self) -> Vec<String> {
+            match self.s.lock().unwrap().contains(self.after) {
+                true => vec![],
+                false => vec![String::from(self.after)],
+            }
         }
     }
 
@@ -485,6 +1054,27 @@ mod tests {
         assert_eq!(*s2.lock().unwrap(), "sloths");
     }
 
+    #[test]
+    fn test_waits_for() {
+        let s = Arc::new(Mutex::new(String::new()));
+        let mut manager = TaskManager::new(None);
+        let dependent = manager.ensure_queue(String::from("dependent"));
+        let other = manager.ensure_queue(String::from("other"));
+        let finished = manager.add_task(&dependent, Dependent { s: s.clone(), m: "d", after: "a" }).unwrap();
+        thread::sleep_ms(50);
+        assert_eq!(*s.lock().unwrap(), "");
+        manager.add_task(&other, Task { s: s.clone(), m: "a" }).unwrap();
+        finished.recv().unwrap();
+        assert_eq!(*s.lock().unwrap(), "ad");
+
+        // A shutdown ends the wait
+        let s = Arc::new(Mutex::new(String::new()));
+        manager.add_task(&dependent, Dependent { s: s.clone(), m: "d", after: "never" }).unwrap();
+        thread::sleep_ms(50);
+        manager.shutdown();
+        assert_eq!(*s.lock().unwrap(), "d");
+    }
+
     #[test]
     #[allow(unused_must_use)]
     fn test_task_manager_limit() {
@@ -518,4 +1108,223 @@ mod tests {
         assert_eq!(*s1.lock().unwrap(), "15");
     }
 
+    #[test]
+    fn test_task_manager_is_stopped() {
+        let mut manager = TaskManager::<Task>::new(None);
+        assert!(!manager.is_stopped());
+        manager.shutdown();
+        assert!(manager.is_stopped());
+        manager.restart();
+        assert!(!manager.is_stopped());
+    }
+
+    #[test]
+    fn test_task_manager_set_limit() {
+        let mut manager = TaskManager::<Task>::new(None);
+        let key = manager.ensure_queue(String::from("existing"));
+        manager.set_limit(Some(3));
+        assert_eq!(manager.find(&key).unwrap().lock().unwrap().limit, Some(3));
+        let key = manager.ensure_queue(String::from("new"));
+        assert_eq!(manager.find(&key).unwrap().lock().unwrap().limit, Some(3));
+        manager.shutdown();
+    }
+
+    #[test]
+    fn test_enqueue() {
+        let s = Arc::new(Mutex::new(String::new()));
+        let task_manager = Arc::new(Mutex::new(TaskManager::new(None)));
+
+        // Threads racing to make the same queue all end up in one of them
+        let threads = (0..8).map(|_| {
+            let shared_manager = task_manager.clone();
+            let s = s.clone();
+            thread::spawn(move || {
+                TaskManager::enqueue(&shared_manager, String::from("shared"), Task { s: s, m: "x" })
+                    .unwrap()
+                    .recv()
+                    .unwrap();
+            })
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*s.lock().unwrap(), "xxxxxxxx");
+        assert_eq!(task_manager.lock().unwrap().snapshot().len(), 1);
+
+        task_manager.lock().unwrap().shutdown();
+        let result = TaskManager::enqueue(&task_manager, String::from("new"), Task { s: s.clone(), m: "y" });
+        assert_eq!(result.err(), Some(Error::Shutdown));
+        assert_eq!(task_manager.lock().unwrap().snapshot().len(), 1);
+    }
+
+    fn repo(owner: &str, name: &str, refstring: &str) -> GitRepo {
+        GitRepo {
+            owner: String::from(owner),
+            name: String::from(name),
+            refstring: String::from(refstring),
+            reftype: RefType::branch,
+            sha: String::from("abc123"),
+            remote_path: String::from("doesn't matter"),
+            local_path: PathBuf::from("irrelevant"),
+            clone_protocol: CloneProtocol::Ssh,
+            token: None,
+            submodules: false,
+            transfer: Transfer::default(),
+        }
+    }
+
+    #[test]
+    fn test_queue_key_for_repo() {
+        let key = QueueKey::for_repo(&repo("Brian", "HookShot", "feature/a\u{f1}adir"), QueueStrategy::Ref);
+        assert_eq!(key.as_str(), "brian/hookshot/feature/a\u{f1}adir");
+        // Decomposed, n + combining tilde
+        assert_eq!(QueueKey::for_repo(&repo("brian", "hookshot", "feature/an\u{303}adir"), QueueStrategy::Ref),
+                   key);
+        // Refs are case sensitive
+        assert!(QueueKey::for_repo(&repo("brian", "hookshot", "Feature/a\u{f1}adir"), QueueStrategy::Ref) != key);
+
+        let repo_key = QueueKey::for_repo(&repo("Brian", "HookShot", "master"), QueueStrategy::Repo);
+        assert_eq!(repo_key.as_str(), "brian/hookshot");
+        assert_eq!(QueueKey::for_repo(&repo("brian", "hookshot", "v1.0"), QueueStrategy::Repo), repo_key);
+
+        let project_key = QueueKey::for_project(&repo("Brian", "HookShot", "master"), "api", QueueStrategy::Repo);
+        assert_eq!(project_key.as_str(), "brian/hookshot~api");
+        assert!(QueueKey::for_project(&repo("brian", "hookshot", "master"), "web", QueueStrategy::Repo) !=
+                project_key);
+    }
+
+    #[test]
+    fn test_on_event() {
+        let s = Arc::new(Mutex::new(String::new()));
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut manager = TaskManager::new(None);
+        {
+            let events = events.clone();
+            manager.on_event(Box::new(move |event| events.lock().unwrap().push(event)));
+        }
+        let key = manager.ensure_queue(String::from("events"));
+        let (_, outcome) = manager.add_task(&key, Task { s: s.clone(), m: "ok" }).unwrap().recv().unwrap();
+        assert_eq!(*events.lock().unwrap(),
+                   vec![QueueEvent::Enqueued { queue: key.clone(), task: Some(String::from("ok")) },
+                        QueueEvent::Started { queue: key.clone(), task: Some(String::from("ok")) },
+                        QueueEvent::Finished {
+                            queue: key.clone(),
+                            task: Some(String::from("ok")),
+                            outcome: outcome.clone(),
+                        }]);
+
+        events.lock().unwrap().clear();
+        manager.set_limit(Some(0));
+        assert!(manager.add_task(&key, Task { s: s.clone(), m: "no room" }).unwrap().recv().is_err());
+        assert_eq!(*events.lock().unwrap(),
+                   vec![QueueEvent::Dropped { queue: key.clone(), task: Some(String::from("no room")) }]);
+        manager.shutdown();
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let s = Arc::new(Mutex::new(String::new()));
+        let mut manager = TaskManager::new(None);
+        let queue_key = manager.ensure_queue(String::from("q"));
+        assert_eq!(manager.snapshot()[&queue_key],
+                   QueueState {
+                       running: None,
+                       waiting: vec![],
+                   });
+        manager.add_task(&queue_key, Task { s: s.clone(), m: "a" }).unwrap();
+        manager.add_task(&queue_key, Task { s: s.clone(), m: "b" }).unwrap();
+        let last = manager.add_task(&queue_key, Task { s: s.clone(), m: "c" }).unwrap();
+        thread::sleep_ms(20);
+        assert_eq!(manager.snapshot()[&queue_key],
+                   QueueState {
+                       running: Some(String::from("a")),
+                       waiting: vec![String::from("b"), String::from("c")],
+                   });
+        last.recv().unwrap();
+        thread::sleep_ms(20);
+        assert_eq!(manager.snapshot()[&queue_key].running, None);
+    }
+
+    #[test]
+    fn test_locks() {
+        let locks = Locks::new();
+        assert!(locks.try_acquire("production-db", "a"));
+        assert!(locks.try_acquire("production-db", "a"));
+        assert!(!locks.try_acquire("production-db", "b"));
+        assert!(!locks.try_acquire("production-db", "c"));
+        assert!(!locks.try_acquire("production-db", "b"));
+        assert!(locks.try_acquire("staging-db", "d"));
+        assert_eq!(locks.snapshot()["production-db"],
+                   LockState {
+                       holder: Some(String::from("a")),
+                       waiting: vec![String::from("b"), String::from("c")],
+                   });
+
+        // Whoever asked first is next
+        locks.release("production-db", "a");
+        assert!(!locks.try_acquire("production-db", "c"));
+        assert!(locks.try_acquire("production-db", "b"));
+
+        // Giving up a place in line
+        locks.release("production-db", "c");
+        locks.release("production-db", "b");
+        locks.release("staging-db", "d");
+        assert!(locks.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_locks_acquire() {
+        let locks = Locks::new();
+        assert!(locks.try_acquire("production-db", "a"));
+
+        // Released while waiting
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || locks.acquire("production-db", "b", None, || false))
+        };
+        while locks.snapshot()["production-db"].waiting.is_empty() {
+            thread::sleep_ms(10);
+        }
+        locks.release("production-db", "a");
+        assert_eq!(waiter.join().unwrap(), Ok(()));
+        assert_eq!(locks.snapshot()["production-db"].holder, Some(String::from("b")));
+
+        // Timed out, and out of line
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert_eq!(locks.acquire("production-db", "c", Some(deadline), || false),
+                   Err(LockError::TimedOut));
+        assert!(locks.snapshot()["production-db"].waiting.is_empty());
+
+        // Cancelled while waiting
+        let cancelled = Arc::new(Mutex::new(false));
+        let waiter = {
+            let locks = locks.clone();
+            let cancelled = cancelled.clone();
+            thread::spawn(move || locks.acquire("production-db", "d", None, || *cancelled.lock().unwrap()))
+        };
+        while locks.snapshot()["production-db"].waiting.is_empty() {
+            thread::sleep_ms(10);
+        }
+        *cancelled.lock().unwrap() = true;
+        locks.changes().notify();
+        assert_eq!(waiter.join().unwrap(), Err(LockError::Cancelled));
+        assert_eq!(locks.snapshot()["production-db"].waiting, Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_task_outcome() {
+        let s = Arc::new(Mutex::new(String::new()));
+        let mut manager = TaskManager::new(None);
+        let key = manager.ensure_queue(String::from("outcome"));
+        let (task, outcome) = manager.add_task(&key, Task { s: s.clone(), m: "ok" }).unwrap().recv().unwrap();
+        assert_eq!(task.m, "ok");
+        assert_eq!(outcome.status, TaskStatus::Success);
+        assert_eq!(outcome.error, None);
+        // The manager times tasks that don't time themselves
+        assert!(outcome.duration >= Duration::from_millis(50));
+        let (_, outcome) = manager.add_task(&key, Task { s: s.clone(), m: "!" }).unwrap().recv().unwrap();
+        assert_eq!(outcome.status, TaskStatus::Failed);
+        assert_eq!(outcome.error, Some(String::from("exclaimed")));
+        manager.shutdown();
+    }
 }
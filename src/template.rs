@@ -0,0 +1,110 @@
+//! Placeholders like `{owner}` or `{refstring}` in notifier urls and
+//! payload templates.
+//!
+//! Each value is escaped for where it ends up: percent-encoded in urls and
+//! form bodies, JSON-escaped in JSON bodies. Braces around anything that
+//! isn't a known placeholder are left as they are, so JSON objects in a
+//! template need no escaping of their own.
+
+use percent;
+use rustc_serialize::json;
+
+/// Replace every `{name}` in `template` that has a value in `vars` with
+/// `escape` of that value.
+pub fn expand(template: &str, vars: &[(&str, &str)], escape: fn(&str) -> String) -> String {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            let name = &rest[1..end];
+            vars.iter().find(|&&(var, _)| var == name).map(|&(_, value)| (end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                expanded.push_str(&escape(value));
+                rest = &rest[end + 1..];
+            }
+            None => {
+                expanded.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// For urls and form bodies, see `percent::encode`.
+pub fn url_escape(value: &str) -> String {
+    percent::encode(value)
+}
+
+/// For plain text, where values go in as they are.
+pub fn verbatim(value: &str) -> String {
+    String::from(value)
+}
+
+/// `value` escaped to go between the quotes of a JSON string.
+pub fn json_escape(value: &str) -> String {
+    let quoted = json::encode(&value).unwrap_or(String::from("\"\""));
+    String::from(&quoted[1..quoted.len() - 1])
+}
+
+/// What a placeholder that always holds text is filled with when checking a
+/// JSON template. Like a real value, it's only valid JSON between quotes.
+const SAMPLE_TEXT: &'static str = "sample \"value\"";
+
+/// Whether `template` renders to valid JSON with any values filled in for
+/// `placeholders`. The ones in `scalars` hold numbers or booleans, and can
+/// go outside of quotes, every other one has to go inside a string.
+pub fn renders_json(template: &str, placeholders: &[&str], scalars: &[&str]) -> bool {
+    let vars = placeholders.iter()
+                           .map(|name| match scalars.contains(name) {
+                               true => (*name, "0"),
+                               false => (*name, SAMPLE_TEXT),
+                           })
+                           .collect::<Vec<_>>();
+    json::Json::from_str(&expand(template, &vars, json_escape)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand, json_escape, renders_json, url_escape};
+
+    #[test]
+    fn test_expand() {
+        let vars = [("owner", "brianloveswords"),
+                    ("repo", "hookshot"),
+                    ("refstring", "feature/thing"),
+                    ("status", "started"),
+                    ("task_id", "abc123")];
+        assert_eq!(expand("http://127.0.0.1:7231", &vars, url_escape), "http://127.0.0.1:7231");
+        assert_eq!(expand("http://chat.example.org/{owner}/{repo}/{refstring}?status={status}&task={task_id}",
+                          &vars,
+                          url_escape),
+                   "http://chat.example.org/brianloveswords/hookshot/feature%2Fthing?status=started&task=abc123");
+        assert_eq!(expand("http://example.org/{nope}/{repo}/{", &vars, url_escape),
+                   "http://example.org/{nope}/hookshot/{");
+        assert_eq!(expand(r#"{"text": "{repo} {refstring}: {status}"}"#, &vars, json_escape),
+                   r#"{"text": "hookshot feature/thing: started"}"#);
+    }
+
+    #[test]
+    fn test_renders_json() {
+        let placeholders = ["task_id", "attempt"];
+        let scalars = ["attempt"];
+        assert!(renders_json(r#"{"id": "{task_id}", "attempt": {attempt}}"#, &placeholders, &scalars));
+        assert!(renders_json(r#"{"text": "attempt {attempt} of {task_id}"}"#, &placeholders, &scalars));
+        // A uuid outside of quotes isn't JSON
+        assert!(!renders_json(r#"{"id": {task_id}}"#, &placeholders, &scalars));
+        assert!(!renders_json(r#"{"id": "{task_id}""#, &placeholders, &scalars));
+    }
+
+    #[test]
+    fn test_json_escape() {
+        assert_eq!(json_escape("plain"), "plain");
+        assert_eq!(json_escape("say \"hi\"\n"), "say \\\"hi\\\"\\n");
+    }
+}
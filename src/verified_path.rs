@@ -3,7 +3,7 @@ use std::fs;
 use std::path::Path;
 use std::string::ToString;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct VerifiedPath {
     path: String,
 }
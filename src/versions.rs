@@ -0,0 +1,106 @@
+//! Tags as versions, for tag sections with a range like
+//! `semver = ">=1.2, <2"`. The ranges are the `semver` crate's, which parses
+//! and matches them like Cargo does.
+//!
+//! Tags are read as versions with an optional leading `v`: `v1.2.3`, `1.2`
+//! and `2` all are, `release-5` isn't. Missing minor and patch numbers count
+//! as 0.
+
+use semver::{Version, VersionReq};
+
+/// The version a tag like `v1.2.3`, `1.2.3-rc.1` or `1.2` stands for.
+pub fn tag_version(tag: &str) -> Option<Version> {
+    let tag = match tag.starts_with('v') {
+        true => &tag[1..],
+        false => tag,
+    };
+    let end = tag.find(|c| c == '-' || c == '+').unwrap_or(tag.len());
+    let (numbers, rest) = tag.split_at(end);
+    let parts = numbers.split('.').collect::<Vec<&str>>();
+    if parts.len() > 3 || parts.iter().any(|part| part.is_empty() || !part.chars().all(|c| c.is_digit(10))) {
+        return None;
+    }
+    let padded = (parts.len()..3).fold(String::from(numbers), |s, _| s + ".0");
+    Version::parse(&format!("{}{}", padded, rest)).ok()
+}
+
+/// The releases a range mentions, with missing numbers as 0.
+fn mentioned(req: &VersionReq) -> Vec<Version> {
+    req.to_string()
+       .split(|c: char| !c.is_digit(10) && c != '.')
+       .filter_map(|token| {
+           let numbers = token.split('.')
+                              .take_while(|part| !part.is_empty())
+                              .map(|part| part.parse::<u64>().ok())
+                              .collect::<Option<Vec<u64>>>();
+           match numbers {
+               Some(ref numbers) if !numbers.is_empty() && numbers.len() <= 3 => {
+                   let number = |i: usize| numbers.get(i).cloned().unwrap_or(0);
+                   Some(Version::new(number(0), number(1), number(2)))
+               }
+               _ => None,
+           }
+       })
+       .collect()
+}
+
+/// Whether a release could be in both ranges. The `semver` crate can't
+/// intersect ranges, so this tries 0.0.0, the releases the ranges mention
+/// and the first ones after them; the lowest release in both ranges, if
+/// there is one, is among those.
+pub fn overlaps(a: &VersionReq, b: &VersionReq) -> bool {
+    let mut candidates = vec![Version::new(0, 0, 0)];
+    for version in mentioned(a).into_iter().chain(mentioned(b)) {
+        candidates.push(Version::new(version.major + 1, 0, 0));
+        candidates.push(Version::new(version.major, version.minor + 1, 0));
+        candidates.push(Version::new(version.major, version.minor, version.patch + 1));
+        candidates.push(version);
+    }
+    candidates.iter().any(|version| a.matches(version) && b.matches(version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{overlaps, tag_version};
+    use semver::{Version, VersionReq};
+
+    fn matches(req: &str, tag: &str) -> bool {
+        VersionReq::parse(req).unwrap().matches(&tag_version(tag).unwrap())
+    }
+
+    #[test]
+    fn test_tag_version() {
+        assert_eq!(tag_version("v1.2.3"), Version::parse("1.2.3").ok());
+        assert_eq!(tag_version("v1.2.3-rc.1+build.5"), Version::parse("1.2.3-rc.1+build.5").ok());
+        assert_eq!(tag_version("1.2"), Version::parse("1.2.0").ok());
+        assert_eq!(tag_version("2"), Version::parse("2.0.0").ok());
+        assert_eq!(tag_version("release-5"), None);
+        assert_eq!(tag_version("vv1.2.3"), None);
+        assert_eq!(tag_version("1.2.3.4"), None);
+        assert_eq!(tag_version("1..2"), None);
+        assert_eq!(tag_version(""), None);
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches(">=1.2, <2", "v1.2"));
+        assert!(!matches(">=1.2, <2", "v2"));
+        // Release candidates of 2.0.0 sort below it, but aren't 1.x
+        assert!(!matches(">=1, <2", "v2.0.0-rc.1"));
+        assert!(matches(">=2.0.0-rc.1, <2.0.1", "v2.0.0-rc.2"));
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let overlaps = |a: &str, b: &str| overlaps(&VersionReq::parse(a).unwrap(), &VersionReq::parse(b).unwrap());
+        assert!(!overlaps(">=1, <2", "^2"));
+        assert!(overlaps(">=1, <2", "^1.5"));
+        assert!(overlaps("<=2.0.0", "^2"));
+        assert!(!overlaps("<2.0.0", ">=2.0.0"));
+        assert!(!overlaps("~1.2", "~1.3"));
+        assert!(overlaps(">1", "=3"));
+        assert!(!overlaps(">1.2.3", "<1.2.4"));
+        assert!(overlaps("<1", "<2"));
+        assert!(overlaps("=1.2.3", "1.2"));
+    }
+}
@@ -0,0 +1,346 @@
+//! Housekeeping for the checkouts under `checkout_root`.
+//!
+//! Every deploy stamps its checkout with the time it was last used, in a file
+//! inside the checkout's `.git` directory so it survives resets and never
+//! shows up in the working tree. Cleanup removes checkouts that haven't been
+//! used in a while and, if there is a quota, the least recently used
+//! checkouts until everything fits.
+
+use git;
+use history;
+use openssl::crypto::hash::{self, Type};
+use rustc_serialize::hex::ToHex;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+const LAST_USED_FILE: &'static str = "hookshot-last-used";
+/// Longest checkout directory name, what most filesystems allow.
+const MAX_NAME_LEN: usize = 255;
+/// Hex digits of the hash that ends a checkout name that had to be cut
+/// short.
+const NAME_HASH_LEN: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct Checkout {
+    pub path: PathBuf,
+    /// Unix timestamp (seconds) of the last deploy that used the checkout.
+    pub last_used: i64,
+    /// Size on disk in bytes.
+    pub size: u64,
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CleanupReport {
+    /// Checkouts that were removed.
+    pub removed: Vec<String>,
+    /// Bytes freed by removing them.
+    pub freed_bytes: u64,
+    /// Bytes used by the checkouts that are left.
+    pub total_bytes: u64,
+}
+
+/// What to do when a repo's checkout is bigger than its quota after a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaAction {
+    /// Fail the task, so the problem gets noticed.
+    Fail,
+    /// Remove the checkout, the next task starts with a fresh clone.
+    Clean,
+}
+
+impl QuotaAction {
+    pub fn from_str(s: &str) -> Option<QuotaAction> {
+        match s {
+            "fail" => Some(QuotaAction::Fail),
+            "clean" => Some(QuotaAction::Clean),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            QuotaAction::Fail => "fail",
+            QuotaAction::Clean => "clean",
+        }
+    }
+}
+
+/// A limit on the size of one repo's checkout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quota {
+    pub bytes: u64,
+    pub action: QuotaAction,
+}
+
+/// Where the checkout of a ref lives: a directory under the checkout root
+/// named `owner.name.ref`, with owner and repo names in lowercase and the ref
+/// in Unicode NFC.
+///
+/// The name is made safe to use as a single path component. Dots in the
+/// owner and repo name, slashes, backslashes and control characters become
+/// `!`, which GitHub names can't contain, so the name can't escape the root
+/// or be split by `.` in more than one way. Names too
+/// long for the filesystem are cut short and end in a hash of the whole name
+/// instead, so different refs still get different checkouts. This is
+/// intended to prevent accidents, not malicious behavior -- that's what the
+/// signature is (hopefully) for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckoutPath(PathBuf);
+
+impl CheckoutPath {
+    pub fn new(root: &Path, owner: &str, name: &str, refstring: &str) -> CheckoutPath {
+        let refstring = refstring.nfc().collect::<String>();
+        let name = format!("{}.{}.{}",
+                           git::normalize_name(owner).replace(".", "!"),
+                           git::normalize_name(name).replace(".", "!"),
+                           refstring);
+        CheckoutPath(root.join(safe_name(&name)))
+    }
+
+    /// The checkout of one project of a monorepo, next to the repo's own
+    /// and ending in `~project`, which no ref can.
+    pub fn for_project(root: &Path, owner: &str, name: &str, refstring: &str, project: &str) -> CheckoutPath {
+        let refstring = format!("{}~{}", refstring, project);
+        CheckoutPath::new(root, owner, name, &refstring)
+    }
+
+    pub fn as_path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn into_path_buf(self) -> PathBuf {
+        self.0
+    }
+}
+
+fn safe_name(name: &str) -> String {
+    let safe = name.chars()
+                   .map(|c| match c {
+                       '/' | '\\' => '!',
+                       c if c.is_control() => '!',
+                       c => c,
+                   })
+                   .collect::<String>();
+    if safe.len() <= MAX_NAME_LEN {
+        return safe;
+    }
+    let mut end = MAX_NAME_LEN - NAME_HASH_LEN - 1;
+    while !safe.is_char_boundary(end) {
+        end -= 1;
+    }
+    let digest = hash::hash(Type::SHA1, name.as_bytes()).to_hex();
+    format!("{}-{}", &safe[..end], &digest[..NAME_HASH_LEN])
+}
+
+/// Record that a checkout was just used.
+#[allow(unused_must_use)]
+pub fn touch(checkout: &Path) {
+    if let Ok(mut file) = File::create(checkout.join(".git").join(LAST_USED_FILE)) {
+        file.write_all(format!("{}", history::now()).as_bytes());
+    }
+}
+
+/// When a checkout was last used. Checkouts that have never been stamped fall
+/// back to the modification time of the directory.
+fn last_used(checkout: &Path) -> i64 {
+    let mut contents = String::new();
+    if let Ok(mut file) = File::open(checkout.join(".git").join(LAST_USED_FILE)) {
+        if file.read_to_string(&mut contents).is_ok() {
+            if let Ok(timestamp) = contents.trim().parse::<i64>() {
+                return timestamp;
+            }
+        }
+    }
+    match fs::metadata(checkout) {
+        Ok(metadata) => metadata.mtime(),
+        Err(_) => 0,
+    }
+}
+
+/// Total size of everything under `path`. Symlinks are counted as themselves,
+/// never followed.
+pub fn dir_size(path: &Path) -> u64 {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return 0,
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries.filter_map(|entry| entry.ok())
+           .map(|entry| dir_size(&entry.path()))
+           .fold(metadata.len(), |total, size| total + size)
+}
+
+/// Every checkout directory under `root`.
+pub fn scan(root: &Path) -> Vec<Checkout> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    entries.filter_map(|entry| entry.ok())
+           .map(|entry| entry.path())
+           .filter(|path| path.is_dir())
+           .map(|path| {
+               Checkout {
+                   last_used: last_used(&path),
+                   size: dir_size(&path),
+                   path: path,
+               }
+           })
+           .collect()
+}
+
+/// Remove checkouts under `root` that haven't been used in `max_age` seconds,
+/// then remove the least recently used checkouts until the rest fit in `quota`
+/// bytes. Every checkout that should go is handed to `remove`, which returns
+/// whether it removed it, see `remove_unless_busy`. Checkouts it keeps stay
+/// even if that means going over the quota.
+pub fn cleanup<F>(root: &Path, max_age: Option<i64>, quota: Option<u64>, mut remove: F) -> CleanupReport
+    where F: FnMut(&Path) -> bool
+{
+    let now = history::now();
+    let mut checkouts = scan(root);
+    // Least recently used first
+    checkouts.sort_by(|a, b| a.last_used.cmp(&b.last_used));
+
+    let mut report = CleanupReport::default();
+    report.total_bytes = checkouts.iter().fold(0, |total, c| total + c.size);
+
+    for checkout in checkouts {
+        let expired = match max_age {
+            Some(max_age) => now - checkout.last_used > max_age,
+            None => false,
+        };
+        let over_quota = match quota {
+            Some(quota) => report.total_bytes > quota,
+            None => false,
+        };
+        if !expired && !over_quota {
+            continue;
+        }
+        if !remove(&checkout.path) {
+            continue;
+        }
+        report.removed.push(checkout.path.to_string_lossy().into_owned());
+        report.freed_bytes += checkout.size;
+        report.total_bytes -= checkout.size;
+    }
+    report
+}
+
+/// Remove the checkout at `path` unless it's in `in_use`. Returns whether it
+/// was removed. Whatever decides what is in use has to stay locked until
+/// this returns, or a task could start using the checkout as it's removed.
+pub fn remove_unless_busy(path: &Path, in_use: &BTreeSet<PathBuf>) -> bool {
+    !in_use.contains(path) && fs::remove_dir_all(path).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    fn checkout(root: &Path, name: &str, last_used: i64, bytes: usize) {
+        let path = root.join(name);
+        fs::create_dir_all(path.join(".git")).unwrap();
+        let mut file = File::create(path.join("data")).unwrap();
+        file.write_all(&vec![0u8; bytes]).unwrap();
+        let mut stamp = File::create(path.join(".git").join("hookshot-last-used")).unwrap();
+        stamp.write_all(format!("{}", last_used).as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_checkout_path() {
+        let root = Path::new("/tmp");
+        assert_eq!(CheckoutPath::new(root, "owner", "repo", "feature/thing").as_path(),
+                   Path::new("/tmp/owner.repo.feature!thing"));
+        // GitHub names are case insensitive, refs aren't
+        assert_eq!(CheckoutPath::new(root, "Owner", "Repo", "Feature/thing").as_path(),
+                   Path::new("/tmp/owner.repo.Feature!thing"));
+        // Refs are deployed from the same checkout however their unicode is
+        // composed, and spaces and deeper slashes stay in the one component
+        assert_eq!(CheckoutPath::new(root, "owner", "repo", "feature/an\u{303}adir soporte/v2").as_path(),
+                   Path::new("/tmp/owner.repo.feature!a\u{f1}adir soporte!v2"));
+        assert_eq!(CheckoutPath::new(root, "$", "repo", "../..\\x\ny").as_path(),
+                   Path::new("/tmp/$.repo..!..!x!y"));
+        assert_eq!(CheckoutPath::new(root, "a.b", "c", "d").as_path(),
+                   Path::new("/tmp/a!b.c.d"));
+        // Dots in repo names don't run into the ref
+        assert_eq!(CheckoutPath::new(root, "o", "socket.io", "master").as_path(),
+                   Path::new("/tmp/o.socket!io.master"));
+        assert!(CheckoutPath::new(root, "o", "a.b", "c") != CheckoutPath::new(root, "o", "a", "b.c"));
+        assert_eq!(CheckoutPath::for_project(root, "owner", "repo", "feature/thing", "api").as_path(),
+                   Path::new("/tmp/owner.repo.feature!thing~api"));
+    }
+
+    #[test]
+    fn test_checkout_path_too_long() {
+        let root = Path::new("/tmp");
+        let refstring = (0..200).map(|_| "\u{f1}").collect::<String>();
+        let long = CheckoutPath::new(root, "owner", "repo", &refstring);
+        let other = CheckoutPath::new(root, "owner", "repo", &format!("{}x", refstring));
+        let name = long.as_path().file_name().unwrap().to_str().unwrap().to_owned();
+        assert!(name.len() <= 255);
+        assert!(name.starts_with("owner.repo.\u{f1}"));
+        assert!(long != other);
+        assert_eq!(long.as_path().parent(), Some(root));
+    }
+
+    #[test]
+    fn test_touch() {
+        let root = TempDir::new("hookshot-workspace-test").unwrap();
+        checkout(root.path(), "a", 0, 0);
+        touch(&root.path().join("a"));
+        let checkouts = scan(root.path());
+        assert_eq!(checkouts.len(), 1);
+        assert!(checkouts[0].last_used > 0);
+    }
+
+    #[test]
+    fn test_cleanup_max_age() {
+        let root = TempDir::new("hookshot-workspace-test").unwrap();
+        let now = ::history::now();
+        checkout(root.path(), "old", now - 1000, 10);
+        checkout(root.path(), "new", now, 10);
+
+        let report = cleanup(root.path(), Some(500), None, |path| remove_unless_busy(path, &BTreeSet::new()));
+        assert_eq!(report.removed.len(), 1);
+        assert!(report.removed[0].ends_with("old"));
+        assert!(!root.path().join("old").exists());
+        assert!(root.path().join("new").exists());
+    }
+
+    #[test]
+    fn test_cleanup_quota() {
+        let root = TempDir::new("hookshot-workspace-test").unwrap();
+        let now = ::history::now();
+        checkout(root.path(), "oldest", now - 30, 10000);
+        checkout(root.path(), "older", now - 20, 10000);
+        checkout(root.path(), "newest", now - 10, 10000);
+
+        let mut in_use = BTreeSet::new();
+        in_use.insert(root.path().join("oldest"));
+
+        // Just over the quota, but the oldest checkout is busy
+        let total = scan(root.path()).iter().fold(0, |total, c| total + c.size);
+        let report = cleanup(root.path(), None, Some(total - 1), |path| remove_unless_busy(path, &in_use));
+        assert_eq!(report.removed.len(), 1);
+        assert!(report.removed[0].ends_with("older"));
+        assert!(root.path().join("oldest").exists());
+        assert!(root.path().join("newest").exists());
+        assert!(report.freed_bytes >= 10000);
+    }
+}